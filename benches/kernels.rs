@@ -0,0 +1,78 @@
+// kernels.rs
+//
+// Times a batch update of the Kinship matrix by every kernel (and the CPU one
+// in single precision) against the reference calc_partial_kinship loop:
+// cargo bench --bench kernels
+
+use std::time::{Duration, Instant};
+
+use rqtl2::kinship::{
+  available_kernels, calc_partial_kinship, calc_partial_kinship_tiled, kernel_by_name,
+};
+
+/// @brief Deterministic genotype codes (0, 0.5, 1) of rows x ids_num.
+fn genotypes(rows: usize, ids_num: usize) -> Vec<f64> {
+  let mut state = 0x2545_f491_4f6c_dd1d_u64;
+  (0..rows * ids_num)
+    .map(|_| {
+      state ^= state << 13;
+      state ^= state >> 7;
+      state ^= state << 17;
+      (state % 3) as f64 / 2.0
+    })
+    .collect()
+}
+
+/// @brief Best of a few runs of update.
+fn time<T: Clone + Default, F: FnMut(&mut [T])>(ids_num: usize, mut update: F) -> Duration {
+  let mut matrix = vec![T::default(); ids_num * ids_num];
+  (0..5)
+    .map(|_| {
+      let started = Instant::now();
+      update(&mut matrix);
+      started.elapsed()
+    })
+    .min()
+    .unwrap_or_default()
+}
+
+fn main() {
+  println!("{:>8} {:>6} {:>10} {:>12} {:>8}", "ids", "rows", "kernel", "time, ms", "speedup");
+  for (ids_num, rows) in [(256, 256), (1000, 256), (2000, 512)] {
+    let snps = genotypes(rows, ids_num);
+    let reference =
+      time(ids_num, |matrix: &mut [f64]| calc_partial_kinship(&snps, matrix, ids_num));
+    println!(
+      "{:>8} {:>6} {:>10} {:>12.2} {:>8.2}",
+      ids_num,
+      rows,
+      "reference",
+      reference.as_secs_f64() * 1e3,
+      1.0
+    );
+    let mut timings = available_kernels()
+      .into_iter()
+      .map(|name| {
+        let kernel = kernel_by_name(name).unwrap();
+        let elapsed =
+          time(ids_num, |matrix: &mut [f64]| kernel.update(&snps, matrix, ids_num).unwrap());
+        (name, elapsed)
+      })
+      .collect::<Vec<(&str, Duration)>>();
+    let single = snps.iter().map(|value| *value as f32).collect::<Vec<f32>>();
+    let elapsed = time(ids_num, |matrix: &mut [f32]| {
+      calc_partial_kinship_tiled(&single, matrix, ids_num)
+    });
+    timings.push(("cpu f32", elapsed));
+    for (name, elapsed) in timings {
+      println!(
+        "{:>8} {:>6} {:>10} {:>12.2} {:>8.2}",
+        ids_num,
+        rows,
+        name,
+        elapsed.as_secs_f64() * 1e3,
+        reference.as_secs_f64() / elapsed.as_secs_f64()
+      );
+    }
+  }
+}
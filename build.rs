@@ -0,0 +1,12 @@
+// build.rs
+
+fn main() {
+  println!("cargo:rerun-if-changed=build.rs");
+  println!("cargo:rerun-if-env-changed=RQTL2_BLAS_LIB");
+  if std::env::var_os("CARGO_FEATURE_BLAS").is_some() {
+    let lib = std::env::var("RQTL2_BLAS_LIB").unwrap_or_else(|_| String::from("openblas"));
+    println!("cargo:rustc-link-lib={}", lib);
+    // Reported by environment::report.
+    println!("cargo:rustc-env=RQTL2_BLAS_LINKED={}", lib);
+  }
+}
@@ -0,0 +1,39 @@
+// heritability.rs
+//
+// Kinship matrix to REML heritability of a phenotype adjusted for sex, on the
+// bundled synthetic F2 cross:
+//
+//   cargo run --example heritability
+
+use std::path::Path;
+
+use rqtl2::io::{na, ControlFile};
+use rqtl2::kinship::KinshipOptions;
+use rqtl2::lmm::{heritability, DesignBuilder};
+
+fn main() -> std::io::Result<()> {
+  let control =
+    ControlFile::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/data/f2.yaml"))?;
+  let mut geno = control.geno_parser()?;
+  let kinship = geno.calc_kinship_matrix(&KinshipOptions::default())?;
+  let ids = kinship.ids().to_vec();
+
+  // Phenotypes and covariates are put in the order of the genotype file.
+  let pheno = control.pheno_parser()?.read_matrix()?;
+  let covar = control.table("covar")?;
+  let design = DesignBuilder::new(&covar).factor("sex").build(&ids)?;
+  for name in &pheno.phenotypes {
+    let values = pheno.column(name).unwrap_or_default();
+    let values = ids
+      .iter()
+      .map(|id| pheno.ids.iter().position(|other| other == id).map_or(na::NA, |i| values[i]))
+      .collect::<Vec<f64>>();
+    let estimate = heritability(kinship.as_slice(), &values, Some(design.as_covariates()))?;
+    println!(
+      "{}: h2 = {:.3} (se {:.3}), REML log-likelihood {:.3}, covariates {:?}",
+      name, estimate.h2, estimate.se, estimate.loglik, design.columns
+    );
+    assert!((0.0..=1.0).contains(&estimate.h2));
+  }
+  Ok(())
+}
@@ -0,0 +1,52 @@
+// kinship.rs
+//
+// Genotypes to Kinship matrix on the bundled synthetic F2 cross, exported in
+// GEMMA and CSV formats:
+//
+//   cargo run --example kinship [output dir]
+
+use std::path::{Path, PathBuf};
+
+use rqtl2::io::ControlFile;
+use rqtl2::kinship::{KinshipKind, KinshipOptions};
+
+/// @brief Bundled dataset, see examples/data/f2.yaml.
+fn control_file() -> std::io::Result<ControlFile> {
+  ControlFile::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/data/f2.yaml"))
+}
+
+/// @brief Directory given as the first argument, a temporary one otherwise.
+fn output_dir() -> std::io::Result<PathBuf> {
+  let dir = match std::env::args().nth(1) {
+    Some(dir) => PathBuf::from(dir),
+    None => std::env::temp_dir().join("rqtl2_examples"),
+  };
+  std::fs::create_dir_all(&dir)?;
+  Ok(dir)
+}
+
+fn main() -> std::io::Result<()> {
+  let control = control_file()?;
+  let mut geno = control.geno_parser()?;
+  for warning in geno.get_warnings() {
+    eprintln!("warning: {}", warning);
+  }
+
+  let options = KinshipOptions {
+    kind: KinshipKind::Centered,
+    ..KinshipOptions::default()
+  };
+  let (kinship, summary) = geno.calc_kinship_summarized(&options)?;
+  println!("{}", summary);
+  let kinship = rqtl2::kinship::KinshipMatrix::new(geno.get_markers().clone(), kinship)?;
+  let ids_num = kinship.ids_num();
+  let diagonal = (0..ids_num).map(|i| kinship.as_slice()[i * ids_num + i]).sum::<f64>();
+  println!("{} individuals, mean self-relatedness {:.4}", ids_num, diagonal / ids_num as f64);
+  assert_eq!(summary.markers_read, summary.markers_used);
+
+  let dir = output_dir()?;
+  kinship.write_gemma(dir.join("kinship.gemma.txt"))?;
+  kinship.write_csv(dir.join("kinship.csv"))?;
+  println!("Kinship matrix written to {}", dir.display());
+  Ok(())
+}
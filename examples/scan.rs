@@ -0,0 +1,125 @@
+// scan.rs
+//
+// Genome scan of the bundled synthetic F2 cross with leave-one-chromosome-out
+// Kinship matrices: markers of every chromosome are tested against the null
+// model fitted with the Kinship matrix of the other chromosomes, at the h2 of
+// that model (as R/qtl2 scan1 with kinship from calc_kinship(type = "loco")).
+// LOD scores are exported as CSV:
+//
+//   cargo run --example scan [output dir]
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use rqtl2::io::ControlFile;
+use rqtl2::kinship::KinshipOptions;
+use rqtl2::lmm::{DesignBuilder, Lmm};
+
+/// @brief Residual sum of squares of weighted least squares of y on the
+/// columns of x (row-major, cols_num columns).
+fn weighted_rss(x: &[f64], cols_num: usize, y: &[f64], weights: &[f64]) -> f64 {
+  // Normal equations X.T * W * X * beta = X.T * W * y, solved by Gaussian
+  // elimination with partial pivoting.
+  let mut a = vec![0.0; cols_num * (cols_num + 1)];
+  for (k, (row, w)) in x.chunks(cols_num).zip(weights).enumerate() {
+    for i in 0..cols_num {
+      for j in 0..cols_num {
+        a[i * (cols_num + 1) + j] += w * row[i] * row[j];
+      }
+      a[i * (cols_num + 1) + cols_num] += w * row[i] * y[k];
+    }
+  }
+  let width = cols_num + 1;
+  for col in 0..cols_num {
+    let pivot = (col..cols_num)
+      .max_by(|p, q| a[p * width + col].abs().total_cmp(&a[q * width + col].abs()))
+      .unwrap_or(col);
+    for j in 0..width {
+      a.swap(col * width + j, pivot * width + j);
+    }
+    for row in (0..cols_num).filter(|row| *row != col) {
+      let factor = a[row * width + col] / a[col * width + col];
+      for j in col..width {
+        a[row * width + j] -= factor * a[col * width + j];
+      }
+    }
+  }
+  let beta =
+    (0..cols_num).map(|i| a[i * width + cols_num] / a[i * width + i]).collect::<Vec<f64>>();
+  x.chunks(cols_num)
+    .zip(y)
+    .zip(weights)
+    .map(|((row, y), w)| {
+      let residual = y - row.iter().zip(&beta).map(|(x, b)| x * b).sum::<f64>();
+      w * residual * residual
+    })
+    .sum()
+}
+
+fn main() -> std::io::Result<()> {
+  let control =
+    ControlFile::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/data/f2.yaml"))?;
+  let mut geno = control.geno_parser()?;
+  let gmap = control.gmap()?;
+  let ids = geno.get_markers().clone();
+  let ids_num = ids.len();
+
+  let pheno = control.pheno_parser()?.read_matrix()?;
+  let name = &pheno.phenotypes[0];
+  let values = pheno.column(name).unwrap_or_default();
+  let y = ids
+    .iter()
+    .map(|id| pheno.ids.iter().position(|other| other == id).map(|i| values[i]))
+    .collect::<Option<Vec<f64>>>()
+    .expect("Every genotyped individual has a phenotype.");
+  let design = DesignBuilder::new(&control.table("covar")?).factor("sex").build(&ids)?;
+
+  let loco = geno.calc_kinship_loco(&gmap.chromosomes(), &KinshipOptions::default())?;
+  let genotypes = geno.read_all()?.into_iter().collect::<HashMap<String, Vec<f64>>>();
+
+  let dir = match std::env::args().nth(1) {
+    Some(dir) => PathBuf::from(dir),
+    None => std::env::temp_dir().join("rqtl2_examples"),
+  };
+  std::fs::create_dir_all(&dir)?;
+  let mut writer = BufWriter::new(File::create(dir.join("scan.csv"))?);
+  writeln!(writer, "marker,chr,pos,{}", name)?;
+  let mut peak = (String::new(), f64::NEG_INFINITY);
+  for (chr, markers) in gmap.by_chromosome() {
+    let lmm = Lmm::new(&loco[chr], ids_num, Some(design.as_covariates()))?;
+    let null = lmm.fit_null(&y)?;
+    println!("chr {}: h2 = {:.3} without its markers", chr, null.h2);
+    let eigen = lmm.eigen();
+    let weights = eigen
+      .values
+      .iter()
+      .map(|value| 1.0 / (null.h2 * value.max(0.0) + 1.0 - null.h2))
+      .collect::<Vec<f64>>();
+    let covariates_num = design.cols_num();
+    let rss0 = weighted_rss(lmm.rotated_covariates(), covariates_num, &null.rotated, &weights);
+    for marker in markers {
+      let snps = match genotypes.get(&marker.id) {
+        Some(snps) => snps,
+        None => continue,
+      };
+      // Covariates and the marker rotated by U.T, a row per eigenvector.
+      let mut x = Vec::with_capacity(ids_num * (covariates_num + 1));
+      for (k, covariates) in lmm.rotated_covariates().chunks(covariates_num).enumerate() {
+        x.extend_from_slice(covariates);
+        x.push((0..ids_num).map(|i| eigen.vectors[i * ids_num + k] * snps[i]).sum::<f64>());
+      }
+      let rss1 = weighted_rss(&x, covariates_num + 1, &null.rotated, &weights);
+      let lod = ids_num as f64 / 2.0 * (rss0 / rss1).log10();
+      writeln!(writer, "{},{},{},{:.4}", marker.id, chr, marker.position, lod)?;
+      if lod > peak.1 {
+        peak = (marker.id.clone(), lod);
+      }
+    }
+  }
+  writer.flush()?;
+  println!("Peak of {} at {} (LOD {:.2}), scan written to {}", name, peak.0, peak.1, dir.display());
+  assert_eq!(Some("2"), gmap.chromosome(&peak.0));
+  Ok(())
+}
@@ -0,0 +1,459 @@
+// rqtl2-kinship.rs
+//
+// Command line Kinship matrix calculation for genetics pipelines:
+//
+//   rqtl2-kinship [options] <geno file | control file>
+//   rqtl2-kinship info [<individuals | geno file | control file>]
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rqtl2::error::{Error, ErrorFormat};
+use rqtl2::io::{ControlFile, GeneticMap, GenoStream, MapParser, NumberFormat};
+use rqtl2::kinship::{
+  available_kernels, calc_kinship_loco, kernel_by_name, select_backend, Backend, CpuLimit,
+  KinshipMatrix, KinshipOptions, MissingPolicy, ProgressHook,
+};
+use rqtl2::util::environment;
+use rqtl2::util::na;
+use rqtl2::util::GenoParser;
+
+const USAGE: &str = "\
+Usage: rqtl2-kinship [options] <geno file | control file>
+       rqtl2-kinship info [<individuals | geno file | control file>]
+
+Calculates the Kinship matrix of an R/qtl2 genotype file, or of the geno file
+of a control file (.yaml or .json). Input - reads the genotype file from
+standard input.
+
+The info command prints the CPU, GPU and memory found on the machine and the
+options recommended for the amount of individuals, or the individuals of a
+genotype file (1000 by default).
+
+Options:
+  -o, --output <path>        Output file, standard output if not given. The
+                             output directory with --loco.
+  -f, --format <format>      gemma (default) or csv.
+      --numbers <spec>       full (default), fixed:<digits>, sci:<digits> or
+                             sig:<digits>.
+  -c, --codes <mapping>      Genotype codes of a geno file, A=0,H=0.5,B=1 by
+                             default. NA marks a missing genotype code, e.g.
+                             -=NA. Control files define them in genotypes.
+  -b, --batch-size <lines>   SNP lines processed at once, 1000 by default.
+  -t, --threads <n>          Worker threads, all logical cores by default.
+      --max-cpu <limit>      CPU usage limit, e.g. 50% or 0.5.
+      --deterministic        Bit-identical results from run to run.
+      --compensated          Compensated summation, more accurate for many
+                             SNP lines at about twice the cost. Not with
+                             --loco.
+      --missing <policy>     Missing genotypes: reject (default), skip or mean.
+      --loco                 Leave-one-chromosome-out matrices, a file per
+                             chromosome in the output directory.
+      --gmap <path>          Genetic map for --loco, the gmap of the control
+                             file by default.
+      --kernel <name>        Batch update kernel, see --version.
+      --backend <name>       cpu (default), gpu or auto: the GPU when it's
+                             usable, the CPU otherwise. The GPU needs a build
+                             with the cuda feature.
+      --gpu                  Same as --backend gpu, fails if there is no
+                             usable GPU.
+      --progress             Reports progress on standard error.
+      --error-format <fmt>   text (default) or json.
+  -h, --help                 Prints this help.
+  -V, --version              Prints version and available kernels.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+  Gemma,
+  Csv,
+}
+
+#[derive(Debug)]
+struct Args {
+  input: PathBuf,
+  output: Option<PathBuf>,
+  format: Format,
+  numbers: NumberFormat,
+  codes: Option<HashMap<char, f64>>,
+  batch_size: usize,
+  threads: Option<usize>,
+  max_cpu: Option<CpuLimit>,
+  deterministic: bool,
+  compensated: bool,
+  missing: MissingPolicy,
+  loco: bool,
+  gmap: Option<PathBuf>,
+  kernel: Option<String>,
+  backend: Backend,
+  progress: bool,
+  error_format: ErrorFormat,
+}
+
+/// @brief Parses the command line (without the program name). Returns None
+/// if it only asked for help or version, which are printed.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Args>, String> {
+  let mut parsed = Args {
+    input: PathBuf::new(),
+    output: None,
+    format: Format::Gemma,
+    numbers: NumberFormat::default(),
+    codes: None,
+    batch_size: KinshipOptions::default().batch_size,
+    threads: None,
+    max_cpu: None,
+    deterministic: false,
+    compensated: false,
+    missing: MissingPolicy::Reject,
+    loco: false,
+    gmap: None,
+    kernel: None,
+    backend: Backend::Cpu,
+    progress: false,
+    error_format: ErrorFormat::Text,
+  };
+  let mut input = None;
+  while let Some(arg) = args.next() {
+    // Both "--option value" and "--option=value" are accepted.
+    let (name, inline) = match arg.split_once('=') {
+      Some((name, value)) if name.starts_with("--") => (String::from(name), Some(value.into())),
+      _ => (arg.clone(), None),
+    };
+    let mut value = || -> Result<String, String> {
+      inline.clone().or_else(|| args.next()).ok_or(format!("{} requires a value", name))
+    };
+    let invalid = |value: &str| format!("invalid value <{}> of {}", value, name);
+    match name.as_str() {
+      "-h" | "--help" => {
+        println!("{}", USAGE);
+        return Ok(None);
+      }
+      "-V" | "--version" => {
+        println!("rqtl2-kinship {}", env!("CARGO_PKG_VERSION"));
+        println!("kernels: {}", available_kernels().join(", "));
+        return Ok(None);
+      }
+      "-o" | "--output" => parsed.output = Some(PathBuf::from(value()?)),
+      "-f" | "--format" => {
+        parsed.format = match value()?.as_str() {
+          "gemma" => Format::Gemma,
+          "csv" => Format::Csv,
+          other => return Err(invalid(other)),
+        }
+      }
+      "--numbers" => {
+        let spec = value()?;
+        parsed.numbers = NumberFormat::parse(&spec).ok_or_else(|| invalid(&spec))?;
+      }
+      "-c" | "--codes" => parsed.codes = Some(parse_codes(&value()?)?),
+      "-b" | "--batch-size" => {
+        let lines = value()?;
+        parsed.batch_size = match lines.parse::<usize>() {
+          Ok(lines) if lines > 0 => lines,
+          _ => return Err(invalid(&lines)),
+        }
+      }
+      "-t" | "--threads" => {
+        let threads = value()?;
+        parsed.threads = Some(threads.parse::<usize>().map_err(|_| invalid(&threads))?);
+      }
+      "--max-cpu" => {
+        let limit = value()?;
+        parsed.max_cpu = Some(CpuLimit::parse(&limit).ok_or_else(|| invalid(&limit))?);
+      }
+      "--missing" => {
+        parsed.missing = match value()?.as_str() {
+          "reject" => MissingPolicy::Reject,
+          "skip" => MissingPolicy::SkipMarker,
+          "mean" => MissingPolicy::MeanImpute,
+          other => return Err(invalid(other)),
+        }
+      }
+      "--deterministic" => parsed.deterministic = true,
+      "--compensated" => parsed.compensated = true,
+      "--loco" => parsed.loco = true,
+      "--gmap" => parsed.gmap = Some(PathBuf::from(value()?)),
+      "--kernel" => parsed.kernel = Some(value()?),
+      "--backend" => {
+        let name = value()?;
+        parsed.backend = Backend::parse(&name).ok_or_else(|| invalid(&name))?;
+      }
+      "--gpu" => parsed.backend = Backend::Gpu,
+      "--progress" => parsed.progress = true,
+      "--error-format" => {
+        let format = value()?;
+        parsed.error_format = ErrorFormat::parse(&format).ok_or_else(|| invalid(&format))?;
+      }
+      _ if name.starts_with('-') && name.len() > 1 => {
+        return Err(format!("unknown option {}", name));
+      }
+      _ if input.is_none() => input = Some(PathBuf::from(arg)),
+      _ => return Err(format!("unexpected argument {}", arg)),
+    }
+  }
+  parsed.input = input.ok_or("no input file given")?;
+  Ok(Some(parsed))
+}
+
+/// @brief Parses genotype codes like "A=0,H=0.5,B=1", value NA marks the
+/// missing genotype codes.
+fn parse_codes(mapping: &str) -> Result<HashMap<char, f64>, String> {
+  let invalid = || format!("invalid genotype codes <{}>", mapping);
+  let mut codes = HashMap::new();
+  for entry in mapping.split(',') {
+    let (code, value) = entry.split_once('=').ok_or_else(invalid)?;
+    let mut chars = code.trim().chars();
+    let code = match (chars.next(), chars.next()) {
+      (Some(code), None) => code,
+      _ => return Err(invalid()),
+    };
+    let value = match value.trim() {
+      "NA" => na::NA,
+      value => value.parse::<f64>().map_err(|_| invalid())?,
+    };
+    codes.insert(code, value);
+  }
+  Ok(codes)
+}
+
+fn default_codes() -> HashMap<char, f64> {
+  HashMap::from([('A', 0.0), ('H', 0.5), ('B', 1.0)])
+}
+
+fn is_control_file(path: &Path) -> bool {
+  let extension = path.extension().and_then(|extension| extension.to_str());
+  matches!(extension, Some("yaml") | Some("yml") | Some("json"))
+}
+
+fn kinship_options(args: &Args) -> std::io::Result<KinshipOptions> {
+  let mut options = KinshipOptions {
+    batch_size: args.batch_size,
+    threads: args.threads,
+    max_cpu: args.max_cpu,
+    deterministic: args.deterministic,
+    compensated: args.compensated,
+    missing: args.missing,
+    ..KinshipOptions::default()
+  };
+  if let Some(name) = &args.kernel {
+    let available = available_kernels().join(", ");
+    options.kernel = Some(kernel_by_name(name).ok_or_else(|| {
+      Error::Validation(format!("Unknown kernel <{}>, available: {}.", name, available))
+    })?);
+  }
+  if args.progress {
+    options.progress = Some(ProgressHook::new(|progress| {
+      let mut stderr = std::io::stderr();
+      let _ = match progress.fraction() {
+        Some(fraction) => write!(
+          stderr,
+          "\r{} markers ({:.0}%)",
+          progress.markers,
+          fraction * 100.0
+        ),
+        None => write!(stderr, "\r{} markers", progress.markers),
+      };
+      if progress.finished {
+        let _ = writeln!(stderr);
+      }
+    }));
+  }
+  Ok(options)
+}
+
+/// @brief Sets the GPU kernel when --backend selects the GPU for ids_num
+/// individuals, reports why auto falls back to the CPU.
+fn apply_backend(args: &Args, ids_num: usize, options: &mut KinshipOptions) -> std::io::Result<()> {
+  if args.backend == Backend::Cpu {
+    return Ok(());
+  }
+  let choice = select_backend(args.backend, ids_num, options, &environment::report())?;
+  if let Some(reason) = &choice.fallback {
+    eprintln!("note: calculating on the CPU, GPU is unusable: {}", reason);
+  }
+  if choice.is_gpu() {
+    options.kernel = choice.kernel;
+  }
+  Ok(())
+}
+
+fn write_matrix<W: Write>(
+  matrix: &KinshipMatrix,
+  args: &Args,
+  writer: &mut W,
+) -> std::io::Result<()> {
+  match args.format {
+    Format::Gemma => matrix.write_gemma_to(writer, &args.numbers)?,
+    Format::Csv => matrix.write_csv_to(writer, &args.numbers)?,
+  }
+  writer.flush()
+}
+
+/// @brief Genetic map for --loco: the --gmap one, the gmap of control
+/// otherwise.
+fn loco_map(args: &Args, control: Option<&ControlFile>) -> std::io::Result<GeneticMap> {
+  match (&args.gmap, control) {
+    (Some(path), _) => MapParser::new().read(path),
+    (None, Some(control)) => control.gmap(),
+    (None, None) => {
+      Err(Error::Validation(String::from("--loco requires a genetic map, see --gmap.")).into())
+    }
+  }
+}
+
+fn write_output(matrix: &KinshipMatrix, args: &Args) -> std::io::Result<()> {
+  match &args.output {
+    Some(path) => write_matrix(matrix, args, &mut BufWriter::new(File::create(path)?)),
+    None => write_matrix(matrix, args, &mut std::io::stdout().lock()),
+  }
+}
+
+/// @brief Writes a file per chromosome of gmap to the output directory.
+fn write_loco(
+  loco: &HashMap<String, Vec<f64>>,
+  gmap: &GeneticMap,
+  ids: &[String],
+  args: &Args,
+) -> std::io::Result<()> {
+  let dir = args.output.clone().unwrap_or_else(|| PathBuf::from("."));
+  std::fs::create_dir_all(&dir)?;
+  let extension = match args.format {
+    Format::Gemma => "txt",
+    Format::Csv => "csv",
+  };
+  for chr in gmap.chromosome_names() {
+    if let Some(matrix) = loco.get(chr) {
+      let matrix = KinshipMatrix::new(ids.to_vec(), matrix.clone())?;
+      let path = dir.join(format!("kinship_loco_{}.{}", chr, extension));
+      write_matrix(&matrix, args, &mut BufWriter::new(File::create(path)?))?;
+    }
+  }
+  Ok(())
+}
+
+/// @brief Genotypes streamed from standard input (input "-"), in a single
+/// pass.
+fn run_stdin(args: &Args, mut options: KinshipOptions) -> std::io::Result<()> {
+  let codes = args.codes.clone().unwrap_or_else(default_codes);
+  let mut stream = GenoStream::new(std::io::stdin().lock(), codes)?;
+  apply_backend(args, stream.get_markers().len(), &mut options)?;
+  let options = &options;
+  if args.loco {
+    let gmap = loco_map(args, None)?;
+    let ids = stream.get_markers().clone();
+    let mut source = stream.snp_source(options.missing.allows_na())?;
+    let loco = calc_kinship_loco(&mut source, &gmap.chromosomes(), options)?;
+    write_loco(&loco, &gmap, &ids, args)
+  } else {
+    write_output(&stream.calc_kinship_matrix(options)?, args)
+  }
+}
+
+fn run(args: &Args) -> std::io::Result<()> {
+  let mut options = kinship_options(args)?;
+  if args.input == Path::new("-") {
+    return run_stdin(args, options);
+  }
+  let control = match is_control_file(&args.input) {
+    true => Some(ControlFile::read(&args.input)?),
+    false => None,
+  };
+  let mut geno = match &control {
+    Some(_) if args.codes.is_some() => {
+      return Err(
+        Error::Validation(String::from(
+          "Genotype codes of a control file are defined in its genotypes section.",
+        ))
+        .into(),
+      )
+    }
+    Some(control) => control.geno_parser()?,
+    None => GenoParser::new(
+      args.input.to_string_lossy().into_owned(),
+      args.codes.clone().unwrap_or_else(default_codes),
+    )?,
+  };
+  let ids = geno.get_markers().clone();
+  apply_backend(args, ids.len(), &mut options)?;
+  if args.loco {
+    let gmap = loco_map(args, control.as_ref())?;
+    write_loco(&geno.calc_kinship_loco(&gmap.chromosomes(), &options)?, &gmap, &ids, args)?;
+  } else {
+    write_output(&KinshipMatrix::new(ids, geno.calc_kinship_with_options(&options)?)?, args)?;
+  }
+  for warning in geno.get_warnings() {
+    eprintln!("warning: {}", warning);
+  }
+  Ok(())
+}
+
+/// @brief Individuals the info command recommends options for by default.
+const INFO_IDS: usize = 1000;
+
+/// @brief The info command: prints the environment report and the options
+/// it recommends for the individuals of input, see USAGE.
+fn info(input: Option<&str>) -> std::io::Result<()> {
+  let ids_num = match input.map(|input| (input, input.parse::<usize>())) {
+    None => INFO_IDS,
+    Some((_, Ok(ids_num))) => ids_num,
+    Some((path, Err(_))) if is_control_file(Path::new(path)) => {
+      ControlFile::read(path)?.geno_parser()?.get_markers().len()
+    }
+    Some((path, Err(_))) => {
+      GenoParser::new(String::from(path), default_codes())?.get_markers().len()
+    }
+  };
+  let report = environment::report();
+  let options = report.recommended_options(ids_num);
+  let mut stdout = std::io::stdout().lock();
+  write!(stdout, "{}", report)?;
+  writeln!(stdout, "Recommended options for {} individuals:", ids_num)?;
+  writeln!(stdout, "  Mode:             {:?}", options.mode)?;
+  writeln!(stdout, "  Threads:          {}", options.threads_num())?;
+  writeln!(stdout, "  Batch size:       {} SNP lines", options.batch_rows(ids_num))?;
+  writeln!(
+    stdout,
+    "  Required memory:  {} MiB",
+    options.required_memory(ids_num) / (1024 * 1024)
+  )?;
+  stdout.flush()
+}
+
+/// @brief Reports error e in format and exits with its code.
+fn exit_with(e: std::io::Error, format: ErrorFormat) {
+  // Output piped to e.g. head, which has seen enough.
+  if e.kind() == std::io::ErrorKind::BrokenPipe {
+    return;
+  }
+  let e = Error::from(e);
+  eprintln!("{}", e.report(format));
+  process::exit(e.exit_code());
+}
+
+fn main() {
+  let mut args = std::env::args().skip(1).peekable();
+  if args.peek().map(String::as_str) == Some("info") {
+    let inputs = args.skip(1).collect::<Vec<String>>();
+    if inputs.len() > 1 {
+      eprintln!("rqtl2-kinship: unexpected argument {}\n\n{}", inputs[1], USAGE);
+      process::exit(2);
+    }
+    if let Err(e) = info(inputs.first().map(String::as_str)) {
+      exit_with(e, ErrorFormat::Text);
+    }
+    return;
+  }
+  let args = match parse_args(args) {
+    Ok(Some(args)) => args,
+    Ok(None) => return,
+    Err(msg) => {
+      eprintln!("rqtl2-kinship: {}\n\n{}", msg, USAGE);
+      process::exit(2);
+    }
+  };
+  if let Err(e) = run(&args) {
+    exit_with(e, args.error_format);
+  }
+}
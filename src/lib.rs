@@ -1,43 +1,247 @@
 // -*- mode: Rust; rust-indent-unit: 2; -*-
-/// @brief Tools for working with RQTL2 format.
-///
-/// From
-/// https://kbroman.org/qtl2/assets/vignettes/user_guide.html#Data_file_format:
-///
-/// The input data file formats for R/qtl cannot handle complex crosses, and so
-/// for R/qtl2, we have defined a new format for the data files. We’ll describe
-/// it here briefly; for details, see the separate vignette on the input file
-/// format. QTL mapping data consists of a set of tables of data: marker
-/// genotypes, phenotypes, marker maps, etc. In the new format, these different
-/// tables are in separate comma-delimited (CSV) files. In each file, the first
-/// column is a set of IDs for the rows, and the first row is a set of IDs for
-/// the columns. For example, the phenotype data file will have individual IDs
-/// in the first column and phenotype names in the first row.
+//! @brief Tools for working with RQTL2 format.
+//!
+//! From
+//! https://kbroman.org/qtl2/assets/vignettes/user_guide.html#Data_file_format:
+//!
+//! The input data file formats for R/qtl cannot handle complex crosses, and so
+//! for R/qtl2, we have defined a new format for the data files. We’ll describe
+//! it here briefly; for details, see the separate vignette on the input file
+//! format. QTL mapping data consists of a set of tables of data: marker
+//! genotypes, phenotypes, marker maps, etc. In the new format, these different
+//! tables are in separate comma-delimited (CSV) files. In each file, the first
+//! column is a set of IDs for the rows, and the first row is a set of IDs for
+//! the columns. For example, the phenotype data file will have individual IDs
+//! in the first column and phenotype names in the first row.
 
 pub mod reader;
 
+/// @brief Main types of the crate: `use rqtl2::prelude::*;`.
+pub mod prelude {
+  pub use crate::util::dataset::{Analysis, AnalysisResult, Dataset};
+  pub use crate::util::error::Error;
+  pub use crate::util::kinship::KinshipOptions;
+  pub use crate::util::kinship_matrix::KinshipMatrix;
+  pub use crate::util::idx::{IndividualIdx, MarkerIdx};
+  pub use crate::util::summary::RunSummary;
+  pub use crate::util::GenoParser;
+}
+
+/// @brief Genotype probabilities of simple crosses (R/qtl2 calc_genoprob).
+pub mod genoprob {
+  pub use crate::util::genoprob::*;
+}
+
+/// @brief Reading R/qtl2 data files.
+pub mod io {
+  pub use crate::util::align::{align_individuals, Alignment};
+  pub use crate::util::anonymize::{mask_ids, write_anonymized, IdMapping, IdMasking};
+  pub use crate::util::batches::{Batch, Batches};
+  pub use crate::util::bimbam::{write_bimbam, BimbamReader};
+  pub use crate::util::chunked::ChunkedGenoReader;
+  pub use crate::util::code_table::CodeTable;
+  pub use crate::util::control::{ControlFile, ControlValue};
+  pub use crate::util::dosage::DosageLines;
+  pub use crate::util::founders::{FounderGenotypes, FounderPolarity};
+  pub use crate::util::geno_files::{ChainedSnps, GenoFiles};
+  pub use crate::util::geno_matrix::GenoMatrix;
+  pub use crate::util::geno_writer::GenoWriter;
+  pub use crate::util::gmap::{GeneticMap, MapMarker, MapParser};
+  pub use crate::util::hard_calls::{write_hard_calls, HardCallCounts, HardCaller};
+  pub use crate::util::index::{OrderCheck, OrderPolicy, OrderedSnpIter, SnpIndex};
+  pub use crate::util::mapped::MappedGenoParser;
+  pub use crate::util::matrix_csv::{
+    LabeledTable, MatrixCsvReader, MatrixCsvWriter, NumericTable, TableSnps,
+  };
+  pub use crate::util::metadata::SampleMetadata;
+  pub use crate::util::multi_allele::{DosageCodes, MultiAlleleLines};
+  pub use crate::util::output::{write_gemma_rows, Notation, NumberFormat};
+  pub use crate::util::parse_report::{codes, Diagnostic, ParseReport, Severity};
+  pub use crate::util::pheno_parser::{PhenoMatrix, PhenoParser, PhenoParserIter};
+  pub use crate::util::plink::{PlinkReader, PlinkVariant};
+  pub use crate::util::probs::{write_dosages, ProbsDosageReader};
+  pub use crate::util::na;
+  pub use crate::util::report::{write_individual_stats, write_kinship_pairs};
+  pub use crate::util::stream::GenoStream;
+  pub use crate::util::vcf::VcfReader;
+  pub use crate::util::{GenoParser, GenoParserIter};
+}
+
+/// @brief Kinship matrix calculation.
+pub mod kinship {
+  pub use crate::util::backend::{select_backend, Backend, BackendChoice};
+  pub use crate::util::calc_partial_kinship;
+  pub use crate::util::cancel::CancellationHandle;
+  pub use crate::util::dosage::{calc_kinship_dosages, calc_kinship_table};
+  pub use crate::util::extend::extend_kinship;
+  #[cfg(feature = "blas")]
+  pub use crate::util::kernel::BlasKernel;
+  #[cfg(feature = "cuda")]
+  pub use crate::util::kernel::CudaKernel;
+  pub use crate::util::kernel::{
+    available_kernels, kernel_by_name, CpuKernel, KernelAccumulation, KinshipKernel,
+  };
+  pub use crate::util::kinship::*;
+  pub use crate::util::kinship_matrix::{KinshipMatrix, MarkerCounts, RelatedPair};
+  pub use crate::util::kinship_summary::{kinship_summary, KinshipSummary};
+  pub use crate::util::loco::{
+    calc_kinship_chromosomes, calc_kinship_loco, calc_kinship_loco_files, write_chromosome_kinship,
+    ChromosomeKinship,
+  };
+  pub use crate::util::marker_set::MarkerSet;
+  pub use crate::util::metrics::{KernelFallback, Metrics};
+  pub use crate::util::missing::MissingPolicy;
+  pub use crate::util::out_of_core::{
+    calc_kinship_out_of_core, calc_kinship_out_of_core_from, id_range, MappedKinship,
+  };
+  pub use crate::util::plink::calc_kinship_plink;
+  pub use crate::util::pool::WorkerPool;
+  pub use crate::util::precision::KinshipFloat;
+  pub use crate::util::progress::{Progress, ProgressHook};
+  pub use crate::util::sketch::{calc_kinship_sketch, ApproxKinship, SketchMethod, SketchOptions};
+  pub use crate::util::summary::RunSummary;
+  pub use crate::util::throttle::CpuLimit;
+  pub use crate::util::timed::{
+    calc_kinship_checkpointed, calc_kinship_timed, CheckpointPolicy, KinshipCheckpoint,
+    TimedKinship,
+  };
+  pub use crate::util::transform::{DosageTransform, KinshipKind};
+  pub use crate::util::vcf::calc_kinship_vcf;
+  pub use crate::util::weights::MarkerWeights;
+}
+
+/// @brief Linear mixed models using the Kinship matrix.
+pub mod lmm {
+  pub use crate::util::design::{DesignBuilder, DesignMatrix};
+  pub use crate::util::lmm::*;
+}
+
+/// @brief Phenotype preparation for the LMM.
+pub mod pheno {
+  pub use crate::util::pheno::*;
+}
+
+/// @brief Random genotype files with their expected Kinship matrix, for
+/// tests and benchmarks.
+pub mod simulate {
+  pub use crate::util::simulate::*;
+}
+
+/// @brief Genotype statistics and quality control.
+pub mod stats {
+  pub use crate::util::founders::{FounderCheck, MarkerErrors};
+  pub use crate::util::stats::*;
+}
+
+pub use crate::util::debug;
+pub use crate::util::environment;
+pub use crate::util::error;
+pub use crate::util::testing;
+
+/// @brief Implementation modules. Prefer the paths above (prelude, io,
+/// kinship, stats), which are kept stable as the crate grows.
 pub mod util {
+  pub mod align;
+  pub mod anonymize;
+  pub mod backend;
+  pub mod batches;
+  pub mod bimbam;
+  pub mod cancel;
+  pub mod chunked;
+  pub mod code_table;
+  pub mod control;
+  pub mod dataset;
+  pub mod debug;
+  pub mod design;
+  pub mod dosage;
+  pub mod environment;
+  pub mod error;
+  pub mod extend;
+  pub mod founders;
+  pub mod geno_files;
+  pub mod geno_matrix;
+  pub mod geno_writer;
+  pub mod genoprob;
+  pub mod gmap;
+  pub mod hard_calls;
+  pub mod idx;
+  pub mod index;
+  pub mod kernel;
+  pub mod kinship;
+  pub mod kinship_matrix;
+  pub mod kinship_summary;
+  pub mod lmm;
+  pub mod loco;
+  pub mod mapped;
+  pub mod marker_set;
+  pub mod matrix_csv;
+  pub mod metadata;
+  pub mod metrics;
+  pub mod missing;
+  pub mod multi_allele;
+  pub mod na;
+  pub mod throttle;
+  pub mod timed;
+  pub mod transform;
+  pub mod transpose;
+  pub mod validate;
+  pub mod vcf;
+  pub mod weights;
+  pub mod out_of_core;
+  pub mod output;
+  pub mod parse_report;
+  pub mod pheno;
+  pub mod pheno_parser;
+  pub mod plink;
+  pub mod pool;
+  pub mod precision;
+  pub mod probs;
+  pub mod progress;
+  pub mod report;
+  pub mod simulate;
+  pub mod sketch;
+  pub mod stats;
+  pub mod stream;
+  pub mod summary;
+  pub mod testing;
+  pub mod text;
+
   use std::collections::HashMap;
   use std::fs::File;
   use std::io::BufRead;
   use std::io::BufReader;
   use std::io::Seek;
   use std::io::SeekFrom;
-  use crate::reader::consume_comments2 as consume_comments2;
+  use crate::reader::consume_comments2;
+  pub use self::kinship::calc_kinship_parallel;
+  pub use self::kinship::KinshipOptions;
 
   /// @brief Batch size (number of lines to read).
   /// @brief R/QTL2 genotype data file parser.
   ///
   /// @note https://kbroman.org/qtl2/assets/vignettes/input_files.html
-  pub struct GenoParser {
-    file_reader: BufReader<File>,
+  /// @note Reads a file by default, any seekable reader with from_reader.
+  pub struct GenoParser<R = BufReader<File>> {
+    file_reader: R,
     comments: Vec<String>,
     /// @note Markers names.
     markers: Vec<String>,
     /// @note Maps snps value to f64 values. E.g. A to 0.5, B to 1.0, etc.
     hab_mapper: HashMap<char, f64>,
+    /// @note hab_mapper as a lookup table, used to parse SNP lines.
+    codes: code_table::CodeTable,
     /// @note File cursor position where SNP records start.
     snp_pos_start: u64,
+    /// @note Problems which didn't prevent parsing, e.g. ids decoded as
+    /// Latin-1.
+    report: parse_report::ParseReport,
+    /// @note Byte offsets of SNP lines, see set_index.
+    index: Option<index::SnpIndex>,
+    /// @note Marker names from the header line of a transposed file, see
+    /// set_transposed.
+    transposed: Option<Vec<String>>,
+    /// @note Whether SNP lines start with a row id, see new_with_row_ids.
+    has_row_ids: bool,
   }
 
   impl GenoParser {
@@ -50,97 +254,540 @@ pub mod util {
       Self::new_with_file(file, hab_mapper)
     }
 
+    /// @note Whether SNP lines start with a row id is detected, see
+    /// new_with_row_ids.
     pub fn new_with_file(file: File, hab_mapper: HashMap<char, f64>) -> std::io::Result<Self> {
-      let mut file_reader = BufReader::new(file);
+      Self::new_with_row_ids(file, hab_mapper, None)
+    }
+
+    /// @brief Reads file whose SNP lines start with a row id (the standard
+    /// layout) or, if has_row_ids is false, hold just genotype codes. The
+    /// header line of the latter may omit the field before the ids.
+    ///
+    /// @param[in] has_row_ids None to detect it from the first SNP line:
+    /// without a tab it has no row id.
+    ///
+    /// @note Lines without row ids get their number as the row id (counting
+    /// SNP lines from 1), e.g. in batches and chromosome maps. Methods which
+    /// look SNP lines up by marker name (e.g. extract_region, build_index)
+    /// return Error::Validation for such files.
+    pub fn new_with_row_ids(
+      file: File,
+      hab_mapper: HashMap<char, f64>,
+      has_row_ids: Option<bool>,
+    ) -> std::io::Result<Self> {
+      Self::from_reader_with_row_ids(BufReader::new(file), hab_mapper, has_row_ids)
+    }
+
+    fn parse_into(
+      parsed_snp_buf: &mut [f64],
+      snp_line: &str,
+      codes: &code_table::CodeTable,
+    ) -> std::io::Result<()> {
+      let mut id_snp = snp_line.split('\t');
+      let (row_id, snp) = match (id_snp.next(), id_snp.next()) {
+        (Some(row_id), Some(snp_str)) => (row_id, snp_str),
+        _ => {
+          return Err(
+            error::Error::Parse {
+              line: None,
+              msg: format!(
+                "This line <{}> is an invalid SNP record: snp record and row id should be \
+                 separated with tab (column {}).",
+                snp_line,
+                snp_line.chars().count() + 1
+              ),
+            }
+            .into(),
+          )
+        }
+      };
+      // Codes may be non-ASCII, so they are counted in chars.
+      let codes_num = snp.chars().count();
+      if parsed_snp_buf.len() != codes_num {
+        return Err(
+          error::Error::DimensionMismatch {
+            marker: String::from(row_id),
+            expected: parsed_snp_buf.len(),
+            found: codes_num,
+          }
+          .into(),
+        );
+      }
+      for (column, (buf_slot, snp_char)) in parsed_snp_buf.iter_mut().zip(snp.chars()).enumerate()
+      {
+        *buf_slot = codes.get_char(snp_char).ok_or_else(|| error::Error::UnknownGenotypeCode {
+          marker: String::from(row_id),
+          column: column + 1,
+          code: snp_char,
+        })?;
+      }
+      Ok(())
+    }
+
+    /// @brief Parses SNP lines into the buffer until it is full or EOF is
+    /// reached. Row ids of parsed lines are pushed to row_ids when given.
+    /// Returns amount of parsed lines.
+    ///
+    /// @note Kinship calculation can't use missing genotypes (na::NA), so
+    /// unless allow_na is set an error is returned for lines containing them.
+    /// @note next_line is the line number of the next line of lines_iter, the
+    /// one of Error::Parse, it's advanced by the lines read.
+    fn fill_buffer<L: BufRead>(
+      fill_buf: &mut [f64],
+      lines_iter: &mut std::io::Lines<L>,
+      snp_line_size: usize,
+      codes: &code_table::CodeTable,
+      mut row_ids: Option<&mut Vec<String>>,
+      allow_na: bool,
+      next_line: &mut usize,
+    ) -> std::io::Result<usize> {
+      let mut parsed_lines_counter: usize = 0;
+      for line_slice in fill_buf.chunks_mut(snp_line_size) {
+        // Blank lines (e.g. left by spreadsheet exports) are skipped.
+        let (line, snp_line) = loop {
+          let line = *next_line;
+          let snp_line = match lines_iter.next() {
+            Some(snp_line) => snp_line.map_err(|err| error::at_line(err, line))?,
+            None => return Ok(parsed_lines_counter),
+          };
+          *next_line += 1;
+          if !snp_line.trim().is_empty() {
+            break (line, snp_line);
+          }
+        };
+        GenoParser::parse_into(line_slice, &snp_line, codes)
+          .map_err(|err| error::at_line(err, line))?;
+        if let Some(row_ids) = row_ids.as_deref_mut() {
+          row_ids.push(String::from(snp_line.split('\t').next().unwrap_or_default()));
+        }
+        let missing = line_slice.iter().position(|snp| na::is_na(*snp));
+        if let Some(column) = missing.filter(|_| !allow_na) {
+          return Err(
+            error::Error::Parse {
+              line: Some(line),
+              msg: format!(
+                "This line <{}> contains missing genotypes, the first in column {}.",
+                snp_line,
+                column + 1
+              ),
+            }
+            .into(),
+          );
+        }
+        parsed_lines_counter += 1;
+      }
+      Ok(parsed_lines_counter)
+    }
+  }
+
+  impl<R: BufRead + Seek> GenoParser<R> {
+    /// @brief Reads genotype data from any seekable reader, e.g. an in-memory
+    /// Cursor<Vec<u8>>. See GenoStream for readers which can't seek (stdin,
+    /// network streams).
+    pub fn from_reader(reader: R, hab_mapper: HashMap<char, f64>) -> std::io::Result<Self> {
+      Self::from_reader_with_row_ids(reader, hab_mapper, None)
+    }
+
+    /// @brief Same as new_with_row_ids for any seekable reader.
+    pub fn from_reader_with_row_ids(
+      mut file_reader: R,
+      hab_mapper: HashMap<char, f64>,
+      has_row_ids: Option<bool>,
+    ) -> std::io::Result<Self> {
       let comments = consume_comments2(&mut file_reader)?;
-      let markers = Self::consume_markers(&mut file_reader)?;
+      let (mut markers, latin1) = Self::read_header(&mut file_reader)?;
+      let first_line = Self::peek_snp_line(&mut file_reader)?;
+      let has_row_ids = has_row_ids
+        .unwrap_or_else(|| first_line.as_ref().is_none_or(|line| line.contains('\t')));
+      // Without row ids the header has a field before the ids only if it's
+      // longer than the lines.
+      let codes_num = first_line.map_or(0, |line| line.trim_end().chars().count());
+      if has_row_ids || codes_num != markers.len() {
+        markers.remove(0);
+      }
+      let mut report = parse_report::ParseReport::new();
+      let header_line = Some(comments.len() + 1);
+      if latin1 {
+        report.warn(
+          parse_report::codes::LATIN1_DECODED,
+          header_line,
+          String::from("Header line is not valid UTF-8, ids were decoded as Latin-1."),
+        );
+      }
+      validate::report_duplicate_ids(&mut report, &markers, header_line);
       Ok(GenoParser {
-        snp_pos_start: file_reader.seek(SeekFrom::Current(0))?,
-        file_reader: file_reader,
-        comments: comments,
-        markers: markers,
-        hab_mapper: hab_mapper,
+        snp_pos_start: file_reader.stream_position()?,
+        file_reader,
+        comments,
+        markers,
+        codes: code_table::CodeTable::new(&hab_mapper),
+        hab_mapper,
+        report,
+        index: None,
+        transposed: None,
+        has_row_ids,
       })
     }
 
-    pub fn iter(&mut self) -> std::io::Result<GenoParserIter> {
+    /// @brief The first non-blank line after the cursor, which is left in
+    /// place.
+    fn peek_snp_line(file_reader: &mut R) -> std::io::Result<Option<String>> {
+      let start_pos = file_reader.stream_position()?;
+      let mut res = None;
+      for line in text::Lines::new(&mut *file_reader) {
+        let line = line?;
+        if !line.trim().is_empty() {
+          res = Some(line);
+          break;
+        }
+      }
+      file_reader.seek(SeekFrom::Start(start_pos))?;
+      Ok(res)
+    }
+
+    /// @brief Copy of options in which options.progress has the estimated
+    /// total amount of SNP lines (unless it's given): size of the SNP lines
+    /// part of the file over the length of the first one.
+    fn with_progress_estimate(
+      &mut self,
+      options: &KinshipOptions,
+    ) -> std::io::Result<KinshipOptions> {
+      let mut options = options.clone();
+      let hook = options.progress.as_mut().filter(|hook| hook.estimated_total().is_none());
+      if let Some(hook) = hook {
+        self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+        if let Some(line) = Self::peek_snp_line(&mut self.file_reader)? {
+          let snp_bytes = self.file_reader.seek(SeekFrom::End(0))? - self.snp_pos_start;
+          self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+          let line_bytes = line.len() as u64 + 1;
+          let total = (snp_bytes + line_bytes / 2) / line_bytes;
+          *hook = hook.clone().with_estimated_total(total as usize);
+        }
+      }
+      Ok(options)
+    }
+
+    /// @brief Scans the SNP lines without calculating anything and reports
+    /// their problems with line numbers: lines which are not a row id and
+    /// genotype codes separated with a tab, lines with another amount of
+    /// genotypes than ids, unknown genotype codes and duplicate marker names,
+    /// besides duplicate ids of the header line.
+    ///
+    /// @note Lists at most validate::MAX_DIAGNOSTICS_PER_CODE diagnostics of
+    /// a kind, the rest are counted. Returns Error::Validation for a
+    /// transposed file. Rewinds file cursor to the beginning of SNP lines.
+    pub fn validate(&mut self) -> std::io::Result<parse_report::ParseReport> {
+      self.ensure_snp_rows()?;
+      let header_line = self.comments.len() + 1;
+      let mut report = parse_report::ParseReport::new();
+      validate::report_duplicate_ids(&mut report, &self.markers, Some(header_line));
       self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
-      GenoParserIter::new(&mut self.file_reader, &self.hab_mapper)
+      let res = validate::validate_snp_lines(
+        &mut self.file_reader,
+        self.markers.len(),
+        &self.hab_mapper,
+        self.has_row_ids,
+        header_line + 1,
+      );
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      report.merge(&res?);
+      Ok(report)
     }
 
-    /// @brief Get comments from genotype file.
-    pub fn get_comments(&self) -> &Vec<String> {
-      &self.comments
+    /// @brief Whether SNP lines start with a row id, see new_with_row_ids.
+    pub fn has_row_ids(&self) -> bool {
+      self.has_row_ids
     }
 
-    /// @brief Returns vector of tuples (id, snps) parsed from file.
+    /// @brief Sets whether the file is transposed (geno_transposed of R/qtl2
+    /// control file): individuals as rows, the header line lists marker names
+    /// and every line holds genotypes of an individual at all markers. Ids are
+    /// then read from the first column, which takes a pass over the file.
     ///
-    /// @note Rewinds file cursor to the beginning of SNP lines after finishing
-    /// reading.
-    pub fn read_all(&mut self) -> std::io::Result<Vec<(String, Vec<f64>)>> {
-      let snps_start_pos = self.file_reader.seek(SeekFrom::Current(0))?;
-      let res = read_geno(&mut self.file_reader, &self.hab_mapper);
-      self.file_reader.seek(SeekFrom::Start(snps_start_pos))?;
+    /// @note calc_kinship_with_options and the methods built on it produce
+    /// the same matrices in both orientations, a transposed file is read in
+    /// memory for that. iter and read_all return the lines as they are, the
+    /// other methods reading SNP lines return Error::Validation.
+    pub fn set_transposed(&mut self, transposed: bool) -> std::io::Result<()> {
+      if transposed == self.transposed.is_some() {
+        return Ok(());
+      }
+      match self.transposed.take() {
+        Some(header) => self.markers = header,
+        None => {
+          self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+          let ids = scan_row_ids(&mut self.file_reader, true, &mut self.report);
+          self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+          self.transposed = Some(std::mem::replace(&mut self.markers, ids?));
+        }
+      }
+      Ok(())
+    }
+
+    pub fn is_transposed(&self) -> bool {
+      self.transposed.is_some()
+    }
+
+    pub fn iter(&mut self) -> std::io::Result<GenoParserIter<'_, R>> {
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let has_row_ids = self.has_row_ids;
+      // SNP lines follow the comments and the header line.
+      let first_line = self.comments.len() + 2;
+      GenoParserIter::new(&mut self.file_reader, &self.codes, &mut self.report, first_line)
+        .map(|iter| iter.with_row_ids(has_row_ids))
+    }
+
+    /// @brief Reads SNP lines batch by batch, batch_size lines at a time,
+    /// with their marker names. Missing genotypes are na::NA.
+    ///
+    /// @note File cursor is left where reading stopped.
+    pub fn batches(
+      &mut self,
+      batch_size: usize,
+    ) -> std::io::Result<batches::Batches<chunked::ChunkedGenoReader<'_, R>>> {
+      self.ensure_snp_rows()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let source = self.snp_source(true)?;
+      Ok(batches::Batches::new(source, batch_size))
+    }
+
+    /// @brief Builds index of SNP lines byte offsets.
+    pub fn build_index(&mut self) -> std::io::Result<index::SnpIndex> {
+      self.ensure_row_ids()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = index::SnpIndex::build(&mut self.file_reader);
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
       res
     }
 
-    fn parse_into(
-      parsed_snp_buf: &mut [f64],
-      snp_line: &String,
-      hab_mapper: &HashMap<char, f64>,
-    ) -> std::io::Result<()> {
-      let io_err = |bad_str: String, msg: &str| {
-        std::io::Error::new(
-          std::io::ErrorKind::InvalidInput,
-          format!("This line <{}> is an invalid SNP record: {}", &bad_str, msg),
-        )
-      };
-      let snp = match snp_line.split('\t').skip(1).next() {
-        Some(snp_str) => snp_str,
+    /// @brief Keeps index (built by build_index) to read selected SNP lines
+    /// without streaming the whole file, e.g. in extract_region.
+    pub fn set_index(&mut self, index: index::SnpIndex) {
+      self.index = Some(index);
+    }
+
+    /// @brief Reads genotypes of the markers of gmap within [start, end] of
+    /// chromosome chr into memory, ordered by position. Markers absent from
+    /// the genotype file are left out.
+    ///
+    /// @note Uses the index when it was set, otherwise the whole file is
+    /// streamed. Missing genotypes (na::NA) are allowed.
+    pub fn extract_region(
+      &mut self,
+      chr: &str,
+      start: f64,
+      end: f64,
+      gmap: &gmap::GeneticMap,
+    ) -> std::io::Result<geno_matrix::GenoMatrix> {
+      self.ensure_row_ids()?;
+      let region = gmap.region(chr, start, end);
+      let ids_num = self.markers.len();
+      let mut lines = HashMap::<String, Vec<f64>>::new();
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let (file_reader, codes) = (&mut self.file_reader, &self.codes);
+      let res = match &self.index {
+        Some(index) => region
+          .iter()
+          .filter_map(|marker| index.offset(&marker.id))
+          .try_for_each(|offset| -> std::io::Result<()> {
+            file_reader.seek(SeekFrom::Start(offset))?;
+            let mut line = String::new();
+            text::read_line(file_reader, &mut line)?;
+            let line = line.trim_end_matches(['\n', '\r']);
+            let mut snps = vec![0.0; ids_num];
+            GenoParser::parse_into(&mut snps, line, codes)?;
+            lines.insert(String::from(line.split('\t').next().unwrap_or_default()), snps);
+            Ok(())
+          }),
         None => {
-          return Err(io_err(
-            snp_line.clone(),
-            "snp record and row id should be separated with tab.",
-          ))
+          let wanted = region
+            .iter()
+            .map(|marker| marker.id.as_str())
+            .collect::<std::collections::HashSet<&str>>();
+          text::Lines::new(file_reader).try_for_each(|line| {
+            let line = line?;
+            let id = line.split('\t').next().unwrap_or_default();
+            if wanted.contains(id) {
+              let mut snps = vec![0.0; ids_num];
+              GenoParser::parse_into(&mut snps, &line, codes)?;
+              lines.insert(String::from(id), snps);
+            }
+            Ok(())
+          })
         }
       };
-      if parsed_snp_buf.len() != snp.len() {
-        return Err(io_err(
-          snp_line.clone(),
-          &format!(
-            "Invalid record: there are {} markers, however {} SNPs were parsed.",
-            parsed_snp_buf.len(),
-            snp.len()
-          ),
-        ));
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res?;
+      let mut matrix = geno_matrix::GenoMatrix {
+        marker_ids: Vec::new(),
+        ids: self.markers.clone(),
+        values: Vec::new(),
+      };
+      for marker in region {
+        if let Some(snps) = lines.remove(&marker.id) {
+          matrix.marker_ids.push(marker.id.clone());
+          matrix.values.extend(snps);
+        }
+      }
+      Ok(matrix)
+    }
+
+    /// @brief Iterates over SNP lines expecting them in the given order of row
+    /// ids (e.g. the order of markers in the genetic map). With
+    /// OrderPolicy::Verify the iterator yields an error on the first line out
+    /// of order, with OrderPolicy::Reorder lines are read in the given order
+    /// using the byte offsets index.
+    pub fn iter_in_order<'a>(
+      &'a mut self,
+      order: &'a [String],
+      policy: index::OrderPolicy,
+    ) -> std::io::Result<index::OrderedSnpIter<'a, R>> {
+      self.ensure_row_ids()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      index::OrderedSnpIter::new(
+        &mut self.file_reader,
+        &self.codes,
+        self.markers.len(),
+        order,
+        policy,
+        &mut self.report,
+      )
+    }
+
+    /// @brief Get comments from genotype file.
+    pub fn get_comments(&self) -> &Vec<String> {
+      &self.comments
+    }
+
+    /// @brief Get problems which didn't prevent parsing.
+    pub fn get_report(&self) -> &parse_report::ParseReport {
+      &self.report
+    }
+
+    /// @brief Get messages of the problems which didn't prevent parsing.
+    pub fn get_warnings(&self) -> Vec<String> {
+      self.report.diagnostics().iter().map(|diagnostic| diagnostic.to_string()).collect()
+    }
+
+    /// @brief Get ids from the header line of genotype file (from the first
+    /// column if it's transposed).
+    pub fn get_markers(&self) -> &Vec<String> {
+      &self.markers
+    }
+
+    /// @brief Ids of the individuals, which label the rows and columns of
+    /// the Kinship matrix: the header line ids, the first column of a
+    /// transposed file. Same as get_markers.
+    pub fn ids(&self) -> &[String] {
+      &self.markers
+    }
+
+    /// @brief Marker names of a transposed file, from its header line. None
+    /// for SNP lines, whose marker names are their row ids, see row_ids.
+    pub fn markers(&self) -> Option<&[String]> {
+      self.transposed.as_deref()
+    }
+
+    /// @brief Row ids (first column) of all lines, in file order: marker
+    /// names of SNP lines, ids of a transposed file. Genotypes aren't
+    /// decoded, so it's a cheap scan. Lines without row ids get their number
+    /// (counting from 1), see new_with_row_ids.
+    ///
+    /// @note Rewinds file cursor to the beginning of SNP lines after
+    /// finishing.
+    pub fn row_ids(&mut self) -> std::io::Result<Vec<String>> {
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = scan_row_ids(&mut self.file_reader, self.has_row_ids, &mut self.report);
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Position of the individual with id in the header line, so as
+    /// its row and column in the Kinship matrix.
+    pub fn individual_idx(&self, id: &str) -> Option<idx::IndividualIdx> {
+      self.markers.iter().position(|marker| marker == id).map(idx::IndividualIdx)
+    }
+
+    /// @brief Genotypes of the individual at idx, a value per marker in file
+    /// order (na::NA for missing genotypes). Streams the whole file.
+    pub fn read_individual(&mut self, idx: idx::IndividualIdx) -> std::io::Result<Vec<f64>> {
+      let mut res = Vec::new();
+      self.for_each_individual_in(idx.index()..idx.index() + 1, 1, |_, genotypes| {
+        res = genotypes;
+        Ok(())
+      })?;
+      Ok(res)
+    }
+
+    /// @brief Returns vector of tuples (id, genotypes) per individual, the
+    /// transpose of read_all, held in memory.
+    pub fn read_all_individuals(&mut self) -> std::io::Result<Vec<(String, Vec<f64>)>> {
+      let mut res = Vec::with_capacity(self.markers.len());
+      self.for_each_individual(self.markers.len(), |id, genotypes| {
+        res.push((String::from(id), genotypes));
+        Ok(())
+      })?;
+      Ok(res)
+    }
+
+    /// @brief Streaming transpose: hands (id, genotypes) of every individual
+    /// to on_individual in header order. The file is read once per
+    /// ids_per_pass individuals, so only ids_per_pass x markers values are
+    /// held in memory at a time.
+    ///
+    /// @note Rewinds file cursor to the beginning of SNP lines after
+    /// finishing.
+    pub fn for_each_individual<F>(
+      &mut self,
+      ids_per_pass: usize,
+      on_individual: F,
+    ) -> std::io::Result<()>
+    where
+      F: FnMut(&str, Vec<f64>) -> std::io::Result<()>,
+    {
+      self.for_each_individual_in(0..self.markers.len(), ids_per_pass, on_individual)
+    }
+
+    fn for_each_individual_in<F>(
+      &mut self,
+      ids: std::ops::Range<usize>,
+      ids_per_pass: usize,
+      mut on_individual: F,
+    ) -> std::io::Result<()>
+    where
+      F: FnMut(&str, Vec<f64>) -> std::io::Result<()>,
+    {
+      self.ensure_snp_rows()?;
+      if ids_per_pass < 1 {
+        let msg = "Amount of individuals per pass can't be less than 1.";
+        return Err(error::Error::Validation(String::from(msg)).into());
       }
-      for (buf_slot, snp_char) in parsed_snp_buf.iter_mut().zip(snp.chars()) {
-        *buf_slot = hab_mapper
-          .get(&snp_char)
-          .ok_or(io_err(
-            String::from(snp),
-            &format!(
-              "failed to convert character <{}> to a float value.",
-              snp_char
-            ),
-          ))?
-          .clone();
+      for start in ids.clone().step_by(ids_per_pass) {
+        let cols = start..(start + ids_per_pass).min(ids.end);
+        self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+        let columns = self
+          .snp_source(true)
+          .and_then(|mut source| transpose::read_columns(&mut source, cols.clone()));
+        self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+        for (col, genotypes) in cols.zip(columns?) {
+          on_individual(&self.markers[col], genotypes)?;
+        }
       }
       Ok(())
     }
 
-    fn fill_buffer(
-      fill_buf: &mut Vec<f64>,
-      lines_iter: &mut std::io::Lines<BufReader<&mut File>>,
-      snp_line_size: usize,
-      hab_mapper: HashMap<char, f64>,
-    ) -> std::io::Result<usize> {
-      let mut parsed_lines_counter: usize = 0;
-      for (line_slice, snp_line) in fill_buf.chunks_mut(snp_line_size).zip(lines_iter) {
-        Self::parse_into(line_slice, &snp_line?, &hab_mapper)?;
-        parsed_lines_counter += 1;
-      }
-      Ok(parsed_lines_counter)
+    /// @brief Returns vector of tuples (id, snps) parsed from file.
+    ///
+    /// @note Rewinds file cursor to the beginning of SNP lines after finishing
+    /// reading.
+    pub fn read_all(&mut self) -> std::io::Result<Vec<(String, Vec<f64>)>> {
+      let snps_start_pos = self.file_reader.stream_position()?;
+      // SNP lines follow the comments and the header line.
+      let first_line = self.comments.len() + 2;
+      let res = read_geno_rows(&mut self.file_reader, &self.codes, self.has_row_ids, first_line);
+      self.file_reader.seek(SeekFrom::Start(snps_start_pos))?;
+      res
     }
 
     /// @brief Calculates kinship matrix for given geno data reading it in
@@ -183,167 +830,618 @@ pub mod util {
     /// result.
     ///
     /// Since processing of one batch does not depend on the others, the process
-    /// of Kinship matrix calculation can be parallelized: each logical thread
-    /// gets 2 buffer, first one contains read rows, and a second one stores the
-    /// result of batch multiplication, it is done to not block a shared Kinship
-    /// matrix buffer while the calculation is in process. When the thread is
-    /// spawned, it locks the read and result buffer dispatched to him by a main
-    /// thread and then starts the multiplication. Once the multiplication is
-    /// finished and a result buffer contains the part of the resulting Kinship
-    /// matrix, the thread locks shared Kinship matrix and merges the results
-    /// simultaneously nullifying result buffer to not interfere with the
-    /// results calculated by the next threads obtaining this buffer, then
-    /// messaging the main thread that the buffer pair on this index is freed.
-    ///
-    /// Main thread works in a loop: loads data, parses it into a read buffer,
-    /// dispatches read/result buffer pair to the thread. If all threads are
-    /// busy performing calculations, it waits until one of them will put a
-    /// freed buffer pair index to the concurrent queue.
+    /// of Kinship matrix calculation can be parallelized, see
+    /// `kinship::calc_kinship_parallel` for details.
     pub fn calc_kinship(&mut self, batch_size: usize) -> std::io::Result<Vec<f64>> {
-      if batch_size < 1 {
-        panic!("Batch size can't be less than 1.");
+      self.calc_kinship_with_options(&KinshipOptions {
+        batch_size,
+        ..KinshipOptions::default()
+      })
+    }
+
+    /// @brief Calculates kinship matrix using given calculation options.
+    ///
+    /// @note Rewinds file cursor to the beginning of SNP lines after finishing
+    /// calculation.
+    pub fn calc_kinship_with_options(
+      &mut self,
+      options: &KinshipOptions,
+    ) -> std::io::Result<Vec<f64>> {
+      if self.transposed.is_some() {
+        let table = self.read_transposed(options.missing.allows_na())?;
+        return match options.orientation {
+          kinship::GramOrientation::IdsByIds => {
+            kinship::calc_kinship_observed(&mut table.snp_source(), options, &mut [])
+          }
+          kinship::GramOrientation::SnpsBySnps => {
+            kinship::calc_gram_of(table.values, table.col_ids.len(), options)
+          }
+        };
       }
-      let ids_num = self.markers.len();
-      // Kinship matrix is square.
-      let common_kinship_matrix: Arc<Mutex<Vec<f64>>> =
-        Arc::new(Mutex::new(vec![0.0; ids_num * ids_num]));
-
-      // This amount of snps will be parsed and processed on each iteration.
-      let buf_size = ids_num * batch_size;
-      // For each physical thread a buffer will be created.
-      let buf_num = num_cpus::get();
-      use std::sync::{Arc, Mutex};
-
-      // The compiler can't prove that the buffer ownership won't intersect
-      // (despite it won't intersect), hence the Arc-Mutex is needed.
-      let mut read_bufs = Vec::<Arc<Mutex<Vec<f64>>>>::new();
-      let mut kinship_bufs = Vec::<Arc<Mutex<Vec<f64>>>>::new();
-      for _ in 0..buf_num {
-        read_bufs.push(Arc::new(Mutex::new(vec![0.0; buf_size])));
-        kinship_bufs.push(Arc::new(Mutex::new(vec![0.0; ids_num * ids_num])));
+      let options = &self.with_progress_estimate(options)?;
+      if options.orientation == kinship::GramOrientation::SnpsBySnps {
+        self.ensure_row_ids()?;
+        self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+        let res = self
+          .snp_source(true)
+          .and_then(|mut source| kinship::calc_gram_snps_from(&mut source, options));
+        self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+        return res.map(|(_, res)| res);
+      }
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self.snp_source(options.missing.allows_na()).map(|mut source| {
+        let res = kinship::calc_kinship_observed(&mut source, options, &mut []);
+        (res, source.blank_lines(), source.latin1_row_ids())
+      });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let (res, blank_lines, latin1_row_ids) = res?;
+      report_blank_lines(&mut self.report, blank_lines);
+      report_latin1_row_ids(&mut self.report, latin1_row_ids);
+      res
+    }
+
+    /// @brief Same as calc_kinship_with_options in the floating point type T
+    /// (e.g. f32 for half the memory). See `kinship::calc_kinship_typed`.
+    pub fn calc_kinship_typed<T: precision::KinshipFloat>(
+      &mut self,
+      options: &KinshipOptions,
+    ) -> std::io::Result<Vec<T>> {
+      self.ensure_snp_rows()?;
+      let options = &self.with_progress_estimate(options)?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self.snp_source(options.missing.allows_na()).map(|mut source| {
+        let res = kinship::calc_kinship_typed::<T>(&mut source, options);
+        (res, source.blank_lines(), source.latin1_row_ids())
+      });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let (res, blank_lines, latin1_row_ids) = res?;
+      report_blank_lines(&mut self.report, blank_lines);
+      report_latin1_row_ids(&mut self.report, latin1_row_ids);
+      res
+    }
+
+    /// @brief Same as calc_kinship_with_options, the matrix comes with the
+    /// ids of its rows.
+    ///
+    /// @note Orientation GramOrientation::IdsByIds is expected.
+    pub fn calc_kinship_matrix(
+      &mut self,
+      options: &KinshipOptions,
+    ) -> std::io::Result<kinship_matrix::KinshipMatrix> {
+      let data = self.calc_kinship_with_options(options)?;
+      kinship_matrix::KinshipMatrix::new(self.markers.clone(), data)
+    }
+
+    /// @brief Same as calc_kinship_matrix for the individuals with ids only,
+    /// in the given order. Genotypes of the other individuals are skipped
+    /// while parsing, so memory and time depend on ids.len() rather than on
+    /// the amount of ids in the file.
+    ///
+    /// @note Returns Error::Validation for an id absent from the header line
+    /// or listed more than once, and for a transposed file. Orientation
+    /// GramOrientation::IdsByIds is expected. Missing genotypes of the other
+    /// individuals don't matter, neither for MissingPolicy::Reject nor for
+    /// the means of MissingPolicy::MeanImpute.
+    pub fn calc_kinship_subset(
+      &mut self,
+      ids: &[&str],
+      options: &KinshipOptions,
+    ) -> std::io::Result<kinship_matrix::KinshipMatrix> {
+      self.ensure_snp_rows()?;
+      let positions = ids
+        .iter()
+        .map(|id| {
+          self.individual_idx(id).map(|idx| idx.index()).ok_or_else(|| {
+            error::Error::Validation(format!("There is no individual <{}>.", id))
+          })
+        })
+        .collect::<Result<Vec<usize>, error::Error>>()?;
+      let options = &self.with_progress_estimate(options)?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self
+        .snp_source(options.missing.allows_na())
+        .and_then(|source| source.with_columns(&positions))
+        .map(|mut source| {
+          let res = kinship::calc_kinship_observed(&mut source, options, &mut []);
+          (res, source.blank_lines(), source.latin1_row_ids())
+        });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let (res, blank_lines, latin1_row_ids) = res?;
+      report_blank_lines(&mut self.report, blank_lines);
+      report_latin1_row_ids(&mut self.report, latin1_row_ids);
+      kinship_matrix::KinshipMatrix::new(ids.iter().map(|id| String::from(*id)).collect(), res?)
+    }
+
+    /// @brief Same as calc_kinship_with_options, also returns RunSummary of
+    /// the calculation.
+    pub fn calc_kinship_summarized(
+      &mut self,
+      options: &KinshipOptions,
+    ) -> std::io::Result<(Vec<f64>, summary::RunSummary)> {
+      let run = summary::KinshipRun::start(options);
+      let res = self.calc_kinship_with_options(&run.options)?;
+      Ok((res, run.finish(self.markers.len(), &self.report)))
+    }
+
+    /// @brief Calculates Gram matrix in options.orientation. Returns labels of
+    /// the result rows (and columns) along with the matrix: ids from the header
+    /// line for GramOrientation::IdsByIds, SNP row ids for
+    /// GramOrientation::SnpsBySnps.
+    pub fn calc_gram_matrix(
+      &mut self,
+      options: &KinshipOptions,
+    ) -> std::io::Result<(Vec<String>, Vec<f64>)> {
+      match options.orientation {
+        kinship::GramOrientation::IdsByIds => {
+          Ok((self.markers.clone(), self.calc_kinship_with_options(options)?))
+        }
+        kinship::GramOrientation::SnpsBySnps if self.transposed.is_some() => {
+          let snp_ids = self.transposed.clone().unwrap_or_default();
+          Ok((snp_ids, self.calc_kinship_with_options(options)?))
+        }
+        kinship::GramOrientation::SnpsBySnps => {
+          self.ensure_row_ids()?;
+          self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+          let res = self
+            .snp_source(true)
+            .and_then(|mut source| kinship::calc_gram_snps_from(&mut source, options));
+          self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+          res
+        }
       }
+    }
 
-      use std::sync::mpsc::channel;
-      use std::thread;
-      let (kinship_processor, buffer_filler) = channel::<usize>();
-      // Fill the concurrent queue with the buffers numbers, so the buffer
-      // filler can start parsing into them.
-      for buf_idx in 0..buf_num {
-        kinship_processor.send(buf_idx).unwrap();
+    /// @brief Reads a transposed file in memory, as a table with a row per
+    /// marker and a column per individual (the standard orientation).
+    fn read_transposed(&mut self, allow_na: bool) -> std::io::Result<matrix_csv::NumericTable> {
+      let snp_ids = self.transposed.clone().unwrap_or_default();
+      let snps_num = snp_ids.len();
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let columns =
+        chunked::ChunkedGenoReader::new(&mut self.file_reader, snps_num, &self.hab_mapper, allow_na)
+          .and_then(|mut source| transpose::read_columns(&mut source, 0..snps_num));
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      Ok(matrix_csv::NumericTable {
+        row_ids: snp_ids,
+        col_ids: self.markers.clone(),
+        values: columns?.concat(),
+      })
+    }
+
+    /// @brief Reads the whole file in memory in either orientation, as a
+    /// table with a row per marker and a column per individual. Missing
+    /// genotypes are na::NA.
+    pub(crate) fn read_table(&mut self) -> std::io::Result<matrix_csv::NumericTable> {
+      if self.transposed.is_some() {
+        return self.read_transposed(true);
       }
-      let mut threads = Vec::<thread::JoinHandle<()>>::new();
-      let file_reader = self.file_reader.get_mut();
-      let mut line_iter = BufReader::new(file_reader).lines();
-      let mut total_snps_read: usize = 0;
-      loop {
-        // Get freed buffer index.
-        let freed_buffer_idx = buffer_filler.recv().unwrap();
-        let read_buf = read_bufs[freed_buffer_idx].clone();
-        let kins_buf = kinship_bufs[freed_buffer_idx].clone();
-
-        let read_line_amount = match Self::fill_buffer(
-          &mut *read_buf.lock().unwrap(),
-          &mut line_iter,
-          self.markers.len(),
-          self.hab_mapper.clone(),
-        )? {
-          0 => break,
-          n => {
-            total_snps_read += n;
-            n
+      let ids_num = self.markers.len();
+      let mut row_ids = Vec::new();
+      let mut values = Vec::new();
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self.snp_source(true).and_then(|mut source| {
+        let mut buf = vec![0.0; ids_num.max(1) * 64];
+        loop {
+          let rows = kinship::SnpSource::read_batch(&mut source, &mut buf, Some(&mut row_ids))?;
+          if rows == 0 {
+            return Ok(());
           }
-        };
-        // Reached EOF (there is not enough records to form a full batch.).
-        // Resize buffer to discard data from previous iterations which was not
-        // overwritten because there is not enough lines to fill the whole
-        // buffer.
-        if read_line_amount < batch_size {
-          let buf = &mut *read_buf.lock().unwrap();
-          buf.resize(read_line_amount * ids_num, 0.0);
+          values.extend_from_slice(&buf[..rows * ids_num]);
         }
-        {
-          let (read_buf_arc, kins_buf_arc, res_matrix_arc, kinsh_proc_sender, buf_idx) = (
-            read_buf.clone(),
-            kins_buf.clone(),
-            common_kinship_matrix.clone(),
-            kinship_processor.clone(),
-            freed_buffer_idx.clone(),
-          );
+      });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res?;
+      Ok(matrix_csv::NumericTable {
+        row_ids,
+        col_ids: self.markers.clone(),
+        values,
+      })
+    }
 
-          threads.push(std::thread::spawn(move || {
-            let mut threads_read_buf = read_buf_arc.lock().unwrap();
-            let mut threads_kins_buf = kins_buf_arc.lock().unwrap();
-            calc_partial_kinship(&mut threads_read_buf, &mut threads_kins_buf, ids_num);
-            let mut res_matrix = res_matrix_arc.lock().unwrap();
-            for (buf_elem, common_matrix_elem) in
-              threads_kins_buf.iter_mut().zip(res_matrix.iter_mut())
-            {
-              *common_matrix_elem += *buf_elem;
-              *buf_elem = 0.0;
-            }
-            kinsh_proc_sender.send(buf_idx).unwrap();
-          }));
-        }
+    /// @brief SnpSource over SNP lines from the cursor, with or without row
+    /// ids as the file has them.
+    fn snp_source(
+      &mut self,
+      allow_na: bool,
+    ) -> std::io::Result<chunked::ChunkedGenoReader<'_, R>> {
+      let ids_num = self.markers.len();
+      let source = chunked::ChunkedGenoReader::new(
+        &mut self.file_reader,
+        ids_num,
+        &self.hab_mapper,
+        allow_na,
+      )?;
+      // SNP lines follow the comments and the header line.
+      let source = source.at_line(self.comments.len() + 2);
+      Ok(match self.has_row_ids {
+        true => source,
+        false => source.without_row_ids(),
+      })
+    }
+
+    /// @brief Returns Error::Validation for a transposed file, the methods
+    /// calling it read SNP lines as rows.
+    fn ensure_snp_rows(&self) -> std::io::Result<()> {
+      match self.transposed {
+        Some(_) => Err(
+          error::Error::Validation(String::from(
+            "Genotype file is transposed, however SNP lines are expected as rows.",
+          ))
+          .into(),
+        ),
+        None => Ok(()),
       }
+    }
 
-      assert!(
-        total_snps_read >= ids_num,
-        format!(
-          "Amount of SNPS (lines in file - (1+comments_lines_count)) should be \
-           greater or equal to amount of ids \
-           (amount of markers). SNP number: {}, IDS number: {}",
-          total_snps_read, ids_num
-        )
-      );
+    /// @brief ensure_snp_rows, also returns Error::Validation for a file
+    /// without row ids, the methods calling it read SNP lines by marker name.
+    fn ensure_row_ids(&self) -> std::io::Result<()> {
+      self.ensure_snp_rows()?;
+      match self.has_row_ids {
+        true => Ok(()),
+        false => Err(
+          error::Error::Validation(String::from(
+            "Genotype file has no row id column, however marker names are expected.",
+          ))
+          .into(),
+        ),
+      }
+    }
+
+    /// @brief Calculates kinship matrix into a memory-mapped file at path.
+    /// See `out_of_core::calc_kinship_out_of_core`.
+    pub fn calc_kinship_out_of_core<P: AsRef<std::path::Path>>(
+      &mut self,
+      path: P,
+      options: &KinshipOptions,
+    ) -> std::io::Result<out_of_core::MappedKinship> {
+      self.ensure_row_ids()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self.snp_source(options.missing.allows_na()).and_then(|mut source| {
+        out_of_core::calc_kinship_out_of_core_from(&mut source, options, path)
+      });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Calculates kinship matrix of a file coded with multi-allele
+    /// codes (the hab_mapper of the parser is not used). See
+    /// `multi_allele::MultiAlleleLines`.
+    pub fn calc_kinship_multi_allele(
+      &mut self,
+      codes: &multi_allele::DosageCodes,
+      options: &KinshipOptions,
+    ) -> std::io::Result<Vec<f64>> {
+      self.ensure_row_ids()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let mut source =
+        multi_allele::MultiAlleleLines::new(&mut self.file_reader, self.markers.len(), codes);
+      let res = kinship::calc_kinship_observed(&mut source, options, &mut []);
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Calculates kinship matrix with genotypes coded relative to the
+    /// reference founder. See `founders::FounderPolarity`.
+    pub fn calc_kinship_polarized(
+      &mut self,
+      options: &KinshipOptions,
+      founders: &founders::FounderGenotypes,
+      reference: &str,
+    ) -> std::io::Result<Vec<f64>> {
+      self.ensure_row_ids()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let scale = stats::DosageScale::from_mapper(&self.hab_mapper);
+      let res = self
+        .snp_source(options.missing.allows_na())
+        .and_then(|source| founders::FounderPolarity::new(source, founders, reference, scale))
+        .and_then(|mut source| kinship::calc_kinship_observed(&mut source, options, &mut []));
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
 
-      threads.into_iter().for_each(|thread| {
-        thread
-          .join()
-          .expect("The thread creating or execution failed !")
+    /// @brief Extends existing Kinship matrix of this file's individuals with
+    /// the individuals of new, genotyped at the same markers. See
+    /// `extend::extend_kinship`.
+    pub fn calc_kinship_extended(
+      &mut self,
+      existing: &[f64],
+      new: &mut GenoParser,
+      options: &KinshipOptions,
+    ) -> std::io::Result<Vec<f64>> {
+      self.ensure_snp_rows()?;
+      new.ensure_snp_rows()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      new.file_reader.seek(SeekFrom::Start(new.snp_pos_start))?;
+      let allow_na = options.missing.allows_na();
+      let res = self.snp_source(allow_na).and_then(|mut old_source| {
+        let mut new_source = new.snp_source(allow_na)?;
+        extend::extend_kinship(existing, &mut old_source, &mut new_source, options)
       });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      new.file_reader.seek(SeekFrom::Start(new.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Calculates approximate Kinship matrix. See
+    /// `sketch::calc_kinship_sketch`.
+    pub fn calc_kinship_sketch(
+      &mut self,
+      options: &KinshipOptions,
+      sketch_options: &sketch::SketchOptions,
+    ) -> std::io::Result<sketch::ApproxKinship> {
+      self.ensure_snp_rows()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self
+        .snp_source(options.missing.allows_na())
+        .and_then(|mut source| sketch::calc_kinship_sketch(&mut source, options, sketch_options));
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
 
+    /// @brief Calculates Kinship matrix within options.time_limit, resuming
+    /// from the checkpoint of a previous call if given. See
+    /// `timed::calc_kinship_timed`.
+    pub fn calc_kinship_timed(
+      &mut self,
+      options: &KinshipOptions,
+      resume: Option<timed::KinshipCheckpoint>,
+    ) -> std::io::Result<timed::TimedKinship> {
+      self.ensure_snp_rows()?;
+      let start = self.snp_pos_start;
+      self.file_reader.seek(SeekFrom::Start(start))?;
+      let res = self.snp_source(options.missing.allows_na()).map(|source| {
+        let mut source = source.starting_at(start, 0);
+        let res = timed::calc_kinship_timed(&mut source, options, resume);
+        (res, source.blank_lines(), source.latin1_row_ids())
+      });
       self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let (res, blank_lines, latin1_row_ids) = res?;
+      report_blank_lines(&mut self.report, blank_lines);
+      report_latin1_row_ids(&mut self.report, latin1_row_ids);
+      res
+    }
 
-      let mut res = Arc::try_unwrap(common_kinship_matrix)
-        .expect("Arc uwrapping failed. Kinship matrix is not accessible.")
-        .into_inner()
-        .expect("Mutex uwrapping failed. Kinship matrix is not accessible.");
+    /// @brief Calculates Kinship matrix, saving the checkpoint to
+    /// policy.path every policy.every_batches batches. See
+    /// `timed::calc_kinship_checkpointed`.
+    pub fn calc_kinship_checkpointed(
+      &mut self,
+      options: &KinshipOptions,
+      policy: &timed::CheckpointPolicy,
+    ) -> std::io::Result<timed::TimedKinship> {
+      self.continue_checkpointed(options, policy, None)
+    }
 
-      // Mirror Kinship matrix, since only the upper part was calculated (the
-      // Kinship matrix is symmetrical because it's formed from it's transpose times itself).
-      for i in 0..ids_num {
-        let row_length = ids_num;
-        for j in 0..i + 1 {
-          res[j * row_length + i] /= total_snps_read as f64;
-          res[i * row_length + j] = res[j * row_length + i];
+    /// @brief Continues calc_kinship_checkpointed from the checkpoint saved at
+    /// policy.path (e.g. after a crash), saving it as before. The file is
+    /// sought to the offset of the checkpoint, its SNP lines are read again
+    /// only if it has none.
+    ///
+    /// @note The same file and options (except time_limit) are expected.
+    pub fn resume_from_checkpoint(
+      &mut self,
+      options: &KinshipOptions,
+      policy: &timed::CheckpointPolicy,
+    ) -> std::io::Result<timed::TimedKinship> {
+      let checkpoint = timed::KinshipCheckpoint::load(&policy.path)?;
+      self.continue_checkpointed(options, policy, Some(checkpoint))
+    }
+
+    fn continue_checkpointed(
+      &mut self,
+      options: &KinshipOptions,
+      policy: &timed::CheckpointPolicy,
+      resume: Option<timed::KinshipCheckpoint>,
+    ) -> std::io::Result<timed::TimedKinship> {
+      self.ensure_snp_rows()?;
+      let (start, skipped) = match &resume {
+        Some(timed::KinshipCheckpoint {
+          offset: Some(offset),
+          lines_read,
+          ..
+        }) if *offset >= self.snp_pos_start => (*offset, *lines_read),
+        _ => (self.snp_pos_start, 0),
+      };
+      self.file_reader.seek(SeekFrom::Start(start))?;
+      let res = self.snp_source(options.missing.allows_na()).map(|source| {
+        let mut source = source.starting_at(start, skipped);
+        let res = timed::accumulate_checkpointed(&mut source, options, policy, resume, skipped);
+        (res, source.blank_lines(), source.latin1_row_ids())
+      });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let (res, blank_lines, latin1_row_ids) = res?;
+      report_blank_lines(&mut self.report, blank_lines);
+      report_latin1_row_ids(&mut self.report, latin1_row_ids);
+      res
+    }
+
+    /// @brief Per-marker and per-individual statistics (allele frequency,
+    /// missing and heterozygous rates) in a single pass over the file, see
+    /// stats::geno_stats. Missing genotypes are allowed.
+    pub fn geno_stats(&mut self) -> std::io::Result<stats::GenoStats> {
+      self.ensure_snp_rows()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let (ids, scale) = (self.markers.clone(), stats::DosageScale::from_mapper(&self.hab_mapper));
+      let res = self
+        .snp_source(true)
+        .and_then(|mut source| stats::geno_stats(&mut source, ids, scale));
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Computes summary statistics of the Kinship matrix without
+    /// allocating it, see kinship_summary::kinship_summary.
+    pub fn kinship_summary(
+      &mut self,
+      options: &KinshipOptions,
+    ) -> std::io::Result<kinship_summary::KinshipSummary> {
+      self.ensure_snp_rows()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let allow_na = options.missing.allows_na();
+      let res = self
+        .snp_source(allow_na)
+        .and_then(|mut source| kinship_summary::kinship_summary(&mut source, options));
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Calculates Kinship matrix of every chromosome in a single pass.
+    /// See `loco::calc_kinship_chromosomes`.
+    pub fn calc_kinship_chromosomes<F>(
+      &mut self,
+      chromosomes: &HashMap<String, String>,
+      options: &KinshipOptions,
+      on_complete: F,
+    ) -> std::io::Result<()>
+    where
+      F: FnMut(loco::ChromosomeKinship) -> std::io::Result<()>,
+    {
+      self.ensure_snp_rows()?;
+      let options = &self.with_progress_estimate(options)?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self.snp_source(options.missing.allows_na()).and_then(|mut source| {
+        loco::calc_kinship_chromosomes(&mut source, chromosomes, options, on_complete)
+      });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Writes the genotype file with masked ids, see
+    /// `anonymize::write_anonymized`. Returns the mapping of the ids.
+    pub fn write_anonymized<W: std::io::Write>(
+      &mut self,
+      masking: &anonymize::IdMasking,
+      writer: &mut W,
+    ) -> std::io::Result<anonymize::IdMapping> {
+      self.ensure_row_ids()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = anonymize::write_anonymized(&mut self.file_reader, &self.markers, masking, writer);
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Writes SNP lines as BIMBAM mean genotypes, see
+    /// `bimbam::write_bimbam`. Returns amount of markers written.
+    ///
+    /// @note Returns Error::Validation for a transposed file.
+    pub fn write_bimbam<W: std::io::Write>(
+      &mut self,
+      writer: &mut W,
+      number_format: &output::NumberFormat,
+    ) -> std::io::Result<usize> {
+      self.ensure_snp_rows()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let hab_mapper = self.hab_mapper.clone();
+      let res = self.snp_source(true).and_then(|mut source| {
+        bimbam::write_bimbam(&mut source, &hab_mapper, writer, number_format)
+      });
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Same as write_bimbam, returns RunSummary of the conversion.
+    pub fn write_bimbam_summarized<W: std::io::Write>(
+      &mut self,
+      writer: &mut W,
+      number_format: &output::NumberFormat,
+    ) -> std::io::Result<summary::RunSummary> {
+      let started = std::time::Instant::now();
+      let markers = self.write_bimbam(writer, number_format)?;
+      Ok(summary::conversion_summary(started, markers, self.markers.len(), &self.report))
+    }
+
+    /// @brief Calculates leave-one-chromosome-out Kinship matrices. See
+    /// `loco::calc_kinship_loco`.
+    pub fn calc_kinship_loco(
+      &mut self,
+      chromosomes: &HashMap<String, String>,
+      options: &KinshipOptions,
+    ) -> std::io::Result<HashMap<String, Vec<f64>>> {
+      self.ensure_snp_rows()?;
+      let options = &self.with_progress_estimate(options)?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let res = self
+        .snp_source(options.missing.allows_na())
+        .and_then(|mut source| loco::calc_kinship_loco(&mut source, chromosomes, options));
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      res
+    }
+
+    /// @brief Calculates genotype probabilities of the individuals at the
+    /// markers of gmap, see genoprob::calc_genoprob. Reads all SNP lines in
+    /// memory.
+    ///
+    /// @note The genotype values are those of hab_mapper (options.scale is
+    /// replaced by stats::DosageScale::from_mapper).
+    /// @note X chromosomes left out by options.skip_x are warned about in
+    /// the report.
+    /// @note Returns Error::Validation for a transposed file.
+    pub fn calc_genoprob(
+      &mut self,
+      gmap: &gmap::GeneticMap,
+      options: &genoprob::GenoprobOptions,
+    ) -> std::io::Result<Vec<genoprob::GenoProbs>> {
+      self.ensure_snp_rows()?;
+      self.file_reader.seek(SeekFrom::Start(self.snp_pos_start))?;
+      let rows = self.read_all()?;
+      let options = genoprob::GenoprobOptions {
+        scale: stats::DosageScale::from_mapper(&self.hab_mapper),
+        ..*options
+      };
+      let probs = genoprob::calc_genoprob(&rows, &self.markers, gmap, &options)?;
+      if options.skip_x {
+        for chromosome in gmap.chromosome_names() {
+          if genoprob::is_x_chromosome(chromosome) {
+            self.report.warn(
+              parse_report::codes::SKIPPED_CHROMOSOME,
+              None,
+              format!("Genotype probabilities of the X chromosome <{}> were skipped.", chromosome),
+            );
+          }
         }
       }
-      Ok(res)
+      Ok(probs)
     }
 
     /// @brief Consumes markers line from BufRead. File cursor is left right
     /// after comments.
-    pub fn consume_markers(file_reader: &mut BufReader<File>) -> std::io::Result<Vec<String>> {
-      let mut markers = String::new();
-      let start_pos = file_reader.seek(SeekFrom::Current(0))?;
-      let markers_len = file_reader.read_line(&mut markers)?;
+    pub fn consume_markers(file_reader: &mut R) -> std::io::Result<Vec<String>> {
+      Self::read_header(file_reader).map(|(mut fields, _)| {
+        fields.remove(0);
+        fields
+      })
+    }
+
+    /// @brief Reads all fields of the header line, also returns whether the
+    /// line was decoded as Latin-1.
+    fn read_header(file_reader: &mut R) -> std::io::Result<(Vec<String>, bool)> {
+      let start_pos = file_reader.stream_position()?;
+      let (fields, latin1, markers_len) = read_header_line(file_reader)?;
       file_reader.seek(SeekFrom::Start(start_pos + markers_len as u64))?;
-      markers.truncate(markers.len() - 1);
-      Ok(
-        markers
-          .split('\t')
-          .skip(1)
-          .map(|marker| String::from(marker))
-          .collect::<Vec<String>>(),
-      )
+      Ok((fields, latin1))
     }
+
+  }
+
+  /// @brief Reads all fields of the header line, also returns whether the
+  /// line was decoded as Latin-1 and its length in bytes.
+  pub(crate) fn read_header_line<B: BufRead + ?Sized>(
+    file_reader: &mut B,
+  ) -> std::io::Result<(Vec<String>, bool, usize)> {
+    let mut markers = String::new();
+    let (markers_len, latin1) = text::read_line(file_reader, &mut markers)?;
+    text::trim_line_end(&mut markers);
+    Ok((
+      markers.split('\t').map(String::from).collect::<Vec<String>>(),
+      latin1,
+      markers_len,
+    ))
   }
 
-  pub fn calc_partial_kinship(
-    snps: &mut Vec<f64>,
-    partial_matrix: &mut Vec<f64>,
-    ids_num: usize,
-  ) -> () {
+  /// @brief Adds product of transposed SNPs batch by itself (upper triangular
+  /// part only) to partial_matrix.
+  pub fn calc_partial_kinship(snps: &[f64], partial_matrix: &mut [f64], ids_num: usize) {
     let n = ids_num;
     let k = snps.len() / n;
     // Algorithm from BLAS dsyrk:
@@ -383,6 +1481,7 @@ pub mod util {
 
   /// @brief Reads snps from file.
   /// Returns vector of tuples (id, snps) parsed from file.
+  #[deprecated(note = "use GenoParser::read_all")]
   pub fn parse_geno(
     file: &mut File,
     hab_mapper: &HashMap<char, f64>,
@@ -395,7 +1494,8 @@ pub mod util {
 
   /// @note Parse line with markers. File cursor is rewinded to the beginning of
   /// the file.
-  /// Example: marker	10	12	38	39	42	54
+  /// Example: marker 10 12 38 39 42 54 (tab separated)
+  #[deprecated(note = "use GenoParser::get_markers")]
   pub fn parse_markers(file: &mut File) -> std::io::Result<Vec<String>> {
     let mut buf_reader = BufReader::new(file.try_clone()?);
     consume_comments2(&mut buf_reader)?;
@@ -410,6 +1510,7 @@ pub mod util {
   /// @note Comments lines example:
   /// # These are comments.
   /// # Only at the beginning of the R/QTL2 file geno file.
+  #[deprecated(note = "use GenoParser::get_comments")]
   pub fn parse_comments(file: &mut File) -> std::io::Result<Vec<String>> {
     let res = consume_comments2(&mut BufReader::new(file.try_clone()?));
     file.seek(SeekFrom::Start(0))?;
@@ -422,82 +1523,220 @@ pub mod util {
   pub fn read_geno(
     file_reader: &mut dyn BufRead,
     hab_mapper: &HashMap<char, f64>,
+  ) -> std::io::Result<Vec<(String, Vec<f64>)>> {
+    read_geno_rows(file_reader, &code_table::CodeTable::new(hab_mapper), true, 1)
+  }
+
+  /// @brief read_geno of lines which hold just genotype codes unless
+  /// has_row_ids, their numbers (counting from 1) are the row ids then.
+  /// Error::Parse reports lines counting from first_line.
+  fn read_geno_rows(
+    file_reader: &mut dyn BufRead,
+    codes: &code_table::CodeTable,
+    has_row_ids: bool,
+    first_line: usize,
   ) -> std::io::Result<Vec<(String, Vec<f64>)>> {
     let mut contents = Vec::<(String, Vec<f64>)>::new();
-    for line in file_reader.lines() {
-      let id_snp_tuple: (String, Vec<f64>) = parse_snp_rec(line?, &hab_mapper)?;
-      contents.push(id_snp_tuple);
+    for (line_number, line) in (first_line..).zip(file_reader.lines()) {
+      let line = line.map_err(|err| error::at_line(err, line_number))?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      let line = match has_row_ids {
+        true => line,
+        false => format!("{}\t{}", contents.len() + 1, line),
+      };
+      let id_snp_tuple = decode_snp_rec(line, codes);
+      contents.push(id_snp_tuple.map_err(|err| error::at_line(err, line_number))?);
     }
     Ok(contents)
   }
 
+  /// @brief Row ids of the lines of file_reader, see GenoParser::row_ids.
+  /// Blank lines are skipped.
+  fn scan_row_ids(
+    file_reader: &mut dyn BufRead,
+    has_row_ids: bool,
+    report: &mut parse_report::ParseReport,
+  ) -> std::io::Result<Vec<String>> {
+    let mut latin1_row_ids = 0;
+    let mut row_ids = Vec::new();
+    let mut line = Vec::new();
+    loop {
+      line.clear();
+      if file_reader.read_until(b'\n', &mut line)? == 0 {
+        break;
+      }
+      if line.iter().all(|b| b.is_ascii_whitespace()) {
+        continue;
+      }
+      let row_id = match has_row_ids {
+        true => {
+          let end = line.iter().position(|b| *b == b'\t').unwrap_or(line.len());
+          let row_id = line[..end].trim_ascii_end();
+          let (row_id, latin1) = text::decode(row_id.to_vec());
+          latin1_row_ids += latin1 as usize;
+          row_id
+        }
+        false => (row_ids.len() + 1).to_string(),
+      };
+      row_ids.push(row_id);
+    }
+    report_latin1_row_ids(report, latin1_row_ids);
+    Ok(row_ids)
+  }
+
   /// @brief Parses snp geno record into tuple. Consumes line with record.
   /// <rs41245 AABH> to ("rs41245", Vec<f64>(0.0, 0.0, 1.0, 0.5))
+  ///
+  /// @note Codes missing from hab_mapper are Error::UnknownGenotypeCode.
+  /// Builds a CodeTable of hab_mapper per call, GenoParser parses many lines
+  /// with a single one.
   pub fn parse_snp_rec(
     line: String,
     hab_mapper: &HashMap<char, f64>,
+  ) -> std::io::Result<(String, Vec<f64>)> {
+    decode_snp_rec(line, &code_table::CodeTable::new(hab_mapper))
+  }
+
+  /// @brief Same as parse_snp_rec with the codes of a CodeTable.
+  fn decode_snp_rec(
+    line: String,
+    codes: &code_table::CodeTable,
   ) -> std::io::Result<(String, Vec<f64>)> {
     let line_str = line;
     let mut id_snp = line_str.split('\t');
     let id = id_snp.next().unwrap();
-    let parse_snps = |snp_str: &str| {
-      snp_str
-        .chars()
-        .map(|ch| {
-          hab_mapper
-            .get(&ch)
-            .map(|v| *v)
-            .clone()
-            .expect(&format!("No key <{}> in SNP mapper.", ch)[..])
+    let snp_str = id_snp.next().ok_or_else(|| error::Error::Parse {
+      line: None,
+      msg: format!(
+        "This line <{}> is an invalid SNP record: no tab after the row id (column {}).",
+        line_str,
+        id.chars().count() + 1
+      ),
+    })?;
+    let snps = snp_str
+      .chars()
+      .enumerate()
+      .map(|(column, ch)| {
+        codes.get_char(ch).ok_or_else(|| error::Error::UnknownGenotypeCode {
+          marker: String::from(id),
+          column: column + 1,
+          code: ch,
         })
-        .collect::<Vec<f64>>()
-    };
-    let id_snp_tuple: (String, Vec<f64>) = (
-      String::from(id),
-      parse_snps(&id_snp.next().ok_or(std::io::Error::new(
-        std::io::ErrorKind::InvalidInput,
-        format!("This line <{}> is an invalid SNP record.", line_str),
-      ))?),
-    );
-    Ok(id_snp_tuple)
+      })
+      .collect::<Result<Vec<f64>, error::Error>>()?;
+    Ok((String::from(id), snps))
+  }
+
+  /// @brief Adds a warning about blank_lines skipped among SNP lines to
+  /// report, once (every pass over the file skips the same lines).
+  fn report_blank_lines(report: &mut parse_report::ParseReport, blank_lines: usize) {
+    if blank_lines > 0 && !report.has(parse_report::codes::BLANK_LINES) {
+      report.warn(
+        parse_report::codes::BLANK_LINES,
+        None,
+        format!("{} blank lines among SNP lines were skipped.", blank_lines),
+      );
+    }
+  }
+
+  /// @brief Adds a warning about latin1_row_ids row ids of SNP lines which
+  /// were decoded as Latin-1 to report, once.
+  fn report_latin1_row_ids(report: &mut parse_report::ParseReport, latin1_row_ids: usize) {
+    let reported = report.diagnostics().iter().any(|diagnostic| {
+      diagnostic.code == parse_report::codes::LATIN1_DECODED && diagnostic.line.is_none()
+    });
+    if latin1_row_ids > 0 && !reported {
+      report.warn(
+        parse_report::codes::LATIN1_DECODED,
+        None,
+        format!("{} row ids are not valid UTF-8, they were decoded as Latin-1.", latin1_row_ids),
+      );
+    }
   }
 
   /// @brief Parses lines from genotype file.
-  pub struct GenoParserIter<'a> {
-    lines_reader: std::io::Lines<&'a mut BufReader<File>>,
-    hab_mapper: &'a HashMap<char, f64>,
+  ///
+  /// @note Every malformed line yields its error and the iteration goes on
+  /// with the next line, callers decide whether to skip, log or abort. An
+  /// I/O error is the last item.
+  pub struct GenoParserIter<'a, R: BufRead = BufReader<File>> {
+    lines_reader: text::Lines<&'a mut R>,
+    codes: &'a code_table::CodeTable,
+    /// @note Gets the count of skipped blank lines at the end of the file.
+    report: &'a mut parse_report::ParseReport,
+    blank_lines: usize,
+    /// @note Whether lines start with a row id, otherwise lines_read + 1 is.
+    row_ids: bool,
+    lines_read: usize,
+    /// @note Line number of the first SNP line, for Error::Parse.
+    first_line: usize,
+    /// @note Set by an I/O error, after which the iteration ends.
+    failed: bool,
   }
 
-  impl<'a> GenoParserIter<'a> {
+  impl<'a, R: BufRead> GenoParserIter<'a, R> {
     /// @note File cursor must be located at the beginning of SNP records.
     fn new(
-      file_reader: &'a mut BufReader<File>,
-      hab_mapper: &'a HashMap<char, f64>,
+      file_reader: &'a mut R,
+      codes: &'a code_table::CodeTable,
+      report: &'a mut parse_report::ParseReport,
+      first_line: usize,
     ) -> std::io::Result<Self> {
       Ok(Self {
-        lines_reader: file_reader.lines(),
-        hab_mapper: hab_mapper,
+        lines_reader: text::Lines::new(file_reader),
+        codes,
+        report,
+        blank_lines: 0,
+        row_ids: true,
+        lines_read: 0,
+        first_line,
+        failed: false,
       })
     }
+
+    fn with_row_ids(mut self, row_ids: bool) -> Self {
+      self.row_ids = row_ids;
+      self
+    }
   }
 
-  impl<'a> Iterator for GenoParserIter<'a> {
-    type Item = (String, Vec<f64>);
+  impl<'a, R: BufRead> Iterator for GenoParserIter<'a, R> {
+    type Item = std::io::Result<(String, Vec<f64>)>;
 
     /// @brief Parse next line from genotype file. Returns tuple (row_id, snps).
+    ///
+    /// @note Returns None after an I/O error, since the reader can't go on
+    /// past it.
     fn next(&mut self) -> Option<Self::Item> {
-      // While EOF is not reached (and until buffer is filled).
-      match self.lines_reader.next() {
-        Some(line) => {
-          return match parse_snp_rec(line.expect("Failed to read SNP line."), &self.hab_mapper) {
-            Ok(val) => Some(val),
-            Err(e) => {
-              println!("Failed to parse the line. Error: {}", e);
-              return self.next();
-            }
+      if self.failed {
+        return None;
+      }
+      loop {
+        let line_number = self.first_line + self.lines_read + self.blank_lines;
+        let line = match self.lines_reader.next() {
+          Some(Ok(line)) => line,
+          Some(Err(e)) => {
+            self.failed = true;
+            return Some(Err(error::at_line(e, line_number)));
+          }
+          None => {
+            report_blank_lines(self.report, self.blank_lines);
+            report_latin1_row_ids(self.report, self.lines_reader.latin1_lines().len());
+            return None;
           }
+        };
+        if line.trim().is_empty() {
+          self.blank_lines += 1;
+          continue;
         }
-        None => return None,
+        self.lines_read += 1;
+        let line = match self.row_ids {
+          true => line,
+          false => format!("{}\t{}", self.lines_read, line),
+        };
+        return Some(decode_snp_rec(line, self.codes).map_err(|e| error::at_line(e, line_number)));
       }
     }
   }
@@ -1,32 +1,54 @@
 // reader.rs
 
 use std::io::BufRead;
-use std::io::BufReader;
-use std::fs::File;
 use std::io::Seek;
 use std::io::SeekFrom;
 
+use crate::util::error::Error;
+use crate::util::text;
+
 /// @brief Consumes comments lines from the stream. File cursor is left right
 /// after comments.
-pub fn consume_comments2(file_reader: &mut BufReader<File>) -> std::io::Result<Vec<String>> {
+pub fn consume_comments2<R: BufRead + Seek + ?Sized>(
+  file_reader: &mut R,
+) -> std::io::Result<Vec<String>> {
+  let start_pos = file_reader.stream_position()?;
+  let (res, comments_bytes_count) = read_comments(file_reader)?;
+  // Also places the cursor of the underlying file, which may be shared with
+  // other readers, right after comments.
+  file_reader.seek(SeekFrom::Start(start_pos + comments_bytes_count))?;
+  Ok(res)
+}
+
+/// @brief Same as consume_comments2 for streams which can't seek (e.g.
+/// stdin), lines are peeked in the buffer of the reader.
+pub fn consume_comments<R: BufRead + ?Sized>(reader: &mut R) -> std::io::Result<Vec<String>> {
+  read_comments(reader).map(|(res, _)| res)
+}
+
+/// @brief Reads comments lines, returns them and the amount of bytes read.
+fn read_comments<R: BufRead + ?Sized>(reader: &mut R) -> std::io::Result<(Vec<String>, u64)> {
   let mut buf_str = String::new();
   let mut res = Vec::<String>::new();
   let mut comments_bytes_count: u64 = 0;
   loop {
-    let read_bytes_count: usize = file_reader.read_line(&mut buf_str)?;
-    if buf_str.starts_with('#') {
-      res.push(String::from(&buf_str[1..buf_str.len() - 1]));
-    } else {
-      // read_line returns Ok(0) when reached EOF.
-      if read_bytes_count == 0 {
-        return Err(std::io::Error::new(
-          std::io::ErrorKind::InvalidInput,
-          "File is empty.",
-        ));
+    // fill_buf returns an empty buffer only at EOF.
+    match reader.fill_buf()?.first() {
+      None => {
+        return Err(
+          Error::Parse {
+            line: None,
+            msg: String::from("File is empty."),
+          }
+          .into(),
+        )
       }
-      file_reader.seek(SeekFrom::Start(comments_bytes_count))?;
-      return Ok(res);
+      Some(b'#') => (),
+      Some(_) => return Ok((res, comments_bytes_count)),
     }
+    let (read_bytes_count, _) = text::read_line(reader, &mut buf_str)?;
+    text::trim_line_end(&mut buf_str);
+    res.push(String::from(&buf_str[1..]));
     buf_str.clear();
     comments_bytes_count += read_bytes_count as u64;
   }
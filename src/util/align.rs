@@ -0,0 +1,118 @@
+// align.rs
+
+use std::collections::{HashMap, HashSet};
+
+use super::error::Error;
+use super::idx::IndividualIdx;
+use super::parse_report::{codes, ParseReport};
+
+/// @brief Most dropped ids listed by Alignment::report for a single file.
+const MAX_LISTED_IDS: usize = 10;
+
+/// @brief Individuals common to several files (e.g. the genotype, phenotype
+/// and covariate files of a dataset), see align_individuals.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alignment {
+  /// @note Common ids, in the order of the first file.
+  pub ids: Vec<String>,
+  /// @note Per file: position of every common id in the file, in the order of
+  /// ids.
+  pub indices: Vec<Vec<IndividualIdx>>,
+  /// @note Per file: ids of the file absent from any other file, in the order
+  /// of the file.
+  pub dropped: Vec<Vec<String>>,
+}
+
+/// @brief Aligns the ids of several files (e.g. GenoParser::get_markers,
+/// PhenoMatrix::ids, LabeledTable::row_ids): finds the ids present in all of
+/// them and where each of them is in every file.
+///
+/// @note Returns Error::Validation if a file lists an id more than once,
+/// since it can't be told which of the entries to use.
+pub fn align_individuals(files: &[&[String]]) -> std::io::Result<Alignment> {
+  let mut positions = Vec::with_capacity(files.len());
+  for ids in files {
+    let mut file_positions = HashMap::with_capacity(ids.len());
+    for (pos, id) in ids.iter().enumerate() {
+      if file_positions.insert(id.as_str(), pos).is_some() {
+        return Err(
+          Error::Validation(format!("Individual <{}> is listed more than once.", id)).into(),
+        );
+      }
+    }
+    positions.push(file_positions);
+  }
+  let common = |id: &str| positions.iter().all(|file_positions| file_positions.contains_key(id));
+  let ids = match files.first() {
+    Some(first) => first.iter().filter(|id| common(id)).cloned().collect::<Vec<String>>(),
+    None => Vec::new(),
+  };
+  let kept = ids.iter().map(|id| id.as_str()).collect::<HashSet<&str>>();
+  Ok(Alignment {
+    indices: positions
+      .iter()
+      .map(|file_positions| {
+        ids.iter().map(|id| IndividualIdx(file_positions[id.as_str()])).collect()
+      })
+      .collect(),
+    dropped: files
+      .iter()
+      .map(|file_ids| file_ids.iter().filter(|id| !kept.contains(id.as_str())).cloned().collect())
+      .collect(),
+    ids,
+  })
+}
+
+impl Alignment {
+  pub fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  /// @brief Whether no id was dropped, i.e. all files list the same ids.
+  pub fn is_complete(&self) -> bool {
+    self.dropped.iter().all(|dropped| dropped.is_empty())
+  }
+
+  /// @brief Whether file lists the common ids in the same order, so its data
+  /// can be used without reordering.
+  pub fn is_identity(&self, file: usize) -> bool {
+    self.dropped[file].is_empty()
+      && self.indices[file].iter().enumerate().all(|(pos, idx)| idx.index() == pos)
+  }
+
+  /// @brief Reorders row-major data of file (cols_num values per individual,
+  /// e.g. PhenoMatrix::values or covariates) to the common ids.
+  pub fn select_rows<T: Copy>(&self, file: usize, values: &[T], cols_num: usize) -> Vec<T> {
+    self.indices[file]
+      .iter()
+      .flat_map(|idx| &values[idx.index() * cols_num..(idx.index() + 1) * cols_num])
+      .copied()
+      .collect()
+  }
+
+  /// @brief Warning about the dropped ids of every file, files being named
+  /// by names.
+  pub fn report(&self, names: &[&str]) -> ParseReport {
+    let mut report = ParseReport::new();
+    for (name, dropped) in names.iter().zip(&self.dropped) {
+      if dropped.is_empty() {
+        continue;
+      }
+      let mut listed = dropped.iter().take(MAX_LISTED_IDS).cloned().collect::<Vec<String>>();
+      if dropped.len() > MAX_LISTED_IDS {
+        listed.push(format!("{} more", dropped.len() - MAX_LISTED_IDS));
+      }
+      report.warn(
+        codes::DROPPED_ID,
+        None,
+        format!(
+          "{} individuals of {} are absent from other files: {}.",
+          dropped.len(),
+          name,
+          listed.join(", ")
+        ),
+      );
+    }
+    report
+  }
+}
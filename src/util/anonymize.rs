@@ -0,0 +1,106 @@
+// anonymize.rs
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+use super::error::Error;
+use super::sketch::SplitMix64;
+
+/// @brief How individual ids are replaced by write_anonymized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdMasking {
+  /// @brief Ids become "ind1" .. "indN" assigned in a random order, so the
+  /// numbers don't follow the original order. Same seed gives the same ids.
+  Shuffle { seed: u64 },
+  /// @brief Ids become 16 hex digits of a 64-bit (FNV-1a) hash of the salt
+  /// and the id. Stable across files with the same salt, which must be kept
+  /// secret: hashes of guessable ids are reversed by trying them.
+  Hash { salt: String },
+}
+
+/// @brief Original ids along with their replacements, in file order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdMapping {
+  pub original: Vec<String>,
+  pub anonymized: Vec<String>,
+}
+
+impl IdMapping {
+  pub fn anonymized(&self, original: &str) -> Option<&str> {
+    let pos = self.original.iter().position(|id| id == original)?;
+    Some(&self.anonymized[pos])
+  }
+
+  /// @brief Writes "original\tanonymized" line per id, to be kept private.
+  pub fn write<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    self.write_to(&mut writer)?;
+    writer.flush()
+  }
+
+  pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+    writeln!(writer, "original\tanonymized")?;
+    for (original, anonymized) in self.original.iter().zip(&self.anonymized) {
+      writeln!(writer, "{}\t{}", original, anonymized)?;
+    }
+    Ok(())
+  }
+}
+
+/// @brief Replaces ids according to masking.
+///
+/// @note Returns Error::Validation if two hashed ids collide.
+pub fn mask_ids(ids: &[String], masking: &IdMasking) -> std::io::Result<IdMapping> {
+  let anonymized = match masking {
+    IdMasking::Shuffle { seed } => {
+      let mut numbers = (1..=ids.len()).collect::<Vec<usize>>();
+      // Fisher-Yates shuffle.
+      let mut rng = SplitMix64::new(*seed, 0);
+      for i in (1..numbers.len()).rev() {
+        numbers.swap(i, (rng.next_u64() % (i as u64 + 1)) as usize);
+      }
+      numbers.iter().map(|number| format!("ind{}", number)).collect()
+    }
+    IdMasking::Hash { salt } => {
+      let anonymized = ids
+        .iter()
+        .map(|id| format!("{:016x}", fnv1a([salt.as_bytes(), &[0], id.as_bytes()].concat())))
+        .collect::<Vec<String>>();
+      if anonymized.iter().collect::<HashSet<&String>>().len() != ids.len() {
+        return Err(Error::Validation(String::from("Hashes of two ids collide.")).into());
+      }
+      anonymized
+    }
+  };
+  Ok(IdMapping {
+    original: ids.to_vec(),
+    anonymized,
+  })
+}
+
+/// @brief Writes genotype file (the format read by GenoParser) with masked
+/// ids: a header with the replacements, then SNP lines of snp_lines copied
+/// byte for byte. Comments of the original file are not written, since they
+/// may describe the dataset.
+///
+/// @param[in] snp_lines reader at the beginning of SNP lines.
+pub fn write_anonymized<R: BufRead, W: Write>(
+  snp_lines: &mut R,
+  ids: &[String],
+  masking: &IdMasking,
+  writer: &mut W,
+) -> std::io::Result<IdMapping> {
+  let mapping = mask_ids(ids, masking)?;
+  writeln!(writer, "marker\t{}", mapping.anonymized.join("\t"))?;
+  std::io::copy(snp_lines, writer)?;
+  Ok(mapping)
+}
+
+/// @brief 64-bit FNV-1a hash.
+fn fnv1a(bytes: Vec<u8>) -> u64 {
+  bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+  })
+}
@@ -0,0 +1,125 @@
+// backend.rs
+
+//! Selection of the device the Kinship matrix is calculated on, from what
+//! environment::report finds on the machine.
+
+use std::mem::size_of;
+use std::sync::Arc;
+
+use super::environment::{EnvironmentReport, GpuPlatform, CUDA_LIBRARIES};
+use super::error::Error;
+use super::kernel::{available_kernels, kernel_by_name, KinshipKernel};
+use super::kinship::KinshipOptions;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Backend {
+  #[default]
+  Cpu,
+  /// @brief GPU is required, its absence is Error::GpuUnavailable.
+  Gpu,
+  /// @brief GPU when it's usable, the CPU otherwise (with the reason).
+  Auto,
+}
+
+impl Backend {
+  /// @brief Backend by name: "cpu", "gpu" or "auto".
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "cpu" => Some(Backend::Cpu),
+      "gpu" => Some(Backend::Gpu),
+      "auto" => Some(Backend::Auto),
+      _ => None,
+    }
+  }
+}
+
+/// @brief Backend chosen by select_backend.
+#[derive(Clone, Debug)]
+pub struct BackendChoice {
+  /// @note For KinshipOptions::kernel, None for the CPU.
+  pub kernel: Option<Arc<dyn KinshipKernel>>,
+  /// @note Why Backend::Auto fell back to the CPU.
+  pub fallback: Option<String>,
+}
+
+impl BackendChoice {
+  pub fn is_gpu(&self) -> bool {
+    self.kernel.is_some()
+  }
+}
+
+/// @brief Chooses the backend for the Kinship matrix of ids_num individuals
+/// calculated with options. GPU is usable when there is a CUDA device, the
+/// CUDA libraries are found, the matrix and a batch fit in the device memory
+/// and the crate is built with the cuda feature (kernel::CudaKernel). The
+/// free memory reported by the device is used when the libraries load, the
+/// memory of report otherwise, the GPU isn't used when neither is known.
+///
+/// @note Returns Error::GpuUnavailable with the reason for Backend::Gpu when
+/// the GPU isn't usable.
+pub fn select_backend(
+  backend: Backend,
+  ids_num: usize,
+  options: &KinshipOptions,
+  report: &EnvironmentReport,
+) -> std::io::Result<BackendChoice> {
+  let cpu = |fallback| BackendChoice {
+    kernel: None,
+    fallback,
+  };
+  if backend == Backend::Cpu {
+    return Ok(cpu(None));
+  }
+  match gpu_kernel(ids_num, options, report) {
+    Ok(kernel) => Ok(BackendChoice {
+      kernel: Some(kernel),
+      fallback: None,
+    }),
+    Err(reason) if backend == Backend::Auto => Ok(cpu(Some(reason))),
+    Err(reason) => Err(Error::GpuUnavailable(reason).into()),
+  }
+}
+
+/// @brief GPU kernel if the GPU is usable, the reason why not otherwise.
+fn gpu_kernel(
+  ids_num: usize,
+  options: &KinshipOptions,
+  report: &EnvironmentReport,
+) -> Result<Arc<dyn KinshipKernel>, String> {
+  let devices = report.gpu_devices.iter().filter(|device| device.platform == GpuPlatform::Cuda);
+  let memory = devices.map(|device| device.memory).max();
+  let memory = memory.ok_or_else(|| String::from("no CUDA device found"))?;
+  if let Some(library) = CUDA_LIBRARIES.iter().find(|lib| !report.cuda_libraries.contains(lib)) {
+    return Err(format!("{} is not found", library));
+  }
+  let kernel = kernel_by_name("gpu").ok_or_else(|| {
+    format!(
+      "GPU kernel is not compiled in (kernels: {}), build with the cuda feature",
+      available_kernels().join(", ")
+    )
+  });
+  let memory = match device_memory().or(memory) {
+    Some(memory) => memory,
+    None => return Err(kernel.err().unwrap_or_else(|| String::from("device memory unknown"))),
+  };
+  let required = ids_num * (ids_num + options.batch_rows(ids_num)) * size_of::<f64>();
+  if required as u64 > memory {
+    return Err(format!(
+      "{} MiB of GPU memory required, the device has {} MiB",
+      required / (1024 * 1024),
+      memory / (1024 * 1024)
+    ));
+  }
+  kernel
+}
+
+/// @brief Free memory the CUDA device reports, see CudaKernel::free_memory.
+#[cfg(feature = "cuda")]
+fn device_memory() -> Option<u64> {
+  super::kernel::CudaKernel::free_memory()
+}
+
+#[cfg(not(feature = "cuda"))]
+fn device_memory() -> Option<u64> {
+  None
+}
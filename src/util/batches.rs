@@ -0,0 +1,56 @@
+// batches.rs
+
+use super::kinship::SnpSource;
+
+/// @brief SNP lines parsed at once, see Batches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch<'a> {
+  /// @note Row ids (marker names) of the lines.
+  pub markers: Vec<String>,
+  /// @note Parsed genotypes, `rows` lines of ids_num values each, missing
+  /// ones are na::NA.
+  pub data: &'a [f64],
+  pub rows: usize,
+}
+
+/// @brief Reads SNP lines of a SnpSource batch by batch into a buffer reused
+/// between the batches, so custom computations get the parsing of the Kinship
+/// calculation. See GenoParser::batches.
+///
+/// @note Not an Iterator, since a batch borrows the buffer: call next_batch
+/// in a loop.
+pub struct Batches<S: SnpSource> {
+  source: S,
+  buf: Vec<f64>,
+}
+
+impl<S: SnpSource> Batches<S> {
+  pub fn new(source: S, batch_size: usize) -> Self {
+    if batch_size < 1 {
+      panic!("Batch size can't be less than 1.");
+    }
+    let ids_num = source.ids_num();
+    Batches {
+      source,
+      buf: vec![0.0; ids_num * batch_size],
+    }
+  }
+
+  pub fn ids_num(&self) -> usize {
+    self.source.ids_num()
+  }
+
+  /// @brief Reads the next batch, None at the end of source.
+  pub fn next_batch(&mut self) -> Option<std::io::Result<Batch<'_>>> {
+    let mut markers = Vec::new();
+    match self.source.read_batch(&mut self.buf, Some(&mut markers)) {
+      Ok(0) => None,
+      Ok(rows) => Some(Ok(Batch {
+        markers,
+        data: &self.buf[..rows * self.source.ids_num()],
+        rows,
+      })),
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
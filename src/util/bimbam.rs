@@ -0,0 +1,206 @@
+// bimbam.rs
+
+//! BIMBAM mean genotype files (GEMMA -g): a line per marker with its id, the
+//! two alleles and the mean genotype (expected count of the first allele,
+//! 0 to 2) of every individual. Fields are separated by commas and/or
+//! whitespace, there is no header: individuals are in the order of the
+//! phenotype file.
+//!
+//! https://www.xzlab.org/software/GEMMAmanual.pdf
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use super::error::Error;
+use super::kinship::SnpSource;
+use super::na;
+use super::output::NumberFormat;
+use super::stats::DosageScale;
+use super::text;
+
+/// @brief Fields of a line before the mean genotypes.
+const MARKER_FIELDS: usize = 3;
+
+/// @brief SnpSource over the lines of a BIMBAM mean genotype file. Mean
+/// genotypes are halved onto the scale of the default genotype codes (A=0,
+/// H=0.5, B=1 with the first allele as B), so the Kinship matrix equals the
+/// one of the same genotypes in R/qtl2 format.
+///
+/// @note "NA" mean genotypes are na::NA, lines with them are rejected unless
+/// allow_na is set.
+pub struct BimbamReader<R: BufRead> {
+  lines: text::Lines<R>,
+  ids_num: usize,
+  /// @note The first line, read to count the individuals.
+  first: Option<String>,
+  allow_na: bool,
+  line_num: usize,
+}
+
+impl<R: BufRead> BimbamReader<R> {
+  /// @brief Reads the first line, the amount of individuals is taken from
+  /// it.
+  pub fn new(reader: R, allow_na: bool) -> std::io::Result<Self> {
+    let mut lines = text::Lines::new(reader);
+    let mut line_num = 0;
+    let first = loop {
+      match lines.next() {
+        Some(line) => {
+          let line = line?;
+          line_num += 1;
+          if !line.trim().is_empty() {
+            break Some(line);
+          }
+        }
+        None => break None,
+      }
+    };
+    let ids_num = first
+      .as_deref()
+      .map_or(0, |line| fields(line).count().saturating_sub(MARKER_FIELDS));
+    Ok(BimbamReader {
+      lines,
+      ids_num,
+      first,
+      allow_na,
+      line_num,
+    })
+  }
+
+  /// @brief Parses line into dosages. Returns the marker id.
+  fn parse_line(&self, line: &str, dosages: &mut [f64]) -> std::io::Result<String> {
+    let mut line_fields = fields(line);
+    let id = String::from(line_fields.next().unwrap_or_default());
+    let mut found = 0;
+    for field in line_fields.skip(MARKER_FIELDS - 1) {
+      let dosage = match field {
+        "NA" => na::NA,
+        _ => {
+          field.parse::<f64>().map_err(|_| Error::Parse {
+            line: Some(self.line_num),
+            msg: format!("Mean genotype <{}> of marker <{}> is not a number.", field, id),
+          })? / 2.0
+        }
+      };
+      if let Some(elem) = dosages.get_mut(found) {
+        *elem = dosage;
+      }
+      found += 1;
+    }
+    if found != self.ids_num {
+      return Err(
+        Error::DimensionMismatch {
+          marker: id,
+          expected: self.ids_num,
+          found,
+        }
+        .into(),
+      );
+    }
+    if !self.allow_na && na::count_na(dosages) > 0 {
+      return Err(
+        Error::Parse {
+          line: Some(self.line_num),
+          msg: format!("Marker <{}> has missing mean genotypes.", id),
+        }
+        .into(),
+      );
+    }
+    Ok(id)
+  }
+}
+
+impl<R: BufRead> SnpSource for BimbamReader<R> {
+  fn ids_num(&self) -> usize {
+    self.ids_num
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let ids_num = self.ids_num.max(1);
+    let mut rows = 0;
+    while (rows + 1) * ids_num <= buf.len() {
+      let line = match self.first.take() {
+        Some(line) => line,
+        None => match self.lines.next() {
+          Some(line) => {
+            self.line_num += 1;
+            line?
+          }
+          None => break,
+        },
+      };
+      if line.trim().is_empty() {
+        continue;
+      }
+      let id = self.parse_line(&line, &mut buf[rows * ids_num..(rows + 1) * ids_num])?;
+      if let Some(row_ids) = row_ids.as_mut() {
+        row_ids.push(id);
+      }
+      rows += 1;
+    }
+    Ok(rows)
+  }
+}
+
+/// @brief Fields of BIMBAM line, separated by commas and/or whitespace.
+fn fields(line: &str) -> impl Iterator<Item = &str> {
+  line.split(|c: char| c == ',' || c.is_whitespace()).filter(|field| !field.is_empty())
+}
+
+/// @brief Streams SNP lines of source (see GenoParser::write_bimbam) with
+/// genotype values of hab_mapper to writer as BIMBAM mean genotypes, the
+/// inverse of BimbamReader: dosages are mapped from the scale of hab_mapper
+/// (see stats::DosageScale) onto 0 to 2, alleles are written as the codes of
+/// the homozygous genotypes (the counted one, at the maximal value, first),
+/// missing dosages as NA. Returns amount of markers written.
+///
+/// @note Returns Error::Validation if hab_mapper has no two distinct
+/// genotype values.
+pub fn write_bimbam<W: Write>(
+  source: &mut dyn SnpSource,
+  hab_mapper: &HashMap<char, f64>,
+  writer: &mut W,
+  number_format: &NumberFormat,
+) -> std::io::Result<usize> {
+  const BATCH_ROWS: usize = 256;
+  let scale = DosageScale::from_mapper(hab_mapper);
+  if scale.max <= scale.min {
+    return Err(
+      Error::Validation(String::from(
+        "Genotype codes should have distinct homozygous values to write BIMBAM.",
+      ))
+      .into(),
+    );
+  }
+  // The smallest code of a value, in case several codes map to it.
+  let code_of = |value: f64| {
+    let codes = hab_mapper.iter().filter(|(_, code_value)| **code_value == value);
+    codes.map(|(code, _)| *code).min().unwrap_or_default()
+  };
+  let (counted, other) = (code_of(scale.max), code_of(scale.min));
+  let ids_num = source.ids_num();
+  let mut buf = vec![0.0; ids_num.max(1) * BATCH_ROWS];
+  let mut row_ids = Vec::new();
+  let mut markers_num = 0;
+  loop {
+    row_ids.clear();
+    let rows = source.read_batch(&mut buf, Some(&mut row_ids))?;
+    if rows == 0 {
+      break;
+    }
+    for (id, line) in row_ids.iter().zip(buf.chunks(ids_num.max(1))) {
+      write!(writer, "{}, {}, {}", id, counted, other)?;
+      for value in &line[..ids_num] {
+        writer.write_all(b", ")?;
+        number_format.write(writer, scale.allele_freq(*value) * 2.0)?;
+      }
+      writer.write_all(b"\n")?;
+    }
+    markers_num += rows;
+  }
+  Ok(markers_num)
+}
@@ -0,0 +1,31 @@
+// cancel.rs
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// @brief Aborts a long running calculation from another thread (e.g. the UI
+/// thread of a GUI or a request handler of a server), see
+/// KinshipOptions::cancel.
+///
+/// @note Clones share the flag: keep one and pass another in the options.
+/// Once cancelled, a handle stays cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationHandle {
+  cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationHandle {
+  pub fn new() -> Self {
+    CancellationHandle::default()
+  }
+
+  /// @brief Makes the calculations using the handle stop at the next batch
+  /// and return Error::Cancelled.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+}
@@ -0,0 +1,421 @@
+// chunked.rs
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use super::code_table::CodeTable;
+use super::error::Error;
+use super::kinship::SnpSource;
+use super::na;
+use super::text;
+
+/// @brief SnpSource which parses SNP lines directly from the internal buffer
+/// of a BufRead, chunk by chunk across the buffer refills, without collecting
+/// a line into a String first. Memory consumption doesn't depend on the line
+/// length, which matters for files with millions of ids, where every line is
+/// megabytes long.
+///
+/// @note ASCII genotype codes are looked up by byte (see CodeTable), the
+/// bytes of a non-ASCII code are collected into its character first.
+pub struct ChunkedGenoReader<'a, R: BufRead> {
+  reader: &'a mut R,
+  /// @note Amount of genotype columns of the lines.
+  columns_num: usize,
+  codes: CodeTable,
+  allow_na: bool,
+  blank_lines: usize,
+  /// @note Amount of row ids decoded as Latin-1, see latin1_row_ids.
+  latin1_row_ids: usize,
+  /// @note Whether lines start with a row id, see without_row_ids.
+  row_ids: bool,
+  /// @note Amount of SNP lines read so far.
+  lines_read: usize,
+  /// @note Position in the batch lines of every genotype column, None for
+  /// the skipped ones, see with_columns. All columns are kept if absent.
+  slots: Option<Vec<Option<usize>>>,
+  /// @note Amount of values of a batch line, columns_num unless some
+  /// columns are skipped.
+  ids_num: usize,
+  /// @note Position of the reader in the input in bytes, see starting_at.
+  position: u64,
+  /// @note Line of the input of the first SNP line, see at_line.
+  first_line: Option<usize>,
+}
+
+impl<'a, R: BufRead> ChunkedGenoReader<'a, R> {
+  /// @note Reader cursor must be located at the beginning of SNP lines. Lines
+  /// with missing genotypes are rejected unless allow_na is set.
+  pub fn new(
+    reader: &'a mut R,
+    ids_num: usize,
+    hab_mapper: &HashMap<char, f64>,
+    allow_na: bool,
+  ) -> std::io::Result<Self> {
+    Ok(ChunkedGenoReader {
+      reader,
+      columns_num: ids_num,
+      codes: CodeTable::new(hab_mapper),
+      allow_na,
+      blank_lines: 0,
+      latin1_row_ids: 0,
+      row_ids: true,
+      lines_read: 0,
+      slots: None,
+      ids_num,
+      position: 0,
+      first_line: None,
+    })
+  }
+
+  /// @brief Keeps only the genotype columns at positions (counting from 0),
+  /// in the given order: batch lines hold positions.len() values and codes
+  /// of the other columns are skipped without being converted.
+  ///
+  /// @note Returns Error::Validation for a position out of the ids or listed
+  /// more than once.
+  pub fn with_columns(mut self, positions: &[usize]) -> std::io::Result<Self> {
+    let mut slots = vec![None; self.columns_num];
+    for (slot, pos) in positions.iter().enumerate() {
+      match slots.get_mut(*pos) {
+        Some(entry @ None) => *entry = Some(slot),
+        Some(Some(_)) => {
+          return Err(Error::Validation(format!("Column {} is selected twice.", pos)).into())
+        }
+        None => {
+          return Err(
+            Error::Validation(format!(
+              "Column {} is out of the {} genotype columns.",
+              pos, self.columns_num
+            ))
+            .into(),
+          )
+        }
+      }
+    }
+    self.slots = Some(slots);
+    self.ids_num = positions.len();
+    Ok(self)
+  }
+
+  /// @brief Reads lines which hold just genotype codes, their numbers
+  /// (counting from 1) are the row ids.
+  pub fn without_row_ids(mut self) -> Self {
+    self.row_ids = false;
+    self
+  }
+
+  /// @brief Sets position (in bytes) of the reader cursor in the input and
+  /// amount of SNP lines before it, for a reader which doesn't start at the
+  /// first SNP line (e.g. resuming from KinshipCheckpoint::offset). Lines
+  /// without row ids are numbered after them.
+  pub fn starting_at(mut self, position: u64, lines_read: usize) -> Self {
+    self.position = position;
+    self.lines_read = lines_read;
+    self
+  }
+
+  /// @brief Sets the line of the input (counting from 1, comment and header
+  /// lines included) of the first SNP line, so Error::Parse reports lines of
+  /// the input.
+  ///
+  /// @note Blank lines before the lines skipped by starting_at aren't
+  /// counted.
+  pub fn at_line(mut self, line: usize) -> Self {
+    self.first_line = Some(line);
+    self
+  }
+
+  /// @brief Line of the input after the lines (SNP and blank ones) read so
+  /// far, None unless at_line is set.
+  fn next_line(&self) -> Option<usize> {
+    self.first_line.map(|line| line + self.lines_read + self.blank_lines)
+  }
+
+  /// @brief Amount of empty or whitespace-only lines skipped so far.
+  pub fn blank_lines(&self) -> usize {
+    self.blank_lines
+  }
+
+  /// @brief Amount of row ids returned so far which were not valid UTF-8
+  /// and were decoded as Latin-1.
+  pub fn latin1_row_ids(&self) -> usize {
+    self.latin1_row_ids
+  }
+
+  fn consume(&mut self, amount: usize) {
+    self.reader.consume(amount);
+    self.position += amount as u64;
+  }
+
+  /// @brief Error::Parse of the next line, which starts with row_id, failing
+  /// after it (at the column following row_id) for reason msg.
+  fn invalid_record(&self, row_id: &[u8], msg: &str) -> std::io::Error {
+    let row_id = String::from_utf8_lossy(row_id);
+    Error::Parse {
+      line: self.next_line(),
+      msg: format!(
+        "This line <{}> is an invalid SNP record: {} (column {}).",
+        row_id,
+        msg,
+        row_id.chars().count() + 1
+      ),
+    }
+    .into()
+  }
+
+  /// @brief Reads the row id up to the tab, skipping blank lines. Returns
+  /// false at the end of the stream.
+  fn read_row_id(&mut self, row_id: &mut Vec<u8>) -> std::io::Result<bool> {
+    loop {
+      let available = self.reader.fill_buf()?;
+      if available.is_empty() {
+        if row_id.is_empty() {
+          return Ok(false);
+        }
+        if is_blank(row_id) {
+          self.blank_lines += 1;
+          return Ok(false);
+        }
+        let msg = "snp record and row id should be separated with tab";
+        return Err(self.invalid_record(row_id, msg));
+      }
+      match available.iter().position(|b| *b == b'\t' || *b == b'\n') {
+        Some(pos) => {
+          let separator = available[pos];
+          row_id.extend_from_slice(&available[..pos]);
+          self.consume(pos + 1);
+          let blank = is_blank(row_id) && (separator == b'\n' || self.skip_blank_rest()?);
+          if blank {
+            self.blank_lines += 1;
+            row_id.clear();
+            continue;
+          }
+          if separator == b'\n' {
+            let msg = "snp record and row id should be separated with tab";
+            return Err(self.invalid_record(row_id, msg));
+          }
+          if is_blank(row_id) {
+            return Err(self.invalid_record(&[], "row id is empty"));
+          }
+          return Ok(true);
+        }
+        None => {
+          row_id.extend_from_slice(available);
+          let len = available.len();
+          self.consume(len);
+        }
+      }
+    }
+  }
+
+  /// @brief Skips blank lines up to the next line without row id. Returns
+  /// false at the end of the stream.
+  fn skip_to_codes(&mut self) -> std::io::Result<bool> {
+    // Genotype codes aren't whitespace, so leading whitespace is blank lines.
+    let mut line_start = true;
+    loop {
+      let available = self.reader.fill_buf()?;
+      if available.is_empty() {
+        self.blank_lines += !line_start as usize;
+        return Ok(false);
+      }
+      let pos = available.iter().position(|b| !b.is_ascii_whitespace());
+      let skipped = &available[..pos.unwrap_or(available.len())];
+      for byte in skipped {
+        line_start = *byte == b'\n';
+        self.blank_lines += line_start as usize;
+      }
+      let len = skipped.len();
+      self.consume(len);
+      if pos.is_some() {
+        return Ok(true);
+      }
+    }
+  }
+
+  /// @brief Consumes the rest of the line. Returns whether it's blank.
+  fn skip_blank_rest(&mut self) -> std::io::Result<bool> {
+    let mut blank = true;
+    loop {
+      let available = self.reader.fill_buf()?;
+      if available.is_empty() {
+        return Ok(blank);
+      }
+      let (chunk, line_end) = match available.iter().position(|b| *b == b'\n') {
+        Some(pos) => (&available[..pos], true),
+        None => (available, false),
+      };
+      blank = blank && is_blank(chunk);
+      let consumed = chunk.len() + line_end as usize;
+      self.consume(consumed);
+      if line_end {
+        return Ok(blank);
+      }
+    }
+  }
+
+  /// @brief Parses genotypes of a line into snps, the rest of the line after
+  /// the next tab is skipped. Returns amount of genotype codes in the line.
+  fn read_genotypes(&mut self, row_id: &[u8], snps: &mut [f64]) -> std::io::Result<usize> {
+    let mut parsed = 0;
+    let mut skipping = false;
+    // Bytes of a non-ASCII code read so far, it may span buffer refills.
+    let mut pending = Vec::with_capacity(4);
+    loop {
+      let available = self.reader.fill_buf()?;
+      if available.is_empty() {
+        check_pending(&pending, row_id, parsed)?;
+        return Ok(parsed);
+      }
+      let (chunk, line_end) = match available.iter().position(|b| *b == b'\n') {
+        Some(pos) => (&available[..pos], true),
+        None => (available, false),
+      };
+      if !skipping {
+        for byte in chunk {
+          match byte {
+            b'\t' => {
+              skipping = true;
+              break;
+            }
+            b'\r' => continue,
+            _ => (),
+          }
+          let value = match code_value(&self.codes, *byte, &mut pending) {
+            Some(value) => value,
+            None => continue,
+          };
+          let slot = match &self.slots {
+            None => Some(parsed),
+            Some(slots) => slots.get(parsed).copied().flatten(),
+          };
+          if let Some(slot) = slot.filter(|slot| *slot < snps.len()) {
+            snps[slot] = value.map_err(|code| unknown_code(row_id, parsed, code))?;
+          }
+          parsed += 1;
+        }
+      }
+      let consumed = chunk.len() + line_end as usize;
+      self.consume(consumed);
+      if line_end || skipping {
+        check_pending(&pending, row_id, parsed)?;
+      }
+      if line_end {
+        return Ok(parsed);
+      }
+    }
+  }
+}
+
+fn is_blank(bytes: &[u8]) -> bool {
+  bytes.iter().all(|b| b.is_ascii_whitespace())
+}
+
+/// @brief Value of the code which ends with byte, Err with the code if it's
+/// not mapped. Bytes of a non-ASCII code are collected in pending, None is
+/// returned until the code is complete.
+#[inline]
+fn code_value(codes: &CodeTable, byte: u8, pending: &mut Vec<u8>) -> Option<Result<f64, char>> {
+  if byte.is_ascii() && pending.is_empty() {
+    return Some(codes.get(byte).ok_or(char::from(byte)));
+  }
+  pending.push(byte);
+  let width = match pending[0] {
+    0xc0..=0xdf => 2,
+    0xe0..=0xef => 3,
+    0xf0..=0xf7 => 4,
+    _ => 1,
+  };
+  if pending.len() < width && !byte.is_ascii() {
+    return None;
+  }
+  let code = std::str::from_utf8(pending).ok().and_then(|code| code.chars().next());
+  let code = code.unwrap_or(char::REPLACEMENT_CHARACTER);
+  pending.clear();
+  Some(codes.get_char(code).ok_or(code))
+}
+
+/// @brief Error::UnknownGenotypeCode for a non-ASCII code left incomplete at
+/// the end of the genotypes, parsed codes into them.
+fn check_pending(pending: &[u8], row_id: &[u8], parsed: usize) -> std::io::Result<()> {
+  match pending.is_empty() {
+    true => Ok(()),
+    false => Err(unknown_code(row_id, parsed, char::REPLACEMENT_CHARACTER)),
+  }
+}
+
+/// @brief Error::UnknownGenotypeCode of the code following parsed codes of
+/// the line starting with row_id.
+fn unknown_code(row_id: &[u8], parsed: usize, code: char) -> std::io::Error {
+  Error::UnknownGenotypeCode {
+    marker: String::from_utf8_lossy(row_id).into_owned(),
+    column: parsed + 1,
+    code,
+  }
+  .into()
+}
+
+impl<'a, R: BufRead> SnpSource for ChunkedGenoReader<'a, R> {
+  fn ids_num(&self) -> usize {
+    self.ids_num
+  }
+
+  fn position(&self) -> Option<u64> {
+    Some(self.position)
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let mut parsed_lines_counter: usize = 0;
+    let mut row_id = Vec::new();
+    for line_slice in buf.chunks_mut(self.ids_num.max(1)) {
+      row_id.clear();
+      let found = match self.row_ids {
+        true => self.read_row_id(&mut row_id)?,
+        false => self.skip_to_codes()?,
+      };
+      if !found {
+        break;
+      }
+      if !self.row_ids {
+        row_id.extend_from_slice((self.lines_read + 1).to_string().as_bytes());
+      }
+      self.lines_read += 1;
+      let parsed = self.read_genotypes(&row_id, line_slice)?;
+      if parsed != self.columns_num {
+        return Err(
+          Error::DimensionMismatch {
+            marker: String::from_utf8_lossy(&row_id).into_owned(),
+            expected: self.columns_num,
+            found: parsed,
+          }
+          .into(),
+        );
+      }
+      let missing = line_slice.iter().position(|snp| na::is_na(*snp));
+      if let Some(column) = missing.filter(|_| !self.allow_na) {
+        return Err(
+          Error::Parse {
+            line: self.next_line().map(|line| line - 1),
+            msg: format!(
+              "This line <{}> contains missing genotypes, the first in column {}.",
+              String::from_utf8_lossy(&row_id),
+              column + 1
+            ),
+          }
+          .into(),
+        );
+      }
+      if let Some(row_ids) = row_ids.as_deref_mut() {
+        let (row_id, latin1) = text::decode(row_id.clone());
+        self.latin1_row_ids += latin1 as usize;
+        row_ids.push(row_id);
+      }
+      parsed_lines_counter += 1;
+    }
+    Ok(parsed_lines_counter)
+  }
+}
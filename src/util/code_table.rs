@@ -0,0 +1,52 @@
+// code_table.rs
+
+use std::collections::HashMap;
+
+/// @brief Genotype values of hab_mapper codes in a table indexed by byte, so
+/// decoding a genotype call is an array lookup instead of hashing a char.
+/// Built once per parser (or source) and shared by the lines it parses.
+#[derive(Clone, Debug)]
+pub struct CodeTable {
+  /// @note Value of every ASCII code, None (the sentinel) for unmapped bytes.
+  /// Bytes of multi-byte UTF-8 characters are never mapped.
+  values: [Option<f64>; 256],
+  /// @note Values of non-ASCII codes, looked up by get_char only.
+  others: HashMap<char, f64>,
+}
+
+impl CodeTable {
+  pub fn new(hab_mapper: &HashMap<char, f64>) -> Self {
+    let mut values = [None; 256];
+    let mut others = HashMap::new();
+    for (code, value) in hab_mapper {
+      match code.is_ascii() {
+        true => values[*code as usize] = Some(*value),
+        false => {
+          others.insert(*code, *value);
+        }
+      }
+    }
+    CodeTable { values, others }
+  }
+
+  /// @brief Value of the code byte, None if it's not mapped.
+  #[inline]
+  pub fn get(&self, code: u8) -> Option<f64> {
+    self.values[code as usize]
+  }
+
+  /// @brief Same as get for a code of any character.
+  #[inline]
+  pub fn get_char(&self, code: char) -> Option<f64> {
+    match code.is_ascii() {
+      true => self.values[code as usize],
+      false => self.others.get(&code).copied(),
+    }
+  }
+
+  /// @brief A code which isn't an ASCII character, so it can't be looked up
+  /// by get, None if there is none.
+  pub fn non_ascii_code(&self) -> Option<char> {
+    self.others.keys().min().copied()
+  }
+}
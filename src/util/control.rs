@@ -0,0 +1,541 @@
+// control.rs
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use super::error::Error;
+use super::founders::FounderGenotypes;
+use super::geno_files::GenoFiles;
+use super::gmap::{GeneticMap, MapParser};
+use super::matrix_csv::{LabeledTable, MatrixCsvReader};
+use super::na;
+use super::pheno_parser::PhenoParser;
+use super::GenoParser;
+
+/// @brief Value of a control file entry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlValue {
+  /// @note Numbers and booleans are kept as written.
+  Scalar(String),
+  List(Vec<ControlValue>),
+  /// @note Entries in file order.
+  Map(Vec<(String, ControlValue)>),
+}
+
+impl ControlValue {
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      ControlValue::Scalar(value) => Some(value),
+      _ => None,
+    }
+  }
+
+  /// @brief Boolean scalar as written by R (TRUE, true, yes, ...).
+  pub fn as_bool(&self) -> Option<bool> {
+    match self.as_str()? {
+      "TRUE" | "True" | "true" | "T" | "yes" => Some(true),
+      "FALSE" | "False" | "false" | "F" | "no" => Some(false),
+      _ => None,
+    }
+  }
+
+  /// @brief Value of key of a map.
+  pub fn get(&self, key: &str) -> Option<&ControlValue> {
+    match self {
+      ControlValue::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+      _ => None,
+    }
+  }
+}
+
+/// @brief R/qtl2 control file (YAML or JSON, e.g. iron.yaml): names of the
+/// data files, genotype codes and missing value strings of a dataset.
+///
+/// @note https://kbroman.org/qtl2/assets/vignettes/input_files.html#Control_file
+/// Only the subset of YAML written by qtl2::write_control_file is supported:
+/// nested block mappings, block lists, flow lists/mappings and quoted
+/// scalars.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlFile {
+  /// @note Relative file names are resolved against it (the directory of
+  /// the control file).
+  dir: PathBuf,
+  root: ControlValue,
+}
+
+impl ControlFile {
+  /// @brief Reads control file at path, JSON if it has .json extension or
+  /// starts with '{', YAML otherwise.
+  pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)?;
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let json = path.extension().is_some_and(|ext| ext == "json");
+    Self::parse(&contents, dir, json)
+  }
+
+  /// @brief Parses control file contents, file names are resolved against
+  /// dir.
+  ///
+  /// @note Returns Error::Validation for malformed contents.
+  pub fn parse<P: AsRef<Path>>(contents: &str, dir: P, json: bool) -> std::io::Result<Self> {
+    let root = if json || contents.trim_start().starts_with('{') {
+      let mut flow = Flow::new(contents);
+      let root = flow.value()?;
+      flow.end()?;
+      root
+    } else {
+      parse_yaml(contents)?
+    };
+    if !matches!(root, ControlValue::Map(_)) {
+      return Err(Error::Validation(String::from("Control file should be a mapping.")).into());
+    }
+    Ok(ControlFile {
+      dir: dir.as_ref().to_path_buf(),
+      root,
+    })
+  }
+
+  /// @brief Top-level entry, e.g. "crosstype" or "x_chr".
+  pub fn get(&self, key: &str) -> Option<&ControlValue> {
+    self.root.get(key)
+  }
+
+  pub fn crosstype(&self) -> Option<&str> {
+    self.get("crosstype").and_then(ControlValue::as_str)
+  }
+
+  /// @brief Path of file entry key (geno, pheno, covar, phenocovar, gmap,
+  /// pmap, founder_geno), resolved against the control file directory.
+  ///
+  /// @note Returns Error::Validation if the entry is absent or lists several
+  /// files (e.g. one per chromosome).
+  pub fn file(&self, key: &str) -> std::io::Result<PathBuf> {
+    match self.get(key) {
+      Some(ControlValue::Scalar(name)) => Ok(self.dir.join(name)),
+      Some(_) => Err(
+        Error::Validation(format!("Control file entry <{}> should name a single file.", key))
+          .into(),
+      ),
+      None => Err(Error::Validation(format!("Control file has no <{}> entry.", key)).into()),
+    }
+  }
+
+  /// @brief Paths of file entry key which lists one file or several (e.g.
+  /// one per chromosome), resolved against the control file directory.
+  ///
+  /// @note Returns Error::Validation if the entry is absent or isn't a file
+  /// name or a list of them.
+  pub fn files(&self, key: &str) -> std::io::Result<Vec<PathBuf>> {
+    match self.get(key) {
+      Some(ControlValue::List(names)) => names
+        .iter()
+        .map(|name| match name {
+          ControlValue::Scalar(name) => Ok(self.dir.join(name)),
+          _ => Err(
+            Error::Validation(format!("Control file entry <{}> should list file names.", key))
+              .into(),
+          ),
+        })
+        .collect(),
+      _ => Ok(vec![self.file(key)?]),
+    }
+  }
+
+  /// @brief Strings which denote missing values (na.strings), the crate
+  /// defaults if the entry is absent.
+  pub fn na_strings(&self) -> Vec<String> {
+    match self.get("na.strings") {
+      Some(ControlValue::Scalar(value)) => vec![value.clone()],
+      Some(ControlValue::List(values)) => {
+        values.iter().filter_map(|value| value.as_str().map(String::from)).collect()
+      }
+      _ => na::default_na_strings(),
+    }
+  }
+
+  /// @brief Field separator of the CSV files (sep), ',' by default.
+  pub fn separator(&self) -> std::io::Result<char> {
+    let sep = self.get("sep").and_then(ControlValue::as_str).unwrap_or(",");
+    let mut chars = sep.chars();
+    match (chars.next(), chars.next()) {
+      (Some(sep), None) => Ok(sep),
+      _ => Err(Error::Validation(format!("Separator <{}> should be a character.", sep)).into()),
+    }
+  }
+
+  /// @brief hab_mapper built from the genotypes section: genotype numbers
+  /// are rescaled to [0, 1] (e.g. AA: 1, AB: 2, BB: 3 to 0, 0.5, 1) and the
+  /// single character na.strings map to na::NA.
+  ///
+  /// @note Returns Error::Validation if the section is absent or a genotype
+  /// code is not a single character (genotype file lines are strings of
+  /// codes).
+  pub fn hab_mapper(&self) -> std::io::Result<HashMap<char, f64>> {
+    let entries = match self.get("genotypes") {
+      Some(ControlValue::Map(entries)) => entries,
+      _ => {
+        return Err(
+          Error::Validation(String::from("Control file has no genotypes section.")).into(),
+        )
+      }
+    };
+    let mut genotypes = Vec::new();
+    for (code, value) in entries {
+      let number = value
+        .as_str()
+        .and_then(|value| value.parse::<f64>().ok())
+        .ok_or_else(|| {
+          Error::Validation(format!("Genotype <{}> should be a number.", code))
+        })?;
+      genotypes.push((single_char(code)?, number));
+    }
+    let min = genotypes.iter().map(|(_, number)| *number).fold(f64::INFINITY, f64::min);
+    let max = genotypes.iter().map(|(_, number)| *number).fold(f64::NEG_INFINITY, f64::max);
+    let range = if max > min { max - min } else { 1.0 };
+    let mut hab_mapper = genotypes
+      .into_iter()
+      .map(|(code, number)| (code, (number - min) / range))
+      .collect::<HashMap<char, f64>>();
+    for na_string in self.na_strings() {
+      if let Ok(code) = single_char(&na_string) {
+        hab_mapper.entry(code).or_insert(na::NA);
+      }
+    }
+    Ok(hab_mapper)
+  }
+
+  /// @brief Parser of the genotype file, transposed if geno_transposed is
+  /// set.
+  ///
+  /// @note Returns Error::Validation if geno lists several files, see
+  /// geno_files for them.
+  pub fn geno_parser(&self) -> std::io::Result<GenoParser> {
+    let files = self.files("geno")?;
+    if files.len() != 1 {
+      return Err(
+        Error::Validation(format!(
+          "Control file lists {} genotype files, read them with ControlFile::geno_files.",
+          files.len()
+        ))
+        .into(),
+      );
+    }
+    let mut parser = GenoParser::new_with_file(File::open(&files[0])?, self.hab_mapper()?)?;
+    let transposed = self.get("geno_transposed").and_then(ControlValue::as_bool);
+    parser.set_transposed(transposed.unwrap_or(false))?;
+    Ok(parser)
+  }
+
+  /// @brief Genotype files (geno), one or several (e.g. one per
+  /// chromosome), read as a single stream of SNP lines.
+  ///
+  /// @note Returns Error::Validation if geno_transposed is set, see
+  /// geno_parser for a single transposed file.
+  pub fn geno_files(&self) -> std::io::Result<GenoFiles> {
+    if self.get("geno_transposed").and_then(ControlValue::as_bool) == Some(true) {
+      return Err(
+        Error::Validation(String::from(
+          "Transposed genotype files can't be read as a stream of SNP lines.",
+        ))
+        .into(),
+      );
+    }
+    GenoFiles::open(&self.files("geno")?, self.hab_mapper()?)
+  }
+
+  /// @brief Founder genotypes (founder_geno) of a multi-parent cross, read
+  /// with the genotype codes of the control file in the format of the
+  /// genotype file. Transposed if founder_geno_transposed is set.
+  pub fn founder_genotypes(&self) -> std::io::Result<FounderGenotypes> {
+    let mut parser =
+      GenoParser::new_with_file(File::open(self.file("founder_geno")?)?, self.hab_mapper()?)?;
+    let transposed = self.get("founder_geno_transposed").and_then(ControlValue::as_bool);
+    parser.set_transposed(transposed.unwrap_or(false))?;
+    FounderGenotypes::from_geno_parser(&mut parser)
+  }
+
+  /// @brief Phenotype file (pheno) with the separator and na.strings of the
+  /// control file.
+  pub fn pheno_parser(&self) -> std::io::Result<PhenoParser<BufReader<File>>> {
+    PhenoParser::with_csv(self.file("pheno")?, self.csv_reader()?)
+  }
+
+  /// @brief Reads table file entry key (e.g. covar, phenocovar) with the
+  /// separator and na.strings of the control file.
+  pub fn table(&self, key: &str) -> std::io::Result<LabeledTable> {
+    self.csv_reader()?.read(self.file(key)?)
+  }
+
+  fn csv_reader(&self) -> std::io::Result<MatrixCsvReader> {
+    Ok(MatrixCsvReader::new().with_delimiter(self.separator()?).with_na_strings(self.na_strings()))
+  }
+
+  /// @brief Genetic map (gmap).
+  pub fn gmap(&self) -> std::io::Result<GeneticMap> {
+    self.map_parser()?.read(self.file("gmap")?)
+  }
+
+  /// @brief Physical map (pmap).
+  pub fn pmap(&self) -> std::io::Result<GeneticMap> {
+    self.map_parser()?.read(self.file("pmap")?)
+  }
+
+  fn map_parser(&self) -> std::io::Result<MapParser> {
+    Ok(MapParser::new().with_delimiter(self.separator()?).with_na_strings(self.na_strings()))
+  }
+}
+
+fn single_char(code: &str) -> std::io::Result<char> {
+  let mut chars = code.chars();
+  match (chars.next(), chars.next()) {
+    (Some(code), None) => Ok(code),
+    _ => Err(
+      Error::Validation(format!("Genotype code <{}> should be a single character.", code)).into(),
+    ),
+  }
+}
+
+fn invalid(msg: String) -> std::io::Error {
+  Error::Validation(msg).into()
+}
+
+/// @brief Line of YAML: indentation and content without the comment.
+struct YamlLine<'a> {
+  num: usize,
+  indent: usize,
+  content: &'a str,
+}
+
+fn parse_yaml(contents: &str) -> std::io::Result<ControlValue> {
+  let lines = contents
+    .lines()
+    .enumerate()
+    .filter_map(|(num, line)| {
+      let content = strip_comment(line).trim_end();
+      let indent = content.len() - content.trim_start().len();
+      let content = content.trim_start();
+      match content.is_empty() || content == "---" {
+        true => None,
+        false => Some(YamlLine {
+          num: num + 1,
+          indent,
+          content,
+        }),
+      }
+    })
+    .collect::<Vec<YamlLine>>();
+  if lines.is_empty() {
+    return Ok(ControlValue::Map(Vec::new()));
+  }
+  let mut pos = 0;
+  let root = yaml_block(&lines, &mut pos, lines[0].indent)?;
+  match lines.get(pos) {
+    Some(line) => Err(invalid(format!("Unexpected indentation at line {}.", line.num))),
+    None => Ok(root),
+  }
+}
+
+/// @brief Removes "# comment" which is not inside quotes.
+fn strip_comment(line: &str) -> &str {
+  let mut quote = None;
+  let mut prev = ' ';
+  for (pos, c) in line.char_indices() {
+    match quote {
+      Some(q) if c == q => quote = None,
+      Some(_) => (),
+      None if c == '"' || c == '\'' => quote = Some(c),
+      None if c == '#' && prev.is_whitespace() => return &line[..pos],
+      None => (),
+    }
+    prev = c;
+  }
+  line
+}
+
+fn is_list_item(content: &str) -> bool {
+  content == "-" || content.starts_with("- ")
+}
+
+/// @brief Parses block mapping or list starting at lines[pos] of indent.
+fn yaml_block(lines: &[YamlLine], pos: &mut usize, indent: usize) -> std::io::Result<ControlValue> {
+  if is_list_item(lines[*pos].content) {
+    let mut items = Vec::new();
+    while let Some(line) = lines.get(*pos).filter(|line| line.indent == indent) {
+      if !is_list_item(line.content) {
+        break;
+      }
+      let item = line.content[1..].trim();
+      if item.contains(": ") || item.is_empty() {
+        return Err(invalid(format!("Unsupported list item at line {}.", line.num)));
+      }
+      items.push(yaml_scalar(item, line.num)?);
+      *pos += 1;
+    }
+    return Ok(ControlValue::List(items));
+  }
+
+  let mut entries = Vec::new();
+  while let Some(line) = lines.get(*pos).filter(|line| line.indent == indent) {
+    if is_list_item(line.content) {
+      return Err(invalid(format!("Unexpected list item at line {}.", line.num)));
+    }
+    let (key, value) = match line.content.find(": ") {
+      Some(split) => (&line.content[..split], line.content[split + 2..].trim()),
+      None => match line.content.strip_suffix(':') {
+        Some(key) => (key, ""),
+        None => return Err(invalid(format!("Expected \"key: value\" at line {}.", line.num))),
+      },
+    };
+    let key = unquote(key.trim());
+    *pos += 1;
+    // Lists may be indented as their key.
+    let nested = lines.get(*pos).filter(|next| {
+      next.indent > indent || (next.indent == indent && is_list_item(next.content))
+    });
+    let value = match (value.is_empty(), nested) {
+      (false, _) => yaml_scalar(value, line.num)?,
+      (true, Some(next)) => yaml_block(lines, pos, next.indent)?,
+      (true, None) => ControlValue::Scalar(String::new()),
+    };
+    entries.push((key, value));
+  }
+  Ok(ControlValue::Map(entries))
+}
+
+fn yaml_scalar(value: &str, line_num: usize) -> std::io::Result<ControlValue> {
+  if value.starts_with('[') || value.starts_with('{') {
+    let mut flow = Flow::new(value);
+    let res = flow.value().and_then(|res| flow.end().map(|_| res));
+    return res.map_err(|e| invalid(format!("{} (line {})", e, line_num)));
+  }
+  Ok(ControlValue::Scalar(unquote(value)))
+}
+
+fn unquote(value: &str) -> String {
+  for quote in ['"', '\''] {
+    if let Some(inner) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+      return String::from(inner);
+    }
+  }
+  String::from(value)
+}
+
+/// @brief Parser of JSON and of YAML flow collections ([a, b], {a: 1}),
+/// which may have unquoted scalars.
+struct Flow<'a> {
+  text: &'a str,
+  pos: usize,
+}
+
+impl<'a> Flow<'a> {
+  fn new(text: &'a str) -> Self {
+    Flow { text, pos: 0 }
+  }
+
+  fn skip_whitespace(&mut self) {
+    let rest = &self.text[self.pos..];
+    self.pos += rest.len() - rest.trim_start().len();
+  }
+
+  fn peek(&mut self) -> Option<char> {
+    self.skip_whitespace();
+    self.text[self.pos..].chars().next()
+  }
+
+  fn expect(&mut self, c: char) -> std::io::Result<()> {
+    match self.peek() {
+      Some(next) if next == c => {
+        self.pos += c.len_utf8();
+        Ok(())
+      }
+      _ => Err(invalid(format!("Expected <{}> at offset {}.", c, self.pos))),
+    }
+  }
+
+  fn end(&mut self) -> std::io::Result<()> {
+    match self.peek() {
+      None => Ok(()),
+      Some(_) => Err(invalid(format!("Unexpected text at offset {}.", self.pos))),
+    }
+  }
+
+  fn value(&mut self) -> std::io::Result<ControlValue> {
+    match self.peek() {
+      Some('{') => {
+        self.pos += 1;
+        let mut entries = Vec::new();
+        if self.peek() == Some('}') {
+          self.pos += 1;
+          return Ok(ControlValue::Map(entries));
+        }
+        loop {
+          let key = self.scalar(true)?;
+          self.expect(':')?;
+          entries.push((key, self.value()?));
+          match self.peek() {
+            Some(',') => self.pos += 1,
+            _ => break,
+          }
+        }
+        self.expect('}')?;
+        Ok(ControlValue::Map(entries))
+      }
+      Some('[') => {
+        self.pos += 1;
+        let mut items = Vec::new();
+        if self.peek() == Some(']') {
+          self.pos += 1;
+          return Ok(ControlValue::List(items));
+        }
+        loop {
+          items.push(self.value()?);
+          match self.peek() {
+            Some(',') => self.pos += 1,
+            _ => break,
+          }
+        }
+        self.expect(']')?;
+        Ok(ControlValue::List(items))
+      }
+      Some(_) => self.scalar(false).map(ControlValue::Scalar),
+      None => Err(invalid(String::from("Unexpected end of control file."))),
+    }
+  }
+
+  /// @brief Quoted or bare scalar, a bare key ends at ':'.
+  fn scalar(&mut self, key: bool) -> std::io::Result<String> {
+    let quote = match self.peek() {
+      Some(quote) if quote == '"' || quote == '\'' => quote,
+      _ => {
+        let rest = &self.text[self.pos..];
+        let end = rest
+          .find(|c: char| c == ',' || c == ']' || c == '}' || (key && c == ':'))
+          .unwrap_or(rest.len());
+        self.pos += end;
+        return Ok(String::from(rest[..end].trim()));
+      }
+    };
+    self.pos += 1;
+    let mut res = String::new();
+    let mut chars = self.text[self.pos..].char_indices();
+    while let Some((offset, c)) = chars.next() {
+      match c {
+        c if c == quote => {
+          self.pos += offset + 1;
+          return Ok(res);
+        }
+        '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+          Some('n') => res.push('\n'),
+          Some('t') => res.push('\t'),
+          Some(c) => res.push(c),
+          None => break,
+        },
+        c => res.push(c),
+      }
+    }
+    Err(invalid(String::from("Unterminated string in control file.")))
+  }
+}
@@ -0,0 +1,200 @@
+// dataset.rs
+
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use super::error::Error;
+use super::founders::{FounderCheck, FounderGenotypes, MarkerErrors};
+use super::kinship::{
+  calc_kinship_observed, read_observed_batch, BatchObserver, GramOrientation,
+  KinshipOptions,
+};
+use super::metadata::SampleMetadata;
+use super::parse_report::{codes, ParseReport};
+use super::stats::{
+  DosageScale, IbsAccumulator, IndividualStats, IndividualStatsAccumulator, MarkerStats,
+  MarkerStatsAccumulator,
+};
+use super::summary::{KinshipRun, RunSummary};
+use super::GenoParser;
+
+/// @brief Analysis which can be computed over genotype data.
+#[derive(Clone, Debug)]
+pub enum Analysis {
+  /// @note Only GramOrientation::IdsByIds is supported.
+  Kinship(KinshipOptions),
+  MarkerStats,
+  IndividualStats,
+  /// @brief Consistency of offspring genotypes with the founders, see
+  /// FounderCheck.
+  FounderErrors(FounderGenotypes),
+  /// @brief Identity-by-state matrix, see IbsAccumulator. Covers the lines
+  /// of the requested Kinship matrix (IbsAccumulator::with_options).
+  Ibs,
+}
+
+/// @brief Result of an Analysis.
+#[derive(Clone, Debug)]
+pub enum AnalysisResult {
+  Kinship(Vec<f64>),
+  MarkerStats(Vec<MarkerStats>),
+  IndividualStats(Vec<IndividualStats>),
+  FounderErrors(Vec<MarkerErrors>),
+  Ibs(Vec<f64>),
+}
+
+/// @brief R/qtl2 cross data.
+pub struct Dataset {
+  geno: GenoParser,
+  metadata: Option<SampleMetadata>,
+}
+
+impl Dataset {
+  pub fn new(geno: GenoParser) -> Self {
+    Dataset {
+      geno,
+      metadata: None,
+    }
+  }
+
+  /// @brief Attaches per-individual metadata (e.g. parsed from covar), to be
+  /// included in the reports (see report::write_kinship_pairs).
+  pub fn with_metadata(mut self, metadata: SampleMetadata) -> Self {
+    self.metadata = Some(metadata);
+    self
+  }
+
+  pub fn geno(&mut self) -> &mut GenoParser {
+    &mut self.geno
+  }
+
+  pub fn metadata(&self) -> Option<&SampleMetadata> {
+    self.metadata.as_ref()
+  }
+
+  /// @brief Problems found in the genotype file, and individuals without
+  /// metadata when it is attached.
+  pub fn report(&self) -> ParseReport {
+    let mut report = self.geno.get_report().clone();
+    if let Some(metadata) = &self.metadata {
+      for id in metadata.missing_ids(self.geno.get_markers()) {
+        report.warn(
+          codes::METADATA_MISSING,
+          None,
+          format!("Individual <{}> has no metadata.", id),
+        );
+      }
+    }
+    report
+  }
+
+  /// @brief Computes all analyses in a single pass over the genotype file.
+  /// Returns results in the order of analyses, each analysis may be listed
+  /// only once.
+  ///
+  /// @note Missing genotypes are allowed unless Kinship is requested.
+  pub fn compute(&mut self, analyses: &[Analysis]) -> std::io::Result<Vec<AnalysisResult>> {
+    self.compute_summarized(analyses).map(|(results, _)| results)
+  }
+
+  /// @brief Same as compute, also returns RunSummary of the pass (warnings
+  /// include the ones of report).
+  pub fn compute_summarized(
+    &mut self,
+    analyses: &[Analysis],
+  ) -> std::io::Result<(Vec<AnalysisResult>, RunSummary)> {
+    let report = self.report();
+    let mut kinship_options = None;
+    let mut founders = None;
+    let (mut wants_markers, mut wants_individuals, mut wants_ibs) = (false, false, false);
+    for analysis in analyses {
+      let already_requested = match analysis {
+        Analysis::Kinship(options) => {
+          if options.orientation != GramOrientation::IdsByIds {
+            return Err(
+              Error::Validation(String::from(
+                "Only IdsByIds Kinship can be computed along with other analyses.",
+              ))
+              .into(),
+            );
+          }
+          kinship_options.replace(options).is_some()
+        }
+        Analysis::MarkerStats => std::mem::replace(&mut wants_markers, true),
+        Analysis::IndividualStats => std::mem::replace(&mut wants_individuals, true),
+        Analysis::FounderErrors(genotypes) => founders.replace(genotypes).is_some(),
+        Analysis::Ibs => std::mem::replace(&mut wants_ibs, true),
+      };
+      if already_requested {
+        return Err(Error::Validation(format!("{:?} is requested twice.", analysis)).into());
+      }
+    }
+
+    let geno = &mut self.geno;
+    let scale = DosageScale::from_mapper(&geno.hab_mapper);
+    let mut marker_stats = wants_markers.then(|| MarkerStatsAccumulator::new(scale));
+    let mut individual_stats =
+      wants_individuals.then(|| IndividualStatsAccumulator::new(scale, geno.markers.clone()));
+    let mut founder_check = founders.map(|founders| FounderCheck::new(founders, scale));
+    // IBS covers the lines the Kinship matrix does.
+    let ibs_options = kinship_options.cloned().unwrap_or_default();
+    let mut ibs = wants_ibs
+      .then(|| IbsAccumulator::new(scale, geno.markers.len()).with_options(&ibs_options));
+    let mut observers = Vec::<&mut dyn BatchObserver>::new();
+    if let Some(acc) = marker_stats.as_mut() {
+      observers.push(acc);
+    }
+    if let Some(acc) = individual_stats.as_mut() {
+      observers.push(acc);
+    }
+    if let Some(acc) = founder_check.as_mut() {
+      observers.push(acc);
+    }
+    if let Some(acc) = ibs.as_mut() {
+      observers.push(acc);
+    }
+
+    geno.file_reader.seek(SeekFrom::Start(geno.snp_pos_start))?;
+    let ids_num = geno.markers.len();
+    let allow_na = kinship_options.is_none_or(|options| options.missing.allows_na());
+    let mut source = geno.snp_source(allow_na)?;
+    // Without Kinship the lines are only counted.
+    let run = KinshipRun::start(kinship_options.unwrap_or(&KinshipOptions::default()));
+    let res = match kinship_options {
+      Some(_) => calc_kinship_observed(&mut source, &run.options, &mut observers).map(Some),
+      None => {
+        let mut buf = vec![0.0; ids_num * KinshipOptions::default().batch_rows(ids_num)];
+        loop {
+          match read_observed_batch(&mut source, &mut buf, &mut observers, None) {
+            Ok(0) => break Ok(None),
+            Ok(n) => run.metrics().record_batch(n),
+            Err(e) => break Err(e),
+          }
+        }
+      }
+    };
+    drop(observers);
+    geno.file_reader.seek(SeekFrom::Start(geno.snp_pos_start))?;
+    let mut kinship = res?;
+
+    let mut marker_stats = marker_stats.map(MarkerStatsAccumulator::finish);
+    let mut individual_stats = individual_stats.map(IndividualStatsAccumulator::finish);
+    let mut founder_errors = founder_check.map(FounderCheck::finish);
+    let mut ibs = ibs.map(IbsAccumulator::finish);
+    let results = analyses
+      .iter()
+      .map(|analysis| match analysis {
+        Analysis::Kinship(_) => AnalysisResult::Kinship(kinship.take().unwrap()),
+        Analysis::MarkerStats => AnalysisResult::MarkerStats(marker_stats.take().unwrap()),
+        Analysis::IndividualStats => {
+          AnalysisResult::IndividualStats(individual_stats.take().unwrap())
+        }
+        Analysis::FounderErrors(_) => {
+          AnalysisResult::FounderErrors(founder_errors.take().unwrap())
+        }
+        Analysis::Ibs => AnalysisResult::Ibs(ibs.take().unwrap()),
+      })
+      .collect();
+    Ok((results, run.finish(ids_num, &report)))
+  }
+}
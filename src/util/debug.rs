@@ -0,0 +1,149 @@
+// debug.rs
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::anonymize::{mask_ids, IdMasking};
+use super::code_table::CodeTable;
+use super::environment;
+use super::error::Error;
+use super::kinship::KinshipOptions;
+use super::na;
+use super::text;
+use super::GenoParser;
+
+/// @brief What make_repro_bundle puts into the bundle besides the SNP lines.
+#[derive(Clone, Debug, Default)]
+pub struct ReproOptions {
+  /// @note Genotype codes the file is parsed with.
+  pub hab_mapper: HashMap<char, f64>,
+  /// @note Options of the failing calculation, written to the bundle. Lines
+  /// with missing genotypes are offending unless options.missing allows them.
+  pub kinship: KinshipOptions,
+  /// @note Masks ids of the header and leaves the comments out when given.
+  pub masking: Option<IdMasking>,
+}
+
+/// @brief Writes a tar archive with a reproduction of a problem with the
+/// genotype file at path, to be attached to an issue:
+///
+/// - geno.txt: the comments, the header and n_rows SNP lines: the ones up to
+///   the first line which fails to parse (offending), or the first n_rows
+///   lines if every line parses,
+/// - error.txt: the parse error of the offending line, if there is one,
+/// - environment.txt: environment::report() of the machine,
+/// - options.txt: the options.
+///
+/// Returns number (counting from 1) of the offending line in the file.
+///
+/// @note The mapping of masked ids is not written, it should stay private.
+pub fn make_repro_bundle<P: AsRef<Path>, W: Write>(
+  path: P,
+  n_rows: usize,
+  options: &ReproOptions,
+  writer: &mut W,
+) -> std::io::Result<Option<usize>> {
+  let mut geno = GenoParser::new_with_file(File::open(path)?, options.hab_mapper.clone())?;
+  geno.file_reader.seek(SeekFrom::Start(geno.snp_pos_start))?;
+  let ids_num = geno.markers.len();
+  let header_lines = geno.comments.len() + 1;
+  // The first n_rows lines, then the last n_rows ones once an error is found.
+  let (mut first, mut last) = (Vec::<String>::new(), VecDeque::<String>::new());
+  let mut offending = None;
+  let mut parsed = vec![0.0; ids_num];
+  let codes = CodeTable::new(&options.hab_mapper);
+  for (line_num, line) in text::Lines::new(&mut geno.file_reader).enumerate() {
+    let line = line?;
+    let res = GenoParser::parse_into(&mut parsed, &line, &codes).and_then(|_| {
+      match options.kinship.missing.allows_na() || !parsed.iter().any(|snp| na::is_na(*snp)) {
+        true => Ok(()),
+        false => Err(
+          Error::Parse {
+            line: None,
+            msg: format!("This line <{}> contains missing genotypes.", line),
+          }
+          .into(),
+        ),
+      }
+    });
+    if first.len() < n_rows {
+      first.push(line.clone());
+    }
+    if last.len() == n_rows {
+      last.pop_front();
+    }
+    last.push_back(line);
+    if let Err(e) = res {
+      offending = Some((header_lines + line_num + 1, e.to_string()));
+      break;
+    }
+  }
+
+  let mut geno_txt = String::new();
+  let ids = match &options.masking {
+    Some(masking) => mask_ids(&geno.markers, masking)?.anonymized,
+    None => {
+      for comment in &geno.comments {
+        geno_txt.push_str(&format!("#{}\n", comment));
+      }
+      geno.markers.clone()
+    }
+  };
+  geno_txt.push_str(&format!("marker\t{}\n", ids.join("\t")));
+  let lines = match offending {
+    Some(_) => Vec::from(last),
+    None => first,
+  };
+  for line in lines {
+    geno_txt.push_str(&line);
+    geno_txt.push('\n');
+  }
+  let options_txt = format!(
+    "n_rows: {}\nmasked ids: {}\nhab_mapper: {:?}\nkinship: {:#?}\n",
+    n_rows,
+    options.masking.is_some(),
+    options.hab_mapper,
+    options.kinship
+  );
+
+  write_tar_entry(writer, "geno.txt", geno_txt.as_bytes())?;
+  if let Some((line_num, error)) = &offending {
+    let error_txt = format!("line {}: {}\n", line_num, error);
+    write_tar_entry(writer, "error.txt", error_txt.as_bytes())?;
+  }
+  let environment_txt = format!("{}\n", environment::report());
+  write_tar_entry(writer, "environment.txt", environment_txt.as_bytes())?;
+  write_tar_entry(writer, "options.txt", options_txt.as_bytes())?;
+  // End of archive: two zero blocks.
+  writer.write_all(&[0; 2 * TAR_BLOCK])?;
+  Ok(offending.map(|(line_num, _)| line_num))
+}
+
+const TAR_BLOCK: usize = 512;
+
+/// @brief Writes a regular file entry of ustar archive.
+fn write_tar_entry<W: Write>(writer: &mut W, name: &str, contents: &[u8]) -> std::io::Result<()> {
+  let mut header = [0u8; TAR_BLOCK];
+  let mut field = |offset: usize, value: &[u8]| {
+    header[offset..offset + value.len()].copy_from_slice(value);
+  };
+  field(0, name.as_bytes());
+  field(100, b"0000644\0");
+  field(108, b"0000000\0");
+  field(116, b"0000000\0");
+  field(124, format!("{:011o}\0", contents.len()).as_bytes());
+  field(136, b"00000000000\0");
+  field(156, b"0");
+  field(257, b"ustar\0");
+  field(263, b"00");
+  // The checksum is computed with its own field filled with spaces.
+  field(148, b"        ");
+  let checksum = header.iter().map(|byte| *byte as u32).sum::<u32>();
+  header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+  writer.write_all(&header)?;
+  writer.write_all(contents)?;
+  let padding = (TAR_BLOCK - contents.len() % TAR_BLOCK) % TAR_BLOCK;
+  writer.write_all(&vec![0; padding])
+}
@@ -0,0 +1,211 @@
+// design.rs
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::error::Error;
+use super::matrix_csv::LabeledTable;
+
+/// @brief Term of a design matrix, see DesignBuilder.
+#[derive(Clone, Debug, PartialEq)]
+enum Term {
+  Numeric(String),
+  /// @note Column and the reference level, the first one if None.
+  Factor(String, Option<String>),
+  Interaction(String, String),
+}
+
+/// @brief Builds covariate design matrix (e.g. for Lmm::new) from columns of
+/// a covar table: numeric columns are used as is, categorical ones are dummy
+/// (treatment) encoded, a column per level except the reference one.
+#[derive(Clone, Debug)]
+pub struct DesignBuilder<'a> {
+  table: &'a LabeledTable,
+  intercept: bool,
+  terms: Vec<Term>,
+}
+
+impl<'a> DesignBuilder<'a> {
+  /// @brief Starts a design with the intercept only.
+  pub fn new(table: &'a LabeledTable) -> Self {
+    DesignBuilder {
+      table,
+      intercept: true,
+      terms: Vec::new(),
+    }
+  }
+
+  /// @brief Leaves the intercept column out.
+  ///
+  /// @note The first factor then gets a column for every level, as in R
+  /// model formulas with "- 1".
+  pub fn without_intercept(mut self) -> Self {
+    self.intercept = false;
+    self
+  }
+
+  pub fn numeric(mut self, column: &str) -> Self {
+    self.terms.push(Term::Numeric(String::from(column)));
+    self
+  }
+
+  /// @brief Dummy encodes column, the first level (in lexicographic order) is
+  /// the reference.
+  pub fn factor(mut self, column: &str) -> Self {
+    self.terms.push(Term::Factor(String::from(column), None));
+    self
+  }
+
+  pub fn factor_with_reference(mut self, column: &str, reference: &str) -> Self {
+    self.terms.push(Term::Factor(String::from(column), Some(String::from(reference))));
+    self
+  }
+
+  /// @brief Products of the encoded columns of two terms added before (e.g.
+  /// a column per non-reference level of a factor times a numeric column).
+  pub fn interaction(mut self, first: &str, second: &str) -> Self {
+    self.terms.push(Term::Interaction(String::from(first), String::from(second)));
+    self
+  }
+
+  /// @brief Builds the design matrix with a row per individual of ids, in
+  /// that order (e.g. the ids of the genotype file).
+  ///
+  /// @note Returns Error::Validation if an individual or a column is absent
+  /// from the table, a used value is missing, a numeric column holds a
+  /// non-number, or an interaction refers to a term which was not added.
+  pub fn build(&self, ids: &[String]) -> std::io::Result<DesignMatrix> {
+    let rows = ids
+      .iter()
+      .map(|id| {
+        self.table.row_index(id).ok_or_else(|| {
+          Error::Validation(format!("Individual <{}> is absent from covariates.", id))
+        })
+      })
+      .collect::<Result<Vec<usize>, Error>>()?;
+
+    let mut columns = Vec::<(String, Vec<f64>)>::new();
+    if self.intercept {
+      columns.push((String::from("intercept"), vec![1.0; ids.len()]));
+    }
+    let mut encoded = HashMap::<&str, Vec<(String, Vec<f64>)>>::new();
+    let mut full_factor = !self.intercept;
+    for term in &self.terms {
+      let term_columns = match term {
+        Term::Numeric(column) => {
+          let cells = self.cells(column, &rows)?;
+          let values = cells
+            .iter()
+            .map(|cell| {
+              cell.parse::<f64>().map_err(|_| {
+                Error::Validation(format!("Value <{}> of <{}> is not a number.", cell, column))
+              })
+            })
+            .collect::<Result<Vec<f64>, Error>>()?;
+          let term_columns = vec![(column.clone(), values)];
+          encoded.insert(column, term_columns.clone());
+          term_columns
+        }
+        Term::Factor(column, reference) => {
+          let cells = self.cells(column, &rows)?;
+          let levels = cells.iter().copied().collect::<BTreeSet<&str>>();
+          let reference = match reference {
+            Some(reference) => reference.as_str(),
+            None => levels.iter().next().copied().unwrap_or_default(),
+          };
+          if !levels.contains(reference) {
+            return Err(
+              Error::Validation(format!("<{}> is not a level of <{}>.", reference, column)).into(),
+            );
+          }
+          let dummies = |keep_reference: bool| {
+            levels
+              .iter()
+              .filter(|level| keep_reference || **level != reference)
+              .map(|level| {
+                let values = cells.iter().map(|cell| (cell == level) as u8 as f64).collect();
+                (format!("{}={}", column, level), values)
+              })
+              .collect::<Vec<(String, Vec<f64>)>>()
+          };
+          // Interactions use contrasts to the reference level.
+          encoded.insert(column, dummies(false));
+          let term_columns = dummies(full_factor);
+          full_factor = false;
+          term_columns
+        }
+        Term::Interaction(first, second) => {
+          let term = |name: &str| {
+            encoded.get(name).ok_or_else(|| {
+              Error::Validation(format!("Interaction term <{}> was not added before.", name))
+            })
+          };
+          let (first, second) = (term(first)?, term(second)?);
+          let mut term_columns = Vec::new();
+          for (first_name, first_values) in first {
+            for (second_name, second_values) in second {
+              let values = first_values.iter().zip(second_values).map(|(a, b)| a * b).collect();
+              term_columns.push((format!("{}:{}", first_name, second_name), values));
+            }
+          }
+          term_columns
+        }
+      };
+      columns.extend(term_columns);
+    }
+
+    let cols_num = columns.len();
+    let mut values = vec![0.0; ids.len() * cols_num];
+    for (col, (_, column)) in columns.iter().enumerate() {
+      for (row, value) in column.iter().enumerate() {
+        values[row * cols_num + col] = *value;
+      }
+    }
+    Ok(DesignMatrix {
+      ids: ids.to_vec(),
+      columns: columns.into_iter().map(|(name, _)| name).collect(),
+      values,
+    })
+  }
+
+  /// @brief Cells of column at rows, which must all be present.
+  fn cells(&self, column: &str, rows: &[usize]) -> std::io::Result<Vec<&'a str>> {
+    let table = self.table;
+    let col = table
+      .col_index(column)
+      .ok_or_else(|| Error::Validation(format!("There is no covariate <{}>.", column)))?;
+    rows
+      .iter()
+      .map(|row| {
+        table.get(*row, col).ok_or_else(|| {
+          Error::Validation(format!(
+            "Covariate <{}> of individual <{}> is missing.",
+            column, table.row_ids[*row]
+          ))
+          .into()
+        })
+      })
+      .collect()
+  }
+}
+
+/// @brief Covariate design matrix built by DesignBuilder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DesignMatrix {
+  pub ids: Vec<String>,
+  /// @note Names of the columns: "intercept", numeric columns by name,
+  /// dummies as "column=level", interactions as "first:second".
+  pub columns: Vec<String>,
+  /// @note Row-major ids.len() x columns.len().
+  pub values: Vec<f64>,
+}
+
+impl DesignMatrix {
+  pub fn cols_num(&self) -> usize {
+    self.columns.len()
+  }
+
+  /// @brief The matrix in the form taken by Lmm::new.
+  pub fn as_covariates(&self) -> (&[f64], usize) {
+    (&self.values, self.columns.len())
+  }
+}
@@ -0,0 +1,185 @@
+// dosage.rs
+
+//! Dosages (a number per individual per marker, e.g. imputed dosages or the
+//! expected dosages of genotype probabilities) as the input of the Kinship
+//! matrix calculation instead of genotype codes. They go through the same
+//! pipeline as genotype files, only the SNP lines are parsed differently.
+
+use std::io::BufRead;
+
+use super::error::Error;
+use super::kinship::{calc_kinship_observed, GramOrientation, KinshipOptions, SnpSource};
+use super::kinship_matrix::KinshipMatrix;
+use super::matrix_csv::{unquote, NumericTable};
+use super::na;
+use super::text;
+
+/// @brief SnpSource over CSV lines of dosages, as written by
+/// probs::write_dosages: comments (lines starting with '#'), a header line
+/// with ids, then a line per marker with its id and a dosage per individual.
+/// Lines are parsed one batch at a time, so files of any size are streamed.
+///
+/// @note Fields matching na::DEFAULT_NA_STRINGS are na::NA, lines with them
+/// are rejected unless allow_na is set.
+pub struct DosageLines<R: BufRead> {
+  lines: text::Lines<R>,
+  delimiter: char,
+  ids: Vec<String>,
+  na_strings: Vec<String>,
+  allow_na: bool,
+  line_num: usize,
+}
+
+impl<R: BufRead> DosageLines<R> {
+  /// @brief Reads comments and the header line.
+  pub fn new(reader: R, delimiter: char, allow_na: bool) -> std::io::Result<Self> {
+    let mut lines = text::Lines::new(reader);
+    let mut line_num = 0;
+    let header = loop {
+      line_num += 1;
+      match lines.next() {
+        Some(line) => {
+          let line = line?;
+          if !line.starts_with('#') && !line.trim().is_empty() {
+            break line;
+          }
+        }
+        None => {
+          return Err(
+            Error::Parse {
+              line: None,
+              msg: String::from("There is no header line in the dosages file."),
+            }
+            .into(),
+          )
+        }
+      }
+    };
+    Ok(DosageLines {
+      lines,
+      delimiter,
+      ids: header.split(delimiter).skip(1).map(unquote).collect(),
+      na_strings: na::default_na_strings(),
+      allow_na,
+      line_num,
+    })
+  }
+
+  /// @brief Individual ids from the header line, in the order of dosages.
+  pub fn ids(&self) -> &[String] {
+    &self.ids
+  }
+
+  /// @brief Parses line into dosages. Returns the marker id.
+  fn parse_line(&self, line: &str, dosages: &mut [f64]) -> std::io::Result<String> {
+    let mut fields = line.trim_end_matches('\r').split(self.delimiter);
+    let id = unquote(fields.next().unwrap_or_default());
+    let mut found = 0;
+    for field in fields {
+      let field = field.trim();
+      let dosage = if self.na_strings.iter().any(|s| s == field) {
+        na::NA
+      } else {
+        field.parse::<f64>().map_err(|_| Error::Parse {
+          line: Some(self.line_num),
+          msg: format!("Dosage <{}> of marker <{}> is not a number.", field, id),
+        })?
+      };
+      if let Some(elem) = dosages.get_mut(found) {
+        *elem = dosage;
+      }
+      found += 1;
+    }
+    if found != self.ids.len() {
+      return Err(
+        Error::DimensionMismatch {
+          marker: id,
+          expected: self.ids.len(),
+          found,
+        }
+        .into(),
+      );
+    }
+    if !self.allow_na && dosages.iter().any(|dosage| na::is_na(*dosage)) {
+      return Err(
+        Error::Parse {
+          line: Some(self.line_num),
+          msg: format!("Marker <{}> has missing dosages.", id),
+        }
+        .into(),
+      );
+    }
+    Ok(id)
+  }
+}
+
+impl<R: BufRead> SnpSource for DosageLines<R> {
+  fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let ids_num = self.ids.len().max(1);
+    let mut rows = 0;
+    while (rows + 1) * ids_num <= buf.len() {
+      let line = match self.lines.next() {
+        Some(line) => line?,
+        None => break,
+      };
+      self.line_num += 1;
+      if line.starts_with('#') || line.trim().is_empty() {
+        continue;
+      }
+      let id = self.parse_line(&line, &mut buf[rows * ids_num..(rows + 1) * ids_num])?;
+      if let Some(row_ids) = row_ids.as_mut() {
+        row_ids.push(id);
+      }
+      rows += 1;
+    }
+    Ok(rows)
+  }
+}
+
+/// @brief Calculates Kinship matrix from CSV of dosages (see DosageLines),
+/// the same way GenoParser::calc_kinship_with_options does from genotype
+/// codes.
+///
+/// @note Returns Error::Validation for GramOrientation::SnpsBySnps.
+pub fn calc_kinship_dosages<R: BufRead>(
+  reader: R,
+  delimiter: char,
+  options: &KinshipOptions,
+) -> std::io::Result<KinshipMatrix> {
+  let mut source = DosageLines::new(reader, delimiter, options.missing.allows_na())?;
+  let ids = source.ids().to_vec();
+  calc_kinship_labeled(&mut source, ids, options)
+}
+
+/// @brief Same as calc_kinship_dosages for dosages in memory, markers as
+/// rows and individuals as columns (e.g. genoprob::expected_dosages).
+pub fn calc_kinship_table(
+  table: &NumericTable,
+  options: &KinshipOptions,
+) -> std::io::Result<KinshipMatrix> {
+  calc_kinship_labeled(&mut table.snp_source(), table.col_ids.clone(), options)
+}
+
+/// @brief Calculates Kinship matrix of source labeled by ids.
+pub(crate) fn calc_kinship_labeled(
+  source: &mut dyn SnpSource,
+  ids: Vec<String>,
+  options: &KinshipOptions,
+) -> std::io::Result<KinshipMatrix> {
+  if options.orientation == GramOrientation::SnpsBySnps {
+    return Err(
+      Error::Validation(String::from("Gram matrix of SNPs is not supported for this input."))
+        .into(),
+    );
+  }
+  let data = calc_kinship_observed(source, options, &mut [])?;
+  KinshipMatrix::new(ids, data)
+}
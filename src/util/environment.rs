@@ -0,0 +1,283 @@
+// environment.rs
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::error::available_memory;
+use super::kinship::{KinshipOptions, ParallelMode};
+
+/// @brief GPU programming platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuPlatform {
+  Cuda,
+  Rocm,
+}
+
+/// @brief GPU device found on the machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GpuDevice {
+  pub platform: GpuPlatform,
+  pub name: String,
+  /// @note Device memory in bytes, None if the driver doesn't expose it (or
+  /// nvidia-smi isn't found for a CUDA device).
+  pub memory: Option<u64>,
+}
+
+/// @brief Capabilities of the machine relevant to the Kinship matrix
+/// calculation.
+#[derive(Clone, Debug)]
+pub struct EnvironmentReport {
+  pub logical_cores: usize,
+  pub physical_cores: usize,
+  /// @note SIMD extensions supported by the CPU, e.g. "avx2".
+  pub simd_features: Vec<&'static str>,
+  /// @note BLAS library the crate is linked with (the blas feature, see
+  /// kernel::BlasKernel), e.g. "openblas", None without it.
+  pub blas: Option<&'static str>,
+  pub gpu_devices: Vec<GpuDevice>,
+  /// @note CUDA libraries found in the library search path, e.g.
+  /// "libcudart".
+  pub cuda_libraries: Vec<&'static str>,
+  /// @note Memory available for new allocations in bytes.
+  pub available_memory: Option<u64>,
+}
+
+/// @brief Probes the machine.
+pub fn report() -> EnvironmentReport {
+  EnvironmentReport {
+    logical_cores: num_cpus::get(),
+    physical_cores: num_cpus::get_physical(),
+    simd_features: simd_features(),
+    blas: blas_library(),
+    gpu_devices: gpu_devices(),
+    cuda_libraries: cuda_libraries(),
+    available_memory: available_memory(),
+  }
+}
+
+/// @brief Library linked by build.rs for the blas feature (RQTL2_BLAS_LIB).
+fn blas_library() -> Option<&'static str> {
+  match cfg!(feature = "blas") {
+    true => Some(option_env!("RQTL2_BLAS_LINKED").unwrap_or("unknown")),
+    false => None,
+  }
+}
+
+impl EnvironmentReport {
+  /// @brief Options suggested for a Kinship matrix of ids_num individuals:
+  /// ParallelMode::Batches when the per-thread matrices fit in the available
+  /// memory, ParallelMode::ColumnBlocks otherwise.
+  pub fn recommended_options(&self, ids_num: usize) -> KinshipOptions {
+    let mut options = KinshipOptions::default();
+    if let Some(available) = self.available_memory {
+      if options.required_memory(ids_num) > available {
+        options.mode = ParallelMode::ColumnBlocks;
+      }
+    }
+    options
+  }
+}
+
+impl fmt::Display for EnvironmentReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let or_none = |list: Vec<String>| {
+      if list.is_empty() {
+        String::from("none")
+      } else {
+        list.join(", ")
+      }
+    };
+    writeln!(
+      f,
+      "CPU cores:        {} logical, {} physical",
+      self.logical_cores, self.physical_cores
+    )?;
+    writeln!(
+      f,
+      "SIMD features:    {}",
+      or_none(self.simd_features.iter().map(|s| s.to_string()).collect())
+    )?;
+    writeln!(f, "BLAS:             {}", self.blas.unwrap_or("none"))?;
+    let devices = self
+      .gpu_devices
+      .iter()
+      .map(|device| match device.memory {
+        Some(memory) => format!(
+          "{} ({:?}, {} MiB)",
+          device.name,
+          device.platform,
+          memory / (1024 * 1024)
+        ),
+        None => format!("{} ({:?})", device.name, device.platform),
+      })
+      .collect();
+    writeln!(f, "GPU devices:      {}", or_none(devices))?;
+    writeln!(
+      f,
+      "CUDA libraries:   {}",
+      or_none(self.cuda_libraries.iter().map(|s| s.to_string()).collect())
+    )?;
+    match self.available_memory {
+      Some(memory) => writeln!(f, "Available memory: {} MiB", memory / (1024 * 1024)),
+      None => writeln!(f, "Available memory: unknown"),
+    }
+  }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn simd_features() -> Vec<&'static str> {
+  let mut features = Vec::new();
+  if is_x86_feature_detected!("sse2") {
+    features.push("sse2");
+  }
+  if is_x86_feature_detected!("sse4.2") {
+    features.push("sse4.2");
+  }
+  if is_x86_feature_detected!("avx") {
+    features.push("avx");
+  }
+  if is_x86_feature_detected!("avx2") {
+    features.push("avx2");
+  }
+  if is_x86_feature_detected!("fma") {
+    features.push("fma");
+  }
+  if is_x86_feature_detected!("avx512f") {
+    features.push("avx512f");
+  }
+  features
+}
+
+#[cfg(target_arch = "aarch64")]
+fn simd_features() -> Vec<&'static str> {
+  let mut features = Vec::new();
+  if std::arch::is_aarch64_feature_detected!("neon") {
+    features.push("neon");
+  }
+  if std::arch::is_aarch64_feature_detected!("sve") {
+    features.push("sve");
+  }
+  features
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn simd_features() -> Vec<&'static str> {
+  Vec::new()
+}
+
+/// @brief Lists GPU devices known to the NVIDIA and AMD kernel drivers.
+fn gpu_devices() -> Vec<GpuDevice> {
+  let mut devices = Vec::new();
+  // NVIDIA driver: one directory per device (named by its PCI bus id) with
+  // "Model:" line in the information file, the memory comes from nvidia-smi.
+  if let Ok(entries) = std::fs::read_dir("/proc/driver/nvidia/gpus") {
+    let memory = nvidia_memory();
+    for entry in entries.flatten() {
+      let information = std::fs::read_to_string(entry.path().join("information"));
+      let name = information.ok().and_then(|information| {
+        information
+          .lines()
+          .find_map(|line| line.strip_prefix("Model:"))
+          .map(|model| String::from(model.trim()))
+      });
+      devices.push(GpuDevice {
+        platform: GpuPlatform::Cuda,
+        name: name.unwrap_or_else(|| String::from("NVIDIA GPU")),
+        memory: memory.get(&bus_id(&entry.file_name().to_string_lossy())).copied(),
+      });
+    }
+  }
+  // AMD driver exposes VRAM size of each card.
+  if let Ok(entries) = std::fs::read_dir("/sys/class/drm") {
+    for entry in entries.flatten() {
+      let file_name = entry.file_name();
+      let file_name = file_name.to_string_lossy();
+      if !file_name.starts_with("card") || file_name.contains('-') {
+        continue;
+      }
+      let device = entry.path().join("device");
+      if read_trimmed(&device.join("vendor")).as_deref() != Some("0x1002") {
+        continue;
+      }
+      devices.push(GpuDevice {
+        platform: GpuPlatform::Rocm,
+        name: read_trimmed(&device.join("product_name"))
+          .unwrap_or_else(|| format!("AMD GPU ({})", file_name)),
+        memory: read_trimmed(&device.join("mem_info_vram_total"))
+          .and_then(|memory| memory.parse().ok()),
+      });
+    }
+  }
+  devices
+}
+
+/// @brief Memory of NVIDIA devices in bytes by bus_id, as nvidia-smi reports
+/// it. Empty if nvidia-smi isn't installed.
+fn nvidia_memory() -> HashMap<String, u64> {
+  let output = Command::new("nvidia-smi")
+    .args(["--query-gpu=pci.bus_id,memory.total", "--format=csv,noheader,nounits"])
+    .output();
+  let stdout = match output {
+    Ok(output) if output.status.success() => output.stdout,
+    _ => return HashMap::new(),
+  };
+  String::from_utf8_lossy(&stdout)
+    .lines()
+    .filter_map(|line| {
+      let (device, memory) = line.split_once(',')?;
+      let mebibytes = memory.trim().parse::<u64>().ok()?;
+      Some((bus_id(device), mebibytes * 1024 * 1024))
+    })
+    .collect()
+}
+
+/// @brief PCI bus id without the domain, which the driver and nvidia-smi
+/// write with different widths, e.g. "01:00.0" of "00000000:01:00.0".
+fn bus_id(device: &str) -> String {
+  let device = device.trim().to_lowercase();
+  match device.split_once(':') {
+    Some((_, bus_id)) if bus_id.contains(':') => String::from(bus_id),
+    _ => device,
+  }
+}
+
+/// @brief CUDA libraries used by GPU kernels.
+pub const CUDA_LIBRARIES: [&str; 2] = ["libcudart", "libcublas"];
+
+/// @brief Lists CUDA_LIBRARIES found by find_cuda_library.
+fn cuda_libraries() -> Vec<&'static str> {
+  CUDA_LIBRARIES
+    .iter()
+    .copied()
+    .filter(|library| find_cuda_library(library).is_some())
+    .collect()
+}
+
+/// @brief Path of the shared library (any version of it, e.g.
+/// "libcudart.so.12" for "libcudart") in the directories of LD_LIBRARY_PATH
+/// or the usual install locations, the GPU kernel loads it from there.
+pub(crate) fn find_cuda_library(library: &str) -> Option<PathBuf> {
+  let mut dirs = std::env::var_os("LD_LIBRARY_PATH")
+    .map(|paths| std::env::split_paths(&paths).collect::<Vec<_>>())
+    .unwrap_or_default();
+  dirs.extend(
+    ["/usr/local/cuda/lib64", "/usr/lib/x86_64-linux-gnu", "/usr/lib64", "/usr/lib"]
+      .iter()
+      .map(PathBuf::from),
+  );
+  let prefix = format!("{}.so", library);
+  dirs
+    .iter()
+    .filter_map(|dir| std::fs::read_dir(dir).ok())
+    .flat_map(|entries| entries.flatten())
+    .find(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+    .map(|entry| entry.path())
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+  std::fs::read_to_string(path)
+    .ok()
+    .map(|s| String::from(s.trim()))
+}
@@ -0,0 +1,277 @@
+// error.rs
+
+use std::fmt;
+
+/// @brief Classified failure of a parsing or calculation routine.
+///
+/// The public API returns std::io::Result, an Error is carried inside the
+/// std::io::Error (see From implementations), so callers which need the
+/// failure class can convert the io::Error back with Error::from and match on
+/// the variants.
+#[derive(Debug)]
+pub enum Error {
+  /// @brief Reading or writing failed.
+  Io(std::io::Error),
+  /// @brief Malformed input data.
+  Parse { line: Option<usize>, msg: String },
+  /// @brief SNP line holds a code missing from the genotype mapper.
+  ///
+  /// @note marker is the row id of the line (its number for files without row
+  /// ids), column is the position of the code among the genotypes of the line
+  /// counting from 1. Classified as a Parse failure by kind and exit_code.
+  UnknownGenotypeCode { marker: String, column: usize, code: char },
+  /// @brief SNP line holds found genotypes, however there are expected ids.
+  ///
+  /// @note Classified as a Parse failure by kind and exit_code.
+  DimensionMismatch { marker: String, expected: usize, found: usize },
+  /// @brief Well-formed, but inconsistent input (e.g. dimensions mismatch).
+  Validation(String),
+  /// @brief GPU calculation was requested, but no usable device was found.
+  GpuUnavailable(String),
+  /// @brief Calculation was not started because it would not fit in memory.
+  OutOfMemory { required: u64, available: u64 },
+  /// @brief A worker failed (panicked) while processing a batch of SNP lines,
+  /// the remaining work was cancelled.
+  Processing { batch: Option<usize>, msg: String },
+  /// @brief Calculation was aborted through its CancellationHandle.
+  Cancelled,
+}
+
+/// @brief Format of error reports printed by command line tools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+  Text,
+  Json,
+}
+
+impl ErrorFormat {
+  pub fn parse(format: &str) -> Option<Self> {
+    match format {
+      "text" => Some(ErrorFormat::Text),
+      "json" => Some(ErrorFormat::Json),
+      _ => None,
+    }
+  }
+}
+
+impl Error {
+  /// @brief Short machine-readable name of the failure class.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Error::Io(_) => "io",
+      Error::Parse { .. } | Error::UnknownGenotypeCode { .. } | Error::DimensionMismatch { .. } => {
+        "parse"
+      }
+      Error::Validation(_) => "validation",
+      Error::GpuUnavailable(_) => "gpu_unavailable",
+      Error::OutOfMemory { .. } => "out_of_memory",
+      Error::Processing { .. } => "processing",
+      Error::Cancelled => "cancelled",
+    }
+  }
+
+  /// @brief Process exit code for the failure class. 1 is left for
+  /// unclassified failures and 2 for command line usage errors.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Error::Parse { .. } | Error::UnknownGenotypeCode { .. } | Error::DimensionMismatch { .. } => {
+        3
+      }
+      Error::Validation(_) => 4,
+      Error::GpuUnavailable(_) => 5,
+      Error::OutOfMemory { .. } => 6,
+      Error::Io(_) => 7,
+      Error::Processing { .. } => 8,
+      Error::Cancelled => 9,
+    }
+  }
+
+  /// @brief Single line JSON object describing the error.
+  pub fn to_json(&self) -> String {
+    let mut fields = vec![
+      format!("\"error\":\"{}\"", self.kind()),
+      format!("\"exit_code\":{}", self.exit_code()),
+      format!("\"message\":\"{}\"", escape_json(&self.to_string())),
+    ];
+    match self {
+      Error::Parse {
+        line: Some(line), ..
+      } => fields.push(format!("\"line\":{}", line)),
+      Error::UnknownGenotypeCode {
+        marker,
+        column,
+        code,
+      } => {
+        fields.push(format!("\"marker\":\"{}\"", escape_json(marker)));
+        fields.push(format!("\"column\":{}", column));
+        fields.push(format!("\"code\":\"{}\"", escape_json(&code.to_string())));
+      }
+      Error::DimensionMismatch {
+        marker,
+        expected,
+        found,
+      } => {
+        fields.push(format!("\"marker\":\"{}\"", escape_json(marker)));
+        fields.push(format!("\"expected\":{}", expected));
+        fields.push(format!("\"found\":{}", found));
+      }
+      Error::OutOfMemory {
+        required,
+        available,
+      } => {
+        fields.push(format!("\"required_bytes\":{}", required));
+        fields.push(format!("\"available_bytes\":{}", available));
+      }
+      Error::Processing {
+        batch: Some(batch), ..
+      } => fields.push(format!("\"batch\":{}", batch)),
+      _ => (),
+    }
+    format!("{{{}}}", fields.join(","))
+  }
+
+  /// @brief Formats error report for printing.
+  pub fn report(&self, format: ErrorFormat) -> String {
+    match format {
+      ErrorFormat::Text => format!("Error: {}", self),
+      ErrorFormat::Json => self.to_json(),
+    }
+  }
+}
+
+pub(crate) fn escape_json(s: &str) -> String {
+  let mut res = String::with_capacity(s.len());
+  for ch in s.chars() {
+    match ch {
+      '"' => res.push_str("\\\""),
+      '\\' => res.push_str("\\\\"),
+      '\n' => res.push_str("\\n"),
+      '\r' => res.push_str("\\r"),
+      '\t' => res.push_str("\\t"),
+      ch if (ch as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", ch as u32)),
+      ch => res.push(ch),
+    }
+  }
+  res
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::Parse {
+        line: Some(line),
+        msg,
+      } => write!(f, "line {}: {}", line, msg),
+      Error::Parse { line: None, msg } => write!(f, "{}", msg),
+      Error::UnknownGenotypeCode {
+        marker,
+        column,
+        code,
+      } => write!(
+        f,
+        "marker <{}>: unknown genotype code <{}> in column {}",
+        marker, code, column
+      ),
+      Error::DimensionMismatch {
+        marker,
+        expected,
+        found,
+      } => write!(
+        f,
+        "marker <{}>: there are {} ids, however {} genotypes were parsed",
+        marker, expected, found
+      ),
+      Error::Validation(msg) => write!(f, "{}", msg),
+      Error::GpuUnavailable(msg) => write!(f, "GPU is unavailable: {}", msg),
+      Error::OutOfMemory {
+        required,
+        available,
+      } => write!(
+        f,
+        "calculation requires {} bytes of memory, however only {} bytes are available",
+        required, available
+      ),
+      Error::Processing {
+        batch: Some(batch),
+        msg,
+      } => write!(f, "processing of batch {} failed: {}", batch, msg),
+      Error::Processing { batch: None, msg } => write!(f, "processing failed: {}", msg),
+      Error::Cancelled => write!(f, "calculation was cancelled"),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<Error> for std::io::Error {
+  fn from(e: Error) -> Self {
+    match e {
+      Error::Io(e) => e,
+      Error::Parse { .. }
+      | Error::UnknownGenotypeCode { .. }
+      | Error::DimensionMismatch { .. }
+      | Error::Validation(_) => std::io::Error::new(std::io::ErrorKind::InvalidInput, e),
+      Error::GpuUnavailable(_) => std::io::Error::new(std::io::ErrorKind::Unsupported, e),
+      Error::OutOfMemory { .. } => std::io::Error::new(std::io::ErrorKind::OutOfMemory, e),
+      Error::Processing { .. } | Error::Cancelled => std::io::Error::other(e),
+    }
+  }
+}
+
+/// @note Recovers Error carried inside io::Error. Errors of InvalidInput and
+/// InvalidData kinds produced by the parsers are classified as Parse errors.
+impl From<std::io::Error> for Error {
+  fn from(e: std::io::Error) -> Self {
+    if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+      return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+    }
+    match e.kind() {
+      std::io::ErrorKind::InvalidInput | std::io::ErrorKind::InvalidData => Error::Parse {
+        line: None,
+        msg: e.to_string(),
+      },
+      _ => Error::Io(e),
+    }
+  }
+}
+
+/// @brief Sets the line (of the input, counting from 1) of Error::Parse
+/// carried by err unless it has one, other errors are returned as they are.
+pub(crate) fn at_line(err: std::io::Error, line: usize) -> std::io::Error {
+  match Error::from(err) {
+    Error::Parse { line: None, msg } => Error::Parse {
+      line: Some(line),
+      msg,
+    }
+    .into(),
+    err => err.into(),
+  }
+}
+
+/// @brief Returns amount of memory available for new allocations in bytes,
+/// None if it can't be determined on this platform.
+pub fn available_memory() -> Option<u64> {
+  let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+  let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+  let kbytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+  Some(kbytes * 1024)
+}
+
+/// @brief Returns Error::OutOfMemory if required bytes exceed available memory.
+pub fn ensure_memory(required: u64) -> Result<(), Error> {
+  match available_memory() {
+    Some(available) if required > available => Err(Error::OutOfMemory {
+      required,
+      available,
+    }),
+    _ => Ok(()),
+  }
+}
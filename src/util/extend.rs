@@ -0,0 +1,181 @@
+// extend.rs
+
+use std::thread;
+
+use super::error::Error;
+use super::kinship::{
+  calc_partial_kinship_tiled, finalize_kinship_by, read_accumulated_batch, Accumulated,
+  KinshipOptions, SnpSource,
+};
+
+/// @brief Extends Kinship matrix of n individuals with m new individuals
+/// genotyped at the same markers, without recomputing the existing n x n
+/// part: only the n x m block (old against new) and the m x m corner are
+/// calculated, streaming both genotype files in lockstep.
+///
+/// @param[in] existing  Full n x n Kinship matrix calculated from old with
+/// the same options.
+/// @param[in] old       SNP lines of the n individuals of existing.
+/// @param[in] new       SNP lines of the m new individuals.
+/// @note Returns full (n + m) x (n + m) matrix, old individuals first. Both
+/// sources must contain the same markers in the same order, otherwise
+/// Error::Validation is returned.
+/// @note Every SNP line is filtered, imputed and transformed over all n + m
+/// individuals (see read_accumulated_batch), so the new rows equal those of
+/// a full recompute. The existing block is kept as given, with
+/// DosageTransform::Center, non-raw KinshipKind, MAF filter or imputation it
+/// is the one of the old individuals alone.
+/// @note Returns Error::Validation for KinshipOptions::time_limit.
+pub fn extend_kinship(
+  existing: &[f64],
+  old: &mut dyn SnpSource,
+  new: &mut dyn SnpSource,
+  options: &KinshipOptions,
+) -> std::io::Result<Vec<f64>> {
+  let (n, m) = (old.ids_num(), new.ids_num());
+  if existing.len() != n * n {
+    return Err(
+      Error::Validation(format!(
+        "Existing Kinship matrix has {} elements, expected {} for {} individuals.",
+        existing.len(),
+        n * n,
+        n
+      ))
+      .into(),
+    );
+  }
+  if options.time_limit.is_some() {
+    let msg = "Time limit isn't supported when extending Kinship matrix.";
+    return Err(Error::Validation(String::from(msg)).into());
+  }
+  let size = n + m;
+  let batch_size = options.batch_rows(size);
+  let mut source = Lockstep {
+    old,
+    new,
+    old_batch: vec![0.0; n * batch_size],
+    new_batch: vec![0.0; m * batch_size],
+    old_ids: Vec::new(),
+    new_ids: Vec::new(),
+    lines_read: 0,
+  };
+  let mut batch = vec![0.0; size * batch_size];
+  let mut new_snps = vec![0.0; m * batch_size];
+  // New individuals against old ones, row j holds n values.
+  let mut cross = vec![0.0; m * n];
+  let mut corner = vec![0.0; m * m];
+  let mut accumulated = Accumulated::start(options);
+  loop {
+    let rows = read_accumulated_batch(&mut source, &mut batch, &mut [], options, &mut accumulated)?;
+    if rows == 0 {
+      break;
+    }
+    let lines = &batch[..rows * size];
+    for (line, new_line) in lines.chunks(size).zip(new_snps.chunks_mut(m.max(1))) {
+      new_line.copy_from_slice(&line[n..]);
+    }
+    update_cross(lines, &mut cross, n, m, options.threads_num());
+    calc_partial_kinship_tiled(&new_snps[..rows * m], &mut corner, m);
+  }
+  if accumulated.snps == 0 || accumulated.weight <= 0.0 {
+    return Err(Error::Validation(String::from("There are no SNP lines.")).into());
+  }
+  finalize_kinship_by(&mut corner, m, accumulated.weight);
+
+  let mut res = vec![0.0; size * size];
+  for i in 0..n {
+    res[i * size..i * size + n].copy_from_slice(&existing[i * n..(i + 1) * n]);
+  }
+  for j in 0..m {
+    for i in 0..n {
+      let value = cross[j * n + i] / accumulated.weight;
+      res[i * size + n + j] = value;
+      res[(n + j) * size + i] = value;
+    }
+    res[(n + j) * size + n..(n + j + 1) * size].copy_from_slice(&corner[j * m..(j + 1) * m]);
+  }
+  Ok(res)
+}
+
+/// @brief SnpSource joining the lines of old and new sources, the values of
+/// the old individuals first.
+struct Lockstep<'a> {
+  old: &'a mut dyn SnpSource,
+  new: &'a mut dyn SnpSource,
+  old_batch: Vec<f64>,
+  new_batch: Vec<f64>,
+  old_ids: Vec<String>,
+  new_ids: Vec<String>,
+  lines_read: usize,
+}
+
+impl SnpSource for Lockstep<'_> {
+  fn ids_num(&self) -> usize {
+    self.old.ids_num() + self.new.ids_num()
+  }
+
+  /// @note Returns Error::Validation if the sources differ in markers.
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let (n, m) = (self.old.ids_num(), self.new.ids_num());
+    let lines = buf.len() / (n + m).max(1);
+    self.old_ids.clear();
+    self.new_ids.clear();
+    let old_batch = &mut self.old_batch[..lines * n];
+    let new_batch = &mut self.new_batch[..lines * m];
+    let old_rows = self.old.read_batch(old_batch, Some(&mut self.old_ids))?;
+    let new_rows = self.new.read_batch(new_batch, Some(&mut self.new_ids))?;
+    let (old_ids, new_ids) = (&self.old_ids, &self.new_ids);
+    let mismatch = (0..old_rows.max(new_rows)).find(|pos| old_ids.get(*pos) != new_ids.get(*pos));
+    if let Some(pos) = mismatch {
+      return Err(
+        Error::Validation(format!(
+          "SNP line {} is <{}> in the existing genotypes, however <{}> in the new ones.",
+          self.lines_read + pos + 1,
+          old_ids.get(pos).map_or("", String::as_str),
+          new_ids.get(pos).map_or("", String::as_str)
+        ))
+        .into(),
+      );
+    }
+    for row in 0..old_rows {
+      let line = &mut buf[row * (n + m)..(row + 1) * (n + m)];
+      line[..n].copy_from_slice(&old_batch[row * n..(row + 1) * n]);
+      line[n..].copy_from_slice(&new_batch[row * m..(row + 1) * m]);
+    }
+    if let Some(row_ids) = row_ids {
+      row_ids.append(&mut self.old_ids);
+    }
+    self.lines_read += old_rows;
+    Ok(old_rows)
+  }
+}
+
+/// @brief Adds new.T * old to cross (m x n) for SNP lines of n + m values
+/// (old individuals first), the rows of cross are split between threads_num
+/// threads.
+fn update_cross(lines: &[f64], cross: &mut [f64], n: usize, m: usize, threads_num: usize) {
+  if n == 0 || m == 0 {
+    return;
+  }
+  let rows_per_thread = m.div_ceil(threads_num.max(1));
+  thread::scope(|scope| {
+    let blocks = cross.chunks_mut(rows_per_thread * n);
+    for (block, first_row) in blocks.zip((0..m).step_by(rows_per_thread)) {
+      scope.spawn(move || {
+        for line in lines.chunks(n + m) {
+          let (old_line, new_line) = line.split_at(n);
+          for (out, new_value) in block.chunks_mut(n).zip(&new_line[first_row..]) {
+            out
+              .iter_mut()
+              .zip(old_line)
+              .for_each(|(out, old_value)| *out += new_value * old_value);
+          }
+        }
+      });
+    }
+  });
+}
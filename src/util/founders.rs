@@ -0,0 +1,312 @@
+// founders.rs
+
+use std::collections::HashMap;
+use std::io::{BufRead, Seek};
+use std::path::Path;
+
+use super::error::Error;
+use super::kinship::{BatchObserver, SnpSource};
+use super::matrix_csv::{LabeledTable, MatrixCsvReader, NumericTable};
+use super::na;
+use super::stats::DosageScale;
+use super::GenoParser;
+
+/// @brief Genotypes of the founder strains (R/qtl2 founder_geno file):
+/// markers as rows, founders as columns.
+#[derive(Clone, Debug)]
+pub struct FounderGenotypes {
+  founders: Vec<String>,
+  /// @note Marker names in file order.
+  markers: Vec<String>,
+  positions: HashMap<String, usize>,
+  /// @note founders.len() values per marker, NA for missing genotypes.
+  values: Vec<f64>,
+}
+
+impl FounderGenotypes {
+  /// @brief Reads founder_geno CSV file, genotype codes are converted with
+  /// hab_mapper.
+  pub fn read<P: AsRef<Path>>(path: P, hab_mapper: &HashMap<char, f64>) -> std::io::Result<Self> {
+    Self::from_table(&MatrixCsvReader::new().read(path)?, hab_mapper)
+  }
+
+  pub fn from_table(
+    table: &LabeledTable,
+    hab_mapper: &HashMap<char, f64>,
+  ) -> std::io::Result<Self> {
+    let mut values = Vec::with_capacity(table.cells.len());
+    for (cell, pos) in table.cells.iter().zip(0..) {
+      let value = match cell {
+        None => na::NA,
+        Some(code) => {
+          let mut chars = code.chars();
+          match (chars.next(), chars.next()) {
+            (Some(ch), None) if hab_mapper.contains_key(&ch) => hab_mapper[&ch],
+            _ => {
+              return Err(
+                Error::Parse {
+                  line: None,
+                  msg: format!(
+                    "Founder genotype <{}> of marker <{}> is not a known genotype code.",
+                    code,
+                    table.row_ids[pos / table.col_ids.len()]
+                  ),
+                }
+                .into(),
+              )
+            }
+          }
+        }
+      };
+      values.push(value);
+    }
+    Self::from_values(table.col_ids.clone(), table.row_ids.clone(), values)
+  }
+
+  /// @brief Reads founder_geno file in the genotype file format of GenoParser,
+  /// founders being the individuals of the header line. Transposed files
+  /// (founder_geno_transposed) are read as well, see GenoParser::set_transposed.
+  pub fn from_geno_parser<R: BufRead + Seek>(parser: &mut GenoParser<R>) -> std::io::Result<Self> {
+    let table = parser.read_table()?;
+    Self::from_values(table.col_ids, table.row_ids, table.values)
+  }
+
+  /// @note Returns Error::Validation if a founder or a marker is listed more
+  /// than once.
+  fn from_values(
+    founders: Vec<String>,
+    markers: Vec<String>,
+    values: Vec<f64>,
+  ) -> std::io::Result<Self> {
+    check_unique("Founder", &founders)?;
+    check_unique("Marker", &markers)?;
+    Ok(FounderGenotypes {
+      founders,
+      positions: markers.iter().enumerate().map(|(pos, id)| (id.clone(), pos)).collect(),
+      markers,
+      values,
+    })
+  }
+
+  pub fn founders(&self) -> &[String] {
+    &self.founders
+  }
+
+  /// @brief Marker names in file order.
+  pub fn markers(&self) -> &[String] {
+    &self.markers
+  }
+
+  /// @brief Dosages of all founders: a row per marker and a column per
+  /// founder, NA for missing genotypes. E.g. for genotype probability code,
+  /// or its snp_source to treat the founders as individuals.
+  pub fn dosage_matrix(&self) -> NumericTable {
+    NumericTable {
+      row_ids: self.markers.clone(),
+      col_ids: self.founders.clone(),
+      values: self.values.clone(),
+    }
+  }
+
+  /// @brief Genotypes of all founders at marker, None if the marker is absent.
+  pub fn marker(&self, id: &str) -> Option<&[f64]> {
+    let founders_num = self.founders.len();
+    self
+      .positions
+      .get(id)
+      .map(|pos| &self.values[pos * founders_num..(pos + 1) * founders_num])
+  }
+}
+
+/// @brief Returns Error::Validation naming the first id (a founder or a
+/// marker, named what) listed more than once and its positions, counting
+/// from 1.
+fn check_unique(what: &str, ids: &[String]) -> std::io::Result<()> {
+  let mut positions = HashMap::with_capacity(ids.len());
+  for (pos, id) in ids.iter().enumerate() {
+    if let Some(first) = positions.insert(id.as_str(), pos) {
+      return Err(
+        Error::Validation(format!(
+          "{} <{}> is listed more than once, at positions {} and {}.",
+          what,
+          id,
+          first + 1,
+          pos + 1
+        ))
+        .into(),
+      );
+    }
+  }
+  Ok(())
+}
+
+/// @brief Result of the founder consistency check of a single marker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarkerErrors {
+  pub id: String,
+  /// @note Amount of non missing offspring genotypes checked.
+  pub checked: usize,
+  /// @note Amount of offspring genotypes impossible given the founders.
+  pub errors: usize,
+}
+
+impl MarkerErrors {
+  /// @brief Fraction of impossible genotypes, NA if nothing was checked
+  /// (e.g. the marker is absent from the founders, or founder genotypes are
+  /// missing).
+  pub fn error_rate(&self) -> f64 {
+    if self.checked == 0 {
+      na::NA
+    } else {
+      self.errors as f64 / self.checked as f64
+    }
+  }
+
+  /// @brief QC filter: whether the marker can be used for Kinship matrix
+  /// calculation. Markers which couldn't be checked pass.
+  pub fn passes(&self, max_error_rate: f64) -> bool {
+    na::to_option(self.error_rate()).is_none_or(|rate| rate <= max_error_rate)
+  }
+}
+
+/// @brief Checks offspring genotypes of every marker against the founders.
+///
+/// An offspring can only carry alleles present in the founders: when all
+/// founders are homozygous for the same allele (DosageScale::min or max),
+/// any other offspring genotype is impossible. Markers where founders are
+/// polymorphic, heterozygous or missing can't produce errors.
+pub struct FounderCheck<'a> {
+  founders: &'a FounderGenotypes,
+  scale: DosageScale,
+  results: Vec<MarkerErrors>,
+}
+
+impl<'a> FounderCheck<'a> {
+  pub fn new(founders: &'a FounderGenotypes, scale: DosageScale) -> Self {
+    FounderCheck {
+      founders,
+      scale,
+      results: Vec::new(),
+    }
+  }
+
+  pub fn finish(self) -> Vec<MarkerErrors> {
+    self.results
+  }
+
+  /// @brief The only genotype possible at a marker, None if any genotype is.
+  fn fixed_genotype(&self, founder_genotypes: &[f64]) -> Option<f64> {
+    let first = *founder_genotypes.first()?;
+    let fixed = (first == self.scale.min || first == self.scale.max)
+      && founder_genotypes.iter().all(|value| *value == first);
+    if fixed {
+      Some(first)
+    } else {
+      None
+    }
+  }
+}
+
+impl<'a> BatchObserver for FounderCheck<'a> {
+  fn observe(&mut self, row_ids: &[String], snps: &[f64], ids_num: usize) {
+    for (id, line) in row_ids.iter().zip(snps.chunks(ids_num.max(1))) {
+      let fixed = self
+        .founders
+        .marker(id)
+        .and_then(|genotypes| self.fixed_genotype(genotypes));
+      let (checked, errors) = match fixed {
+        Some(fixed) => (
+          na::present(line).count(),
+          na::present(line).filter(|value| *value != fixed).count(),
+        ),
+        None => (0, 0),
+      };
+      self.results.push(MarkerErrors {
+        id: id.clone(),
+        checked,
+        errors,
+      });
+    }
+  }
+}
+
+/// @brief SnpSource which codes genotypes relative to a reference founder:
+/// the allele of the reference founder becomes DosageScale::min and the other
+/// one DosageScale::max, regardless of how the alleles are coded in the file
+/// (e.g. for BXD-style recombinant inbred panels, where A/B don't follow the
+/// founders).
+///
+/// @note Markers absent from the founders, or where the reference founder is
+/// missing or heterozygous, are passed as is and counted in
+/// unpolarized_markers.
+pub struct FounderPolarity<'a, S: SnpSource> {
+  source: S,
+  founders: &'a FounderGenotypes,
+  reference: usize,
+  scale: DosageScale,
+  row_ids: Vec<String>,
+  unpolarized: usize,
+}
+
+impl<'a, S: SnpSource> FounderPolarity<'a, S> {
+  /// @note Returns Error::Validation if there is no reference founder.
+  pub fn new(
+    source: S,
+    founders: &'a FounderGenotypes,
+    reference: &str,
+    scale: DosageScale,
+  ) -> std::io::Result<Self> {
+    let reference = founders
+      .founders()
+      .iter()
+      .position(|founder| founder == reference)
+      .ok_or_else(|| Error::Validation(format!("There is no founder <{}>.", reference)))?;
+    Ok(FounderPolarity {
+      source,
+      founders,
+      reference,
+      scale,
+      row_ids: Vec::new(),
+      unpolarized: 0,
+    })
+  }
+
+  /// @brief Amount of markers read so far which couldn't be polarized.
+  pub fn unpolarized_markers(&self) -> usize {
+    self.unpolarized
+  }
+}
+
+impl<'a, S: SnpSource> SnpSource for FounderPolarity<'a, S> {
+  fn ids_num(&self) -> usize {
+    self.source.ids_num()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    self.row_ids.clear();
+    let rows = self.source.read_batch(buf, Some(&mut self.row_ids))?;
+    let ids_num = self.source.ids_num().max(1);
+    for (id, line) in self.row_ids.iter().zip(buf.chunks_mut(ids_num)) {
+      let reference = self
+        .founders
+        .marker(id)
+        .map(|genotypes| genotypes[self.reference]);
+      match reference {
+        Some(value) if value == self.scale.min => (),
+        // Swap the alleles, NA stays NA.
+        Some(value) if value == self.scale.max => line
+          .iter_mut()
+          .for_each(|value| *value = self.scale.min + self.scale.max - *value),
+        _ => self.unpolarized += 1,
+      }
+    }
+    if let Some(row_ids) = row_ids {
+      row_ids.append(&mut self.row_ids);
+    }
+    Ok(rows)
+  }
+}
@@ -0,0 +1,168 @@
+// geno_files.rs
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::chunked::ChunkedGenoReader;
+use super::error::Error;
+use super::kinship::{calc_kinship_observed, GramOrientation, KinshipOptions, SnpSource};
+use super::kinship_matrix::KinshipMatrix;
+use super::GenoParser;
+
+/// @brief Genotypes split into several files (e.g. a file per chromosome, as
+/// R/qtl2 control files may list them), read as a single stream of SNP
+/// lines, file after file.
+pub struct GenoFiles {
+  paths: Vec<PathBuf>,
+  parsers: Vec<GenoParser>,
+}
+
+impl GenoFiles {
+  /// @brief Opens the genotype files at paths, in the given order.
+  ///
+  /// @note Returns Error::Validation if there are no files, or the header
+  /// lines of the files list different ids (or the same ids in another
+  /// order), since their columns couldn't be concatenated then.
+  pub fn open<P: AsRef<Path>>(
+    paths: &[P],
+    hab_mapper: HashMap<char, f64>,
+  ) -> std::io::Result<Self> {
+    let mut parsers = Vec::with_capacity(paths.len());
+    for path in paths {
+      parsers.push(GenoParser::new_with_file(File::open(path)?, hab_mapper.clone())?);
+    }
+    let paths = paths.iter().map(|path| path.as_ref().to_path_buf()).collect::<Vec<PathBuf>>();
+    let first = match parsers.first() {
+      Some(first) => first.get_markers(),
+      None => {
+        return Err(Error::Validation(String::from("There are no genotype files.")).into())
+      }
+    };
+    if let Some(pos) = parsers.iter().position(|parser| parser.get_markers() != first) {
+      return Err(
+        Error::Validation(format!(
+          "Ids of genotype file {} differ from the ids of genotype file {}.",
+          paths[pos].display(),
+          paths[0].display()
+        ))
+        .into(),
+      );
+    }
+    Ok(GenoFiles { paths, parsers })
+  }
+
+  /// @brief Ids of the individuals, the same in every file.
+  pub fn get_markers(&self) -> &Vec<String> {
+    self.parsers[0].get_markers()
+  }
+
+  pub fn paths(&self) -> &[PathBuf] {
+    &self.paths
+  }
+
+  /// @brief Parsers of the single files, in order (e.g. to process a
+  /// chromosome on its own).
+  pub fn parsers(&mut self) -> &mut [GenoParser] {
+    &mut self.parsers
+  }
+
+  /// @brief SnpSource over the SNP lines of all the files, file after file.
+  /// Lines with missing genotypes are rejected unless allow_na is set.
+  ///
+  /// @note Returns Error::Validation if a file is transposed.
+  pub fn snp_source(&mut self, allow_na: bool) -> std::io::Result<ChainedSnps<'_>> {
+    let ids_num = self.get_markers().len();
+    let mut sources = Vec::with_capacity(self.parsers.len());
+    for parser in &mut self.parsers {
+      parser.ensure_snp_rows()?;
+      parser.file_reader.seek(SeekFrom::Start(parser.snp_pos_start))?;
+      sources.push(parser.snp_source(allow_na)?);
+    }
+    Ok(ChainedSnps {
+      ids_num,
+      sources,
+      current: 0,
+    })
+  }
+
+  /// @brief Calculates Kinship matrix from the SNP lines of all the files, as
+  /// GenoParser::calc_kinship_with_options does for a single file. Rewinds
+  /// the files after finishing calculation.
+  ///
+  /// @note Returns Error::Validation for GramOrientation::SnpsBySnps.
+  pub fn calc_kinship_with_options(
+    &mut self,
+    options: &KinshipOptions,
+  ) -> std::io::Result<Vec<f64>> {
+    if options.orientation == GramOrientation::SnpsBySnps {
+      return Err(
+        Error::Validation(String::from(
+          "Gram matrix of SNPs is not supported for several genotype files.",
+        ))
+        .into(),
+      );
+    }
+    let res = self
+      .snp_source(options.missing.allows_na())
+      .and_then(|mut source| calc_kinship_observed(&mut source, options, &mut []));
+    self.rewind()?;
+    res
+  }
+
+  /// @brief Same as calc_kinship_with_options, the matrix comes with the ids
+  /// of its rows.
+  pub fn calc_kinship_matrix(
+    &mut self,
+    options: &KinshipOptions,
+  ) -> std::io::Result<KinshipMatrix> {
+    let data = self.calc_kinship_with_options(options)?;
+    KinshipMatrix::new(self.get_markers().clone(), data)
+  }
+
+  /// @brief Rewinds the cursors of all the files to the beginning of SNP
+  /// lines.
+  fn rewind(&mut self) -> std::io::Result<()> {
+    for parser in &mut self.parsers {
+      parser.file_reader.seek(SeekFrom::Start(parser.snp_pos_start))?;
+    }
+    Ok(())
+  }
+}
+
+/// @brief SnpSource reading several sources one after another, see
+/// GenoFiles::snp_source.
+pub struct ChainedSnps<'a> {
+  ids_num: usize,
+  sources: Vec<ChunkedGenoReader<'a, BufReader<File>>>,
+  /// @note Position of the source being read.
+  current: usize,
+}
+
+impl<'a> SnpSource for ChainedSnps<'a> {
+  fn ids_num(&self) -> usize {
+    self.ids_num
+  }
+
+  /// @note A batch may span the end of a file and the beginning of the next
+  /// one, it's short only at the end of the last file.
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let capacity = buf.len() / self.ids_num.max(1);
+    let mut rows = 0;
+    while rows < capacity && self.current < self.sources.len() {
+      let source = &mut self.sources[self.current];
+      let rest = &mut buf[rows * self.ids_num..capacity * self.ids_num];
+      let read = source.read_batch(rest, row_ids.as_deref_mut())?;
+      if read < capacity - rows {
+        self.current += 1;
+      }
+      rows += read;
+    }
+    Ok(rows)
+  }
+}
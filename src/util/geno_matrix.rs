@@ -0,0 +1,53 @@
+// geno_matrix.rs
+
+use super::idx::{IndividualIdx, MarkerIdx};
+use super::kinship::{calc_partial_kinship_tiled, finalize_kinship};
+use super::na;
+
+/// @brief Genotypes of a few markers held in memory, e.g. a genomic window
+/// extracted with GenoParser::extract_region.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenoMatrix {
+  pub marker_ids: Vec<String>,
+  /// @note Individual ids (the header line of genotype file).
+  pub ids: Vec<String>,
+  /// @note Row-major, a line of ids.len() values per marker, na::NA for
+  /// missing genotypes.
+  pub values: Vec<f64>,
+}
+
+impl GenoMatrix {
+  pub fn markers_num(&self) -> usize {
+    self.marker_ids.len()
+  }
+
+  pub fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  pub fn get(&self, marker: MarkerIdx, individual: IndividualIdx) -> f64 {
+    self.values[marker.index() * self.ids.len() + individual.index()]
+  }
+
+  /// @brief Genotypes of all individuals at marker.
+  pub fn marker(&self, marker: MarkerIdx) -> &[f64] {
+    let ids_num = self.ids.len();
+    &self.values[marker.index() * ids_num..(marker.index() + 1) * ids_num]
+  }
+
+  /// @brief Local Kinship matrix of the window (full ids_num x ids_num).
+  ///
+  /// @note Unlike the genome-wide calculation, windows with fewer markers
+  /// than individuals are allowed. Returns None if any genotype is missing
+  /// or there are no markers.
+  pub fn kinship(&self) -> Option<Vec<f64>> {
+    let ids_num = self.ids.len();
+    if self.marker_ids.is_empty() || na::count_na(&self.values) > 0 {
+      return None;
+    }
+    let mut res = vec![0.0; ids_num * ids_num];
+    calc_partial_kinship_tiled(&self.values, &mut res, ids_num);
+    finalize_kinship(&mut res, ids_num, self.marker_ids.len());
+    Some(res)
+  }
+}
@@ -0,0 +1,139 @@
+// geno_writer.rs
+
+//! Genotype files written from memory (ids, marker names and genotype codes
+//! or dosages), the inverse of GenoParser.
+
+use std::io::Write;
+
+use super::error::Error;
+use super::geno_matrix::GenoMatrix;
+use super::hard_calls::HardCaller;
+use super::idx::MarkerIdx;
+
+/// @brief Writer of genotype files in the format read by GenoParser: comment
+/// lines, the header line with the ids, then a line per marker with its
+/// name and a genotype code per individual. Genotypes come as codes or as
+/// dosages called by a HardCaller, e.g. from simulations or converters.
+pub struct GenoWriter<W: Write> {
+  writer: W,
+  ids_num: usize,
+  markers_num: usize,
+  line: String,
+}
+
+impl<W: Write> GenoWriter<W> {
+  /// @brief Writes comments (without the leading '#', as returned by
+  /// GenoParser::get_comments) and the header line with ids.
+  ///
+  /// @note Returns Error::Validation if a comment or an id holds a line
+  /// break, or an id holds a tab.
+  pub fn new(mut writer: W, ids: &[String], comments: &[String]) -> std::io::Result<Self> {
+    if let Some(comment) = comments.iter().find(|comment| comment.contains(['\n', '\r'])) {
+      return Err(
+        Error::Validation(format!("Comment <{}> spans several lines.", comment.trim())).into(),
+      );
+    }
+    for id in ids {
+      check_field("Id", id)?;
+    }
+    for comment in comments {
+      writeln!(writer, "#{}", comment)?;
+    }
+    writeln!(writer, "marker\t{}", ids.join("\t"))?;
+    Ok(GenoWriter {
+      writer,
+      ids_num: ids.len(),
+      markers_num: 0,
+      line: String::new(),
+    })
+  }
+
+  /// @brief Writes the line of marker with a genotype code per individual.
+  ///
+  /// @note Returns Error::Validation if the amount of codes differs from the
+  /// amount of ids, a code is whitespace, or marker holds a tab or a line
+  /// break.
+  pub fn write_codes(&mut self, marker: &str, codes: &[char]) -> std::io::Result<()> {
+    self.line.clear();
+    self.line.extend(codes);
+    self.write_line(marker, codes.len())
+  }
+
+  /// @brief Writes the line of marker with dosages (a value per individual,
+  /// na::NA when missing) converted to codes by caller.
+  pub fn write_dosages(
+    &mut self,
+    marker: &str,
+    dosages: &[f64],
+    caller: &HardCaller,
+  ) -> std::io::Result<()> {
+    self.line.clear();
+    self.line.extend(dosages.iter().map(|dosage| caller.call(*dosage)));
+    self.write_line(marker, dosages.len())
+  }
+
+  /// @brief Writes all markers of matrix, see write_dosages.
+  ///
+  /// @note Returns Error::Validation if the amount of ids of matrix differs.
+  pub fn write_matrix(&mut self, matrix: &GenoMatrix, caller: &HardCaller) -> std::io::Result<()> {
+    if matrix.ids_num() != self.ids_num {
+      return Err(
+        Error::Validation(format!(
+          "Genotype matrix has {} ids, the file has {}.",
+          matrix.ids_num(),
+          self.ids_num
+        ))
+        .into(),
+      );
+    }
+    for (marker_idx, marker) in matrix.marker_ids.iter().enumerate() {
+      self.write_dosages(marker, matrix.marker(MarkerIdx(marker_idx)), caller)?;
+    }
+    Ok(())
+  }
+
+  /// @brief Amount of markers written so far.
+  pub fn markers_num(&self) -> usize {
+    self.markers_num
+  }
+
+  /// @brief Flushes and returns the writer.
+  pub fn into_inner(mut self) -> std::io::Result<W> {
+    self.writer.flush()?;
+    Ok(self.writer)
+  }
+
+  /// @brief Writes marker with the codes in line, codes_num of them.
+  fn write_line(&mut self, marker: &str, codes_num: usize) -> std::io::Result<()> {
+    check_field("Marker", marker)?;
+    if codes_num != self.ids_num {
+      return Err(
+        Error::DimensionMismatch {
+          marker: String::from(marker),
+          expected: self.ids_num,
+          found: codes_num,
+        }
+        .into(),
+      );
+    }
+    if let Some(code) = self.line.chars().find(|code| code.is_whitespace()) {
+      let msg = format!("Genotype code <{:?}> of marker <{}> is whitespace.", code, marker);
+      return Err(Error::Validation(msg).into());
+    }
+    writeln!(self.writer, "{}\t{}", marker, self.line)?;
+    self.markers_num += 1;
+    Ok(())
+  }
+}
+
+/// @brief Checks that field (an id or a marker name, named what) fits in a
+/// tab separated line.
+fn check_field(what: &str, field: &str) -> std::io::Result<()> {
+  if field.contains(['\t', '\n', '\r']) {
+    return Err(
+      Error::Validation(format!("{} <{}> holds a tab or a line break.", what, field.trim()))
+        .into(),
+    );
+  }
+  Ok(())
+}
@@ -0,0 +1,395 @@
+// genoprob.rs
+
+//! Genotype probabilities (R/qtl2 calc_genoprob): the true genotype of an
+//! individual at a marker given its observed genotypes along the chromosome,
+//! which may be missing or wrong. Genotypes along a chromosome are modelled
+//! as a hidden Markov chain whose transitions depend on the recombination
+//! fraction between adjacent markers, and the probabilities are obtained by
+//! the forward-backward algorithm.
+//!
+//! https://kbroman.org/qtl2/assets/vignettes/user_guide.html#Calculating_genotype_probabilities
+
+use std::collections::HashMap;
+
+use super::error::Error;
+use super::gmap::GeneticMap;
+use super::matrix_csv::NumericTable;
+use super::na;
+use super::stats::DosageScale;
+
+/// @brief Cross types supported by calc_genoprob, named as in R/qtl2
+/// control files (crosstype).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrossType {
+  /// @note "bc", genotypes AA and AB.
+  Backcross,
+  /// @note "f2", genotypes AA, AB and BB.
+  F2,
+  /// @note "riself", recombinant inbred lines by selfing, genotypes AA and
+  /// BB.
+  RiSelf,
+  /// @note "risib", recombinant inbred lines by sibling mating, genotypes AA
+  /// and BB.
+  RiSib,
+}
+
+impl CrossType {
+  /// @brief Parses R/qtl2 cross type name, e.g. ControlFile::crosstype.
+  pub fn parse(name: &str) -> Option<Self> {
+    match name {
+      "bc" => Some(CrossType::Backcross),
+      "f2" => Some(CrossType::F2),
+      "riself" => Some(CrossType::RiSelf),
+      "risib" => Some(CrossType::RiSib),
+      _ => None,
+    }
+  }
+
+  /// @brief Names of the possible genotypes, in the order of probabilities.
+  pub fn genotypes(&self) -> &'static [&'static str] {
+    match self {
+      CrossType::Backcross => &["AA", "AB"],
+      CrossType::F2 => &["AA", "AB", "BB"],
+      CrossType::RiSelf | CrossType::RiSib => &["AA", "BB"],
+    }
+  }
+
+  /// @brief B allele dosages of the genotypes on scale (homozygous A at
+  /// DosageScale::min, heterozygous halfway, homozygous B at
+  /// DosageScale::max), which observed genotypes are matched against.
+  pub fn dosages(&self, scale: &DosageScale) -> Vec<f64> {
+    let b_alleles: &[f64] = match self {
+      CrossType::Backcross => &[0.0, 0.5],
+      CrossType::F2 => &[0.0, 0.5, 1.0],
+      CrossType::RiSelf | CrossType::RiSib => &[0.0, 1.0],
+    };
+    b_alleles.iter().map(|share| scale.min + share * (scale.max - scale.min)).collect()
+  }
+
+  /// @brief Probability of genotype g at the first marker.
+  fn init(&self, g: usize) -> f64 {
+    match (self, g) {
+      (CrossType::F2, 1) => 0.5,
+      (CrossType::F2, _) => 0.25,
+      _ => 0.5,
+    }
+  }
+
+  /// @brief Transition matrix (from x to, row-major) between markers with
+  /// recombination fraction r.
+  fn transitions(&self, r: f64) -> Vec<f64> {
+    match self {
+      CrossType::Backcross => vec![1.0 - r, r, r, 1.0 - r],
+      CrossType::F2 => {
+        let (stay, cross) = (1.0 - r, r);
+        vec![
+          stay * stay,
+          2.0 * cross * stay,
+          cross * cross,
+          cross * stay,
+          stay * stay + cross * cross,
+          cross * stay,
+          cross * cross,
+          2.0 * cross * stay,
+          stay * stay,
+        ]
+      }
+      // Recombination fractions of RIL are expanded by the generations of
+      // inbreeding (Haldane and Waddington, 1931).
+      CrossType::RiSelf => {
+        let r = 2.0 * r / (1.0 + 2.0 * r);
+        vec![1.0 - r, r, r, 1.0 - r]
+      }
+      CrossType::RiSib => {
+        let r = 4.0 * r / (1.0 + 6.0 * r);
+        vec![1.0 - r, r, r, 1.0 - r]
+      }
+    }
+  }
+}
+
+/// @brief Genotype probabilities calculation options.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GenoprobOptions {
+  pub cross: CrossType,
+  /// @note Probability of a genotyping error: an observed genotype is wrong
+  /// with this probability, any other genotype equally likely. Must be within
+  /// (0, 1).
+  pub error_prob: f64,
+  /// @note Genotype values of homozygous A and B, see CrossType::dosages.
+  /// GenoParser::calc_genoprob takes them from its hab_mapper.
+  pub scale: DosageScale,
+  /// @note Leave out X chromosomes (see is_x_chromosome) instead of failing
+  /// on them, their model isn't implemented.
+  pub skip_x: bool,
+}
+
+impl Default for GenoprobOptions {
+  fn default() -> Self {
+    GenoprobOptions {
+      cross: CrossType::F2,
+      error_prob: 1e-4,
+      // The values of the default codes A=0, H=0.5, B=1.
+      scale: DosageScale { min: 0.0, max: 1.0 },
+      skip_x: false,
+    }
+  }
+}
+
+/// @brief Genotype probabilities of a chromosome.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenoProbs {
+  pub cross: CrossType,
+  /// @note Genotype values of GenoprobOptions::scale.
+  pub scale: DosageScale,
+  pub chromosome: String,
+  /// @note Ordered by position.
+  pub markers: Vec<String>,
+  /// @note Positions of markers (cM).
+  pub positions: Vec<f64>,
+  pub ids: Vec<String>,
+  /// @note Names of genotypes, see CrossType::genotypes.
+  pub genotypes: Vec<String>,
+  /// @note markers x ids x genotypes.
+  values: Vec<f64>,
+}
+
+impl GenoProbs {
+  /// @brief Probabilities of the marker at position marker (in markers),
+  /// ids x genotypes values.
+  pub fn marker(&self, marker: usize) -> &[f64] {
+    let size = self.ids.len() * self.genotypes.len();
+    &self.values[marker * size..(marker + 1) * size]
+  }
+
+  /// @brief Probabilities of the genotypes of individual at marker, they
+  /// sum up to 1.
+  pub fn get(&self, marker: usize, individual: usize) -> &[f64] {
+    let genotypes_num = self.genotypes.len();
+    &self.marker(marker)[individual * genotypes_num..(individual + 1) * genotypes_num]
+  }
+}
+
+/// @brief Expected B allele dosages (see CrossType::dosages, on the scale of
+/// the genotypes the probabilities were calculated from) of probs, markers
+/// of all the chromosomes as rows and individuals as columns. The dosages of
+/// genotype probabilities are the input of dosage::calc_kinship_table.
+///
+/// @note probs must be of the same individuals, e.g. from a single
+/// calc_genoprob call.
+pub fn expected_dosages(probs: &[GenoProbs]) -> NumericTable {
+  let mut table = NumericTable {
+    row_ids: Vec::new(),
+    col_ids: probs.first().map(|probs| probs.ids.clone()).unwrap_or_default(),
+    values: Vec::new(),
+  };
+  for chromosome in probs {
+    let dosages = chromosome.cross.dosages(&chromosome.scale);
+    table.row_ids.extend_from_slice(&chromosome.markers);
+    table.values.extend(chromosome.values.chunks(dosages.len()).map(|genotypes| {
+      genotypes.iter().zip(&dosages).map(|(prob, dosage)| prob * dosage).sum::<f64>()
+    }));
+  }
+  table
+}
+
+/// @brief Calculates genotype probabilities of the individuals ids from
+/// their observed genotypes rows (SNP lines as returned by
+/// GenoParser::read_all), a GenoProbs per chromosome of gmap (in the order
+/// of GeneticMap::chromosome_names).
+///
+/// @note Genotypes are B allele dosages on options.scale, na::NA when
+/// missing, a value which isn't a genotype of the cross (e.g. B in a
+/// backcross) is Error::Validation. Markers absent from gmap or without
+/// position are left out. Positions are converted to recombination
+/// fractions by Haldane map function.
+/// @note The X chromosome model isn't implemented, markers of chromosome X
+/// (see is_x_chromosome) are Error::Validation rather than taken for an
+/// autosome, unless options.skip_x leaves them out.
+pub fn calc_genoprob(
+  rows: &[(String, Vec<f64>)],
+  ids: &[String],
+  gmap: &GeneticMap,
+  options: &GenoprobOptions,
+) -> std::io::Result<Vec<GenoProbs>> {
+  if !(options.error_prob > 0.0 && options.error_prob < 1.0) {
+    return Err(
+      Error::Validation(format!(
+        "Genotyping error probability should be within (0, 1), got {}.",
+        options.error_prob
+      ))
+      .into(),
+    );
+  }
+  let rows_by_id = rows
+    .iter()
+    .map(|(id, genotypes)| (id.as_str(), genotypes.as_slice()))
+    .collect::<HashMap<&str, &[f64]>>();
+  let mut res = Vec::new();
+  for (chromosome, markers) in gmap.by_chromosome() {
+    if is_x_chromosome(chromosome) {
+      if options.skip_x {
+        continue;
+      }
+      return Err(
+        Error::Validation(format!(
+          "Genotype probabilities of the X chromosome <{}> aren't supported, leave it out \
+           of the genetic map or set skip_x.",
+          chromosome
+        ))
+        .into(),
+      );
+    }
+    let markers = markers
+      .into_iter()
+      .filter(|marker| !na::is_na(marker.position) && rows_by_id.contains_key(marker.id.as_str()))
+      .collect::<Vec<_>>();
+    if markers.is_empty() {
+      continue;
+    }
+    let mut observed = Vec::with_capacity(markers.len());
+    for marker in &markers {
+      observed.push(observed_genotypes(
+        &marker.id,
+        rows_by_id[marker.id.as_str()],
+        ids,
+        options,
+      )?);
+    }
+    let positions = markers.iter().map(|marker| marker.position).collect::<Vec<f64>>();
+    let genotypes = options.cross.genotypes();
+    let mut probs = GenoProbs {
+      cross: options.cross,
+      scale: options.scale,
+      chromosome: String::from(chromosome),
+      markers: markers.iter().map(|marker| marker.id.clone()).collect(),
+      positions,
+      ids: ids.to_vec(),
+      genotypes: genotypes.iter().map(|genotype| String::from(*genotype)).collect(),
+      values: vec![0.0; markers.len() * ids.len() * genotypes.len()],
+    };
+    fill_probs(&mut probs, &observed, options);
+    res.push(probs);
+  }
+  Ok(res)
+}
+
+/// @brief Genotypes (positions in CrossType::genotypes) observed at marker,
+/// None when missing.
+fn observed_genotypes(
+  marker: &str,
+  values: &[f64],
+  ids: &[String],
+  options: &GenoprobOptions,
+) -> std::io::Result<Vec<Option<usize>>> {
+  if values.len() != ids.len() {
+    return Err(
+      Error::Validation(format!(
+        "Marker <{}> has {} genotypes, expected {}.",
+        marker,
+        values.len(),
+        ids.len()
+      ))
+      .into(),
+    );
+  }
+  let dosages = options.cross.dosages(&options.scale);
+  let mut res = Vec::with_capacity(values.len());
+  for (value, id) in values.iter().zip(ids) {
+    if na::is_na(*value) {
+      res.push(None);
+      continue;
+    }
+    match dosages.iter().position(|dosage| (dosage - value).abs() < 1e-9) {
+      Some(g) => res.push(Some(g)),
+      None => {
+        return Err(
+          Error::Validation(format!(
+            "Genotype {} of individual <{}> at marker <{}> is not a genotype of {:?} cross.",
+            value, id, marker, options.cross
+          ))
+          .into(),
+        )
+      }
+    }
+  }
+  Ok(res)
+}
+
+/// @brief Runs the forward-backward algorithm for every individual of probs.
+/// observed holds the genotypes of every marker.
+fn fill_probs(probs: &mut GenoProbs, observed: &[Vec<Option<usize>>], options: &GenoprobOptions) {
+  let (cross, error_prob) = (options.cross, options.error_prob);
+  let (markers_num, ids_num) = (probs.markers.len(), probs.ids.len());
+  let n = probs.genotypes.len();
+  let transitions = probs
+    .positions
+    .windows(2)
+    .map(|pair| cross.transitions(haldane((pair[1] - pair[0]).abs())))
+    .collect::<Vec<Vec<f64>>>();
+  let emit = |marker: usize, individual: usize, g: usize| match observed[marker][individual] {
+    None => 1.0,
+    Some(obs) if obs == g => 1.0 - error_prob,
+    Some(_) => error_prob / (n - 1) as f64,
+  };
+  // Scaled forward and backward probabilities, markers x genotypes.
+  let mut alpha = vec![0.0; markers_num * n];
+  let mut beta = vec![0.0; markers_num * n];
+  for individual in 0..ids_num {
+    for (g, elem) in alpha[..n].iter_mut().enumerate() {
+      *elem = cross.init(g) * emit(0, individual, g);
+    }
+    normalize(&mut alpha[..n]);
+    for k in 1..markers_num {
+      let (prev, next) = alpha.split_at_mut(k * n);
+      let prev = &prev[(k - 1) * n..];
+      for to in 0..n {
+        let sum = (0..n).map(|from| prev[from] * transitions[k - 1][from * n + to]).sum::<f64>();
+        next[to] = sum * emit(k, individual, to);
+      }
+      normalize(&mut next[..n]);
+    }
+    beta[(markers_num - 1) * n..].iter_mut().for_each(|elem| *elem = 1.0);
+    for k in (0..markers_num - 1).rev() {
+      let (cur, next) = beta.split_at_mut((k + 1) * n);
+      let cur = &mut cur[k * n..];
+      for from in 0..n {
+        cur[from] = (0..n)
+          .map(|to| transitions[k][from * n + to] * emit(k + 1, individual, to) * next[to])
+          .sum();
+      }
+      normalize(cur);
+    }
+    for k in 0..markers_num {
+      let start = (k * ids_num + individual) * n;
+      let res = &mut probs.values[start..start + n];
+      for g in 0..n {
+        res[g] = alpha[k * n + g] * beta[k * n + g];
+      }
+      normalize(res);
+    }
+  }
+}
+
+/// @brief Whether chromosome is the X chromosome: named X (as R/qtl2 x_chr
+/// by default), in any case and with an optional "chr" prefix.
+pub fn is_x_chromosome(chromosome: &str) -> bool {
+  let name = chromosome.trim();
+  let name = match name.get(..3) {
+    Some(prefix) if prefix.eq_ignore_ascii_case("chr") => &name[3..],
+    _ => name,
+  };
+  name.eq_ignore_ascii_case("x")
+}
+
+/// @brief Recombination fraction of genetic distance d (cM), Haldane map
+/// function.
+fn haldane(d: f64) -> f64 {
+  0.5 * (1.0 - (-2.0 * d / 100.0).exp())
+}
+
+/// @brief Scales values to sum up to 1.
+fn normalize(values: &mut [f64]) {
+  let sum = values.iter().sum::<f64>();
+  values.iter_mut().for_each(|value| *value /= sum);
+}
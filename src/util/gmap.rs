@@ -0,0 +1,184 @@
+// gmap.rs
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+use super::error::Error;
+use super::matrix_csv::{LabeledTable, MatrixCsvReader};
+
+/// @brief Marker of a genetic (cM) or physical (Mbp) map.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapMarker {
+  pub id: String,
+  pub chromosome: String,
+  pub position: f64,
+}
+
+/// @brief Genetic or physical map (R/qtl2 gmap/pmap file): chromosome and
+/// position of every marker.
+#[derive(Clone, Debug, Default)]
+pub struct GeneticMap {
+  /// @note In file order.
+  markers: Vec<MapMarker>,
+  positions: HashMap<String, usize>,
+}
+
+impl GeneticMap {
+  /// @brief Builds map from a table with markers as rows, chromosome in the
+  /// first column and position in the second one (marker,chr,pos).
+  pub fn from_table(table: &LabeledTable) -> std::io::Result<Self> {
+    if table.col_ids.len() < 2 {
+      return Err(
+        Error::Validation(format!(
+          "Map should have chromosome and position columns, got {} columns.",
+          table.col_ids.len()
+        ))
+        .into(),
+      );
+    }
+    let chromosomes = table.column(0);
+    let positions = table.numeric_column(1)?;
+    let mut map = GeneticMap::default();
+    for ((id, chromosome), position) in table.row_ids.iter().zip(chromosomes).zip(positions) {
+      let chromosome = chromosome.ok_or_else(|| {
+        Error::Validation(format!("Chromosome of marker <{}> is missing.", id))
+      })?;
+      if map.positions.insert(id.clone(), map.markers.len()).is_some() {
+        return Err(Error::Validation(format!("Marker <{}> is listed twice.", id)).into());
+      }
+      map.markers.push(MapMarker {
+        id: id.clone(),
+        chromosome: String::from(chromosome),
+        position,
+      });
+    }
+    Ok(map)
+  }
+
+  /// @brief Markers in file order.
+  pub fn markers(&self) -> &[MapMarker] {
+    &self.markers
+  }
+
+  pub fn len(&self) -> usize {
+    self.markers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.markers.is_empty()
+  }
+
+  pub fn get(&self, id: &str) -> Option<&MapMarker> {
+    self.positions.get(id).map(|pos| &self.markers[*pos])
+  }
+
+  pub fn chromosome(&self, id: &str) -> Option<&str> {
+    self.get(id).map(|marker| marker.chromosome.as_str())
+  }
+
+  pub fn position(&self, id: &str) -> Option<f64> {
+    self.get(id).map(|marker| marker.position)
+  }
+
+  /// @brief Markers of chromosome within [start, end], ordered by position.
+  /// Markers with missing position are left out.
+  pub fn region(&self, chromosome: &str, start: f64, end: f64) -> Vec<&MapMarker> {
+    let mut markers = self
+      .markers
+      .iter()
+      .filter(|marker| {
+        marker.chromosome == chromosome && marker.position >= start && marker.position <= end
+      })
+      .collect::<Vec<&MapMarker>>();
+    markers.sort_by(|a, b| a.position.total_cmp(&b.position));
+    markers
+  }
+
+  /// @brief Chromosomes in order: numbered ones by number, then the rest
+  /// (X, Y, MT, ...) by name.
+  pub fn chromosome_names(&self) -> Vec<&str> {
+    let mut names = Vec::<&str>::new();
+    for marker in &self.markers {
+      if !names.contains(&marker.chromosome.as_str()) {
+        names.push(&marker.chromosome);
+      }
+    }
+    names.sort_by(|a, b| compare_chromosomes(a, b));
+    names
+  }
+
+  /// @brief Markers grouped by chromosome (in the order of chromosome_names),
+  /// ordered by position within a chromosome, markers with missing position
+  /// last.
+  pub fn by_chromosome(&self) -> Vec<(&str, Vec<&MapMarker>)> {
+    let mut groups = self
+      .chromosome_names()
+      .into_iter()
+      .map(|name| (name, Vec::new()))
+      .collect::<Vec<(&str, Vec<&MapMarker>)>>();
+    for marker in &self.markers {
+      let group = groups.iter_mut().find(|(name, _)| *name == marker.chromosome).unwrap();
+      group.1.push(marker);
+    }
+    for (_, markers) in &mut groups {
+      markers.sort_by(|a, b| a.position.total_cmp(&b.position));
+    }
+    groups
+  }
+
+  /// @brief Chromosome of every marker, as taken by
+  /// loco::calc_kinship_chromosomes.
+  pub fn chromosomes(&self) -> HashMap<String, String> {
+    self
+      .markers
+      .iter()
+      .map(|marker| (marker.id.clone(), marker.chromosome.clone()))
+      .collect()
+  }
+}
+
+/// @brief Orders chromosome names: numbers numerically, before any name.
+fn compare_chromosomes(a: &str, b: &str) -> Ordering {
+  match (a.parse::<u64>(), b.parse::<u64>()) {
+    (Ok(a), Ok(b)) => a.cmp(&b),
+    (Ok(_), Err(_)) => Ordering::Less,
+    (Err(_), Ok(_)) => Ordering::Greater,
+    (Err(_), Err(_)) => a.cmp(b),
+  }
+}
+
+/// @brief Parser of R/qtl2 gmap (cM) and pmap (Mbp) files: CSV with marker,
+/// chromosome and position columns.
+///
+/// @note https://kbroman.org/qtl2/assets/vignettes/input_files.html#Genetic_map
+#[derive(Default)]
+pub struct MapParser {
+  reader: MatrixCsvReader,
+}
+
+impl MapParser {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_delimiter(mut self, delimiter: char) -> Self {
+    self.reader = self.reader.with_delimiter(delimiter);
+    self
+  }
+
+  /// @brief Sets strings which denote missing positions (R/qtl2 na.strings).
+  pub fn with_na_strings(mut self, na_strings: Vec<String>) -> Self {
+    self.reader = self.reader.with_na_strings(na_strings);
+    self
+  }
+
+  pub fn read<P: AsRef<Path>>(&self, path: P) -> std::io::Result<GeneticMap> {
+    GeneticMap::from_table(&self.reader.read(path)?)
+  }
+
+  pub fn read_from<R: BufRead>(&self, reader: R) -> std::io::Result<GeneticMap> {
+    GeneticMap::from_table(&self.reader.read_from(reader)?)
+  }
+}
@@ -0,0 +1,130 @@
+// hard_calls.rs
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use super::error::Error;
+use super::kinship::SnpSource;
+use super::na;
+
+/// @brief Converts dosages to genotype codes of a hab_mapper: a dosage within
+/// threshold of a genotype value becomes its code, any other (uncertain)
+/// dosage becomes the missing code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HardCaller {
+  /// @note (value, code), ordered by value.
+  levels: Vec<(f64, char)>,
+  missing: char,
+  threshold: f64,
+}
+
+impl HardCaller {
+  /// @brief Takes genotype values from hab_mapper (e.g. A = 0, H = 0.5,
+  /// B = 1), so the written file is read back with the same mapper. The
+  /// missing code is the one hab_mapper maps to na::NA, '-' if there is none.
+  ///
+  /// @note Returns Error::Validation if threshold is negative, not less than
+  /// half of the distance between two genotype values (a dosage could match
+  /// both) or hab_mapper has no genotype values.
+  pub fn new(hab_mapper: &HashMap<char, f64>, threshold: f64) -> std::io::Result<Self> {
+    let mut levels = hab_mapper
+      .iter()
+      .filter(|(_, value)| !na::is_na(**value))
+      .map(|(code, value)| (*value, *code))
+      .collect::<Vec<(f64, char)>>();
+    // The smallest code of a value is used, so the output is deterministic.
+    levels.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    levels.dedup_by(|next, prev| next.0 == prev.0);
+    if levels.is_empty() {
+      return Err(Error::Validation(String::from("There are no genotype values.")).into());
+    }
+    let min_gap = levels
+      .windows(2)
+      .map(|pair| pair[1].0 - pair[0].0)
+      .fold(f64::INFINITY, f64::min);
+    if threshold.is_nan() || threshold < 0.0 || threshold * 2.0 >= min_gap {
+      return Err(
+        Error::Validation(format!(
+          "Hard call threshold {} should be in [0, {}).",
+          threshold,
+          min_gap / 2.0
+        ))
+        .into(),
+      );
+    }
+    let missing = hab_mapper
+      .iter()
+      .filter(|(_, value)| na::is_na(**value))
+      .map(|(code, _)| *code)
+      .min()
+      .unwrap_or('-');
+    Ok(HardCaller {
+      levels,
+      missing,
+      threshold,
+    })
+  }
+
+  /// @brief Sets code written for missing and uncertain dosages.
+  pub fn with_missing(mut self, missing: char) -> Self {
+    self.missing = missing;
+    self
+  }
+
+  pub fn missing(&self) -> char {
+    self.missing
+  }
+
+  /// @brief Code of the genotype value within threshold of dosage, the
+  /// missing code if there is none or dosage is missing.
+  pub fn call(&self, dosage: f64) -> char {
+    self
+      .levels
+      .iter()
+      .find(|(value, _)| (dosage - value).abs() <= self.threshold)
+      .map_or(self.missing, |(_, code)| *code)
+  }
+}
+
+/// @brief Amounts of genotypes written by write_hard_calls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HardCallCounts {
+  pub markers: usize,
+  pub called: usize,
+  /// @note Missing and uncertain dosages.
+  pub missing: usize,
+}
+
+/// @brief Streams dosages of source to writer as genotype file (the format
+/// read by GenoParser): a header with ids, then a line of codes per marker.
+pub fn write_hard_calls<W: Write>(
+  source: &mut dyn SnpSource,
+  ids: &[String],
+  writer: &mut W,
+  caller: &HardCaller,
+) -> std::io::Result<HardCallCounts> {
+  const BATCH_ROWS: usize = 256;
+  let ids_num = source.ids_num();
+  writeln!(writer, "marker\t{}", ids.join("\t"))?;
+  let mut buf = vec![0.0; ids_num.max(1) * BATCH_ROWS];
+  let mut row_ids = Vec::new();
+  let mut counts = HardCallCounts::default();
+  let mut line = String::new();
+  loop {
+    row_ids.clear();
+    let rows = source.read_batch(&mut buf, Some(&mut row_ids))?;
+    if rows == 0 {
+      break;
+    }
+    for (id, dosages) in row_ids.iter().zip(buf.chunks(ids_num.max(1))) {
+      line.clear();
+      line.extend(dosages[..ids_num].iter().map(|dosage| caller.call(*dosage)));
+      let missing = line.chars().filter(|code| *code == caller.missing).count();
+      counts.missing += missing;
+      counts.called += ids_num - missing;
+      writeln!(writer, "{}\t{}", id, line)?;
+    }
+    counts.markers += rows;
+  }
+  Ok(counts)
+}
@@ -0,0 +1,41 @@
+// idx.rs
+
+//! Typed indices. A genotype matrix is indexed by markers (SNP lines) in one
+//! dimension and by individuals (ids from the header line) in the other, both
+//! are plain usize in flat Vec<f64> storages, which makes it easy to swap
+//! them. APIs taking or returning such indices use the newtypes below.
+
+use std::fmt;
+
+/// @brief Position of a marker (SNP line) in the genotype file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MarkerIdx(pub usize);
+
+/// @brief Position of an individual in the header line of the genotype file,
+/// so as in rows and columns of the Kinship matrix.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IndividualIdx(pub usize);
+
+impl MarkerIdx {
+  pub fn index(self) -> usize {
+    self.0
+  }
+}
+
+impl IndividualIdx {
+  pub fn index(self) -> usize {
+    self.0
+  }
+}
+
+impl fmt::Display for MarkerIdx {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl fmt::Display for IndividualIdx {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
@@ -0,0 +1,269 @@
+// index.rs
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use super::code_table::CodeTable;
+use super::error::Error;
+use super::idx::MarkerIdx;
+use super::parse_report::ParseReport;
+use super::report_blank_lines;
+use super::text;
+use super::GenoParser;
+
+/// @brief Byte offsets of SNP lines in a genotype file, keyed by the row id
+/// (first column).
+pub struct SnpIndex {
+  ids: Vec<String>,
+  offsets: Vec<u64>,
+  positions: HashMap<String, usize>,
+  blank_lines: usize,
+}
+
+impl SnpIndex {
+  /// @brief Scans SNP lines starting at the current reader position.
+  pub fn build<R: BufRead + Seek>(reader: &mut R) -> std::io::Result<Self> {
+    let mut index = SnpIndex {
+      ids: Vec::new(),
+      offsets: Vec::new(),
+      positions: HashMap::new(),
+      blank_lines: 0,
+    };
+    let mut offset = reader.stream_position()?;
+    let mut line = String::new();
+    loop {
+      line.clear();
+      let (read_bytes_count, _) = text::read_line(reader, &mut line)?;
+      if read_bytes_count == 0 {
+        break;
+      }
+      let id = line.split('\t').next().unwrap_or_default().trim_end();
+      if line.trim().is_empty() {
+        index.blank_lines += 1;
+      } else if !id.is_empty() {
+        index.positions.insert(String::from(id), index.ids.len());
+        index.ids.push(String::from(id));
+        index.offsets.push(offset);
+      }
+      offset += read_bytes_count as u64;
+    }
+    Ok(index)
+  }
+
+  /// @brief Row ids in file order.
+  pub fn ids(&self) -> &[String] {
+    &self.ids
+  }
+
+  pub fn len(&self) -> usize {
+    self.ids.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ids.is_empty()
+  }
+
+  /// @brief Number of whitespace-only lines among SNP lines.
+  pub fn blank_lines(&self) -> usize {
+    self.blank_lines
+  }
+
+  /// @brief Position of the line with row id in the file.
+  pub fn position(&self, id: &str) -> Option<MarkerIdx> {
+    self.positions.get(id).map(|pos| MarkerIdx(*pos))
+  }
+
+  /// @brief Row id of the line at position.
+  pub fn id(&self, marker: MarkerIdx) -> Option<&str> {
+    self.ids.get(marker.index()).map(|id| id.as_str())
+  }
+
+  /// @brief Byte offset of the line with row id.
+  pub fn offset(&self, id: &str) -> Option<u64> {
+    self.positions.get(id).map(|pos| self.offsets[*pos])
+  }
+
+  /// @brief Determines whether SNP lines in the file are in the expected
+  /// order.
+  pub fn matches_order(&self, expected: &[String]) -> bool {
+    self.ids == expected
+  }
+}
+
+/// @brief What to do when SNP lines of the genotype file are not in the order
+/// expected by an analysis (e.g. the order of the genetic map).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderPolicy {
+  /// @brief Fail on the first SNP line out of order.
+  Verify,
+  /// @brief Read SNP lines in the expected order by seeking to their offsets.
+  Reorder,
+}
+
+/// @brief Verifies on the fly that row ids arrive in the expected order.
+pub struct OrderCheck<'a> {
+  expected: &'a [String],
+  next: usize,
+}
+
+impl<'a> OrderCheck<'a> {
+  pub fn new(expected: &'a [String]) -> Self {
+    OrderCheck { expected, next: 0 }
+  }
+
+  /// @brief Checks the next row id.
+  pub fn check(&mut self, id: &str) -> Result<(), Error> {
+    match self.expected.get(self.next) {
+      Some(expected) if expected == id => {
+        self.next += 1;
+        Ok(())
+      }
+      Some(expected) => Err(Error::Validation(format!(
+        "SNP <{}> found at position {}, where SNP <{}> was expected.",
+        id, self.next, expected
+      ))),
+      None => Err(Error::Validation(format!(
+        "SNP <{}> found after all {} expected SNPs.",
+        id,
+        self.expected.len()
+      ))),
+    }
+  }
+
+  /// @brief Checks that all expected row ids were seen.
+  pub fn finish(&self) -> Result<(), Error> {
+    match self.expected.get(self.next) {
+      Some(expected) => Err(Error::Validation(format!(
+        "SNP <{}> and {} following SNPs are missing.",
+        expected,
+        self.expected.len() - self.next - 1
+      ))),
+      None => Ok(()),
+    }
+  }
+}
+
+/// @brief Iterates over SNP lines in the given order. Yields tuples
+/// (row_id, snps).
+pub struct OrderedSnpIter<'a, R: BufRead + Seek = BufReader<File>> {
+  file_reader: &'a mut R,
+  codes: &'a CodeTable,
+  ids_num: usize,
+  order: &'a [String],
+  policy: OrderPolicy,
+  check: OrderCheck<'a>,
+  index: Option<SnpIndex>,
+  /// @note Gets the count of skipped blank lines at the end of the file.
+  report: &'a mut ParseReport,
+  blank_lines: usize,
+  next: usize,
+  finished: bool,
+}
+
+impl<'a, R: BufRead + Seek> OrderedSnpIter<'a, R> {
+  /// @note File cursor must be located at the beginning of SNP records.
+  pub(crate) fn new(
+    file_reader: &'a mut R,
+    codes: &'a CodeTable,
+    ids_num: usize,
+    order: &'a [String],
+    policy: OrderPolicy,
+    report: &'a mut ParseReport,
+  ) -> std::io::Result<Self> {
+    let index = match policy {
+      OrderPolicy::Verify => None,
+      OrderPolicy::Reorder => {
+        let start = file_reader.stream_position()?;
+        let index = SnpIndex::build(file_reader)?;
+        file_reader.seek(SeekFrom::Start(start))?;
+        Some(index)
+      }
+    };
+    Ok(OrderedSnpIter {
+      file_reader,
+      codes,
+      ids_num,
+      order,
+      policy,
+      check: OrderCheck::new(order),
+      blank_lines: index.as_ref().map_or(0, SnpIndex::blank_lines),
+      index,
+      report,
+      next: 0,
+      finished: false,
+    })
+  }
+
+  fn next_line(&mut self) -> std::io::Result<Option<String>> {
+    if let Some(index) = &self.index {
+      let id = match self.order.get(self.next) {
+        Some(id) => id,
+        None => {
+          report_blank_lines(self.report, self.blank_lines);
+          return Ok(None);
+        }
+      };
+      let offset = index.offset(id).ok_or_else(|| {
+        std::io::Error::from(Error::Validation(format!(
+          "SNP <{}> is not present in genotype file.",
+          id
+        )))
+      })?;
+      self.file_reader.seek(SeekFrom::Start(offset))?;
+    }
+    let mut line = String::new();
+    loop {
+      line.clear();
+      if text::read_line(self.file_reader, &mut line)?.0 == 0 {
+        report_blank_lines(self.report, self.blank_lines);
+        return Ok(None);
+      }
+      // Only reached by sequential reads, the index skips blank lines.
+      if !line.trim().is_empty() {
+        break;
+      }
+      self.blank_lines += 1;
+    }
+    self.next += 1;
+    Ok(Some(String::from(line.trim_end_matches(['\n', '\r']))))
+  }
+
+  fn next_record(&mut self) -> std::io::Result<Option<(String, Vec<f64>)>> {
+    let line = match self.next_line()? {
+      Some(line) => line,
+      None => {
+        if self.policy == OrderPolicy::Verify {
+          self.check.finish()?;
+        }
+        return Ok(None);
+      }
+    };
+    let id = String::from(line.split('\t').next().unwrap_or_default());
+    if self.policy == OrderPolicy::Verify {
+      self.check.check(&id)?;
+    }
+    let mut snps = vec![0.0; self.ids_num];
+    GenoParser::parse_into(&mut snps, &line, self.codes)?;
+    Ok(Some((id, snps)))
+  }
+}
+
+impl<'a, R: BufRead + Seek> Iterator for OrderedSnpIter<'a, R> {
+  type Item = std::io::Result<(String, Vec<f64>)>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.finished {
+      return None;
+    }
+    let res = self.next_record();
+    // Stop after the end or the first error.
+    if !matches!(res, Ok(Some(_))) {
+      self.finished = true;
+    }
+    res.transpose()
+  }
+}
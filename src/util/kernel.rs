@@ -0,0 +1,477 @@
+// kernel.rs
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::error::Error;
+use super::kinship::calc_partial_kinship_tiled;
+
+/// @brief Implementation of the batch update of the Kinship matrix: adds
+/// snps.T * snps to the upper part of partial_matrix (see
+/// calc_partial_kinship for the layout).
+///
+/// @note A failed update may leave partial_matrix partially updated, the
+/// caller is responsible for discarding it.
+pub trait KinshipKernel: Send + Sync + fmt::Debug {
+  /// @brief Short name used in metrics and error reports.
+  fn name(&self) -> &'static str;
+
+  fn update(&self, snps: &[f64], partial_matrix: &mut [f64], ids_num: usize)
+    -> Result<(), Error>;
+
+  /// @brief Starts an accumulation of ids_num x ids_num matrix which the
+  /// kernel keeps between batches (e.g. in device memory), None if every
+  /// batch is added to a partial matrix by update (the default).
+  fn start(&self, _ids_num: usize) -> Result<Option<Box<dyn KernelAccumulation>>, Error> {
+    Ok(None)
+  }
+}
+
+/// @brief Matrix accumulated by a kernel over many batches, see
+/// KinshipKernel::start. Batches come one at a time, possibly from different
+/// threads.
+pub trait KernelAccumulation: Send {
+  /// @brief Adds snps.T * snps to the accumulated matrix.
+  ///
+  /// @note A failed update leaves the accumulated matrix as it was, so the
+  /// batch can be recalculated on the CPU.
+  fn update(&mut self, snps: &[f64]) -> Result<(), Error>;
+
+  /// @brief Adds the accumulated matrix to the upper part of partial_matrix.
+  ///
+  /// @note The batches accumulated so far are lost on failure.
+  fn finish(self: Box<Self>, partial_matrix: &mut [f64]) -> Result<(), Error>;
+}
+
+/// @brief Default CPU kernel (calc_partial_kinship_tiled), never fails.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuKernel;
+
+impl KinshipKernel for CpuKernel {
+  fn name(&self) -> &'static str {
+    "cpu"
+  }
+
+  fn update(
+    &self,
+    snps: &[f64],
+    partial_matrix: &mut [f64],
+    ids_num: usize,
+  ) -> Result<(), Error> {
+    calc_partial_kinship_tiled(snps, partial_matrix, ids_num);
+    Ok(())
+  }
+}
+
+/// @brief Kernel calling cblas_dsyrk of the system BLAS library (OpenBLAS,
+/// MKL, ...), linked when the crate is built with the blas feature.
+///
+/// @note Fails (so the batch is recalculated by CpuKernel) if dimensions
+/// don't fit in the 32-bit BLAS integers.
+#[cfg(feature = "blas")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlasKernel;
+
+#[cfg(feature = "blas")]
+mod cblas {
+  pub const ROW_MAJOR: i32 = 101;
+  pub const UPPER: i32 = 121;
+  pub const TRANS: i32 = 112;
+
+  extern "C" {
+    #[allow(clippy::too_many_arguments)]
+    pub fn cblas_dsyrk(
+      order: i32,
+      uplo: i32,
+      trans: i32,
+      n: i32,
+      k: i32,
+      alpha: f64,
+      a: *const f64,
+      lda: i32,
+      beta: f64,
+      c: *mut f64,
+      ldc: i32,
+    );
+  }
+}
+
+#[cfg(feature = "blas")]
+impl KinshipKernel for BlasKernel {
+  fn name(&self) -> &'static str {
+    "blas"
+  }
+
+  fn update(
+    &self,
+    snps: &[f64],
+    partial_matrix: &mut [f64],
+    ids_num: usize,
+  ) -> Result<(), Error> {
+    if ids_num == 0 || snps.is_empty() {
+      return Ok(());
+    }
+    let rows = snps.len() / ids_num;
+    if ids_num > i32::MAX as usize || rows > i32::MAX as usize {
+      return Err(Error::Validation(format!(
+        "Batch of {} x {} values is too large for BLAS.",
+        rows, ids_num
+      )));
+    }
+    let (n, k) = (ids_num as i32, rows as i32);
+    assert!(partial_matrix.len() >= ids_num * ids_num);
+    // Row-major snps are a k x n matrix A, the upper part of row-major
+    // partial_matrix gets A.T * A, as in calc_partial_kinship.
+    unsafe {
+      cblas::cblas_dsyrk(
+        cblas::ROW_MAJOR,
+        cblas::UPPER,
+        cblas::TRANS,
+        n,
+        k,
+        1.0,
+        snps.as_ptr(),
+        n,
+        1.0,
+        partial_matrix.as_mut_ptr(),
+        n,
+      );
+    }
+    Ok(())
+  }
+}
+
+/// @brief Kernel calling cublasDsyrk on the first CUDA device, with libcudart
+/// and libcublas loaded at run time when the crate is built with the cuda
+/// feature, so the binary starts on machines without them.
+///
+/// @note The batches of a calculation are accumulated on the device (see
+/// KinshipKernel::start), only the batches are copied to it and the matrix
+/// is copied back once. A single update copies the matrix back as well, so
+/// it pays off for large batches only. Fails (so the batch is recalculated
+/// by CpuKernel) on any CUDA error, e.g. when the libraries can't be loaded
+/// or the device memory is exhausted.
+#[cfg(feature = "cuda")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CudaKernel;
+
+#[cfg(feature = "cuda")]
+mod cuda {
+  use std::ffi::{CStr, CString};
+  use std::os::raw::{c_char, c_int, c_void};
+  use std::os::unix::ffi::OsStrExt;
+  use std::path::PathBuf;
+  use std::sync::OnceLock;
+
+  use super::Error;
+  use crate::util::environment::find_cuda_library;
+
+  pub const MEMCPY_HOST_TO_DEVICE: c_int = 1;
+  pub const MEMCPY_DEVICE_TO_HOST: c_int = 2;
+  pub const FILL_MODE_LOWER: c_int = 0;
+  pub const OP_N: c_int = 0;
+  const RTLD_NOW: c_int = 2;
+
+  extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *mut c_char;
+  }
+
+  type Dsyrk = unsafe extern "C" fn(
+    *mut c_void,
+    c_int,
+    c_int,
+    c_int,
+    c_int,
+    *const f64,
+    *const f64,
+    c_int,
+    *const f64,
+    *mut f64,
+    c_int,
+  ) -> c_int;
+
+  /// @brief Functions of libcudart and libcublas used by the kernel.
+  pub struct Api {
+    pub malloc: unsafe extern "C" fn(*mut *mut c_void, usize) -> c_int,
+    pub free: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub memcpy: unsafe extern "C" fn(*mut c_void, *const c_void, usize, c_int) -> c_int,
+    pub memset: unsafe extern "C" fn(*mut c_void, c_int, usize) -> c_int,
+    pub mem_get_info: unsafe extern "C" fn(*mut usize, *mut usize) -> c_int,
+    pub create: unsafe extern "C" fn(*mut *mut c_void) -> c_int,
+    pub destroy: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub dsyrk: Dsyrk,
+  }
+
+  static API: OnceLock<Result<Api, String>> = OnceLock::new();
+
+  /// @brief Loads libcudart and libcublas on first use, from where
+  /// environment::report finds them (by the loader's search path otherwise).
+  /// Error::GpuUnavailable if a library or a function isn't found.
+  pub fn api() -> Result<&'static Api, Error> {
+    let loaded = API.get_or_init(|| unsafe { load() });
+    loaded.as_ref().map_err(|reason| Error::GpuUnavailable(reason.clone()))
+  }
+
+  unsafe fn load() -> Result<Api, String> {
+    let (cudart, cublas) = (open("libcudart")?, open("libcublas")?);
+    Ok(Api {
+      malloc: symbol(cudart, "cudaMalloc")?,
+      free: symbol(cudart, "cudaFree")?,
+      memcpy: symbol(cudart, "cudaMemcpy")?,
+      memset: symbol(cudart, "cudaMemset")?,
+      mem_get_info: symbol(cudart, "cudaMemGetInfo")?,
+      create: symbol(cublas, "cublasCreate_v2")?,
+      destroy: symbol(cublas, "cublasDestroy_v2")?,
+      dsyrk: symbol(cublas, "cublasDsyrk_v2")?,
+    })
+  }
+
+  /// @brief Opens library (e.g. "libcudart"), it stays loaded for the rest
+  /// of the process.
+  unsafe fn open(library: &str) -> Result<*mut c_void, String> {
+    let path =
+      find_cuda_library(library).unwrap_or_else(|| PathBuf::from(format!("{}.so", library)));
+    let name = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let handle = dlopen(name.as_ptr(), RTLD_NOW);
+    match handle.is_null() {
+      true => Err(format!("{} can't be loaded: {}", path.display(), last_error())),
+      false => Ok(handle),
+    }
+  }
+
+  /// @brief Function name of library as F, the function pointer type of its
+  /// signature.
+  unsafe fn symbol<F: Copy>(library: *mut c_void, name: &str) -> Result<F, String> {
+    let c_name = CString::new(name).map_err(|e| e.to_string())?;
+    let ptr = dlsym(library, c_name.as_ptr());
+    if ptr.is_null() {
+      return Err(format!("{} is not found: {}", name, last_error()));
+    }
+    assert_eq!(std::mem::size_of::<F>(), std::mem::size_of::<*mut c_void>());
+    Ok(std::mem::transmute_copy::<*mut c_void, F>(&ptr))
+  }
+
+  unsafe fn last_error() -> String {
+    let error = dlerror();
+    match error.is_null() {
+      true => String::from("unknown error"),
+      false => CStr::from_ptr(error).to_string_lossy().into_owned(),
+    }
+  }
+
+  /// @brief Error of CUDA or cuBLAS call returning status.
+  pub fn check(call: &str, status: c_int) -> Result<(), Error> {
+    match status {
+      0 => Ok(()),
+      status => Err(Error::GpuUnavailable(format!("{} failed with status {}", call, status))),
+    }
+  }
+
+  /// @brief Device buffer of f64 values, freed on drop.
+  pub struct DeviceBuffer {
+    api: &'static Api,
+    pub ptr: *mut c_void,
+    pub values: usize,
+  }
+
+  impl DeviceBuffer {
+    pub fn new(values: usize) -> Result<Self, Error> {
+      let (api, mut ptr) = (api()?, std::ptr::null_mut());
+      check("cudaMalloc", unsafe { (api.malloc)(&mut ptr, values * std::mem::size_of::<f64>()) })?;
+      Ok(DeviceBuffer { api, ptr, values })
+    }
+  }
+
+  impl Drop for DeviceBuffer {
+    fn drop(&mut self) {
+      unsafe { (self.api.free)(self.ptr) };
+    }
+  }
+
+  /// @brief cuBLAS handle, destroyed on drop.
+  pub struct Handle {
+    api: &'static Api,
+    pub ptr: *mut c_void,
+  }
+
+  impl Handle {
+    pub fn new() -> Result<Self, Error> {
+      let (api, mut ptr) = (api()?, std::ptr::null_mut());
+      check("cublasCreate", unsafe { (api.create)(&mut ptr) })?;
+      Ok(Handle { api, ptr })
+    }
+
+    pub fn api(&self) -> &'static Api {
+      self.api
+    }
+  }
+
+  impl Drop for Handle {
+    fn drop(&mut self) {
+      unsafe { (self.api.destroy)(self.ptr) };
+    }
+  }
+}
+
+/// @brief ids_num x ids_num matrix accumulated on the CUDA device, with the
+/// cuBLAS handle and the device buffer of the batches kept between updates.
+#[cfg(feature = "cuda")]
+struct CudaAccumulation {
+  handle: cuda::Handle,
+  matrix: cuda::DeviceBuffer,
+  /// @note Grows to the largest batch.
+  batch: Option<cuda::DeviceBuffer>,
+  ids_num: usize,
+}
+
+// The handle and the buffers are used by one thread at a time, the
+// accumulation is shared behind a mutex.
+#[cfg(feature = "cuda")]
+unsafe impl Send for CudaAccumulation {}
+
+#[cfg(feature = "cuda")]
+impl CudaAccumulation {
+  fn new(ids_num: usize) -> Result<Self, Error> {
+    if ids_num > i32::MAX as usize {
+      return Err(Error::Validation(format!("{} ids are too many for cuBLAS.", ids_num)));
+    }
+    let handle = cuda::Handle::new()?;
+    let matrix = cuda::DeviceBuffer::new(ids_num * ids_num)?;
+    let bytes = matrix.values * std::mem::size_of::<f64>();
+    cuda::check("cudaMemset", unsafe { (handle.api().memset)(matrix.ptr, 0, bytes) })?;
+    Ok(CudaAccumulation {
+      handle,
+      matrix,
+      batch: None,
+      ids_num,
+    })
+  }
+}
+
+#[cfg(feature = "cuda")]
+impl KernelAccumulation for CudaAccumulation {
+  fn update(&mut self, snps: &[f64]) -> Result<(), Error> {
+    use std::os::raw::c_void;
+    let ids_num = self.ids_num;
+    if ids_num == 0 || snps.is_empty() {
+      return Ok(());
+    }
+    let rows = snps.len() / ids_num;
+    if rows > i32::MAX as usize {
+      return Err(Error::Validation(format!(
+        "Batch of {} x {} values is too large for cuBLAS.",
+        rows, ids_num
+      )));
+    }
+    if self.batch.as_ref().is_none_or(|batch| batch.values < snps.len()) {
+      // The smaller buffer is freed first.
+      self.batch = None;
+      self.batch = Some(cuda::DeviceBuffer::new(snps.len())?);
+    }
+    let batch = self.batch.as_ref().unwrap();
+    let (n, k) = (ids_num as i32, rows as i32);
+    // Row-major snps are column-major n x k matrix B and the upper part of
+    // row-major partial matrix is the lower part of column-major C, so
+    // C += B * B.T gives A.T * A, as in calc_partial_kinship.
+    unsafe {
+      let (src, bytes) = (snps.as_ptr() as *const c_void, std::mem::size_of_val(snps));
+      let api = self.handle.api();
+      let status = (api.memcpy)(batch.ptr, src, bytes, cuda::MEMCPY_HOST_TO_DEVICE);
+      cuda::check("cudaMemcpy", status)?;
+      let (alpha, beta) = (1.0f64, 1.0f64);
+      let status = (api.dsyrk)(
+        self.handle.ptr,
+        cuda::FILL_MODE_LOWER,
+        cuda::OP_N,
+        n,
+        k,
+        &alpha,
+        batch.ptr as *const f64,
+        n,
+        &beta,
+        self.matrix.ptr as *mut f64,
+        n,
+      );
+      cuda::check("cublasDsyrk", status)
+    }
+  }
+
+  fn finish(self: Box<Self>, partial_matrix: &mut [f64]) -> Result<(), Error> {
+    use std::os::raw::c_void;
+    let mut matrix = vec![0.0; self.matrix.values];
+    let (dst, bytes) = (matrix.as_mut_ptr() as *mut c_void, std::mem::size_of_val(&matrix[..]));
+    let memcpy = self.handle.api().memcpy;
+    let status = unsafe { memcpy(dst, self.matrix.ptr, bytes, cuda::MEMCPY_DEVICE_TO_HOST) };
+    cuda::check("cudaMemcpy", status)?;
+    // The rest of the device matrix stays zero.
+    for (elem, value) in partial_matrix.iter_mut().zip(matrix) {
+      *elem += value;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(feature = "cuda")]
+impl CudaKernel {
+  /// @brief Free memory of the CUDA device in bytes, None if the CUDA
+  /// libraries can't be loaded or there is no device.
+  pub fn free_memory() -> Option<u64> {
+    let api = cuda::api().ok()?;
+    let (mut free, mut total) = (0usize, 0usize);
+    let status = unsafe { (api.mem_get_info)(&mut free, &mut total) };
+    cuda::check("cudaMemGetInfo", status).ok()?;
+    Some(free as u64)
+  }
+}
+
+#[cfg(feature = "cuda")]
+impl KinshipKernel for CudaKernel {
+  fn name(&self) -> &'static str {
+    "gpu"
+  }
+
+  fn update(
+    &self,
+    snps: &[f64],
+    partial_matrix: &mut [f64],
+    ids_num: usize,
+  ) -> Result<(), Error> {
+    if ids_num == 0 || snps.is_empty() {
+      return Ok(());
+    }
+    let mut accumulation = Box::new(CudaAccumulation::new(ids_num)?);
+    accumulation.update(snps)?;
+    accumulation.finish(&mut partial_matrix[..ids_num * ids_num])
+  }
+
+  fn start(&self, ids_num: usize) -> Result<Option<Box<dyn KernelAccumulation>>, Error> {
+    Ok(Some(Box::new(CudaAccumulation::new(ids_num)?)))
+  }
+}
+
+/// @brief Names of the kernels compiled in, see kernel_by_name.
+pub fn available_kernels() -> Vec<&'static str> {
+  let mut names = vec![CpuKernel.name()];
+  if cfg!(feature = "blas") {
+    names.push("blas");
+  }
+  if cfg!(feature = "cuda") {
+    names.push("gpu");
+  }
+  names
+}
+
+/// @brief Kernel by its name (e.g. from a command line option), for
+/// KinshipOptions::kernel. None if it's unknown or not compiled in.
+pub fn kernel_by_name(name: &str) -> Option<Arc<dyn KinshipKernel>> {
+  match name {
+    "cpu" => Some(Arc::new(CpuKernel)),
+    #[cfg(feature = "blas")]
+    "blas" => Some(Arc::new(BlasKernel)),
+    #[cfg(feature = "cuda")]
+    "gpu" => Some(Arc::new(CudaKernel)),
+    _ => None,
+  }
+}
@@ -0,0 +1,1610 @@
+// kinship.rs
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::cancel::CancellationHandle;
+use super::code_table::CodeTable;
+use super::error::{ensure_memory, Error};
+use super::kernel::{CpuKernel, KinshipKernel};
+use super::metrics::{KernelFallback, Metrics};
+use super::stats::{DosageScale, IbsAccumulator, MafFilter};
+use super::missing::MissingPolicy;
+use super::pool::{spawn_worker, Worker, WorkerPool};
+use super::precision::KinshipFloat;
+use super::progress::ProgressHook;
+use super::throttle::{CpuLimit, Throttle};
+use super::transform::{DosageTransform, KinshipKind};
+use super::weights::MarkerWeights;
+use super::marker_set::MarkerSet;
+use super::GenoParser;
+
+/// @brief Determines how the Kinship matrix calculation is split between
+/// worker threads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParallelMode {
+  /// @brief Each worker takes whole batches of SNPs and accumulates them into
+  /// its own ids_num x ids_num buffer, which is then merged into the result.
+  ///
+  /// @note Memory consumption is ids_num * ids_num * (threads + 1).
+  Batches,
+  /// @brief The result matrix is partitioned into column blocks, each worker
+  /// owns one block of the final matrix and every parsed batch is shared
+  /// between all workers.
+  ///
+  /// @note Memory consumption is ids_num * ids_num in total, which makes this
+  /// mode suitable for huge amount of ids (100k and more).
+  ColumnBlocks,
+}
+
+/// @brief Which Gram matrix of the genotype matrix G (SNP lines as rows, ids
+/// as columns) is calculated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GramOrientation {
+  /// @brief G.T * G: ids_num x ids_num relatedness of individuals, labeled by
+  /// the ids from the header line and normalized by the amount of SNPs.
+  IdsByIds,
+  /// @brief G * G.T: snps_num x snps_num product of SNP lines, labeled by the
+  /// row ids (first column) and normalized by the amount of ids.
+  ///
+  /// @note All SNP lines are loaded in memory, this is meant for small sets
+  /// of individuals.
+  SnpsBySnps,
+}
+
+/// @brief Kinship matrix calculation options.
+#[derive(Clone, Debug)]
+pub struct KinshipOptions {
+  /// @note Amount of SNP lines parsed and processed at once.
+  pub batch_size: usize,
+  /// @note Target size of a parsed batch in bytes. Overrides batch_size when
+  /// given, so the memory taken by batches doesn't depend on the amount of
+  /// ids. See batch_rows.
+  pub batch_bytes: Option<u64>,
+  pub mode: ParallelMode,
+  pub orientation: GramOrientation,
+  /// @note Limits CPU usage, so long calculations can run on shared machines.
+  /// None means all logical cores are used.
+  pub max_cpu: Option<CpuLimit>,
+  /// @note Exact amount of worker threads, overrides the amount derived from
+  /// max_cpu (which still limits the total usage, the threads are throttled
+  /// so together they stay within it).
+  pub threads: Option<usize>,
+  /// @note Runs the workers of ParallelMode::Batches on the threads of the
+  /// pool instead of new threads, so calculations sharing it don't create
+  /// threads of their own. Its amount of threads is the default amount of
+  /// workers. Other calculations (e.g. ParallelMode::ColumnBlocks) borrow
+  /// their data in scoped threads, which are created per calculation.
+  pub pool: Option<Arc<WorkerPool>>,
+  /// @note Merges the batches of ParallelMode::Batches into the matrix in the
+  /// order they were read, instead of the order workers finish them, so the
+  /// result is bit-identical from run to run (and to a single worker) at the
+  /// cost of workers waiting for their turn. The other calculations always
+  /// accumulate in order.
+  pub deterministic: bool,
+  /// @note Accumulates with compensated (Kahan) summation, so the rounding
+  /// errors of many SNP lines don't add up, at about twice the cost of the
+  /// multiplication and an extra matrix per worker. Used by
+  /// ParallelMode::Batches (whose batches are calculated by
+  /// calc_partial_kinship_compensated) and the fast path, the calculations
+  /// return Error::Validation for it with ParallelMode::ColumnBlocks or
+  /// options.kernel.
+  pub compensated: bool,
+  /// @note Applied to every SNP line after decoding, see DosageTransform.
+  pub transform: DosageTransform,
+  /// @note Applied to every SNP line after transform, see KinshipKind.
+  pub kind: KinshipKind,
+  /// @note Excludes monomorphic SNP lines (all present genotypes equal) from
+  /// the accumulation and the normalization, dropped lines are counted in
+  /// metrics. Such lines don't change centered or standardized matrices, but
+  /// do change the ones of DosageTransform::Identity.
+  pub drop_monomorphic: bool,
+  /// @note Excludes SNP lines with minor allele frequency (of the present
+  /// genotypes, before imputation and transform) below the threshold from the
+  /// accumulation and the normalization, dropped lines are counted in
+  /// metrics. Used by the same calculations as missing.
+  pub maf_filter: Option<MafFilter>,
+  /// @note Restricts the calculation to the SNP lines of the markers (by row
+  /// id), e.g. the markers of a region or LD-pruned ones. Lines of the other
+  /// markers are counted in metrics. Used by the same calculations as
+  /// missing. None means all markers are used.
+  pub markers: Option<Arc<MarkerSet>>,
+  /// @note Weights SNP lines by row id (e.g. imputation INFO scores), the
+  /// matrix is normalized by the sum of the weights instead of the amount of
+  /// SNP lines. Used by calc_kinship_observed and calc_kinship_out_of_core.
+  pub marker_weights: Option<Arc<MarkerWeights>>,
+  /// @note Handling of missing genotypes, see MissingPolicy. An imputed SNP
+  /// line counts in the normalization as a whole, as in GEMMA (the matrix is
+  /// divided by the amount of kept lines). Used by calc_kinship_observed,
+  /// calc_kinship_out_of_core and kinship_summary, the other calculations
+  /// reject missing genotypes.
+  pub missing: MissingPolicy,
+  /// @note Kernel used for the batch updates in ParallelMode::Batches. A batch
+  /// on which it fails is recalculated on the CPU and recorded in metrics,
+  /// so a single failure doesn't abort the whole calculation, except for the
+  /// final copy of a matrix accumulated by the kernel (see
+  /// KinshipKernel::start). None means CpuKernel.
+  pub kernel: Option<Arc<dyn KinshipKernel>>,
+  /// @note Collects counters of the calculation when given.
+  pub metrics: Option<Arc<Metrics>>,
+  /// @note Estimated work (multiply-adds, snps_num * ids_num^2 / 2) below
+  /// which the whole input (at most FAST_PATH_MAX_ROWS SNP lines) is read
+  /// into memory and multiplied on the calling thread, since for small inputs
+  /// the worker threads cost more than they save. 0 disables the fast path,
+  /// it's never taken with a custom kernel.
+  pub fast_path_work: u64,
+  /// @note Wall-clock budget of the accumulation: once it's spent no more SNP
+  /// lines are read (at least one batch is), and the matrix is normalized by
+  /// the lines accumulated so far. See calc_kinship_timed for the flag and the
+  /// checkpoint to resume from. None means no limit.
+  pub time_limit: Option<Duration>,
+  /// @note Called after every batch read, see ProgressHook. None means no
+  /// progress is reported.
+  pub progress: Option<ProgressHook>,
+  /// @note Aborts the calculation when cancelled: no more SNP lines are read,
+  /// workers stop before their next batch and Error::Cancelled is returned.
+  pub cancel: Option<CancellationHandle>,
+}
+
+/// @brief Default KinshipOptions::fast_path_work, a few milliseconds of
+/// single-threaded work.
+pub const FAST_PATH_WORK: u64 = 1 << 24;
+
+/// @brief Largest amount of SNP lines handled by the fast path, whatever the
+/// amount of ids.
+pub const FAST_PATH_MAX_ROWS: usize = 1 << 14;
+
+impl Default for KinshipOptions {
+  fn default() -> Self {
+    KinshipOptions {
+      batch_size: 1000,
+      batch_bytes: None,
+      mode: ParallelMode::Batches,
+      orientation: GramOrientation::IdsByIds,
+      max_cpu: None,
+      threads: None,
+      pool: None,
+      deterministic: false,
+      compensated: false,
+      transform: DosageTransform::Identity,
+      kind: KinshipKind::Raw,
+      drop_monomorphic: false,
+      maf_filter: None,
+      markers: None,
+      marker_weights: None,
+      missing: MissingPolicy::Reject,
+      kernel: None,
+      metrics: None,
+      fast_path_work: FAST_PATH_WORK,
+      time_limit: None,
+      progress: None,
+      cancel: None,
+    }
+  }
+}
+
+impl KinshipOptions {
+  /// @brief Amount of worker threads to run.
+  pub fn threads_num(&self) -> usize {
+    let cpus_num = match &self.pool {
+      Some(pool) => pool.threads_num(),
+      None => num_cpus::get(),
+    };
+    match (self.threads, self.max_cpu) {
+      (Some(threads), _) => threads.max(1),
+      (None, Some(limit)) => limit.workers(cpus_num),
+      (None, None) => cpus_num,
+    }
+  }
+
+  /// @brief Amount of SNP lines in a batch for lines of ids_num values: as
+  /// many as fit in batch_bytes (at least one), batch_size if it's not given.
+  pub fn batch_rows(&self, ids_num: usize) -> usize {
+    match self.batch_bytes {
+      Some(bytes) => {
+        let line_bytes = (ids_num.max(1) * std::mem::size_of::<f64>()) as u64;
+        ((bytes / line_bytes) as usize).max(1)
+      }
+      None => self.batch_size,
+    }
+  }
+
+  /// @brief Estimated amount of memory in bytes required to calculate
+  /// ids_num x ids_num Kinship matrix in options.mode.
+  pub fn required_memory(&self, ids_num: usize) -> u64 {
+    let (ids_num, threads_num, batch_size) =
+      (ids_num as u64, self.threads_num() as u64, self.batch_rows(ids_num) as u64);
+    let f64_size = std::mem::size_of::<f64>() as u64;
+    let matrix = ids_num * ids_num * f64_size;
+    // Compensation terms take a matrix of their own.
+    let matrix = match self.compensated {
+      true => matrix * 2,
+      false => matrix,
+    };
+    let batch = ids_num * batch_size * f64_size;
+    match self.mode {
+      ParallelMode::Batches => matrix * (threads_num + 1) + batch * threads_num,
+      // Each worker queue holds up to 2 batches, plus the one being parsed.
+      ParallelMode::ColumnBlocks => matrix + batch * (threads_num * 2 + 1),
+    }
+  }
+
+  /// @brief Same as required_memory for calc_kinship_typed::<T>, which
+  /// always accumulates in ParallelMode::ColumnBlocks.
+  pub fn required_memory_typed<T: KinshipFloat>(&self, ids_num: usize) -> u64 {
+    let (ids_num, threads_num, batch_size) =
+      (ids_num as u64, self.threads_num() as u64, self.batch_rows(ids_num) as u64);
+    let value_size = std::mem::size_of::<T>() as u64;
+    let batch = ids_num * batch_size;
+    // Queued batches are converted to T, the one being parsed is f64.
+    ids_num * ids_num * value_size
+      + batch * threads_num * 2 * value_size
+      + batch * std::mem::size_of::<f64>() as u64
+  }
+
+  /// @brief Largest amount of SNP lines of ids_num values handled by the
+  /// fast path, see fast_path_work.
+  pub(crate) fn fast_path_rows(&self, ids_num: usize) -> usize {
+    if self.kernel.is_some() {
+      return 0;
+    }
+    let ids_num = ids_num.max(1) as u64;
+    let rows = self.fast_path_work.saturating_mul(2) / (ids_num * ids_num);
+    rows.min(FAST_PATH_MAX_ROWS as u64) as usize
+  }
+
+  /// @brief Applies transform and kind to snps, ids_num values per SNP line.
+  ///
+  /// @note Returns Error::Validation if both transform and kind center the
+  /// values, KinshipKind is the way to request a centered or standardized
+  /// matrix.
+  pub(crate) fn prepare_snps(&self, snps: &mut [f64], ids_num: usize) -> std::io::Result<()> {
+    let centered = matches!(self.transform, DosageTransform::Center | DosageTransform::Standardize);
+    if centered && self.kind != KinshipKind::Raw {
+      return Err(
+        Error::Validation(format!(
+          "Transform {:?} can't be combined with Kinship kind {:?}, use the kind alone.",
+          self.transform, self.kind
+        ))
+        .into(),
+      );
+    }
+    self.transform.apply(snps, ids_num);
+    self.kind.apply(snps, ids_num);
+    Ok(())
+  }
+
+  /// @brief Fraction of time each of workers_num workers is allowed to be
+  /// busy under max_cpu (a fraction of all logical cores, whatever the pool),
+  /// None without a limit.
+  pub fn duty_cycle(&self, workers_num: usize) -> Option<f64> {
+    self.max_cpu.map(|limit| limit.workers_duty_cycle(num_cpus::get(), workers_num))
+  }
+
+  pub(crate) fn throttle(&self, workers_num: usize) -> Throttle {
+    match self.max_cpu {
+      Some(limit) => limit.throttle(num_cpus::get(), workers_num),
+      None => Throttle::unlimited(),
+    }
+  }
+
+  pub(crate) fn is_cancelled(&self) -> bool {
+    self.cancel.as_ref().is_some_and(CancellationHandle::is_cancelled)
+  }
+
+  /// @brief Returns Error::Cancelled if the calculation was cancelled.
+  pub(crate) fn check_cancelled(&self) -> std::io::Result<()> {
+    match self.is_cancelled() {
+      true => Err(Error::Cancelled.into()),
+      false => Ok(()),
+    }
+  }
+
+  /// @brief Returns Error::Validation if compensated is set along with
+  /// options it can't be followed with, the calculation accumulating in mode
+  /// (which may differ from self.mode).
+  pub(crate) fn check_compensated(&self, mode: ParallelMode) -> std::io::Result<()> {
+    let conflict = match (self.compensated, mode, &self.kernel) {
+      (false, _, _) => return Ok(()),
+      (true, ParallelMode::ColumnBlocks, _) => "ParallelMode::ColumnBlocks",
+      (true, _, Some(_)) => "a kernel",
+      (true, ParallelMode::Batches, None) => return Ok(()),
+    };
+    Err(Error::Validation(format!("Compensated summation can't be used with {}.", conflict)).into())
+  }
+
+  /// @brief Returns Error::Validation if a batch of ids_num wide SNP lines
+  /// holds no lines (batch_size is 0).
+  pub(crate) fn check_batch_rows(&self, ids_num: usize) -> std::io::Result<()> {
+    if self.batch_rows(ids_num) < 1 {
+      return Err(Error::Validation(String::from("Batch size can't be less than 1.")).into());
+    }
+    Ok(())
+  }
+}
+
+/// @brief Source of parsed SNP lines for the Kinship matrix calculation.
+pub trait SnpSource {
+  /// @brief Amount of values in each SNP line.
+  fn ids_num(&self) -> usize;
+
+  /// @brief Position (in bytes) of the input after the SNP lines read so
+  /// far, None if the source doesn't track it. See KinshipCheckpoint::offset.
+  fn position(&self) -> Option<u64> {
+    None
+  }
+
+  /// @brief Parses SNP lines into buf until it is full (it holds a whole
+  /// number of lines) or the source is exhausted. Row ids of parsed lines are
+  /// pushed to row_ids when given. Returns amount of parsed lines.
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize>;
+}
+
+/// @brief SnpSource over lines of R/qtl2 genotype file.
+pub struct GenoLines<'a, R: BufRead> {
+  lines: &'a mut std::io::Lines<R>,
+  ids_num: usize,
+  codes: CodeTable,
+  allow_na: bool,
+  /// @note Line number of the next line, see at_line.
+  next_line: usize,
+}
+
+impl<'a, R: BufRead> GenoLines<'a, R> {
+  /// @note Lines with missing genotypes are rejected unless allow_na is set.
+  pub fn new(
+    lines: &'a mut std::io::Lines<R>,
+    ids_num: usize,
+    hab_mapper: &'a HashMap<char, f64>,
+    allow_na: bool,
+  ) -> Self {
+    GenoLines {
+      lines,
+      ids_num,
+      codes: CodeTable::new(hab_mapper),
+      allow_na,
+      next_line: 1,
+    }
+  }
+
+  /// @brief Sets the line number (counting from 1) of the first of lines,
+  /// e.g. after the comments and the header line of a file, for
+  /// Error::Parse. Lines are counted from 1 otherwise.
+  pub fn at_line(mut self, line: usize) -> Self {
+    self.next_line = line;
+    self
+  }
+}
+
+impl<'a, R: BufRead> SnpSource for GenoLines<'a, R> {
+  fn ids_num(&self) -> usize {
+    self.ids_num
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    GenoParser::fill_buffer(
+      buf,
+      self.lines,
+      self.ids_num,
+      &self.codes,
+      row_ids,
+      self.allow_na,
+      &mut self.next_line,
+    )
+  }
+}
+
+/// @brief Gets every batch of SNP lines passing through the Kinship matrix
+/// calculation, so other statistics can be gathered in the same pass.
+pub trait BatchObserver {
+  /// @param[in] row_ids ids of the SNP lines in the batch.
+  /// @param[in] snps    parsed SNP lines, ids_num values each.
+  fn observe(&mut self, row_ids: &[String], snps: &[f64], ids_num: usize);
+}
+
+/// @brief Reads the next batch from source and shows it to the observers.
+pub(crate) fn read_observed_batch(
+  source: &mut dyn SnpSource,
+  buf: &mut [f64],
+  observers: &mut [&mut dyn BatchObserver],
+  row_ids: Option<&mut Vec<String>>,
+) -> std::io::Result<usize> {
+  if observers.is_empty() && row_ids.is_none() {
+    return source.read_batch(buf, None);
+  }
+  let mut own_row_ids = Vec::new();
+  let row_ids = row_ids.unwrap_or(&mut own_row_ids);
+  row_ids.clear();
+  let rows = source.read_batch(buf, Some(row_ids))?;
+  let ids_num = source.ids_num();
+  for observer in observers.iter_mut() {
+    observer.observe(row_ids, &buf[..rows * ids_num], ids_num);
+  }
+  Ok(rows)
+}
+
+/// @brief Amount of SNP lines accumulated and sum of their weights, by which
+/// the matrix is normalized (equal to the amount without marker weights).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Accumulated {
+  pub(crate) snps: usize,
+  pub(crate) weight: f64,
+  /// @note SNP lines read from the source, including the dropped ones.
+  pub(crate) lines_read: usize,
+  /// @note Batches read from the source, see KinshipOptions::progress.
+  pub(crate) batches: usize,
+  /// @note No more lines are read after it, see KinshipOptions::time_limit.
+  deadline: Option<Instant>,
+  /// @note Whether reading stopped at the deadline before the end of source.
+  pub(crate) timed_out: bool,
+  /// @note Whether the last batch was short, i.e. source has no more lines.
+  exhausted: bool,
+}
+
+impl Accumulated {
+  /// @brief Starts the clock of options.time_limit.
+  pub(crate) fn start(options: &KinshipOptions) -> Self {
+    Accumulated {
+      deadline: options.time_limit.map(|limit| Instant::now() + limit),
+      ..Accumulated::default()
+    }
+  }
+}
+
+/// @brief Reads the next batch to accumulate into buf: shows it to the
+/// observers, drops monomorphic and rare lines if requested and lines with
+/// missing genotypes under MissingPolicy::SkipMarker (reading further when the
+/// whole batch is dropped), imputes the missing genotypes, applies the transform and
+/// the marker weights. Returns amount of lines left in buf, 0 at the end of
+/// source or at the deadline of accumulated.
+pub(crate) fn read_accumulated_batch(
+  source: &mut dyn SnpSource,
+  buf: &mut [f64],
+  observers: &mut [&mut dyn BatchObserver],
+  options: &KinshipOptions,
+  accumulated: &mut Accumulated,
+) -> std::io::Result<usize> {
+  read_weighted_batch(source, buf, observers, options, accumulated, None)
+}
+
+/// @brief read_accumulated_batch which also pushes the row id and the weight
+/// (its share of Accumulated::weight) of every line left in buf to lines,
+/// e.g. to route the lines to per chromosome accumulators.
+pub(crate) fn read_weighted_batch(
+  source: &mut dyn SnpSource,
+  buf: &mut [f64],
+  observers: &mut [&mut dyn BatchObserver],
+  options: &KinshipOptions,
+  accumulated: &mut Accumulated,
+  lines: Option<&mut Vec<(String, f64)>>,
+) -> std::io::Result<usize> {
+  let ids_num = source.ids_num();
+  let weights = options.marker_weights.as_deref();
+  let markers = options.markers.as_deref();
+  let with_row_ids = weights.is_some() || markers.is_some() || lines.is_some();
+  let mut row_ids = Vec::new();
+  loop {
+    options.check_cancelled()?;
+    // At least a batch is read, and nothing is left after a short one.
+    let reading = accumulated.lines_read == 0 || accumulated.exhausted;
+    let deadline = accumulated.deadline.filter(|_| !reading);
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+      accumulated.timed_out = true;
+      return Ok(0);
+    }
+    let rows = read_observed_batch(source, buf, observers, with_row_ids.then_some(&mut row_ids))?;
+    if rows == 0 {
+      if let Some(progress) = &options.progress {
+        progress.report(accumulated.lines_read, accumulated.batches, true);
+      }
+      return Ok(0);
+    }
+    accumulated.lines_read += rows;
+    accumulated.batches += 1;
+    if let Some(progress) = &options.progress {
+      progress.report(accumulated.lines_read, accumulated.batches, false);
+    }
+    accumulated.exhausted = rows < buf.len() / ids_num.max(1);
+    let selected = match markers {
+      Some(markers) => retain_rows(&mut buf[..rows * ids_num], ids_num, &mut row_ids, |id| {
+        markers.contains(id)
+      }),
+      None => rows,
+    };
+    let kept = match options.drop_monomorphic {
+      true => retain_polymorphic(
+        &mut buf[..selected * ids_num],
+        ids_num,
+        with_row_ids.then_some(&mut row_ids),
+      ),
+      false => selected,
+    };
+    let common = match &options.maf_filter {
+      Some(filter) => retain_lines(
+        &mut buf[..kept * ids_num],
+        ids_num,
+        with_row_ids.then_some(&mut row_ids),
+        |line| filter.keeps(line),
+      ),
+      None => kept,
+    };
+    let complete = retain_lines(
+      &mut buf[..common * ids_num],
+      ids_num,
+      with_row_ids.then_some(&mut row_ids),
+      |line| options.missing.keeps(line),
+    );
+    if let Some(metrics) = &options.metrics {
+      metrics.record_excluded(rows - selected);
+      metrics.record_monomorphic(selected - kept);
+      metrics.record_rare(kept - common);
+      metrics.record_incomplete(common - complete);
+    }
+    let kept = complete;
+    if kept > 0 {
+      // Imputed genotypes count as present ones in the normalization, as in
+      // GEMMA: a kept SNP line counts as a whole.
+      options.missing.impute(&mut buf[..kept * ids_num], ids_num);
+      options.prepare_snps(&mut buf[..kept * ids_num], ids_num)?;
+      accumulated.snps += kept;
+      accumulated.weight += match weights {
+        Some(weights) => weights.apply(&mut buf[..kept * ids_num], ids_num, &row_ids)?,
+        None => kept as f64,
+      };
+      if let Some(lines) = lines {
+        lines.extend(row_ids.drain(..kept).map(|id| {
+          let weight = weights.and_then(|weights| weights.get(&id)).unwrap_or(1.0);
+          (id, weight)
+        }));
+      }
+      if let Some(metrics) = &options.metrics {
+        metrics.record_batch(kept);
+      }
+      return Ok(kept);
+    }
+  }
+}
+
+/// @brief Moves polymorphic lines of snps to its beginning, keeping their
+/// order (and the order of row_ids accordingly). Returns amount of them.
+pub(crate) fn retain_polymorphic(
+  snps: &mut [f64],
+  ids_num: usize,
+  row_ids: Option<&mut Vec<String>>,
+) -> usize {
+  retain_lines(snps, ids_num, row_ids, is_polymorphic)
+}
+
+/// @brief Whether the present genotypes of line aren't all equal.
+pub(crate) fn is_polymorphic(line: &[f64]) -> bool {
+  let mut present = line.iter().filter(|value| !value.is_nan());
+  match present.next() {
+    Some(first) => present.any(|value| value != first),
+    None => false,
+  }
+}
+
+/// @brief Moves lines of snps whose row id keep holds for to its beginning,
+/// keeping their order (and the order of row_ids accordingly). Returns amount
+/// of them.
+fn retain_rows<F: FnMut(&str) -> bool>(
+  snps: &mut [f64],
+  ids_num: usize,
+  row_ids: &mut Vec<String>,
+  mut keep: F,
+) -> usize {
+  let keeps = row_ids.iter().map(|id| keep(id)).collect::<Vec<bool>>();
+  let mut row = 0;
+  retain_lines(snps, ids_num, Some(row_ids), |_| {
+    row += 1;
+    keeps[row - 1]
+  })
+}
+
+/// @brief Moves lines of snps for which keep holds to its beginning, keeping
+/// their order (and the order of row_ids accordingly). Returns amount of them.
+fn retain_lines<F: FnMut(&[f64]) -> bool>(
+  snps: &mut [f64],
+  ids_num: usize,
+  mut row_ids: Option<&mut Vec<String>>,
+  mut keep: F,
+) -> usize {
+  let rows = snps.len() / ids_num.max(1);
+  let mut kept = 0;
+  for row in 0..rows {
+    if keep(&snps[row * ids_num..(row + 1) * ids_num]) {
+      snps.copy_within(row * ids_num..(row + 1) * ids_num, kept * ids_num);
+      if let Some(row_ids) = row_ids.as_mut() {
+        row_ids.swap(row, kept);
+      }
+      kept += 1;
+    }
+  }
+  if let Some(row_ids) = row_ids {
+    row_ids.truncate(kept);
+  }
+  kept
+}
+
+/// @brief Read/result buffer pair passed between the main thread and the
+/// workers.
+pub struct WorkUnit {
+  /// @note Parsed SNPs, `rows` lines of ids_num values each.
+  pub snps: Vec<f64>,
+  /// @note Amount of SNP lines parsed into `snps`.
+  pub rows: usize,
+  /// @note Sequential number of the batch in `snps`.
+  pub batch: usize,
+  /// @note Number of the first SNP line in `snps` (counting from 0).
+  pub first_row: usize,
+  /// @note Partial Kinship matrix calculated from `snps`.
+  pub kinship: Vec<f64>,
+  /// @note Compensation terms of `kinship`, empty unless
+  /// KinshipOptions::compensated is set.
+  pub compensation: Vec<f64>,
+}
+
+/// @brief Calculates Kinship matrix from SNP lines in parallel.
+///
+/// In ParallelMode::Batches each logical thread gets a WorkUnit: a read buffer
+/// with parsed rows and a result buffer for the batch multiplication, so the
+/// shared Kinship matrix is not blocked while the calculation is in process.
+/// Once the multiplication is finished, the worker locks shared Kinship matrix
+/// and merges the results simultaneously nullifying result buffer, then sends
+/// the WorkUnit back to the main thread. Main thread works in a loop: takes a
+/// freed WorkUnit, parses lines into it and dispatches it to the workers. If
+/// all units are busy, it waits until one of them is sent back.
+///
+/// In ParallelMode::ColumnBlocks the result matrix is split into column blocks
+/// with (roughly) equal amount of work, each worker owns a block and updates
+/// it directly from every parsed batch, so there is nothing to merge.
+///
+/// @note Returns normalized and mirrored (full) Kinship matrix. When
+/// options.orientation is GramOrientation::SnpsBySnps the matrix is
+/// snps_num x snps_num, see calc_gram_snps.
+pub fn calc_kinship_parallel<R: BufRead>(
+  lines: &mut std::io::Lines<R>,
+  ids_num: usize,
+  hab_mapper: &HashMap<char, f64>,
+  options: &KinshipOptions,
+) -> std::io::Result<Vec<f64>> {
+  options.check_batch_rows(ids_num)?;
+  if options.orientation == GramOrientation::SnpsBySnps {
+    return calc_gram_snps(lines, ids_num, hab_mapper, options).map(|(_, res)| res);
+  }
+  calc_kinship_observed(
+    &mut GenoLines::new(lines, ids_num, hab_mapper, options.missing.allows_na()),
+    options,
+    &mut [],
+  )
+}
+
+/// @brief Same as calc_kinship_parallel, also returns the identity-by-state
+/// matrix (see stats::IbsAccumulator) gathered in the same pass.
+///
+/// @note IBS covers the SNP lines the Kinship matrix does (options.markers
+/// and the other filters of lines apply to both), see
+/// IbsAccumulator::with_options. Missing genotypes (allowed by
+/// options.missing) are left out of the pairs they belong to, they aren't
+/// imputed. Returns Error::Validation for GramOrientation::SnpsBySnps.
+pub fn calc_kinship_parallel_ibs<R: BufRead>(
+  lines: &mut std::io::Lines<R>,
+  ids_num: usize,
+  hab_mapper: &HashMap<char, f64>,
+  options: &KinshipOptions,
+) -> std::io::Result<(Vec<f64>, Vec<f64>)> {
+  if options.orientation == GramOrientation::SnpsBySnps {
+    return Err(
+      Error::Validation(String::from("IBS is calculated along with IdsByIds Kinship only."))
+        .into(),
+    );
+  }
+  let mut ibs =
+    IbsAccumulator::new(DosageScale::from_mapper(hab_mapper), ids_num).with_options(options);
+  let kinship = calc_kinship_observed(
+    &mut GenoLines::new(lines, ids_num, hab_mapper, options.missing.allows_na()),
+    options,
+    &mut [&mut ibs],
+  )?;
+  Ok((kinship, ibs.finish()))
+}
+
+/// @brief Calculates Kinship matrix (GramOrientation::IdsByIds) from any
+/// SnpSource, showing every batch to the observers.
+pub fn calc_kinship_observed(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  observers: &mut [&mut dyn BatchObserver],
+) -> std::io::Result<Vec<f64>> {
+  let ids_num = source.ids_num();
+  let (mut res, accumulated) = accumulate_kinship(source, options, observers)?;
+  if !accumulated.timed_out {
+    check_snps_num(accumulated.snps, ids_num)?;
+  }
+  check_weight(accumulated.snps, accumulated.weight)?;
+  finalize_kinship_by(&mut res, ids_num, accumulated.weight);
+  Ok(res)
+}
+
+/// @brief Same as calc_kinship_observed in the floating point type T, e.g.
+/// calc_kinship_typed::<f32> takes half the memory: the matrix and the queued
+/// batches are held as T, while SNP lines are parsed and transformed as f64 a
+/// batch at a time.
+///
+/// @note Accumulates in ParallelMode::ColumnBlocks without the fast path,
+/// returns Error::Validation for ParallelMode::Batches, options.kernel
+/// (kernels multiply f64) and GramOrientation::SnpsBySnps.
+pub fn calc_kinship_typed<T: KinshipFloat>(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+) -> std::io::Result<Vec<T>> {
+  let ids_num = source.ids_num();
+  options.check_batch_rows(ids_num)?;
+  let unsupported = match (options.mode, &options.kernel, options.orientation) {
+    (ParallelMode::Batches, _, _) => Some("supports ParallelMode::ColumnBlocks only"),
+    (_, Some(_), _) => Some("doesn't take a kernel, kernels multiply f64"),
+    (_, _, GramOrientation::SnpsBySnps) => Some("supports GramOrientation::IdsByIds only"),
+    _ => None,
+  };
+  if let Some(msg) = unsupported {
+    return Err(Error::Validation(format!("calc_kinship_typed {}.", msg)).into());
+  }
+  ensure_memory(options.required_memory_typed::<T>(ids_num))?;
+  let mut res = vec![T::default(); ids_num * ids_num];
+  let accumulated = accumulate_column_blocks(source, options, &mut [], &mut res)?;
+  if !accumulated.timed_out {
+    check_snps_num(accumulated.snps, ids_num)?;
+  }
+  check_weight(accumulated.snps, accumulated.weight)?;
+  finalize_kinship_by(&mut res, ids_num, accumulated.weight);
+  Ok(res)
+}
+
+/// @brief Accumulates upper part of the (not normalized) Kinship matrix of
+/// source: on the fast path or in options.mode.
+pub(crate) fn accumulate_kinship(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  observers: &mut [&mut dyn BatchObserver],
+) -> std::io::Result<(Vec<f64>, Accumulated)> {
+  let ids_num = source.ids_num();
+  options.check_batch_rows(ids_num)?;
+  options.check_compensated(options.mode)?;
+  let source = &mut Prefetched::new(source, options.fast_path_rows(ids_num))?;
+  if source.is_complete() {
+    return accumulate_in_memory(source, options, observers);
+  }
+  ensure_memory(options.required_memory(ids_num))?;
+  match options.mode {
+    ParallelMode::Batches => accumulate_batches(source, options, observers),
+    ParallelMode::ColumnBlocks => {
+      let mut res = vec![0.0; ids_num * ids_num];
+      let accumulated = accumulate_column_blocks(source, options, observers, &mut res)?;
+      Ok((res, accumulated))
+    }
+  }
+}
+
+/// @brief Fast path of accumulate_kinship: multiplies all SNP lines of
+/// source at once on the calling thread.
+fn accumulate_in_memory(
+  source: &mut Prefetched,
+  options: &KinshipOptions,
+  observers: &mut [&mut dyn BatchObserver],
+) -> std::io::Result<(Vec<f64>, Accumulated)> {
+  let ids_num = source.ids_num();
+  let mut snps = vec![0.0; source.rows * ids_num];
+  let mut accumulated = Accumulated::start(options);
+  loop {
+    let buf = &mut snps[accumulated.snps * ids_num..];
+    if read_accumulated_batch(source, buf, observers, options, &mut accumulated)? == 0 {
+      break;
+    }
+  }
+  let mut res = vec![0.0; ids_num * ids_num];
+  let snps = &snps[..accumulated.snps * ids_num];
+  match options.compensated {
+    true => {
+      let mut compensation = vec![0.0; ids_num * ids_num];
+      calc_partial_kinship_compensated(snps, &mut res, &mut compensation, ids_num);
+      apply_compensation(&mut res, &compensation);
+    }
+    false => calc_partial_kinship_tiled(snps, &mut res, ids_num),
+  }
+  options.check_cancelled()?;
+  Ok((res, accumulated))
+}
+
+/// @brief SnpSource which returns SNP lines read ahead from source (to tell
+/// whether it's small enough for the fast path) before the rest of them.
+struct Prefetched<'a> {
+  source: &'a mut dyn SnpSource,
+  snps: Vec<f64>,
+  row_ids: Vec<String>,
+  rows: usize,
+  pos: usize,
+  complete: bool,
+}
+
+impl<'a> Prefetched<'a> {
+  /// @brief Reads up to max_rows + 1 lines of source.
+  fn new(source: &'a mut dyn SnpSource, max_rows: usize) -> std::io::Result<Self> {
+    const CHUNK_ROWS: usize = 256;
+    let ids_num = source.ids_num();
+    let (mut snps, mut row_ids) = (Vec::new(), Vec::new());
+    let (mut rows, mut complete) = (0, false);
+    // The buffer grows with the input, so a long one doesn't cost the whole
+    // max_rows of memory.
+    while max_rows > 0 && rows <= max_rows {
+      let chunk = CHUNK_ROWS.min(max_rows + 1 - rows);
+      snps.resize((rows + chunk) * ids_num, 0.0);
+      let read = source.read_batch(&mut snps[rows * ids_num..], Some(&mut row_ids))?;
+      rows += read;
+      if read == 0 {
+        complete = true;
+        break;
+      }
+    }
+    Ok(Prefetched {
+      source,
+      snps,
+      row_ids,
+      rows,
+      pos: 0,
+      complete,
+    })
+  }
+
+  /// @brief Whether source has no lines beyond the prefetched ones.
+  fn is_complete(&self) -> bool {
+    self.complete
+  }
+}
+
+impl<'a> SnpSource for Prefetched<'a> {
+  fn ids_num(&self) -> usize {
+    self.source.ids_num()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    if self.pos == self.rows {
+      return self.source.read_batch(buf, row_ids);
+    }
+    let ids_num = self.source.ids_num();
+    let rows = (buf.len() / ids_num.max(1)).min(self.rows - self.pos);
+    let snps = &self.snps[self.pos * ids_num..(self.pos + rows) * ids_num];
+    buf[..rows * ids_num].copy_from_slice(snps);
+    if let Some(row_ids) = row_ids.as_deref_mut() {
+      row_ids.extend_from_slice(&self.row_ids[self.pos..self.pos + rows]);
+    }
+    self.pos += rows;
+    // The rest of buf is filled from source, so a short batch still means
+    // the end of it.
+    match self.complete {
+      true => Ok(rows),
+      false => Ok(rows + self.source.read_batch(&mut buf[rows * ids_num..], row_ids)?),
+    }
+  }
+}
+
+/// @brief Returns Error::Validation if fewer SNP lines than ids were read.
+pub(crate) fn check_snps_num(total_snps_read: usize, ids_num: usize) -> std::io::Result<()> {
+  if total_snps_read < ids_num {
+    return Err(
+      Error::Validation(format!(
+        "Amount of SNPS (lines in file - (1+comments_lines_count)) should be \
+         greater or equal to amount of ids \
+         (amount of markers). SNP number: {}, IDS number: {}",
+        total_snps_read, ids_num
+      ))
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+/// @brief Returns Error::Validation if snps SNP lines were read, but the
+/// sum of their marker weights, the divisor of the matrix, isn't positive.
+pub(crate) fn check_weight(snps: usize, weight: f64) -> std::io::Result<()> {
+  if snps > 0 && weight <= 0.0 {
+    return Err(
+      Error::Validation(format!(
+        "Marker weights of {} SNP lines sum to {}, the Kinship matrix can't be normalized.",
+        snps, weight
+      ))
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+/// @brief Side of the square tile finalize_kinship processes at once.
+const FINALIZE_TILE: usize = 256;
+
+/// @brief Mirrors Kinship matrix, since only the upper part was calculated
+/// (the Kinship matrix is symmetrical because it's formed from it's transpose
+/// times itself), and normalizes it by the amount of SNPs.
+///
+/// @note The matrix is processed tile by tile, so both the upper element and
+/// its mirror are read from a few nearby rows. This keeps the pass cheap when
+/// the matrix is memory-mapped from disk.
+pub(crate) fn finalize_kinship(res: &mut [f64], ids_num: usize, total_snps_read: usize) {
+  finalize_kinship_by(res, ids_num, total_snps_read as f64);
+}
+
+/// @brief Same as finalize_kinship with an arbitrary divisor (e.g. sum of
+/// marker weights).
+pub(crate) fn finalize_kinship_by<T: KinshipFloat>(res: &mut [T], ids_num: usize, divisor: f64) {
+  let (row_length, divisor) = (ids_num, T::from_f64(divisor));
+  for i_tile in (0..ids_num).step_by(FINALIZE_TILE) {
+    for j_tile in (0..=i_tile).step_by(FINALIZE_TILE) {
+      for i in i_tile..(i_tile + FINALIZE_TILE).min(ids_num) {
+        for j in j_tile..(j_tile + FINALIZE_TILE).min(i + 1) {
+          res[j * row_length + i] /= divisor;
+          res[i * row_length + j] = res[j * row_length + i];
+        }
+      }
+    }
+  }
+}
+
+/// @brief Accumulates upper part of Kinship matrix, each worker processes
+/// whole batches. Returns the matrix and amount of processed SNP lines.
+fn accumulate_batches(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  observers: &mut [&mut dyn BatchObserver],
+) -> std::io::Result<(Vec<f64>, Accumulated)> {
+  let ids_num = source.ids_num();
+  let (batch_size, threads_num) = (options.batch_rows(ids_num), options.threads_num());
+  let throttle = options.throttle(threads_num);
+  let kernel = options
+    .kernel
+    .clone()
+    .unwrap_or_else(|| Arc::new(CpuKernel));
+  let compensated = options.compensated;
+  // Batches stay on the device of the kernel and the matrix is copied back
+  // once. Without it every batch goes through update, failing to start is
+  // left to the updates to report.
+  let accumulation = match compensated {
+    true => None,
+    false => kernel.start(ids_num).ok().flatten().map(|started| Arc::new(Mutex::new(started))),
+  };
+  let compensation_len = match compensated {
+    true => ids_num * ids_num,
+    false => 0,
+  };
+  // The matrix and its compensation terms.
+  let common_kinship_matrix = Arc::new(Mutex::new((
+    vec![0.0; ids_num * ids_num],
+    vec![0.0; compensation_len],
+  )));
+  // The first failure of a worker, once set the remaining work is cancelled.
+  let failure = Arc::new(Mutex::new(None::<Error>));
+  let cancelled = Arc::new(AtomicBool::new(false));
+  let merge_order = Arc::new(MergeOrder::default());
+  let (free_sender, free_receiver) = channel::<WorkUnit>();
+  let (work_sender, work_receiver) = channel::<WorkUnit>();
+  // Workers share a single queue, the one which is free takes the next unit.
+  let work_receiver = Arc::new(Mutex::new(work_receiver));
+  for _ in 0..threads_num {
+    free_sender
+      .send(WorkUnit {
+        snps: vec![0.0; ids_num * batch_size],
+        rows: 0,
+        batch: 0,
+        first_row: 0,
+        kinship: vec![0.0; ids_num * ids_num],
+        compensation: vec![0.0; compensation_len],
+      })
+      .unwrap();
+  }
+
+  let mut workers = Vec::<Worker>::new();
+  for _ in 0..threads_num {
+    let (work_receiver, free_sender, res_matrix, kernel, metrics) = (
+      work_receiver.clone(),
+      free_sender.clone(),
+      common_kinship_matrix.clone(),
+      kernel.clone(),
+      options.metrics.clone(),
+    );
+    let accumulation = accumulation.clone();
+    let (failure, cancelled) = (failure.clone(), cancelled.clone());
+    let (cancel, deterministic) = (options.cancel.clone(), options.deterministic);
+    let merge_order = merge_order.clone();
+    workers.push(spawn_worker(options.pool.as_deref(), move || loop {
+      let received = lock(&work_receiver).recv();
+      let mut unit = match received {
+        Ok(unit) => unit,
+        // Main thread finished dispatching.
+        Err(_) => break,
+      };
+      let cancel_requested = cancel.as_ref().is_some_and(CancellationHandle::is_cancelled);
+      if cancelled.load(Ordering::SeqCst) || cancel_requested {
+        break;
+      }
+      let started = Instant::now();
+      let processed = catch_unwind(AssertUnwindSafe(|| {
+        let snps = &unit.snps[..unit.rows * ids_num];
+        let stopped = || {
+          cancelled.load(Ordering::SeqCst)
+            || cancel.as_ref().is_some_and(CancellationHandle::is_cancelled)
+        };
+        // Ok(true) when the batch is accumulated by the kernel.
+        let updated = match &accumulation {
+          Some(accumulation) => {
+            // The device matrix is accumulated in order too.
+            if deterministic && !merge_order.wait(unit.batch, &stopped) {
+              return;
+            }
+            lock(accumulation).update(snps).map(|()| true)
+          }
+          None if compensated => {
+            let (kinship, compensation) = (&mut unit.kinship, &mut unit.compensation);
+            calc_partial_kinship_compensated(snps, kinship, compensation, ids_num);
+            Ok(false)
+          }
+          None => kernel.update(snps, &mut unit.kinship, ids_num).map(|()| false),
+        };
+        match updated {
+          Ok(true) => {
+            if deterministic {
+              merge_order.done(unit.batch);
+            }
+            return;
+          }
+          Ok(false) => {}
+          Err(e) => {
+            // The failed kernel may have left the buffer half updated.
+            unit.kinship.iter_mut().for_each(|elem| *elem = 0.0);
+            calc_partial_kinship_tiled(snps, &mut unit.kinship, ids_num);
+            if let Some(metrics) = &metrics {
+              metrics.record_fallback(KernelFallback {
+                kernel: kernel.name(),
+                batch: unit.batch,
+                reason: e.to_string(),
+              });
+            }
+          }
+        }
+        if deterministic && !merge_order.wait(unit.batch, &stopped) {
+          return;
+        }
+        let mut res_matrix = lock(&res_matrix);
+        let (common_matrix, common_compensation) = &mut *res_matrix;
+        match compensated {
+          true => {
+            let buf = unit.kinship.iter_mut().zip(unit.compensation.iter_mut());
+            let common = common_matrix.iter_mut().zip(common_compensation.iter_mut());
+            for ((buf_elem, buf_comp), (common_elem, common_comp)) in buf.zip(common) {
+              compensated_add(common_elem, common_comp, *buf_elem - *buf_comp);
+              *buf_elem = 0.0;
+              *buf_comp = 0.0;
+            }
+          }
+          false => {
+            for (buf_elem, common_matrix_elem) in unit.kinship.iter_mut().zip(common_matrix) {
+              *common_matrix_elem += *buf_elem;
+              *buf_elem = 0.0;
+            }
+          }
+        }
+        drop(res_matrix);
+        if deterministic {
+          merge_order.done(unit.batch);
+        }
+      }));
+      if let Err(payload) = processed {
+        let rows = unit.first_row..unit.first_row + unit.rows;
+        lock(&failure).get_or_insert(worker_panic(unit.batch, rows, payload));
+        cancelled.store(true, Ordering::SeqCst);
+        break;
+      }
+      throttle.pause(started.elapsed());
+      // Main thread may have already stopped waiting for free units.
+      let _ = free_sender.send(unit);
+    }));
+  }
+  drop(free_sender);
+
+  let mut accumulated = Accumulated::start(options);
+  let mut batches_read: usize = 0;
+  let mut res = Ok(());
+  while !cancelled.load(Ordering::SeqCst) {
+    let mut unit = match free_receiver.recv() {
+      Ok(unit) => unit,
+      // All workers have failed.
+      Err(_) => break,
+    };
+    let first_row = accumulated.snps;
+    match read_accumulated_batch(source, &mut unit.snps, observers, options, &mut accumulated) {
+      Ok(0) => break,
+      Ok(n) => {
+        unit.rows = n;
+        unit.batch = batches_read;
+        unit.first_row = first_row;
+        batches_read += 1;
+      }
+      Err(e) => {
+        res = Err(e);
+        break;
+      }
+    }
+    if work_sender.send(unit).is_err() {
+      break;
+    }
+  }
+  drop(work_sender);
+
+  for worker in workers {
+    if worker.join().is_err() {
+      lock(&failure).get_or_insert(Error::Processing {
+        batch: None,
+        msg: String::from("worker thread panicked"),
+      });
+    }
+  }
+  if let Some(e) = lock(&failure).take() {
+    return Err(e.into());
+  }
+  res?;
+  options.check_cancelled()?;
+
+  let (mut res_matrix, compensation) = Arc::try_unwrap(common_kinship_matrix)
+    .expect("Arc uwrapping failed. Kinship matrix is not accessible.")
+    .into_inner()
+    .expect("Mutex uwrapping failed. Kinship matrix is not accessible.");
+  if let Some(accumulation) = accumulation {
+    let accumulation = Arc::try_unwrap(accumulation)
+      .unwrap_or_else(|_| panic!("Kernel accumulation is still shared after the workers."))
+      .into_inner()
+      .unwrap_or_else(|e| e.into_inner());
+    accumulation.finish(&mut res_matrix)?;
+  }
+  apply_compensation(&mut res_matrix, &compensation);
+  Ok((res_matrix, accumulated))
+}
+
+/// @brief Number of the next batch to merge into the matrix, see
+/// KinshipOptions::deterministic.
+#[derive(Default)]
+struct MergeOrder {
+  next: Mutex<usize>,
+  turn: Condvar,
+}
+
+impl MergeOrder {
+  /// @brief Waits until batch is the next to merge. Returns false if the
+  /// calculation is stopped first, the earlier batches may never be merged
+  /// then.
+  fn wait(&self, batch: usize, stopped: &dyn Fn() -> bool) -> bool {
+    let mut next = lock(&self.next);
+    while *next != batch {
+      if stopped() {
+        return false;
+      }
+      // Cancellation doesn't notify, so the flags are polled.
+      let waited = self.turn.wait_timeout(next, Duration::from_millis(10));
+      next = waited.unwrap_or_else(|e| e.into_inner()).0;
+    }
+    true
+  }
+
+  /// @brief Passes the turn to the batch after batch.
+  fn done(&self, batch: usize) {
+    *lock(&self.next) = batch + 1;
+    self.turn.notify_all();
+  }
+}
+
+/// @brief Locks mutex shared with workers. A worker panic is reported by
+/// worker_panic, so poisoning is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// @brief Converts panic of a worker processing batch (SNP lines rows) into
+/// Error::Processing.
+fn worker_panic(batch: usize, rows: Range<usize>, payload: Box<dyn Any + Send>) -> Error {
+  let reason = match payload.downcast::<String>() {
+    Ok(msg) => *msg,
+    Err(payload) => match payload.downcast::<&str>() {
+      Ok(msg) => String::from(*msg),
+      Err(_) => String::from("unknown panic"),
+    },
+  };
+  Error::Processing {
+    batch: Some(batch),
+    msg: format!(
+      "worker panicked on SNP lines {}..{}: {}",
+      rows.start, rows.end, reason
+    ),
+  }
+}
+
+/// @brief Accumulates upper part of Kinship matrix into res_matrix (which
+/// must be ids_num x ids_num), each worker owns a block of the matrix columns.
+/// Returns amount of processed SNP lines.
+///
+/// @note Batches are parsed as f64 and converted to T before they are queued.
+pub(crate) fn accumulate_column_blocks<T: KinshipFloat>(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  observers: &mut [&mut dyn BatchObserver],
+  res_matrix: &mut [T],
+) -> std::io::Result<Accumulated> {
+  let ids_num = source.ids_num();
+  options.check_compensated(ParallelMode::ColumnBlocks)?;
+  let blocks = column_blocks(ids_num, options.threads_num());
+  let (batch_size, throttle) = (options.batch_rows(ids_num), options.throttle(blocks.len()));
+  let mut accumulated = Accumulated::start(options);
+  let failure = Mutex::new(None::<Error>);
+
+  thread::scope(|scope| -> std::io::Result<()> {
+    let mut batch_senders = Vec::new();
+    let mut rest: &mut [T] = res_matrix;
+    for cols in blocks {
+      // Column j of the upper part is stored at [j * ids_num, (j + 1) * ids_num).
+      let (block, tail) = rest.split_at_mut(cols.len() * ids_num);
+      rest = tail;
+      // Bounded queue keeps the amount of parsed batches in memory limited
+      // when the parsing is faster than the multiplication.
+      let (batch_sender, batch_receiver) = sync_channel::<(usize, Range<usize>, Arc<Vec<T>>)>(2);
+      batch_senders.push(batch_sender);
+      let failure = &failure;
+      scope.spawn(move || {
+        for (batch_num, rows, batch) in batch_receiver {
+          if options.is_cancelled() {
+            // Dropping the receiver makes the main thread stop dispatching.
+            break;
+          }
+          let started = Instant::now();
+          let processed = catch_unwind(AssertUnwindSafe(|| {
+            calc_partial_kinship_block(&batch, block, ids_num, cols.clone())
+          }));
+          if let Err(payload) = processed {
+            // Dropping the receiver makes the main thread stop dispatching.
+            lock(failure).get_or_insert(worker_panic(batch_num, rows, payload));
+            break;
+          }
+          throttle.pause(started.elapsed());
+        }
+      });
+    }
+
+    let mut batches_read = 0;
+    loop {
+      let mut batch = vec![0.0; ids_num * batch_size];
+      let first_row = accumulated.snps;
+      match read_accumulated_batch(source, &mut batch, observers, options, &mut accumulated)? {
+        0 => break,
+        n => batch.truncate(n * ids_num),
+      }
+      let batch = Arc::new(T::from_batch(batch));
+      let sent = batch_senders.iter().all(|batch_sender| {
+        batch_sender
+          .send((batches_read, first_row..accumulated.snps, batch.clone()))
+          .is_ok()
+      });
+      if !sent {
+        break;
+      }
+      batches_read += 1;
+    }
+    // Dropping senders on return makes workers leave their loops, the scope
+    // joins them.
+    Ok(())
+  })?;
+
+  if let Some(e) = lock(&failure).take() {
+    return Err(e.into());
+  }
+  options.check_cancelled()?;
+  Ok(accumulated)
+}
+
+/// @brief Calculates G * G.T product of SNP lines (GramOrientation::SnpsBySnps).
+/// Returns row ids of the SNP lines, which label both dimensions of the
+/// result, and the normalized full matrix.
+pub fn calc_gram_snps<R: BufRead>(
+  lines: &mut std::io::Lines<R>,
+  ids_num: usize,
+  hab_mapper: &HashMap<char, f64>,
+  options: &KinshipOptions,
+) -> std::io::Result<(Vec<String>, Vec<f64>)> {
+  calc_gram_snps_from(&mut GenoLines::new(lines, ids_num, hab_mapper, true), options)
+}
+
+/// @brief Same as calc_gram_snps for any SnpSource.
+pub fn calc_gram_snps_from(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+) -> std::io::Result<(Vec<String>, Vec<f64>)> {
+  let ids_num = source.ids_num();
+  let batch_rows = options.batch_rows(ids_num).max(1);
+  let mut snp_ids = Vec::<String>::new();
+  let mut snps = Vec::<f64>::new();
+  loop {
+    let offset = snps.len();
+    snps.resize(offset + batch_rows * ids_num, 0.0);
+    let rows = source.read_batch(&mut snps[offset..], Some(&mut snp_ids))?;
+    snps.truncate(offset + rows * ids_num);
+    if rows < batch_rows {
+      break;
+    }
+  }
+  Ok((snp_ids, calc_gram_of(snps, ids_num, options)?))
+}
+
+/// @brief Same as calc_gram_snps for SNP lines in memory, ids_num values per
+/// line. Returns the normalized full matrix.
+pub(crate) fn calc_gram_of(
+  mut snps: Vec<f64>,
+  ids_num: usize,
+  options: &KinshipOptions,
+) -> std::io::Result<Vec<f64>> {
+  options.prepare_snps(&mut snps, ids_num)?;
+  let snps_num = snps.len() / ids_num.max(1);
+  if let Some(metrics) = &options.metrics {
+    metrics.record_batch(snps_num);
+  }
+
+  // G * G.T is G.T.T * G.T, so the same kernel is used on transposed data,
+  // where SNP lines become columns.
+  let mut transposed = vec![0.0; snps.len()];
+  for snp in 0..snps_num {
+    for id in 0..ids_num {
+      transposed[id * snps_num + snp] = snps[snp * ids_num + id];
+    }
+  }
+  let mut res = vec![0.0; snps_num * snps_num];
+  calc_partial_kinship_threaded(&transposed, &mut res, snps_num, options.threads_num());
+  finalize_kinship(&mut res, snps_num, ids_num);
+  Ok(res)
+}
+
+/// @brief Same as calc_partial_kinship for in-memory data, with the columns of
+/// partial_matrix split between threads_num threads.
+pub fn calc_partial_kinship_threaded<T: KinshipFloat>(
+  snps: &[T],
+  partial_matrix: &mut [T],
+  ids_num: usize,
+  threads_num: usize,
+) {
+  thread::scope(|scope| {
+    let mut rest: &mut [T] = partial_matrix;
+    for cols in column_blocks(ids_num, threads_num) {
+      let (block, tail) = rest.split_at_mut(cols.len() * ids_num);
+      rest = tail;
+      scope.spawn(move || calc_partial_kinship_block(snps, block, ids_num, cols));
+    }
+  });
+}
+
+/// @brief Splits columns of the upper part of ids_num x ids_num matrix into at
+/// most blocks_num ranges with roughly equal amount of elements.
+///
+/// @note Column j of the upper part contains ids_num - j elements.
+pub(crate) fn column_blocks(ids_num: usize, blocks_num: usize) -> Vec<Range<usize>> {
+  let blocks_num = blocks_num.clamp(1, ids_num.max(1));
+  let total_work = ids_num * (ids_num + 1) / 2;
+  let mut blocks = Vec::with_capacity(blocks_num);
+  let mut block_start = 0;
+  let mut work = 0;
+  for j in 0..ids_num {
+    work += ids_num - j;
+    if j + 1 < ids_num
+      && blocks.len() + 1 < blocks_num
+      && work * blocks_num >= total_work * (blocks.len() + 1)
+    {
+      blocks.push(block_start..j + 1);
+      block_start = j + 1;
+    }
+  }
+  blocks.push(block_start..ids_num);
+  blocks
+}
+
+/// @brief SNP lines per tile of calc_partial_kinship_block.
+const TILE_ROWS: usize = 64;
+/// @brief Elements of a SNP line per tile of calc_partial_kinship_block, so a
+/// tile (TILE_ROWS x TILE_IDS values, 128KiB) stays in L2 cache while every
+/// column of the matrix is updated with it.
+const TILE_IDS: usize = 256;
+
+/// @brief Same as calc_partial_kinship, blocked and cache-tiled. Default
+/// update of CpuKernel.
+pub fn calc_partial_kinship_tiled<T: KinshipFloat>(
+  snps: &[T],
+  partial_matrix: &mut [T],
+  ids_num: usize,
+) {
+  calc_partial_kinship_block(snps, partial_matrix, ids_num, 0..ids_num)
+}
+
+/// @brief Same as calc_partial_kinship, but updates only the columns `cols`
+/// of the upper part. Column j is stored in the block at
+/// [(j - cols.start) * ids_num, (j - cols.start + 1) * ids_num).
+///
+/// @note SNP lines are multiplied tile by tile (TILE_ROWS lines x TILE_IDS
+/// elements) and two columns by four lines at a time, so the inner loop runs
+/// over contiguous slices, reuses the loaded values and is vectorized by the
+/// compiler. The order of the additions differs from calc_partial_kinship, so
+/// the results may differ in the last bits.
+pub fn calc_partial_kinship_block<T: KinshipFloat>(
+  snps: &[T],
+  block: &mut [T],
+  ids_num: usize,
+  cols: Range<usize>,
+) {
+  let n = ids_num;
+  if n == 0 {
+    return;
+  }
+  let k = snps.len() / n;
+  for rows_start in (0..k).step_by(TILE_ROWS) {
+    let rows = &snps[rows_start * n..(rows_start + TILE_ROWS).min(k) * n];
+    // Elements i >= j of column j, so tiles left of cols.start are skipped.
+    for ids_start in (cols.start..n).step_by(TILE_IDS) {
+      let ids = ids_start..(ids_start + TILE_IDS).min(n);
+      let cols_end = cols.end.min(ids.end);
+      for j in (cols.start..cols_end).step_by(2) {
+        let col = &mut block[(j - cols.start) * n..];
+        if j + 1 == cols_end {
+          update_column(rows, n, j, &mut col[..n], ids.start.max(j)..ids.end);
+          continue;
+        }
+        let (first, second) = col[..2 * n].split_at_mut(n);
+        // Element j of the first column is above the second one.
+        if j >= ids.start {
+          update_column(rows, n, j, first, j..j + 1);
+        }
+        update_column_pair(rows, n, j, first, second, ids.start.max(j + 1)..ids.end);
+      }
+    }
+  }
+}
+
+/// @brief Same as calc_partial_kinship_tiled with compensated (Kahan)
+/// summation, see KinshipOptions::compensated. The rounding error of every
+/// element is kept in the same element of compensation (subtracted from the
+/// element, the sum is more accurate, see apply_compensation), so both carry
+/// on from batch to batch.
+///
+/// @note SNP lines are added one by one in order, the compiler can't
+/// reassociate the compensated additions, so it's about twice as slow.
+pub fn calc_partial_kinship_compensated(
+  snps: &[f64],
+  partial_matrix: &mut [f64],
+  compensation: &mut [f64],
+  ids_num: usize,
+) {
+  let n = ids_num;
+  if n == 0 {
+    return;
+  }
+  for line in snps.chunks_exact(n) {
+    for j in 0..n {
+      let a = line[j];
+      let col = &mut partial_matrix[j * n + j..(j + 1) * n];
+      let col_compensation = &mut compensation[j * n + j..(j + 1) * n];
+      for ((elem, elem_compensation), x) in col.iter_mut().zip(col_compensation).zip(&line[j..]) {
+        compensated_add(elem, elem_compensation, a * *x);
+      }
+    }
+  }
+}
+
+/// @brief Adds value to sum, keeping the rounding error in compensation.
+#[inline]
+fn compensated_add(sum: &mut f64, compensation: &mut f64, value: f64) {
+  let value = value - *compensation;
+  let total = *sum + value;
+  *compensation = (total - *sum) - value;
+  *sum = total;
+}
+
+/// @brief Subtracts the compensation terms (if any) from the matrix.
+fn apply_compensation(matrix: &mut [f64], compensation: &[f64]) {
+  for (elem, elem_compensation) in matrix.iter_mut().zip(compensation) {
+    *elem -= *elem_compensation;
+  }
+}
+
+/// @brief Adds products of element j of rows (SNP lines of length n) by
+/// their elements ids to the same elements of col.
+fn update_column<T: KinshipFloat>(
+  rows: &[T],
+  n: usize,
+  j: usize,
+  col: &mut [T],
+  ids: Range<usize>,
+) {
+  let col = &mut col[ids.clone()];
+  let mut lines = rows.chunks_exact(4 * n);
+  for quad in &mut lines {
+    let (l0, l1, l2, l3) = (&quad[..n], &quad[n..2 * n], &quad[2 * n..3 * n], &quad[3 * n..]);
+    let (a0, a1, a2, a3) = (l0[j], l1[j], l2[j], l3[j]);
+    let x = (&l0[ids.clone()], &l1[ids.clone()], &l2[ids.clone()], &l3[ids.clone()]);
+    for ((((elem, x0), x1), x2), x3) in col.iter_mut().zip(x.0).zip(x.1).zip(x.2).zip(x.3) {
+      *elem += a0 * *x0 + a1 * *x1 + a2 * *x2 + a3 * *x3;
+    }
+  }
+  for line in lines.remainder().chunks_exact(n) {
+    let a = line[j];
+    for (elem, x) in col.iter_mut().zip(&line[ids.clone()]) {
+      *elem += a * *x;
+    }
+  }
+}
+
+/// @brief update_column of columns j (first) and j + 1 (second) at once.
+fn update_column_pair<T: KinshipFloat>(
+  rows: &[T],
+  n: usize,
+  j: usize,
+  first: &mut [T],
+  second: &mut [T],
+  ids: Range<usize>,
+) {
+  let (first, second) = (&mut first[ids.clone()], &mut second[ids.clone()]);
+  let mut lines = rows.chunks_exact(4 * n);
+  for quad in &mut lines {
+    let (l0, l1, l2, l3) = (&quad[..n], &quad[n..2 * n], &quad[2 * n..3 * n], &quad[3 * n..]);
+    let (a0, a1, a2, a3) = (l0[j], l1[j], l2[j], l3[j]);
+    let (b0, b1, b2, b3) = (l0[j + 1], l1[j + 1], l2[j + 1], l3[j + 1]);
+    let x = (&l0[ids.clone()], &l1[ids.clone()], &l2[ids.clone()], &l3[ids.clone()]);
+    let elems = first.iter_mut().zip(second.iter_mut());
+    for (((((elem, other), x0), x1), x2), x3) in elems.zip(x.0).zip(x.1).zip(x.2).zip(x.3) {
+      *elem += a0 * *x0 + a1 * *x1 + a2 * *x2 + a3 * *x3;
+      *other += b0 * *x0 + b1 * *x1 + b2 * *x2 + b3 * *x3;
+    }
+  }
+  for line in lines.remainder().chunks_exact(n) {
+    let (a, b) = (line[j], line[j + 1]);
+    for ((elem, other), x) in first.iter_mut().zip(second.iter_mut()).zip(&line[ids.clone()]) {
+      *elem += a * *x;
+      *other += b * *x;
+    }
+  }
+}
+
+/// @brief Parses amount of bytes with an optional binary unit suffix, e.g.
+/// "256MB", "256M", "1GiB" or "4096". Returns None for malformed input.
+pub fn parse_byte_size(size: &str) -> Option<u64> {
+  let size = size.trim();
+  let digits_end = size
+    .find(|ch: char| !ch.is_ascii_digit())
+    .unwrap_or(size.len());
+  let (number, unit) = size.split_at(digits_end);
+  let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+    "" | "B" => 1,
+    "K" | "KB" | "KIB" => 1 << 10,
+    "M" | "MB" | "MIB" => 1 << 20,
+    "G" | "GB" | "GIB" => 1 << 30,
+    "T" | "TB" | "TIB" => 1 << 40,
+    _ => return None,
+  };
+  number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
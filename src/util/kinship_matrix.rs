@@ -0,0 +1,373 @@
+// kinship_matrix.rs
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+use super::error::Error;
+use super::idx::IndividualIdx;
+use super::lmm::{symmetric_eigen, Eigen};
+use super::matrix_csv::{MatrixCsvReader, NumericTable};
+use super::na;
+use super::output::{write_gemma_rows, NumberFormat};
+use super::report::write_record;
+
+/// @brief Pair of individuals found by KinshipMatrix::find_related_pairs or
+/// find_duplicates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelatedPair {
+  pub id1: String,
+  pub id2: String,
+  /// @note Kinship of the pair, or their genetic correlation for
+  /// find_duplicates.
+  pub value: f64,
+}
+
+/// @brief Amounts of markers behind the elements of a Kinship matrix, the
+/// counts of the GCTA GRM written by KinshipMatrix::write_gcta.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkerCounts<'a> {
+  /// @brief Same amount for every pair: the SNP lines accumulated (lines
+  /// left out, e.g. by MissingPolicy::SkipMarker or a filter, aren't
+  /// counted).
+  Uniform(usize),
+  /// @brief Amount per pair, a full ids_num x ids_num matrix, row-major
+  /// (e.g. markers genotyped in both individuals of the pair).
+  PerPair(&'a [f64]),
+}
+
+/// @brief Kinship matrix along with the ids of its rows (and columns).
+#[derive(Clone, Debug, PartialEq)]
+pub struct KinshipMatrix {
+  ids: Vec<String>,
+  /// @note Full (mirrored) ids.len() x ids.len() matrix, row-major.
+  data: Vec<f64>,
+}
+
+impl KinshipMatrix {
+  /// @brief Wraps data, a full ids.len() x ids.len() matrix (as returned by
+  /// GenoParser::calc_kinship).
+  ///
+  /// @note Returns Error::Validation if the size of data doesn't match ids.
+  pub fn new(ids: Vec<String>, data: Vec<f64>) -> std::io::Result<Self> {
+    if data.len() != ids.len() * ids.len() {
+      return Err(
+        Error::Validation(format!(
+          "Kinship matrix of {} ids should have {} values, got {}.",
+          ids.len(),
+          ids.len() * ids.len(),
+          data.len()
+        ))
+        .into(),
+      );
+    }
+    Ok(KinshipMatrix { ids, data })
+  }
+
+  /// @brief Ids of the rows and columns, in the order of the header line of
+  /// the genotype file.
+  pub fn ids(&self) -> &[String] {
+    &self.ids
+  }
+
+  pub fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  /// @brief Amount of rows and columns.
+  pub fn dims(&self) -> (usize, usize) {
+    (self.ids.len(), self.ids.len())
+  }
+
+  pub fn get(&self, i: IndividualIdx, j: IndividualIdx) -> f64 {
+    self.data[i.index() * self.ids.len() + j.index()]
+  }
+
+  /// @brief Element of the individuals with ids a and b, None if an id is
+  /// absent.
+  pub fn get_by_id(&self, a: &str, b: &str) -> Option<f64> {
+    let i = self.idx(a)?;
+    let j = self.idx(b)?;
+    Some(self.get(i, j))
+  }
+
+  pub fn idx(&self, id: &str) -> Option<IndividualIdx> {
+    self.ids.iter().position(|other| other == id).map(IndividualIdx)
+  }
+
+  pub fn row(&self, i: IndividualIdx) -> &[f64] {
+    let ids_num = self.ids.len();
+    &self.data[i.index() * ids_num..(i.index() + 1) * ids_num]
+  }
+
+  pub fn rows(&self) -> impl Iterator<Item = &[f64]> + '_ {
+    self.data.chunks(self.ids.len().max(1))
+  }
+
+  pub fn column(&self, j: IndividualIdx) -> impl Iterator<Item = f64> + '_ {
+    self.data.iter().skip(j.index()).step_by(self.ids.len().max(1)).copied()
+  }
+
+  /// @brief Submatrix of the individuals at idx, in the given order (e.g.
+  /// Alignment::indices of the genotype file).
+  pub fn select(&self, idx: &[IndividualIdx]) -> KinshipMatrix {
+    KinshipMatrix {
+      ids: idx.iter().map(|i| self.ids[i.index()].clone()).collect(),
+      data: idx.iter().flat_map(|i| idx.iter().map(move |j| self.get(*i, *j))).collect(),
+    }
+  }
+
+  /// @brief Flat row-major data.
+  pub fn as_slice(&self) -> &[f64] {
+    &self.data
+  }
+
+  pub fn into_vec(self) -> Vec<f64> {
+    self.data
+  }
+
+  /// @brief Eigendecomposition K = U * S * U.T (eigenvalues in ascending
+  /// order, eigenvectors as columns of U), e.g. for an LMM of another tool.
+  ///
+  /// @note Returns Error::Validation if the matrix holds NA (e.g. a pair
+  /// without markers in common) or the decomposition doesn't converge.
+  pub fn eigen(&self) -> std::io::Result<Eigen> {
+    symmetric_eigen(&self.data, self.ids.len())
+  }
+
+  /// @brief Pairs of individuals (off the diagonal) with kinship of at least
+  /// threshold, the most related first. Pairs with NA kinship are skipped.
+  pub fn find_related_pairs(&self, threshold: f64) -> Vec<RelatedPair> {
+    self.find_pairs(threshold, |_, _, kinship| kinship)
+  }
+
+  /// @brief Pairs of individuals which are likely the same sample: the
+  /// genetic correlation K[i][j] / sqrt(K[i][i] * K[j][j]) of the pair is at
+  /// least threshold (e.g. 0.95, identical genotypes give 1 whatever the
+  /// scale of the matrix is), the most similar first.
+  ///
+  /// @note The correlation is taken of the matrix centered over individuals
+  /// (C * K * C with C = I - 1/n), which is the matrix of the SNP lines
+  /// centered by their means. So a matrix of raw dosages (where unrelated
+  /// individuals sharing the common allele look alike) gives the same pairs
+  /// as the centered one, and centering a centered matrix changes nothing.
+  /// NA elements are left out of the means. Pairs with NA kinship or with a
+  /// non-positive centered diagonal element are skipped.
+  pub fn find_duplicates(&self, threshold: f64) -> Vec<RelatedPair> {
+    let n = self.ids.len();
+    let centered = self.centered();
+    self.find_pairs(threshold, |i, j, _| {
+      let (ii, jj) = (centered[i * n + i], centered[j * n + j]);
+      match ii > 0.0 && jj > 0.0 {
+        true => centered[i * n + j] / (ii * jj).sqrt(),
+        false => na::NA,
+      }
+    })
+  }
+
+  /// @brief Matrix centered over individuals, see find_duplicates.
+  fn centered(&self) -> Vec<f64> {
+    let mean = |values: &mut dyn Iterator<Item = f64>| {
+      let (sum, count) = values
+        .filter(|value| !na::is_na(*value))
+        .fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+      sum / count.max(1) as f64
+    };
+    let row_means = self.rows().map(|row| mean(&mut row.iter().copied())).collect::<Vec<_>>();
+    let total_mean = mean(&mut self.data.iter().copied());
+    let n = self.ids.len();
+    let mut centered = self.data.clone();
+    for (i, row) in centered.chunks_mut(n.max(1)).enumerate() {
+      for (j, value) in row.iter_mut().enumerate() {
+        *value += total_mean - row_means[i] - row_means[j];
+      }
+    }
+    centered
+  }
+
+  /// @brief Pairs of the upper triangle whose value (given i, j and the
+  /// kinship of the pair) is at least threshold, by descending value.
+  fn find_pairs<F>(&self, threshold: f64, value: F) -> Vec<RelatedPair>
+  where
+    F: Fn(usize, usize, f64) -> f64,
+  {
+    let n = self.ids.len();
+    let mut pairs = Vec::new();
+    for i in 0..n {
+      for j in i + 1..n {
+        let value = value(i, j, self.data[i * n + j]);
+        if !na::is_na(value) && value >= threshold {
+          pairs.push(RelatedPair {
+            id1: self.ids[i].clone(),
+            id2: self.ids[j].clone(),
+            value,
+          });
+        }
+      }
+    }
+    // Stable, so equal values keep the order of the matrix.
+    pairs.sort_by(|a, b| b.value.total_cmp(&a.value));
+    pairs
+  }
+
+  /// @brief Writes the matrix at path as GEMMA relatedness matrix (-k) in
+  /// full precision, see output::write_gemma_rows.
+  pub fn write_gemma<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    self.write_gemma_to(&mut writer, &NumberFormat::default())?;
+    writer.flush()
+  }
+
+  pub fn write_gemma_to<W: Write>(
+    &self,
+    writer: &mut W,
+    number_format: &NumberFormat,
+  ) -> std::io::Result<()> {
+    write_gemma_rows(writer, self.rows(), number_format)
+  }
+
+  /// @brief Writes the matrix at path as CSV with ids in the first row and
+  /// column, as R writes the matrix of R/qtl2 calc_kinship with write.csv
+  /// (ids are quoted only when they hold commas or quotes).
+  ///
+  /// @note Returns Error::Validation if an id holds a line break, the
+  /// matrix couldn't be read back by read_csv.
+  pub fn write_csv<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    check_csv_ids(&self.ids)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    self.write_csv_to(&mut writer, &NumberFormat::default())?;
+    writer.flush()
+  }
+
+  pub fn write_csv_to<W: Write>(
+    &self,
+    writer: &mut W,
+    number_format: &NumberFormat,
+  ) -> std::io::Result<()> {
+    check_csv_ids(&self.ids)?;
+    let header = std::iter::once(String::new()).chain(self.ids.iter().cloned());
+    write_record(writer, &header.collect::<Vec<String>>())?;
+    let mut record = Vec::with_capacity(self.ids.len() + 1);
+    for (id, row) in self.ids.iter().zip(self.rows()) {
+      record.clear();
+      record.push(id.clone());
+      record.extend(row.iter().map(|value| number_format.format(*value)));
+      write_record(writer, &record)?;
+    }
+    Ok(())
+  }
+
+  /// @brief Writes the matrix as GCTA binary GRM, files prefix.grm.bin,
+  /// prefix.grm.N.bin and prefix.grm.id (prefix as given to gcta --grm).
+  ///
+  /// @param[in] markers Amounts of markers the elements were calculated from.
+  /// @note GCTA takes the GRM for the one of standardized genotypes
+  /// (KinshipKind::Standardized), matrices of the other kinds are written
+  /// as they are and keep their own scale.
+  pub fn write_gcta<P: AsRef<Path>>(
+    &self,
+    prefix: P,
+    markers: MarkerCounts,
+  ) -> std::io::Result<()> {
+    let create = |extension: &str| -> std::io::Result<BufWriter<File>> {
+      let mut path = OsString::from(prefix.as_ref().as_os_str());
+      path.push(extension);
+      Ok(BufWriter::new(File::create(path)?))
+    };
+    let mut grm = create(".grm.bin")?;
+    let mut counts = create(".grm.N.bin")?;
+    let mut ids = create(".grm.id")?;
+    self.write_gcta_to(&mut grm, &mut counts, &mut ids, markers)?;
+    grm.flush()?;
+    counts.flush()?;
+    ids.flush()
+  }
+
+  /// @brief Writes the lower triangle (with the diagonal, row by row) to
+  /// grm and the marker counts of the same pairs to counts, as little-endian
+  /// 32-bit floats, and a "FID IID" line per individual to ids, the id being
+  /// both (as plink --double-id).
+  ///
+  /// @note Returns Error::Validation if an id holds whitespace, GCTA would
+  /// split it, or MarkerCounts::PerPair doesn't match the matrix.
+  pub fn write_gcta_to<G: Write, N: Write, I: Write>(
+    &self,
+    grm: &mut G,
+    counts: &mut N,
+    ids: &mut I,
+    markers: MarkerCounts,
+  ) -> std::io::Result<()> {
+    if let Some(id) = self.ids.iter().find(|id| id.contains(char::is_whitespace)) {
+      return Err(Error::Validation(format!("Id <{}> holds whitespace.", id)).into());
+    }
+    if let MarkerCounts::PerPair(pair_counts) = markers {
+      if pair_counts.len() != self.data.len() {
+        return Err(
+          Error::Validation(format!(
+            "Marker counts of {} ids should have {} values, got {}.",
+            self.ids.len(),
+            self.data.len(),
+            pair_counts.len()
+          ))
+          .into(),
+        );
+      }
+    }
+    for id in &self.ids {
+      writeln!(ids, "{}\t{}", id, id)?;
+    }
+    let n = self.ids.len();
+    for (i, row) in self.rows().enumerate() {
+      for (j, value) in row[..=i].iter().enumerate() {
+        let count = match markers {
+          MarkerCounts::Uniform(markers_num) => markers_num as f32,
+          MarkerCounts::PerPair(pair_counts) => pair_counts[i * n + j] as f32,
+        };
+        grm.write_all(&(*value as f32).to_le_bytes())?;
+        counts.write_all(&count.to_le_bytes())?;
+      }
+    }
+    Ok(())
+  }
+
+  /// @brief Reads the matrix written by write_csv (or by R write.csv).
+  pub fn read_csv<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    Self::from_table(MatrixCsvReader::new().read(path)?.to_numeric()?)
+  }
+
+  pub fn read_csv_from<R: BufRead>(reader: R) -> std::io::Result<Self> {
+    Self::from_table(MatrixCsvReader::new().read_from(reader)?.to_numeric()?)
+  }
+
+  /// @note Returns Error::Validation unless the rows and the columns have
+  /// the same ids.
+  fn from_table(table: NumericTable) -> std::io::Result<Self> {
+    if table.row_ids != table.col_ids {
+      return Err(
+        Error::Validation(String::from(
+          "Kinship matrix should have the same ids in the first row and column.",
+        ))
+        .into(),
+      );
+    }
+    Self::new(table.row_ids, table.values)
+  }
+
+  /// @brief Matrix as a vector of rows.
+  pub fn to_dense(&self) -> Vec<Vec<f64>> {
+    self.rows().map(|row| row.to_vec()).collect()
+  }
+}
+
+/// @brief Returns Error::Validation if one of ids (labels of a CSV matrix)
+/// holds a line break.
+pub(crate) fn check_csv_ids(ids: &[String]) -> std::io::Result<()> {
+  match ids.iter().find(|id| id.contains(['\n', '\r'])) {
+    Some(id) => Err(
+      Error::Validation(format!("Id <{}> holds a line break, CSV is read line by line.", id.trim()))
+        .into(),
+    ),
+    None => Ok(()),
+  }
+}
@@ -0,0 +1,87 @@
+// kinship_summary.rs
+
+use super::kinship::{
+  check_snps_num, check_weight, read_accumulated_batch, Accumulated, KinshipOptions, SnpSource,
+};
+use super::na;
+
+/// @brief Summary statistics of the Kinship matrix computed without the
+/// matrix itself, see kinship_summary.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KinshipSummary {
+  pub ids_num: usize,
+  /// @note SNP lines which contributed to the statistics.
+  pub snps_num: usize,
+  pub trace: f64,
+  pub mean_diagonal: f64,
+  /// @note na::NA for less than 2 individuals.
+  pub mean_off_diagonal: f64,
+  /// @note Bounds of the largest eigenvalue: the largest of the diagonal
+  /// maximum and the Rayleigh quotient of the all-ones vector below, the
+  /// trace (or the largest row sum, when no element is negative) above. All
+  /// eigenvalues are non-negative, the matrix being a Gram one.
+  pub max_eigenvalue_lower: f64,
+  pub max_eigenvalue_upper: f64,
+}
+
+/// @brief Computes KinshipSummary of the Kinship matrix of source in a single
+/// pass, keeping O(ids_num) numbers instead of the ids_num x ids_num matrix
+/// (QC of cohorts too large to compute the matrix yet).
+///
+/// @note options.batch_size, options.transform, options.kind,
+/// options.drop_monomorphic, options.marker_weights and options.metrics are
+/// used, the computation runs on the calling thread.
+/// @note Returns Error::Validation for a batch size of 0, fewer kept SNP
+/// lines than individuals, or marker weights summing to 0, as the
+/// calculations of the matrix do.
+pub fn kinship_summary(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+) -> std::io::Result<KinshipSummary> {
+  let ids_num = source.ids_num();
+  options.check_batch_rows(ids_num)?;
+  let mut batch = vec![0.0; ids_num * options.batch_rows(ids_num)];
+  let mut accumulated = Accumulated::default();
+  // Diagonal and row sums of the Gram matrix.
+  let (mut diagonal, mut row_sums) = (vec![0.0; ids_num], vec![0.0; ids_num]);
+  let (mut total, mut nonnegative) = (0.0, true);
+  loop {
+    let rows = read_accumulated_batch(source, &mut batch, &mut [], options, &mut accumulated)?;
+    if rows == 0 {
+      break;
+    }
+    for line in batch[..rows * ids_num].chunks(ids_num.max(1)) {
+      let line_sum = line.iter().sum::<f64>();
+      total += line_sum * line_sum;
+      nonnegative &= line.iter().all(|value| *value >= 0.0);
+      for ((diagonal, row_sum), value) in diagonal.iter_mut().zip(&mut row_sums).zip(line) {
+        *diagonal += value * value;
+        *row_sum += value * line_sum;
+      }
+    }
+  }
+
+  check_snps_num(accumulated.snps, ids_num)?;
+  check_weight(accumulated.snps, accumulated.weight)?;
+  let divisor = accumulated.weight;
+  let n = ids_num as f64;
+  let trace = diagonal.iter().sum::<f64>() / divisor;
+  let max_diagonal = diagonal.iter().fold(0.0f64, |max, value| max.max(*value)) / divisor;
+  let mut max_eigenvalue_upper = trace;
+  if nonnegative {
+    let max_row_sum = row_sums.iter().fold(0.0f64, |max, value| max.max(*value)) / divisor;
+    max_eigenvalue_upper = max_eigenvalue_upper.min(max_row_sum);
+  }
+  Ok(KinshipSummary {
+    ids_num,
+    snps_num: accumulated.snps,
+    trace,
+    mean_diagonal: trace / n,
+    mean_off_diagonal: match ids_num {
+      0 | 1 => na::NA,
+      _ => (total / divisor - trace) / (n * (n - 1.0)),
+    },
+    max_eigenvalue_lower: max_diagonal.max(total / divisor / n),
+    max_eigenvalue_upper,
+  })
+}
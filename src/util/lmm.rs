@@ -0,0 +1,598 @@
+// lmm.rs
+
+//! Linear mixed model y = Xb + g + e, where g ~ N(0, sg2 * K) and
+//! e ~ N(0, se2 * I), with K the Kinship matrix.
+//!
+//! Following GEMMA/pylmm, K = U * S * U.T is decomposed once, after which the
+//! model rotated by U.T has diagonal covariance sg2 * S + se2 * I, so the
+//! REML likelihood of any phenotype is evaluated in O(n) for a given
+//! heritability h2 = sg2 / (sg2 + se2).
+
+use std::ops::Range;
+use std::thread;
+
+use super::error::Error;
+use super::na;
+
+/// @brief Upper bound of h2, so that residual variance stays positive.
+const MAX_H2: f64 = 1.0 - 1e-6;
+
+/// @brief Eigendecomposition of a symmetric ids_num x ids_num matrix.
+#[derive(Clone, Debug)]
+pub struct Eigen {
+  /// @note In ascending order.
+  pub values: Vec<f64>,
+  /// @note Row-major, column k is the eigenvector of values[k].
+  pub vectors: Vec<f64>,
+}
+
+/// @brief Eigendecomposition of symmetric n x n matrix (Householder
+/// tridiagonalization followed by implicit QL, as EISPACK tred2/tql2).
+///
+/// @note Only the lower part of matrix is used.
+pub fn symmetric_eigen(matrix: &[f64], n: usize) -> std::io::Result<Eigen> {
+  if matrix.len() != n * n {
+    return Err(
+      Error::Validation(format!("Matrix has {} elements, expected {}.", matrix.len(), n * n))
+        .into(),
+    );
+  }
+  if matrix.iter().any(|value| !value.is_finite()) {
+    return Err(Error::Validation(String::from("Matrix contains NA or infinite values.")).into());
+  }
+  if n == 0 {
+    return Ok(Eigen {
+      values: Vec::new(),
+      vectors: Vec::new(),
+    });
+  }
+  let mut v = matrix.to_vec();
+  let mut d = vec![0.0; n];
+  let mut e = vec![0.0; n];
+  tridiagonalize(&mut v, &mut d, &mut e, n);
+  diagonalize(&mut v, &mut d, &mut e, n)?;
+  Ok(Eigen {
+    values: d,
+    vectors: v,
+  })
+}
+
+fn tridiagonalize(v: &mut [f64], d: &mut [f64], e: &mut [f64], n: usize) {
+  d.copy_from_slice(&v[(n - 1) * n..n * n]);
+  for i in (1..n).rev() {
+    let scale = d[..i].iter().map(|value| value.abs()).sum::<f64>();
+    let mut h = 0.0;
+    if scale == 0.0 {
+      e[i] = d[i - 1];
+      for j in 0..i {
+        d[j] = v[(i - 1) * n + j];
+        v[i * n + j] = 0.0;
+        v[j * n + i] = 0.0;
+      }
+    } else {
+      for value in d[..i].iter_mut() {
+        *value /= scale;
+        h += *value * *value;
+      }
+      let mut f = d[i - 1];
+      let mut g = if f > 0.0 { -h.sqrt() } else { h.sqrt() };
+      e[i] = scale * g;
+      h -= f * g;
+      d[i - 1] = f - g;
+      e[..i].iter_mut().for_each(|value| *value = 0.0);
+      for j in 0..i {
+        f = d[j];
+        v[j * n + i] = f;
+        g = e[j] + v[j * n + j] * f;
+        for k in j + 1..i {
+          g += v[k * n + j] * d[k];
+          e[k] += v[k * n + j] * f;
+        }
+        e[j] = g;
+      }
+      f = 0.0;
+      for j in 0..i {
+        e[j] /= h;
+        f += e[j] * d[j];
+      }
+      let hh = f / (h + h);
+      for j in 0..i {
+        e[j] -= hh * d[j];
+      }
+      for j in 0..i {
+        f = d[j];
+        g = e[j];
+        for k in j..i {
+          v[k * n + j] -= f * e[k] + g * d[k];
+        }
+        d[j] = v[(i - 1) * n + j];
+        v[i * n + j] = 0.0;
+      }
+    }
+    d[i] = h;
+  }
+  // Accumulate the transformations.
+  for i in 0..n - 1 {
+    v[(n - 1) * n + i] = v[i * n + i];
+    v[i * n + i] = 1.0;
+    let h = d[i + 1];
+    if h != 0.0 {
+      for k in 0..=i {
+        d[k] = v[k * n + i + 1] / h;
+      }
+      for j in 0..=i {
+        let g = (0..=i).map(|k| v[k * n + i + 1] * v[k * n + j]).sum::<f64>();
+        for k in 0..=i {
+          v[k * n + j] -= g * d[k];
+        }
+      }
+    }
+    for k in 0..=i {
+      v[k * n + i + 1] = 0.0;
+    }
+  }
+  for j in 0..n {
+    d[j] = v[(n - 1) * n + j];
+    v[(n - 1) * n + j] = 0.0;
+  }
+  v[n * n - 1] = 1.0;
+  e[0] = 0.0;
+}
+
+fn diagonalize(v: &mut [f64], d: &mut [f64], e: &mut [f64], n: usize) -> std::io::Result<()> {
+  const MAX_ITERATIONS: usize = 64;
+  for i in 1..n {
+    e[i - 1] = e[i];
+  }
+  e[n - 1] = 0.0;
+  let mut f = 0.0;
+  let mut tst1 = 0.0_f64;
+  for l in 0..n {
+    tst1 = tst1.max(d[l].abs() + e[l].abs());
+    let m = (l..n).find(|m| e[*m].abs() <= f64::EPSILON * tst1).unwrap_or(n - 1);
+    let mut iterations = 0;
+    while m > l && e[l].abs() > f64::EPSILON * tst1 {
+      iterations += 1;
+      if iterations > MAX_ITERATIONS {
+        return Err(
+          Error::Validation(String::from("Eigendecomposition did not converge.")).into(),
+        );
+      }
+      let mut g = d[l];
+      let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+      let mut r = if p < 0.0 { -p.hypot(1.0) } else { p.hypot(1.0) };
+      d[l] = e[l] / (p + r);
+      d[l + 1] = e[l] * (p + r);
+      let dl1 = d[l + 1];
+      let mut h = g - d[l];
+      for value in d[l + 2..].iter_mut() {
+        *value -= h;
+      }
+      f += h;
+      p = d[m];
+      let (mut c, mut c2, mut c3) = (1.0, 1.0, 1.0);
+      let el1 = e[l + 1];
+      let (mut s, mut s2) = (0.0, 0.0);
+      for i in (l..m).rev() {
+        c3 = c2;
+        c2 = c;
+        s2 = s;
+        g = c * e[i];
+        h = c * p;
+        r = p.hypot(e[i]);
+        e[i + 1] = s * r;
+        s = e[i] / r;
+        c = p / r;
+        p = c * d[i] - s * g;
+        d[i + 1] = h + s * (c * g + s * d[i]);
+        for k in 0..n {
+          h = v[k * n + i + 1];
+          v[k * n + i + 1] = s * v[k * n + i] + c * h;
+          v[k * n + i] = c * v[k * n + i] - s * h;
+        }
+      }
+      p = -s * s2 * c3 * el1 * e[l] / dl1;
+      e[l] = s * p;
+      d[l] = c * p;
+    }
+    d[l] += f;
+    e[l] = 0.0;
+  }
+  // Sort in ascending order.
+  for i in 0..n - 1 {
+    let k = (i..n).fold(i, |k, j| if d[j] < d[k] { j } else { k });
+    if k != i {
+      d.swap(i, k);
+      for j in 0..n {
+        v.swap(j * n + i, j * n + k);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// @brief Null model (without a marker) fitted to a single phenotype.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NullModel {
+  /// @brief Heritability sg2 / (sg2 + se2) maximizing REML likelihood.
+  pub h2: f64,
+  /// @brief Genetic variance sg2.
+  pub sigma_g2: f64,
+  /// @brief Residual variance se2.
+  pub sigma_e2: f64,
+  /// @brief Fixed effects, one per covariate.
+  pub beta: Vec<f64>,
+  /// @brief REML log-likelihood at h2.
+  pub loglik: f64,
+  /// @brief Phenotype rotated by U.T (ids_num values).
+  pub rotated: Vec<f64>,
+}
+
+/// @brief Linear mixed model sharing a single eigendecomposition of K between
+/// any amount of phenotypes (e.g. thousands of expression traits).
+#[derive(Clone, Debug)]
+pub struct Lmm {
+  ids_num: usize,
+  eigen: Eigen,
+  covariates_num: usize,
+  /// @note Covariates rotated by U.T, row-major ids_num x covariates_num.
+  rotated_covariates: Vec<f64>,
+  /// @note log det(X.T * X), constant term of REML likelihood.
+  logdet_xtx: f64,
+  threads_num: usize,
+}
+
+impl Lmm {
+  /// @brief Decomposes ids_num x ids_num Kinship matrix.
+  ///
+  /// @param[in] covariates Row-major ids_num x covariates_num matrix, None for
+  /// the intercept only.
+  pub fn new(
+    kinship: &[f64],
+    ids_num: usize,
+    covariates: Option<(&[f64], usize)>,
+  ) -> std::io::Result<Self> {
+    let intercept = vec![1.0; ids_num];
+    let (covariates, covariates_num) = covariates.unwrap_or((&intercept, 1));
+    if covariates.len() != ids_num * covariates_num || covariates_num >= ids_num {
+      return Err(
+        Error::Validation(format!(
+          "Covariates should be {} x c matrix with c < {}, got {} values.",
+          ids_num,
+          ids_num,
+          covariates.len()
+        ))
+        .into(),
+      );
+    }
+    if na::count_na(covariates) > 0 {
+      return Err(Error::Validation(String::from("Covariates contain NA.")).into());
+    }
+    let eigen = symmetric_eigen(kinship, ids_num)?;
+    let threads_num = num_cpus::get();
+    let rotated_covariates =
+      rotate(&eigen.vectors, covariates, ids_num, covariates_num, threads_num);
+    let xtx = weighted_cross(covariates, covariates_num, None);
+    let logdet_xtx = cholesky_logdet(&xtx, covariates_num)
+      .ok_or_else(|| Error::Validation(String::from("Covariates are linearly dependent.")))?;
+    Ok(Lmm {
+      ids_num,
+      eigen,
+      covariates_num,
+      rotated_covariates,
+      logdet_xtx,
+      threads_num,
+    })
+  }
+
+  /// @brief Amount of threads used by fit_null_batch, all logical cores by
+  /// default.
+  pub fn with_threads(mut self, threads_num: usize) -> Self {
+    self.threads_num = threads_num.max(1);
+    self
+  }
+
+  pub fn eigen(&self) -> &Eigen {
+    &self.eigen
+  }
+
+  pub fn rotated_covariates(&self) -> &[f64] {
+    &self.rotated_covariates
+  }
+
+  pub fn fit_null(&self, pheno: &[f64]) -> std::io::Result<NullModel> {
+    Ok(self.fit_null_batch(pheno, 1)?.pop().unwrap())
+  }
+
+  /// @brief Fits the null model to phenos_num phenotypes at once: the
+  /// phenotypes are rotated together and fitted in parallel.
+  ///
+  /// @param[in] phenos Row-major ids_num x phenos_num matrix (individuals as
+  /// rows, as in the R/qtl2 pheno file).
+  /// @note Phenotypes can't contain NA, since individuals with missing values
+  /// would need a decomposition of their own.
+  pub fn fit_null_batch(
+    &self,
+    phenos: &[f64],
+    phenos_num: usize,
+  ) -> std::io::Result<Vec<NullModel>> {
+    let n = self.ids_num;
+    if phenos.len() != n * phenos_num {
+      return Err(
+        Error::Validation(format!(
+          "Phenotypes should be {} x {} matrix, got {} values.",
+          n,
+          phenos_num,
+          phenos.len()
+        ))
+        .into(),
+      );
+    }
+    if let Some(pos) = phenos.iter().position(|value| na::is_na(*value)) {
+      return Err(
+        Error::Validation(format!(
+          "Phenotype {} of individual {} is NA, impute or drop it first.",
+          pos % phenos_num,
+          pos / phenos_num
+        ))
+        .into(),
+      );
+    }
+    let rotated = rotate(&self.eigen.vectors, phenos, n, phenos_num, self.threads_num);
+    let mut models = Vec::with_capacity(phenos_num);
+    thread::scope(|scope| {
+      let workers = blocks(phenos_num, self.threads_num)
+        .into_iter()
+        .map(|block| {
+          let rotated = &rotated;
+          scope.spawn(move || {
+            block
+              .map(|p| {
+                let y = (0..n).map(|k| rotated[k * phenos_num + p]).collect();
+                self.fit_rotated(y)
+              })
+              .collect::<Vec<NullModel>>()
+          })
+        })
+        .collect::<Vec<_>>();
+      for worker in workers {
+        models.extend(worker.join().unwrap());
+      }
+    });
+    Ok(models)
+  }
+
+  /// @brief Standard error of model.h2 from the curvature of REML
+  /// log-likelihood (observed information), NA if the likelihood is flat.
+  ///
+  /// @note At the boundaries (h2 near 0 or 1) the estimate is one-sided and
+  /// should be taken as a rough guide only.
+  pub fn h2_se(&self, model: &NullModel) -> f64 {
+    const STEP: f64 = 1e-4;
+    let loglik = |h2: f64| self.reml(&model.rotated, h2).map_or(na::NA, |fit| fit.0);
+    let h2 = model.h2.clamp(STEP, MAX_H2 - STEP);
+    let curvature = (loglik(h2 + STEP) - 2.0 * loglik(h2) + loglik(h2 - STEP)) / (STEP * STEP);
+    if curvature < 0.0 {
+      (-1.0 / curvature).sqrt()
+    } else {
+      na::NA
+    }
+  }
+
+  fn fit_rotated(&self, y: Vec<f64>) -> NullModel {
+    const GRID: usize = 100;
+    let loglik = |h2: f64| self.reml(&y, h2).map_or(f64::NEG_INFINITY, |fit| fit.0);
+    let mut best = (0..=GRID)
+      .map(|step| (step as f64 / GRID as f64).min(MAX_H2))
+      .map(|h2| (h2, loglik(h2)))
+      .fold((0.0, f64::NEG_INFINITY), |best, fit| if fit.1 > best.1 { fit } else { best });
+    // Golden-section search around the best grid point.
+    let step = 1.0 / GRID as f64;
+    let (mut a, mut b) = ((best.0 - step).max(0.0), (best.0 + step).min(MAX_H2));
+    let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+    for _ in 0..40 {
+      let (c, d) = (b - ratio * (b - a), a + ratio * (b - a));
+      if loglik(c) > loglik(d) {
+        b = d;
+      } else {
+        a = c;
+      }
+    }
+    let h2 = (a + b) / 2.0;
+    if loglik(h2) > best.1 {
+      best = (h2, loglik(h2));
+    }
+    let (loglik, sigma2, beta) = self.reml(&y, best.0).unwrap_or((na::NA, na::NA, Vec::new()));
+    NullModel {
+      h2: best.0,
+      sigma_g2: best.0 * sigma2,
+      sigma_e2: (1.0 - best.0) * sigma2,
+      beta,
+      loglik,
+      rotated: y,
+    }
+  }
+
+  /// @brief REML log-likelihood, total variance and fixed effects of rotated
+  /// phenotype y at h2. Covariance of rotated data is
+  /// sigma2 * diag(h2 * s + 1 - h2).
+  fn reml(&self, y: &[f64], h2: f64) -> Option<(f64, f64, Vec<f64>)> {
+    let n = self.ids_num;
+    let c = self.covariates_num;
+    let x = &self.rotated_covariates;
+    let weights = self
+      .eigen
+      .values
+      .iter()
+      .map(|s| 1.0 / (h2 * s.max(0.0) + 1.0 - h2))
+      .collect::<Vec<f64>>();
+    let xtwx = weighted_cross(x, c, Some(&weights));
+    let xtwy = (0..c)
+      .map(|a| (0..n).map(|k| x[k * c + a] * weights[k] * y[k]).sum::<f64>())
+      .collect::<Vec<f64>>();
+    let beta = cholesky_solve(&xtwx, &xtwy, c)?;
+    let rss = (0..n)
+      .map(|k| {
+        let fitted = (0..c).map(|a| x[k * c + a] * beta[a]).sum::<f64>();
+        weights[k] * (y[k] - fitted) * (y[k] - fitted)
+      })
+      .sum::<f64>();
+    let dof = (n - c) as f64;
+    let sigma2 = rss / dof;
+    let logdet_v = -weights.iter().map(|w| w.ln()).sum::<f64>();
+    let loglik = -0.5
+      * (dof * (2.0 * std::f64::consts::PI * sigma2).ln()
+        + logdet_v
+        + cholesky_logdet(&xtwx, c)?
+        - self.logdet_xtx
+        + dof);
+    Some((loglik, sigma2, beta))
+  }
+}
+
+/// @brief U.T * m, where m is row-major n x cols matrix. Rows of the result
+/// are split between threads_num threads.
+fn rotate(u: &[f64], m: &[f64], n: usize, cols: usize, threads_num: usize) -> Vec<f64> {
+  let mut res = vec![0.0; n * cols];
+  if cols == 0 {
+    return res;
+  }
+  thread::scope(|scope| {
+    let mut rest: &mut [f64] = &mut res;
+    for rows in blocks(n, threads_num) {
+      let (block, tail) = rest.split_at_mut(rows.len() * cols);
+      rest = tail;
+      scope.spawn(move || {
+        for (k, out) in rows.zip(block.chunks_mut(cols)) {
+          for (i, line) in m.chunks(cols).enumerate() {
+            let weight = u[i * n + k];
+            out.iter_mut().zip(line).for_each(|(out, value)| *out += weight * value);
+          }
+        }
+      });
+    }
+  });
+  res
+}
+
+/// @brief Splits 0..len into at most blocks_num ranges of roughly equal size.
+fn blocks(len: usize, blocks_num: usize) -> Vec<Range<usize>> {
+  let blocks_num = blocks_num.clamp(1, len.max(1));
+  (0..blocks_num)
+    .map(|block| block * len / blocks_num..(block + 1) * len / blocks_num)
+    .collect()
+}
+
+/// @brief X.T * diag(weights) * X for row-major n x c matrix x.
+fn weighted_cross(x: &[f64], c: usize, weights: Option<&[f64]>) -> Vec<f64> {
+  let mut res = vec![0.0; c * c];
+  for (k, line) in x.chunks(c).enumerate() {
+    let weight = weights.map_or(1.0, |weights| weights[k]);
+    for a in 0..c {
+      for b in 0..c {
+        res[a * c + b] += weight * line[a] * line[b];
+      }
+    }
+  }
+  res
+}
+
+/// @brief Lower triangular L of symmetric positive definite c x c matrix
+/// m = L * L.T, None if m is not positive definite.
+fn cholesky(m: &[f64], c: usize) -> Option<Vec<f64>> {
+  let mut l = vec![0.0; c * c];
+  for i in 0..c {
+    for j in 0..=i {
+      let sum = m[i * c + j] - (0..j).map(|k| l[i * c + k] * l[j * c + k]).sum::<f64>();
+      if i == j {
+        if sum.is_nan() || sum <= 1e-12 * m[i * c + i].abs() {
+          return None;
+        }
+        l[i * c + i] = sum.sqrt();
+      } else {
+        l[i * c + j] = sum / l[j * c + j];
+      }
+    }
+  }
+  Some(l)
+}
+
+fn cholesky_logdet(m: &[f64], c: usize) -> Option<f64> {
+  let l = cholesky(m, c)?;
+  Some(2.0 * (0..c).map(|i| l[i * c + i].ln()).sum::<f64>())
+}
+
+fn cholesky_solve(m: &[f64], rhs: &[f64], c: usize) -> Option<Vec<f64>> {
+  let l = cholesky(m, c)?;
+  let mut z = vec![0.0; c];
+  for i in 0..c {
+    z[i] = (rhs[i] - (0..i).map(|k| l[i * c + k] * z[k]).sum::<f64>()) / l[i * c + i];
+  }
+  let mut res = vec![0.0; c];
+  for i in (0..c).rev() {
+    res[i] = (z[i] - (i + 1..c).map(|k| l[k * c + i] * res[k]).sum::<f64>()) / l[i * c + i];
+  }
+  Some(res)
+}
+
+/// @brief Heritability estimate of a single phenotype.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct H2Estimate {
+  pub h2: f64,
+  /// @brief Standard error of h2, see Lmm::h2_se.
+  pub se: f64,
+  /// @brief REML log-likelihood at h2.
+  pub loglik: f64,
+}
+
+/// @brief REML heritability of a phenotype given the Kinship matrix (e.g.
+/// from GenoParser::calc_kinship): the usual "kinship then h2" workflow in one
+/// call.
+///
+/// @param[in] kinship ids_num x ids_num matrix, where ids_num = pheno.len().
+/// @param[in] covariates Row-major ids_num x covariates_num matrix, None for
+/// the intercept only.
+/// @note Unlike Lmm::fit_null, individuals with NA phenotype are dropped.
+pub fn heritability(
+  kinship: &[f64],
+  pheno: &[f64],
+  covariates: Option<(&[f64], usize)>,
+) -> std::io::Result<H2Estimate> {
+  let ids_num = pheno.len();
+  if kinship.len() != ids_num * ids_num {
+    return Err(
+      Error::Validation(format!(
+        "Kinship matrix has {} elements, expected {} for {} phenotype values.",
+        kinship.len(),
+        ids_num * ids_num,
+        ids_num
+      ))
+      .into(),
+    );
+  }
+  let kept = (0..ids_num).filter(|i| !na::is_na(pheno[*i])).collect::<Vec<usize>>();
+  let kinship = kept
+    .iter()
+    .flat_map(|i| kept.iter().map(move |j| kinship[i * ids_num + j]))
+    .collect::<Vec<f64>>();
+  let pheno = kept.iter().map(|i| pheno[*i]).collect::<Vec<f64>>();
+  let covariates = covariates.map(|(covariates, covariates_num)| {
+    let kept = kept
+      .iter()
+      .flat_map(|i| covariates.chunks(covariates_num.max(1)).nth(*i).unwrap_or_default())
+      .copied()
+      .collect::<Vec<f64>>();
+    (kept, covariates_num)
+  });
+  let lmm = Lmm::new(
+    &kinship,
+    kept.len(),
+    covariates.as_ref().map(|(covariates, covariates_num)| (&covariates[..], *covariates_num)),
+  )?;
+  let model = lmm.fit_null(&pheno)?;
+  Ok(H2Estimate {
+    h2: model.h2,
+    se: lmm.h2_se(&model),
+    loglik: model.loglik,
+  })
+}
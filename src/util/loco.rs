@@ -0,0 +1,408 @@
+// loco.rs
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::error::{ensure_memory, Error};
+use super::kinship::{
+  accumulate_kinship, calc_partial_kinship_threaded, finalize_kinship_by, read_weighted_batch,
+  Accumulated, KinshipOptions, SnpSource,
+};
+use super::out_of_core::MappedKinship;
+use super::GenoParser;
+
+/// @brief Kinship matrix calculated from the markers of a single chromosome.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChromosomeKinship {
+  pub chromosome: String,
+  pub ids_num: usize,
+  /// @note Amount of SNP lines the matrix was calculated from.
+  pub snps_num: usize,
+  /// @note Sum of their weights the matrix is normalized by, see
+  /// KinshipOptions::marker_weights and KinshipOptions::missing.
+  pub weight: f64,
+  /// @note Normalized and mirrored (full) ids_num x ids_num matrix.
+  pub matrix: Vec<f64>,
+}
+
+/// @brief Calculates Kinship matrix of every chromosome in a single pass.
+/// SNP lines must be grouped by chromosome (as in a file sorted by the
+/// genetic map), the matrix of a chromosome is handed to on_complete as soon
+/// as its group ends, so only one ids_num x ids_num accumulator is held in
+/// memory at a time, regardless of the amount of chromosomes. See
+/// calc_kinship_loco for the leave-one-chromosome-out (LOCO) matrices.
+///
+/// @param[in] chromosomes chromosome of every SNP row id.
+/// @param[in] on_complete gets chromosome matrices in the file order, e.g.
+/// write_chromosome_kinship.
+///
+/// @note Results don't depend on the amount of threads or their scheduling:
+/// each SNP line is routed to the accumulator by its row id (never by the
+/// order in which workers finish), and every element of an accumulator is
+/// updated by a single thread, SNP lines in file order.
+/// @note SNP lines are filtered, imputed, transformed and weighted as in
+/// calc_kinship_observed (see read_accumulated_batch), progress and cancel
+/// of options apply too.
+/// @note Returns Error::Validation if a row id is absent from chromosomes or
+/// a chromosome group appears twice, and for options which don't apply to
+/// per chromosome accumulation (see check_chromosome_options).
+pub fn calc_kinship_chromosomes<F>(
+  source: &mut dyn SnpSource,
+  chromosomes: &HashMap<String, String>,
+  options: &KinshipOptions,
+  mut on_complete: F,
+) -> std::io::Result<()>
+where
+  F: FnMut(ChromosomeKinship) -> std::io::Result<()>,
+{
+  check_chromosome_options(options)?;
+  let ids_num = source.ids_num();
+  options.check_batch_rows(ids_num)?;
+  let batch_size = options.batch_rows(ids_num);
+  let f64_size = std::mem::size_of::<f64>() as u64;
+  ensure_memory((ids_num * ids_num + ids_num * batch_size) as u64 * f64_size)?;
+  let threads_num = options.threads_num();
+  let mut finished = HashSet::<String>::new();
+  let mut current: Option<ChromosomeKinship> = None;
+  let mut batch = vec![0.0; ids_num * batch_size];
+  let mut accumulated = Accumulated::start(options);
+  let mut lines = Vec::new();
+  let mut complete = |chr: ChromosomeKinship, finished: &mut HashSet<String>| {
+    let mut chr = chr;
+    finalize_chromosome(&chr.chromosome, &mut chr.matrix, ids_num, chr.weight)?;
+    finished.insert(chr.chromosome.clone());
+    on_complete(chr)
+  };
+
+  loop {
+    let runs = read_chromosome_runs(
+      source,
+      &mut batch,
+      chromosomes,
+      options,
+      &mut accumulated,
+      &mut lines,
+    )?;
+    if runs.is_empty() {
+      break;
+    }
+    for (chr, rows, weight) in runs {
+      if current.as_ref().map(|current| current.chromosome.as_str()) != Some(chr) {
+        if finished.contains(chr) {
+          return Err(
+            Error::Validation(format!(
+              "SNP <{}> of chromosome {} is separated from the other SNPs of the \
+               chromosome, SNP lines must be grouped by chromosome.",
+              lines[rows.start].0, chr
+            ))
+            .into(),
+          );
+        }
+        if let Some(done) = current.take() {
+          complete(done, &mut finished)?;
+        }
+        current = Some(ChromosomeKinship {
+          chromosome: String::from(chr),
+          ids_num,
+          snps_num: 0,
+          weight: 0.0,
+          matrix: vec![0.0; ids_num * ids_num],
+        });
+      }
+      let acc = current.as_mut().unwrap();
+      calc_partial_kinship_threaded(
+        &batch[rows.start * ids_num..rows.end * ids_num],
+        &mut acc.matrix,
+        ids_num,
+        threads_num,
+      );
+      acc.snps_num += rows.len();
+      acc.weight += weight;
+    }
+  }
+  if let Some(done) = current.take() {
+    complete(done, &mut finished)?;
+  }
+  Ok(())
+}
+
+/// @brief Calculates leave-one-chromosome-out Kinship matrices: the matrix
+/// of a chromosome is calculated from the markers of all other chromosomes.
+/// Streams source once, accumulating an unnormalized matrix G(c) and SNP
+/// weight w(c) per chromosome, then produces every matrix by subtraction from
+/// the totals: LOCO(c) = (G - G(c)) / (w - w(c)). SNP lines may come in any
+/// order, each one is routed to the accumulator of its chromosome.
+///
+/// @param[in] chromosomes chromosome of every SNP row id.
+///
+/// @note Holds an ids_num x ids_num accumulator per chromosome and returns
+/// normalized and mirrored (full) matrices keyed by chromosome.
+/// @note SNP lines are read as in calc_kinship_chromosomes.
+/// @note Returns Error::Validation if a row id is absent from chromosomes or
+/// a single chromosome holds all the markers, and for options which don't
+/// apply to per chromosome accumulation (see check_chromosome_options).
+pub fn calc_kinship_loco(
+  source: &mut dyn SnpSource,
+  chromosomes: &HashMap<String, String>,
+  options: &KinshipOptions,
+) -> std::io::Result<HashMap<String, Vec<f64>>> {
+  check_chromosome_options(options)?;
+  let ids_num = source.ids_num();
+  options.check_batch_rows(ids_num)?;
+  let batch_size = options.batch_rows(ids_num);
+  let f64_size = std::mem::size_of::<f64>() as u64;
+  let matrix_size = (ids_num * ids_num) as u64 * f64_size;
+  ensure_memory(matrix_size + (ids_num * batch_size) as u64 * f64_size)?;
+  let threads_num = options.threads_num();
+  let mut per_chromosome = HashMap::<&str, (Vec<f64>, f64)>::new();
+  let mut batch = vec![0.0; ids_num * batch_size];
+  let mut accumulated = Accumulated::start(options);
+  let mut lines = Vec::new();
+
+  loop {
+    let runs = read_chromosome_runs(
+      source,
+      &mut batch,
+      chromosomes,
+      options,
+      &mut accumulated,
+      &mut lines,
+    )?;
+    if runs.is_empty() {
+      break;
+    }
+    for (chr, rows, run_weight) in runs {
+      if !per_chromosome.contains_key(chr) {
+        // Another accumulator besides the ones held and the total.
+        ensure_memory(matrix_size * (per_chromosome.len() + 2) as u64)?;
+      }
+      let (matrix, weight) = per_chromosome
+        .entry(chr)
+        .or_insert_with(|| (vec![0.0; ids_num * ids_num], 0.0));
+      calc_partial_kinship_threaded(
+        &batch[rows.start * ids_num..rows.end * ids_num],
+        matrix,
+        ids_num,
+        threads_num,
+      );
+      *weight += run_weight;
+    }
+  }
+
+  let mut total = vec![0.0; ids_num * ids_num];
+  for (matrix, _) in per_chromosome.values() {
+    total.iter_mut().zip(matrix).for_each(|(total, value)| *total += value);
+  }
+  let mut loco = HashMap::with_capacity(per_chromosome.len());
+  for (chr, (mut matrix, weight)) in per_chromosome {
+    let weight = accumulated.weight - weight;
+    if weight <= 0.0 {
+      return Err(
+        Error::Validation(format!(
+          "All markers are on chromosome {}, there are none left out of it.",
+          chr
+        ))
+        .into(),
+      );
+    }
+    matrix.iter_mut().zip(&total).for_each(|(value, total)| *value = total - *value);
+    finalize_kinship_by(&mut matrix, ids_num, weight);
+    loco.insert(String::from(chr), matrix);
+  }
+  Ok(loco)
+}
+
+/// @brief Returns Error::Validation for KinshipOptions::kernel,
+/// KinshipOptions::compensated and KinshipOptions::time_limit, per
+/// chromosome accumulators are updated on the CPU without compensation and
+/// a matrix of a part of the markers is of no use.
+fn check_chromosome_options(options: &KinshipOptions) -> std::io::Result<()> {
+  let option = match (&options.kernel, options.compensated, &options.time_limit) {
+    (Some(_), _, _) => "Kinship kernel",
+    (_, true, _) => "Compensated summation",
+    (_, _, Some(_)) => "Time limit",
+    (None, false, None) => return Ok(()),
+  };
+  Err(
+    Error::Validation(format!(
+      "{} isn't supported by the per chromosome calculation.",
+      option
+    ))
+    .into(),
+  )
+}
+
+/// @brief Reads the next batch with read_weighted_batch into batch and splits
+/// its lines into runs of consecutive lines of the same chromosome, with the
+/// rows and the weight of each run. Row ids and weights of the lines are
+/// left in lines. Returns no runs at the end of source.
+fn read_chromosome_runs<'a>(
+  source: &mut dyn SnpSource,
+  batch: &mut [f64],
+  chromosomes: &'a HashMap<String, String>,
+  options: &KinshipOptions,
+  accumulated: &mut Accumulated,
+  lines: &mut Vec<(String, f64)>,
+) -> std::io::Result<Vec<(&'a str, Range<usize>, f64)>> {
+  lines.clear();
+  let rows = read_weighted_batch(source, batch, &mut [], options, accumulated, Some(lines))?;
+  let mut runs = Vec::new();
+  let mut run_start = 0;
+  while run_start < rows {
+    let chr = chromosome_of(chromosomes, &lines[run_start].0)?;
+    let mut run_end = run_start + 1;
+    while run_end < rows && chromosome_of(chromosomes, &lines[run_end].0)? == chr {
+      run_end += 1;
+    }
+    let weight = lines[run_start..run_end].iter().map(|(_, weight)| weight).sum();
+    runs.push((chr, run_start..run_end, weight));
+    run_start = run_end;
+  }
+  Ok(runs)
+}
+
+/// @brief Normalizes the matrix of chromosome by weight, see
+/// finalize_kinship_by.
+///
+/// @note Returns Error::Validation if the weight isn't positive.
+fn finalize_chromosome(
+  chromosome: &str,
+  matrix: &mut [f64],
+  ids_num: usize,
+  weight: f64,
+) -> std::io::Result<()> {
+  if weight <= 0.0 {
+    return Err(
+      Error::Validation(format!("Markers of chromosome {} have no weight.", chromosome)).into(),
+    );
+  }
+  finalize_kinship_by(matrix, ids_num, weight);
+  Ok(())
+}
+
+/// @brief Calculates leave-one-chromosome-out Kinship matrices of genotypes
+/// split into a file per chromosome. Chromosomes are calculated concurrently,
+/// the threads of options (see KinshipOptions::threads_num) are shared
+/// between them, then LOCO(c) = (G - G(c)) / (w - w(c)) for the sum G of the
+/// unnormalized chromosome matrices G(c) and the sums w of the SNP amounts
+/// (or marker weights).
+///
+/// @param[in] files chromosome and path of its genotype file, all the files
+/// must have the same ids in the same order.
+///
+/// @note options apply to every chromosome, so drop_monomorphic,
+/// marker_weights and missing work as in calc_kinship_observed.
+/// @note Returns Error::Validation if the ids of the files differ or there
+/// is a single chromosome.
+pub fn calc_kinship_loco_files<P: AsRef<Path> + Sync>(
+  files: &[(String, P)],
+  hab_mapper: &HashMap<char, f64>,
+  options: &KinshipOptions,
+) -> std::io::Result<HashMap<String, Vec<f64>>> {
+  let threads_num = options.threads_num();
+  let concurrent = files.len().clamp(1, threads_num);
+  let mut chromosome_options = options.clone();
+  chromosome_options.threads = Some((threads_num / concurrent).max(1));
+  let next = AtomicUsize::new(0);
+  let results = Mutex::new((0..files.len()).map(|_| None).collect::<Vec<_>>());
+  thread::scope(|scope| {
+    for _ in 0..concurrent {
+      scope.spawn(|| loop {
+        let pos = next.fetch_add(1, Ordering::Relaxed);
+        if pos >= files.len() {
+          break;
+        }
+        let res = accumulate_file(files[pos].1.as_ref(), hab_mapper, &chromosome_options);
+        results.lock().unwrap()[pos] = Some(res);
+      });
+    }
+  });
+
+  let mut chromosomes = Vec::with_capacity(files.len());
+  for res in results.into_inner().unwrap() {
+    chromosomes.push(res.expect("Every file is processed.")?);
+  }
+  let ids = chromosomes.first().map(|(ids, _, _)| ids.clone()).unwrap_or_default();
+  let ids_num = ids.len();
+  if let Some(pos) = chromosomes.iter().position(|(other, _, _)| *other != ids) {
+    return Err(
+      Error::Validation(format!(
+        "Ids of chromosome {} differ from the ids of chromosome {}.",
+        files[pos].0, files[0].0
+      ))
+      .into(),
+    );
+  }
+  let mut total = vec![0.0; ids_num * ids_num];
+  let mut total_weight = 0.0;
+  for (_, matrix, accumulated) in &chromosomes {
+    total.iter_mut().zip(matrix).for_each(|(total, value)| *total += value);
+    total_weight += accumulated.weight;
+  }
+
+  let mut loco = HashMap::with_capacity(files.len());
+  for ((chromosome, _), (_, mut matrix, accumulated)) in files.iter().zip(chromosomes) {
+    let weight = total_weight - accumulated.weight;
+    if weight <= 0.0 {
+      return Err(
+        Error::Validation(format!(
+          "All markers are on chromosome {}, there are none left out of it.",
+          chromosome
+        ))
+        .into(),
+      );
+    }
+    matrix.iter_mut().zip(&total).for_each(|(value, total)| *value = total - *value);
+    finalize_kinship_by(&mut matrix, ids_num, weight);
+    loco.insert(chromosome.clone(), matrix);
+  }
+  Ok(loco)
+}
+
+/// @brief Ids, upper part of the unnormalized Kinship matrix and the
+/// accumulated amounts of the genotype file at path.
+fn accumulate_file(
+  path: &Path,
+  hab_mapper: &HashMap<char, f64>,
+  options: &KinshipOptions,
+) -> std::io::Result<(Vec<String>, Vec<f64>, Accumulated)> {
+  let mut geno = GenoParser::new_with_file(File::open(path)?, hab_mapper.clone())?;
+  geno.file_reader.seek(SeekFrom::Start(geno.snp_pos_start))?;
+  let mut source = geno.snp_source(options.missing.allows_na())?;
+  let (matrix, accumulated) = accumulate_kinship(&mut source, options, &mut [])?;
+  Ok((geno.markers, matrix, accumulated))
+}
+
+fn chromosome_of<'a>(
+  chromosomes: &'a HashMap<String, String>,
+  row_id: &str,
+) -> Result<&'a str, Error> {
+  chromosomes
+    .get(row_id)
+    .map(|chr| chr.as_str())
+    .ok_or_else(|| Error::Validation(format!("SNP <{}> is absent from the map.", row_id)))
+}
+
+/// @brief Returns on_complete callback for calc_kinship_chromosomes which
+/// writes each chromosome matrix to `<dir>/<chromosome>.kinship` (see
+/// MappedKinship for the format) and frees it. Written paths are pushed to
+/// written.
+pub fn write_chromosome_kinship<'a>(
+  dir: &'a Path,
+  written: &'a mut Vec<(String, PathBuf)>,
+) -> impl FnMut(ChromosomeKinship) -> std::io::Result<()> + 'a {
+  move |chr| {
+    let path = dir.join(format!("{}.kinship", chr.chromosome));
+    let mut mapped = MappedKinship::create(&path, chr.ids_num)?;
+    mapped.as_mut_slice().copy_from_slice(&chr.matrix);
+    mapped.flush()?;
+    written.push((chr.chromosome, path));
+    Ok(())
+  }
+}
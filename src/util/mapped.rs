@@ -0,0 +1,104 @@
+// mapped.rs
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use super::chunked::ChunkedGenoReader;
+use super::error::Error;
+use super::kinship::SnpSource;
+use super::GenoParser;
+
+/// @brief GenoParser of a memory-mapped genotype file, see open_mapped.
+pub type MappedGenoParser = GenoParser<Cursor<Mmap>>;
+
+impl GenoParser<Cursor<Mmap>> {
+  /// @brief Maps genotype file at path into memory. SNP lines are parsed
+  /// directly from the mapped bytes, without copying them into a BufReader
+  /// buffer first, and can be split into ranges parsed independently, see
+  /// line_ranges.
+  ///
+  /// @note The file must not be modified while mapped, reading a truncated
+  /// mapping crashes the process.
+  pub fn open_mapped<P: AsRef<Path>>(
+    path: P,
+    hab_mapper: HashMap<char, f64>,
+  ) -> std::io::Result<Self> {
+    let file = File::open(path)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    Self::from_reader(Cursor::new(mmap), hab_mapper)
+  }
+
+  /// @brief Splits SNP lines into at most parts ranges (byte offsets in the
+  /// file) of about the same size, each of whole lines.
+  pub fn line_ranges(&self, parts: usize) -> Vec<Range<u64>> {
+    let bytes = self.file_reader.get_ref();
+    let (start, end) = (self.snp_pos_start as usize, bytes.len());
+    let parts = parts.max(1);
+    let mut ranges = Vec::with_capacity(parts);
+    let mut range_start = start;
+    for part in 1..=parts {
+      let target = (start + (end - start) * part / parts).max(range_start);
+      let range_end = match bytes[target..].iter().position(|b| *b == b'\n') {
+        Some(pos) if part < parts => target + pos + 1,
+        _ => end,
+      };
+      if range_end > range_start {
+        ranges.push(range_start as u64..range_end as u64);
+      }
+      range_start = range_end;
+    }
+    ranges
+  }
+
+  /// @brief Calls f with SnpSource over the SNP lines of range (see
+  /// line_ranges), parsed directly from the mapped bytes. Takes &self, so
+  /// ranges can be parsed by several threads at once. Lines with missing
+  /// genotypes are rejected unless allow_na is set.
+  ///
+  /// @note Returns Error::Validation for a transposed file.
+  pub fn with_range<T, F>(&self, range: Range<u64>, allow_na: bool, f: F) -> std::io::Result<T>
+  where
+    F: FnOnce(&mut dyn SnpSource) -> std::io::Result<T>,
+  {
+    self.ensure_snp_rows()?;
+    let bytes = self.file_reader.get_ref();
+    let (start, end) = (range.start as usize, range.end as usize);
+    // Lines without row ids are numbered from the first SNP line.
+    let lines_before = match self.has_row_ids {
+      true => 0,
+      false => bytes[self.snp_pos_start as usize..start]
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.iter().all(|b| b.is_ascii_whitespace()))
+        .count(),
+    };
+    let mut range_bytes = &bytes[start..end];
+    let ids_num = self.markers.len();
+    let source = ChunkedGenoReader::new(&mut range_bytes, ids_num, &self.hab_mapper, allow_na)?;
+    let mut source = match self.has_row_ids {
+      true => source,
+      false => source.without_row_ids(),
+    }
+    .starting_at(range.start, lines_before)
+    .at_line(1);
+    f(&mut source).map_err(|err| match Error::from(err) {
+      // Lines of the range to lines of the file, counted only on failure.
+      Error::Parse {
+        line: Some(line),
+        msg,
+      } => {
+        let newlines = bytes[..start].iter().filter(|b| **b == b'\n').count();
+        Error::Parse {
+          line: Some(newlines + line - lines_before),
+          msg,
+        }
+        .into()
+      }
+      err => err.into(),
+    })
+  }
+}
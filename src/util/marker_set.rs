@@ -0,0 +1,60 @@
+// marker_set.rs
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use super::gmap::GeneticMap;
+use super::text;
+
+/// @brief Markers (SNP row ids) a Kinship matrix is calculated from, e.g.
+/// LD-pruned markers or the markers of a genomic region. See
+/// KinshipOptions::markers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MarkerSet {
+  markers: HashSet<String>,
+}
+
+impl MarkerSet {
+  pub fn new(markers: HashSet<String>) -> Self {
+    MarkerSet { markers }
+  }
+
+  /// @brief Markers of gmap on chromosome within [start, end], see
+  /// GeneticMap::region.
+  pub fn region(gmap: &GeneticMap, chromosome: &str, start: f64, end: f64) -> Self {
+    let markers = gmap.region(chromosome, start, end).into_iter().map(|marker| marker.id.clone());
+    MarkerSet::new(markers.collect())
+  }
+
+  /// @brief Reads marker list file: a marker per line (e.g. PLINK
+  /// --extract lists). Blank lines and lines starting with '#' are skipped.
+  pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    Self::read_from(BufReader::new(File::open(path)?))
+  }
+
+  pub fn read_from<R: BufRead>(reader: R) -> std::io::Result<Self> {
+    let mut markers = HashSet::new();
+    for line in text::Lines::new(reader) {
+      let line = line?;
+      let marker = line.trim();
+      if !marker.is_empty() && !marker.starts_with('#') {
+        markers.insert(String::from(marker));
+      }
+    }
+    Ok(MarkerSet::new(markers))
+  }
+
+  pub fn contains(&self, id: &str) -> bool {
+    self.markers.contains(id)
+  }
+
+  pub fn len(&self) -> usize {
+    self.markers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.markers.is_empty()
+  }
+}
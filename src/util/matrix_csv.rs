@@ -0,0 +1,413 @@
+// matrix_csv.rs
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use super::error::Error;
+use super::kinship::SnpSource;
+use super::na;
+use super::output::NumberFormat;
+use super::parse_report::{codes, ParseReport};
+use super::text;
+
+/// @brief Reader for R/qtl2 matrix-like CSV files (covar, phenocovar,
+/// founder geno, etc.).
+///
+/// @note https://kbroman.org/qtl2/assets/vignettes/input_files.html
+///
+/// Lines starting with '#' are comments. The first line contains column IDs,
+/// the first column contains row IDs. Fields matching one of na_strings are
+/// treated as missing values.
+pub struct MatrixCsvReader {
+  delimiter: char,
+  na_strings: Vec<String>,
+}
+
+impl Default for MatrixCsvReader {
+  fn default() -> Self {
+    MatrixCsvReader {
+      delimiter: ',',
+      na_strings: na::default_na_strings(),
+    }
+  }
+}
+
+impl MatrixCsvReader {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_delimiter(mut self, delimiter: char) -> Self {
+    self.delimiter = delimiter;
+    self
+  }
+
+  /// @brief Sets strings which denote missing values (R/qtl2 na.strings).
+  pub fn with_na_strings(mut self, na_strings: Vec<String>) -> Self {
+    self.na_strings = na_strings;
+    self
+  }
+
+  /// @brief Fields of line split at the delimiter, unquoted, see
+  /// split_fields.
+  pub(crate) fn fields(&self, line: &str) -> std::vec::IntoIter<String> {
+    split_fields(line, self.delimiter).into_iter()
+  }
+
+  /// @brief Whether field is one of na_strings.
+  pub(crate) fn is_na(&self, field: &str) -> bool {
+    self.na_strings.iter().any(|na| na == field)
+  }
+
+  /// @brief Reads table from file at path.
+  pub fn read<P: AsRef<Path>>(&self, path: P) -> std::io::Result<LabeledTable> {
+    self.read_from(BufReader::new(File::open(path)?))
+  }
+
+  /// @brief Reads table from any buffered reader.
+  pub fn read_from<R: BufRead>(&self, reader: R) -> std::io::Result<LabeledTable> {
+    let invalid = |msg: String| -> std::io::Error { Error::Parse { line: None, msg }.into() };
+    let mut comments = Vec::<String>::new();
+    let mut header: Option<Vec<String>> = None;
+    let mut row_ids = Vec::<String>::new();
+    let mut cells = Vec::<Option<String>>::new();
+    let mut lines = text::Lines::new(reader);
+    for (line_num, line) in lines.by_ref().enumerate() {
+      let line = line?;
+      let line = line.trim_end_matches('\r');
+      if let Some(comment) = line.strip_prefix('#') {
+        comments.push(String::from(comment));
+        continue;
+      }
+      if line.trim().is_empty() {
+        continue;
+      }
+      let mut fields = self.fields(line);
+      let row_id = fields.next().unwrap_or_default();
+      let fields = fields.collect::<Vec<String>>();
+      match &header {
+        None => header = Some([vec![row_id], fields].concat()),
+        Some(header) => {
+          if fields.len() + 1 != header.len() {
+            return Err(invalid(format!(
+              "Line {} has {} fields, however the header has {}.",
+              line_num + 1,
+              fields.len() + 1,
+              header.len()
+            )));
+          }
+          row_ids.push(row_id);
+          cells.extend(fields.into_iter().map(|field| {
+            if self.is_na(&field) {
+              None
+            } else {
+              Some(field)
+            }
+          }));
+        }
+      }
+    }
+    let mut header = header.ok_or_else(|| invalid(String::from("File is empty.")))?;
+    let col_ids = header.split_off(1);
+    let mut report = ParseReport::new();
+    for line_num in lines.latin1_lines() {
+      report.warn(
+        codes::LATIN1_DECODED,
+        Some(*line_num),
+        String::from("Line is not valid UTF-8, it was decoded as Latin-1."),
+      );
+    }
+    let mut seen = HashSet::new();
+    for id in row_ids.iter().filter(|id| !seen.insert(id.as_str())) {
+      report.warn(codes::DUPLICATE_ID, None, format!("Row <{}> is listed more than once.", id));
+    }
+    Ok(LabeledTable {
+      comments,
+      report,
+      corner: header.pop().unwrap_or_default(),
+      row_ids,
+      col_ids,
+      cells,
+    })
+  }
+}
+
+/// @brief Fields of line split at delimiter, trimmed and unquoted. A field
+/// in quotes may hold the delimiter, a doubled quote in it stands for a
+/// quote. A quote which isn't closed is kept as is.
+pub(crate) fn split_fields(line: &str, delimiter: char) -> Vec<String> {
+  let mut fields = Vec::new();
+  let mut rest = line;
+  loop {
+    let (field, next) = match quoted_field(rest, delimiter) {
+      Some(quoted) => quoted,
+      None => match rest.split_once(delimiter) {
+        Some((field, next)) => (String::from(field.trim()), Some(next)),
+        None => (String::from(rest.trim()), None),
+      },
+    };
+    fields.push(field);
+    match next {
+      Some(next) => rest = next,
+      None => return fields,
+    }
+  }
+}
+
+/// @brief Unquoted field at the start of line if it's in quotes, with the
+/// rest of line after its delimiter (None for the last field).
+fn quoted_field(line: &str, delimiter: char) -> Option<(String, Option<&str>)> {
+  let field = line.trim_start_matches(|ch: char| ch.is_whitespace() && ch != delimiter);
+  let quoted = field.strip_prefix('"')?;
+  let end = closing_quote(quoted)?;
+  let after = &quoted[end + 1..];
+  let (tail, next) = match after.split_once(delimiter) {
+    Some((tail, next)) => (tail, Some(next)),
+    None => (after, None),
+  };
+  Some((quoted[..end].replace("\"\"", "\"") + tail.trim(), next))
+}
+
+/// @brief Position of the quote closing quoted (the text after the opening
+/// one), skipping doubled quotes.
+fn closing_quote(quoted: &str) -> Option<usize> {
+  let bytes = quoted.as_bytes();
+  let mut pos = 0;
+  while let Some(found) = quoted[pos..].find('"') {
+    let quote = pos + found;
+    if bytes.get(quote + 1) != Some(&b'"') {
+      return Some(quote);
+    }
+    pos = quote + 2;
+  }
+  None
+}
+
+pub(crate) fn unquote(field: &str) -> String {
+  let field = field.trim();
+  match field.strip_prefix('"').and_then(|field| field.strip_suffix('"')) {
+    Some(quoted) => quoted.replace("\"\"", "\""),
+    None => String::from(field),
+  }
+}
+
+/// @brief Table of string cells labeled by row and column IDs. Missing values
+/// are None.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabeledTable {
+  pub comments: Vec<String>,
+  /// @note Problems which didn't prevent parsing, e.g. lines decoded as
+  /// Latin-1.
+  pub report: ParseReport,
+  /// @note Top-left field of the header line (e.g. "id").
+  pub corner: String,
+  pub row_ids: Vec<String>,
+  pub col_ids: Vec<String>,
+  /// @note Cells in row-major order.
+  pub cells: Vec<Option<String>>,
+}
+
+impl LabeledTable {
+  pub fn get(&self, row: usize, col: usize) -> Option<&str> {
+    self.cells[row * self.col_ids.len() + col].as_deref()
+  }
+
+  pub fn col_index(&self, col_id: &str) -> Option<usize> {
+    self.col_ids.iter().position(|id| id == col_id)
+  }
+
+  pub fn row_index(&self, row_id: &str) -> Option<usize> {
+    self.row_ids.iter().position(|id| id == row_id)
+  }
+
+  /// @brief Returns cells of the column at index col.
+  pub fn column(&self, col: usize) -> Vec<Option<&str>> {
+    (0..self.row_ids.len()).map(|row| self.get(row, col)).collect()
+  }
+
+  /// @brief Determines whether all non missing cells of the column are numbers.
+  pub fn is_numeric_column(&self, col: usize) -> bool {
+    self
+      .column(col)
+      .iter()
+      .flatten()
+      .all(|cell| cell.parse::<f64>().is_ok())
+  }
+
+  /// @brief Parses the column at index col as numbers, missing values become
+  /// na::NA.
+  pub fn numeric_column(&self, col: usize) -> std::io::Result<Vec<f64>> {
+    self
+      .column(col)
+      .into_iter()
+      .enumerate()
+      .map(|(row, cell)| self.parse_cell(row, col, cell))
+      .collect()
+  }
+
+  /// @brief Converts the whole table to numbers. Fails on the first cell
+  /// which is not a number.
+  pub fn to_numeric(&self) -> std::io::Result<NumericTable> {
+    let cols_num = self.col_ids.len();
+    let values = self
+      .cells
+      .iter()
+      .enumerate()
+      .map(|(i, cell)| self.parse_cell(i / cols_num, i % cols_num, cell.as_deref()))
+      .collect::<std::io::Result<Vec<f64>>>()?;
+    Ok(NumericTable {
+      row_ids: self.row_ids.clone(),
+      col_ids: self.col_ids.clone(),
+      values,
+    })
+  }
+
+  fn parse_cell(&self, row: usize, col: usize, cell: Option<&str>) -> std::io::Result<f64> {
+    match cell {
+      None => Ok(na::NA),
+      Some(cell) => cell.parse::<f64>().map_err(|_| {
+        Error::Parse {
+          line: None,
+          msg: format!(
+            "Value <{}> at row <{}>, column <{}> is not a number.",
+            cell, self.row_ids[row], self.col_ids[col]
+          ),
+        }
+        .into()
+      }),
+    }
+  }
+}
+
+/// @brief Table of numbers labeled by row and column IDs. Missing values are
+/// na::NA.
+#[derive(Clone, Debug)]
+pub struct NumericTable {
+  pub row_ids: Vec<String>,
+  pub col_ids: Vec<String>,
+  /// @note Values in row-major order.
+  pub values: Vec<f64>,
+}
+
+impl NumericTable {
+  pub fn get(&self, row: usize, col: usize) -> f64 {
+    self.values[row * self.col_ids.len() + col]
+  }
+
+  /// @brief SnpSource over the rows of the table, with columns as
+  /// individuals (e.g. dosages written by probs::write_dosages).
+  pub fn snp_source(&self) -> TableSnps<'_> {
+    TableSnps {
+      table: self,
+      row: 0,
+    }
+  }
+}
+
+/// @brief SnpSource reading rows of a NumericTable, see
+/// NumericTable::snp_source.
+pub struct TableSnps<'a> {
+  table: &'a NumericTable,
+  row: usize,
+}
+
+impl<'a> SnpSource for TableSnps<'a> {
+  fn ids_num(&self) -> usize {
+    self.table.col_ids.len()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let ids_num = self.table.col_ids.len();
+    if ids_num == 0 {
+      return Ok(0);
+    }
+    let rows = (buf.len() / ids_num).min(self.table.row_ids.len() - self.row);
+    let values = &self.table.values[self.row * ids_num..(self.row + rows) * ids_num];
+    buf[..rows * ids_num].copy_from_slice(values);
+    if let Some(row_ids) = row_ids.as_mut() {
+      row_ids.extend_from_slice(&self.table.row_ids[self.row..self.row + rows]);
+    }
+    self.row += rows;
+    Ok(rows)
+  }
+}
+
+/// @brief Writer of NumericTable in the format read by MatrixCsvReader.
+pub struct MatrixCsvWriter {
+  delimiter: char,
+  corner: String,
+  number_format: NumberFormat,
+}
+
+impl Default for MatrixCsvWriter {
+  fn default() -> Self {
+    MatrixCsvWriter {
+      delimiter: ',',
+      corner: String::from("id"),
+      number_format: NumberFormat::default(),
+    }
+  }
+}
+
+impl MatrixCsvWriter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_delimiter(mut self, delimiter: char) -> Self {
+    self.delimiter = delimiter;
+    self
+  }
+
+  /// @brief Sets the first field of the header line.
+  pub fn with_corner(mut self, corner: &str) -> Self {
+    self.corner = String::from(corner);
+    self
+  }
+
+  pub fn with_number_format(mut self, number_format: NumberFormat) -> Self {
+    self.number_format = number_format;
+    self
+  }
+
+  /// @brief Writes table to file at path.
+  pub fn write<P: AsRef<Path>>(&self, path: P, table: &NumericTable) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    self.write_to(&mut writer, table)?;
+    writer.flush()
+  }
+
+  /// @brief Writes table to any writer.
+  pub fn write_to<W: Write>(&self, writer: &mut W, table: &NumericTable) -> std::io::Result<()> {
+    let mut delimiter = [0; 4];
+    let delimiter = self.delimiter.encode_utf8(&mut delimiter).as_bytes();
+    writer.write_all(self.corner.as_bytes())?;
+    for col_id in &table.col_ids {
+      writer.write_all(delimiter)?;
+      writer.write_all(col_id.as_bytes())?;
+    }
+    writer.write_all(b"\n")?;
+    for (row_id, row) in table
+      .row_ids
+      .iter()
+      .zip(table.values.chunks(table.col_ids.len().max(1)))
+    {
+      writer.write_all(row_id.as_bytes())?;
+      for value in row {
+        writer.write_all(delimiter)?;
+        self.number_format.write(writer, *value)?;
+      }
+      writer.write_all(b"\n")?;
+    }
+    Ok(())
+  }
+}
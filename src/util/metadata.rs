@@ -0,0 +1,85 @@
+// metadata.rs
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::error::Error;
+use super::matrix_csv::{LabeledTable, MatrixCsvReader};
+
+/// @brief Arbitrary per-individual metadata (e.g. sex, cohort from the R/qtl2
+/// covar file), looked up by individual id.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleMetadata {
+  columns: Vec<String>,
+  rows: HashMap<String, Vec<Option<String>>>,
+}
+
+impl SampleMetadata {
+  /// @brief Reads covar-like CSV file: individuals as rows.
+  pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    Ok(Self::from_table(&MatrixCsvReader::new().read(path)?))
+  }
+
+  pub fn from_table(table: &LabeledTable) -> Self {
+    let cols_num = table.col_ids.len().max(1);
+    SampleMetadata {
+      columns: table.col_ids.clone(),
+      rows: table
+        .row_ids
+        .iter()
+        .cloned()
+        .zip(table.cells.chunks(cols_num).map(|row| row.to_vec()))
+        .collect(),
+    }
+  }
+
+  pub fn columns(&self) -> &[String] {
+    &self.columns
+  }
+
+  /// @brief Value of column for individual id, None if either is absent or
+  /// the value is missing.
+  pub fn get(&self, id: &str, column: &str) -> Option<&str> {
+    let col = self.columns.iter().position(|c| c == column)?;
+    self.rows.get(id)?[col].as_deref()
+  }
+
+  /// @brief Values of all columns for individual id.
+  pub fn values(&self, id: &str) -> Vec<Option<&str>> {
+    match self.rows.get(id) {
+      Some(row) => row.iter().map(|value| value.as_deref()).collect(),
+      None => vec![None; self.columns.len()],
+    }
+  }
+
+  /// @brief Keeps only given columns, in the given order.
+  ///
+  /// @note Returns Error::Validation for an unknown column.
+  pub fn select(&self, columns: &[&str]) -> std::io::Result<Self> {
+    let positions = columns
+      .iter()
+      .map(|column| {
+        self.columns.iter().position(|c| c == column).ok_or_else(|| {
+          Error::Validation(format!("There is no metadata column <{}>.", column))
+        })
+      })
+      .collect::<Result<Vec<usize>, Error>>()?;
+    Ok(SampleMetadata {
+      columns: columns.iter().map(|column| String::from(*column)).collect(),
+      rows: self
+        .rows
+        .iter()
+        .map(|(id, row)| (id.clone(), positions.iter().map(|pos| row[*pos].clone()).collect()))
+        .collect(),
+    })
+  }
+
+  /// @brief Ids which have no metadata.
+  pub fn missing_ids<'a>(&self, ids: &'a [String]) -> Vec<&'a str> {
+    ids
+      .iter()
+      .filter(|id| !self.rows.contains_key(*id))
+      .map(|id| id.as_str())
+      .collect()
+  }
+}
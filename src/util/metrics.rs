@@ -0,0 +1,115 @@
+// metrics.rs
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use super::parse_report::{codes, ParseReport};
+
+/// @brief Batch which was recalculated on the CPU after its kernel failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelFallback {
+  /// @note Name of the failed kernel.
+  pub kernel: &'static str,
+  /// @note Sequential number of the batch, starting from 0.
+  pub batch: usize,
+  pub reason: String,
+}
+
+/// @brief Counters of a running calculation. Shared between threads, so it
+/// can be inspected while the calculation is in process.
+#[derive(Debug, Default)]
+pub struct Metrics {
+  batches: AtomicUsize,
+  snps: AtomicUsize,
+  monomorphic: AtomicUsize,
+  incomplete: AtomicUsize,
+  rare: AtomicUsize,
+  excluded: AtomicUsize,
+  fallbacks: Mutex<Vec<KernelFallback>>,
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    Metrics::default()
+  }
+
+  pub(crate) fn record_batch(&self, rows: usize) {
+    self.batches.fetch_add(1, Ordering::Relaxed);
+    self.snps.fetch_add(rows, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_monomorphic(&self, rows: usize) {
+    self.monomorphic.fetch_add(rows, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_incomplete(&self, rows: usize) {
+    self.incomplete.fetch_add(rows, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_rare(&self, rows: usize) {
+    self.rare.fetch_add(rows, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_excluded(&self, rows: usize) {
+    self.excluded.fetch_add(rows, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_fallback(&self, fallback: KernelFallback) {
+    self.fallbacks.lock().unwrap().push(fallback);
+  }
+
+  /// @brief Amount of batches read so far.
+  pub fn batches(&self) -> usize {
+    self.batches.load(Ordering::Relaxed)
+  }
+
+  /// @brief Amount of SNP lines accumulated so far.
+  pub fn snps(&self) -> usize {
+    self.snps.load(Ordering::Relaxed)
+  }
+
+  /// @brief Amount of monomorphic SNP lines dropped so far, see
+  /// KinshipOptions::drop_monomorphic.
+  pub fn monomorphic(&self) -> usize {
+    self.monomorphic.load(Ordering::Relaxed)
+  }
+
+  /// @brief Amount of SNP lines with missing genotypes dropped so far, see
+  /// MissingPolicy::SkipMarker.
+  pub fn incomplete(&self) -> usize {
+    self.incomplete.load(Ordering::Relaxed)
+  }
+
+  /// @brief Amount of SNP lines dropped so far for their minor allele
+  /// frequency, see KinshipOptions::maf_filter.
+  pub fn rare(&self) -> usize {
+    self.rare.load(Ordering::Relaxed)
+  }
+
+  /// @brief Amount of SNP lines skipped so far for their markers, see
+  /// KinshipOptions::markers.
+  pub fn excluded(&self) -> usize {
+    self.excluded.load(Ordering::Relaxed)
+  }
+
+  /// @brief Batches recalculated on the CPU, in order of occurrence.
+  pub fn fallbacks(&self) -> Vec<KernelFallback> {
+    self.fallbacks.lock().unwrap().clone()
+  }
+
+  /// @brief Fallbacks as report warnings.
+  pub fn report(&self) -> ParseReport {
+    let mut report = ParseReport::new();
+    for fallback in self.fallbacks() {
+      report.warn(
+        codes::FALLBACK,
+        None,
+        format!(
+          "Batch {} was recalculated on the CPU after kernel <{}> failed: {}",
+          fallback.batch, fallback.kernel, fallback.reason
+        ),
+      );
+    }
+    report
+  }
+}
@@ -0,0 +1,47 @@
+// missing.rs
+
+use super::na;
+
+/// @brief Handling of missing genotypes (na::NA, e.g. '-' mapped to NA by
+/// hab_mapper) in the Kinship matrix calculation.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum MissingPolicy {
+  /// @brief SNP lines with missing genotypes are an error.
+  #[default]
+  Reject,
+  /// @brief SNP lines with missing genotypes are left out of the calculation
+  /// and the normalization.
+  SkipMarker,
+  /// @brief Missing genotypes get the mean of the present ones of the marker.
+  MeanImpute,
+  /// @brief Missing genotypes get the value.
+  Fixed(f64),
+}
+
+impl MissingPolicy {
+  /// @brief Determines whether sources should pass lines with missing
+  /// genotypes on.
+  pub fn allows_na(&self) -> bool {
+    *self != MissingPolicy::Reject
+  }
+
+  /// @brief Determines whether line is used under the policy.
+  pub(crate) fn keeps(&self, line: &[f64]) -> bool {
+    *self != MissingPolicy::SkipMarker || !line.iter().any(|value| na::is_na(*value))
+  }
+
+  /// @brief Replaces missing genotypes of snps, ids_num values per SNP line.
+  ///
+  /// @note Lines with no present genotype are imputed with 0 by MeanImpute.
+  pub(crate) fn impute(&self, snps: &mut [f64], ids_num: usize) {
+    for line in snps.chunks_mut(ids_num.max(1)) {
+      let value = match self {
+        MissingPolicy::Reject | MissingPolicy::SkipMarker => return,
+        _ if !line.iter().any(|snp| na::is_na(*snp)) => continue,
+        MissingPolicy::MeanImpute => na::to_option(na::mean(line)).unwrap_or(0.0),
+        MissingPolicy::Fixed(value) => *value,
+      };
+      line.iter_mut().filter(|snp| na::is_na(**snp)).for_each(|snp| *snp = value);
+    }
+  }
+}
@@ -0,0 +1,159 @@
+// multi_allele.rs
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use super::error::Error;
+use super::kinship::SnpSource;
+use super::text;
+
+/// @brief Genotype alphabet where a code stands for a vector of dosages, e.g.
+/// founder codes of a multiparent cross mapped to the founder contributions
+/// (the generalization of hab_mapper).
+#[derive(Clone, Debug, PartialEq)]
+pub struct DosageCodes {
+  dims: usize,
+  codes: HashMap<char, Vec<f64>>,
+}
+
+impl DosageCodes {
+  /// @note Returns Error::Validation if there are no codes or their vectors
+  /// differ in length.
+  pub fn new(codes: HashMap<char, Vec<f64>>) -> std::io::Result<Self> {
+    let dims = codes.values().next().map_or(0, |dosages| dosages.len());
+    if dims == 0 {
+      return Err(Error::Validation(String::from("Dosage codes can't be empty.")).into());
+    }
+    if let Some((code, dosages)) = codes.iter().find(|(_, dosages)| dosages.len() != dims) {
+      return Err(
+        Error::Validation(format!(
+          "Code <{}> has {} dosages, however the others have {}.",
+          code,
+          dosages.len(),
+          dims
+        ))
+        .into(),
+      );
+    }
+    Ok(DosageCodes { dims, codes })
+  }
+
+  /// @brief Length of the dosage vectors.
+  pub fn dims(&self) -> usize {
+    self.dims
+  }
+
+  pub fn get(&self, code: char) -> Option<&[f64]> {
+    self.codes.get(&code).map(|dosages| dosages.as_slice())
+  }
+}
+
+/// @brief SnpSource over genotype file lines coded with DosageCodes. Every
+/// marker is widened to dims SNP lines (a line per dosage component, with the
+/// row id of the marker), so GᵀG sums the dot products of the dosage vectors.
+///
+/// @note Lines are scaled by sqrt(dims), so the Kinship matrix stays
+/// normalized by the amount of markers rather than of the widened lines.
+pub struct MultiAlleleLines<'a, R: BufRead> {
+  lines: text::Lines<&'a mut R>,
+  ids_num: usize,
+  codes: &'a DosageCodes,
+  /// @note Widened lines of the current marker not returned yet.
+  pending: Vec<f64>,
+  pending_rows: usize,
+  row_id: String,
+}
+
+impl<'a, R: BufRead> MultiAlleleLines<'a, R> {
+  /// @note Reader cursor must be located at the beginning of SNP lines.
+  pub fn new(reader: &'a mut R, ids_num: usize, codes: &'a DosageCodes) -> Self {
+    MultiAlleleLines {
+      lines: text::Lines::new(reader),
+      ids_num,
+      codes,
+      pending: vec![0.0; codes.dims * ids_num],
+      pending_rows: 0,
+      row_id: String::new(),
+    }
+  }
+
+  /// @brief Decodes the next marker into pending, skipping blank lines.
+  /// Returns false at the end of the lines.
+  fn read_marker(&mut self) -> std::io::Result<bool> {
+    let line = loop {
+      match self.lines.next() {
+        Some(line) => {
+          let line = line?;
+          if !line.trim().is_empty() {
+            break line;
+          }
+        }
+        None => return Ok(false),
+      }
+    };
+    let invalid = |msg: String| Error::Parse {
+      line: None,
+      msg: format!("This line <{}> is an invalid SNP record: {}", line, msg),
+    };
+    let (row_id, genotypes) = line
+      .split_once('\t')
+      .ok_or_else(|| invalid(String::from("snp record and row id should be separated with tab.")))?;
+    let genotypes = genotypes.split('\t').next().unwrap_or_default();
+    if genotypes.chars().count() != self.ids_num {
+      return Err(
+        Error::DimensionMismatch {
+          marker: String::from(row_id),
+          expected: self.ids_num,
+          found: genotypes.chars().count(),
+        }
+        .into(),
+      );
+    }
+    let (dims, ids_num) = (self.codes.dims, self.ids_num);
+    let scale = (dims as f64).sqrt();
+    for (id, code) in genotypes.chars().enumerate() {
+      let dosages = self.codes.get(code).ok_or_else(|| Error::UnknownGenotypeCode {
+        marker: String::from(row_id),
+        column: id + 1,
+        code,
+      })?;
+      for (dim, dosage) in dosages.iter().enumerate() {
+        self.pending[dim * ids_num + id] = dosage * scale;
+      }
+    }
+    self.row_id = String::from(row_id);
+    self.pending_rows = dims;
+    Ok(true)
+  }
+}
+
+impl<'a, R: BufRead> SnpSource for MultiAlleleLines<'a, R> {
+  fn ids_num(&self) -> usize {
+    self.ids_num
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let (dims, ids_num) = (self.codes.dims, self.ids_num);
+    let capacity = buf.len() / ids_num.max(1);
+    let mut rows = 0;
+    while rows < capacity {
+      if self.pending_rows == 0 && !self.read_marker()? {
+        break;
+      }
+      let count = self.pending_rows.min(capacity - rows);
+      let first = dims - self.pending_rows;
+      buf[rows * ids_num..(rows + count) * ids_num]
+        .copy_from_slice(&self.pending[first * ids_num..(first + count) * ids_num]);
+      if let Some(row_ids) = row_ids.as_deref_mut() {
+        row_ids.extend(std::iter::repeat_n(self.row_id.clone(), count));
+      }
+      self.pending_rows -= count;
+      rows += count;
+    }
+    Ok(rows)
+  }
+}
@@ -0,0 +1,55 @@
+// na.rs
+
+//! Missing numeric values are represented by NaN everywhere in the crate:
+//! parsers produce NA for missing cells, and calculations either skip NA
+//! values explicitly or refuse them with an error. Use the helpers below
+//! rather than comparing values to NaN directly.
+
+/// @brief Value representing a missing numeric value.
+pub const NA: f64 = f64::NAN;
+
+/// @brief Strings treated as missing values by default (R/qtl2 na.strings).
+pub const DEFAULT_NA_STRINGS: [&str; 2] = ["NA", "-"];
+
+pub fn is_na(value: f64) -> bool {
+  value.is_nan()
+}
+
+/// @brief Converts NA to None.
+pub fn to_option(value: f64) -> Option<f64> {
+  if is_na(value) {
+    None
+  } else {
+    Some(value)
+  }
+}
+
+/// @brief Converts None to NA.
+pub fn from_option(value: Option<f64>) -> f64 {
+  value.unwrap_or(NA)
+}
+
+pub fn default_na_strings() -> Vec<String> {
+  DEFAULT_NA_STRINGS.iter().map(|s| String::from(*s)).collect()
+}
+
+pub fn count_na(values: &[f64]) -> usize {
+  values.iter().filter(|value| is_na(**value)).count()
+}
+
+/// @brief Iterates over values which are not NA.
+pub fn present(values: &[f64]) -> impl Iterator<Item = f64> + '_ {
+  values.iter().copied().filter(|value| !is_na(*value))
+}
+
+/// @brief Mean of values which are not NA, NA if there are none.
+pub fn mean(values: &[f64]) -> f64 {
+  let (sum, count) = present(values).fold((0.0, 0usize), |(sum, count), value| {
+    (sum + value, count + 1)
+  });
+  if count == 0 {
+    NA
+  } else {
+    sum / count as f64
+  }
+}
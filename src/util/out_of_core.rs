@@ -0,0 +1,248 @@
+// out_of_core.rs
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+use memmap2::MmapMut;
+
+use super::error::Error;
+use super::idx::IndividualIdx;
+use super::kinship::{
+  accumulate_column_blocks, check_snps_num, check_weight, finalize_kinship_by, GenoLines,
+  SnpSource,
+};
+use super::kinship_matrix::check_csv_ids;
+use super::output::{write_gemma_rows, NumberFormat};
+use super::report::write_record;
+use super::KinshipOptions;
+
+/// @brief Kinship matrix stored in a memory-mapped file.
+///
+/// The file contains ids_num x ids_num native-endian f64 values in row-major
+/// order, without any header.
+pub struct MappedKinship {
+  mmap: MmapMut,
+  ids_num: usize,
+}
+
+impl MappedKinship {
+  /// @brief Creates (or truncates) file at path, sized for ids_num x ids_num
+  /// zero-filled matrix and maps it into memory.
+  pub fn create<P: AsRef<Path>>(path: P, ids_num: usize) -> std::io::Result<Self> {
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(path)?;
+    file.set_len((ids_num * ids_num * std::mem::size_of::<f64>()) as u64)?;
+    Self::map(&file, ids_num)
+  }
+
+  /// @brief Maps previously calculated Kinship matrix from file at path.
+  pub fn open<P: AsRef<Path>>(path: P, ids_num: usize) -> std::io::Result<Self> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let expected_len = (ids_num * ids_num * std::mem::size_of::<f64>()) as u64;
+    if file.metadata()?.len() != expected_len {
+      return Err(
+        Error::Validation(format!(
+          "Kinship file size should be {} bytes for {} ids.",
+          expected_len, ids_num
+        ))
+        .into(),
+      );
+    }
+    Self::map(&file, ids_num)
+  }
+
+  fn map(file: &File, ids_num: usize) -> std::io::Result<Self> {
+    // The file is created by this process and is not expected to be modified
+    // by anyone else while mapped.
+    let mmap = unsafe { MmapMut::map_mut(file)? };
+    Ok(MappedKinship { mmap, ids_num })
+  }
+
+  pub fn ids_num(&self) -> usize {
+    self.ids_num
+  }
+
+  /// @brief Returns the matrix element at row i, column j.
+  pub fn get(&self, i: IndividualIdx, j: IndividualIdx) -> f64 {
+    self.as_slice()[i.index() * self.ids_num + j.index()]
+  }
+
+  pub fn as_slice(&self) -> &[f64] {
+    let len = self.ids_num * self.ids_num;
+    // Mappings are page aligned, which satisfies f64 alignment.
+    unsafe { std::slice::from_raw_parts(self.mmap.as_ptr() as *const f64, len) }
+  }
+
+  pub fn as_mut_slice(&mut self) -> &mut [f64] {
+    let len = self.ids_num * self.ids_num;
+    unsafe { std::slice::from_raw_parts_mut(self.mmap.as_mut_ptr() as *mut f64, len) }
+  }
+
+  /// @brief Writes modified pages back to the file.
+  pub fn flush(&self) -> std::io::Result<()> {
+    self.mmap.flush()
+  }
+
+  /// @brief Copies rows x cols block of the matrix (row-major), e.g. a tile
+  /// requested by a genome browser. Only the pages of the block are read.
+  ///
+  /// @note Returns Error::Validation if a range exceeds ids_num.
+  pub fn block(
+    &self,
+    rows: Range<IndividualIdx>,
+    cols: Range<IndividualIdx>,
+  ) -> std::io::Result<Vec<f64>> {
+    let (rows, cols) = self.check_block(&rows, &cols)?;
+    let matrix = self.as_slice();
+    let mut res = Vec::with_capacity(rows.len() * cols.len());
+    for row in rows {
+      let offset = row * self.ids_num;
+      res.extend_from_slice(&matrix[offset + cols.start..offset + cols.end]);
+    }
+    Ok(res)
+  }
+
+  /// @brief Streams the matrix to writer as GEMMA relatedness matrix (-k),
+  /// see output::write_gemma_rows.
+  pub fn write_gemma<W: Write>(
+    &self,
+    writer: &mut W,
+    number_format: &NumberFormat,
+  ) -> std::io::Result<()> {
+    write_gemma_rows(writer, self.as_slice().chunks(self.ids_num.max(1)), number_format)
+  }
+
+  /// @brief Streams rows x cols block to writer as CSV labeled by ids (the
+  /// format of KinshipMatrix::write_csv, read by MatrixCsvReader), a row at
+  /// a time.
+  ///
+  /// @note Returns Error::Validation if a range exceeds ids_num, the amount
+  /// of ids differs from it or an id holds a line break.
+  pub fn write_block<W: Write>(
+    &self,
+    writer: &mut W,
+    ids: &[String],
+    rows: Range<IndividualIdx>,
+    cols: Range<IndividualIdx>,
+    number_format: &NumberFormat,
+  ) -> std::io::Result<()> {
+    if ids.len() != self.ids_num {
+      return Err(
+        Error::Validation(format!(
+          "Expected {} ids for the Kinship matrix, got {}.",
+          self.ids_num,
+          ids.len()
+        ))
+        .into(),
+      );
+    }
+    let (rows, cols) = self.check_block(&rows, &cols)?;
+    check_csv_ids(ids)?;
+    let header = std::iter::once(String::new()).chain(ids[cols.clone()].iter().cloned());
+    write_record(writer, &header.collect::<Vec<String>>())?;
+    let (matrix, mut record) = (self.as_slice(), Vec::with_capacity(cols.len() + 1));
+    for row in rows {
+      let offset = row * self.ids_num;
+      record.clear();
+      record.push(ids[row].clone());
+      let values = &matrix[offset + cols.start..offset + cols.end];
+      record.extend(values.iter().map(|value| number_format.format(*value)));
+      write_record(writer, &record)?;
+    }
+    Ok(())
+  }
+
+  /// @brief Positions of rows and cols in the storage.
+  ///
+  /// @note Returns Error::Validation if a range exceeds ids_num.
+  fn check_block(
+    &self,
+    rows: &Range<IndividualIdx>,
+    cols: &Range<IndividualIdx>,
+  ) -> std::io::Result<(Range<usize>, Range<usize>)> {
+    for range in [rows, cols] {
+      if range.start > range.end || range.end.index() > self.ids_num {
+        return Err(
+          Error::Validation(format!(
+            "Block range {}..{} is out of the {} x {} Kinship matrix.",
+            range.start, range.end, self.ids_num, self.ids_num
+          ))
+          .into(),
+        );
+      }
+    }
+    Ok((rows.start.index()..rows.end.index(), cols.start.index()..cols.end.index()))
+  }
+}
+
+/// @brief Range of ids from id first to id last (inclusive), for selecting
+/// blocks of MappedKinship by individual ids.
+///
+/// @note Returns Error::Validation if an id is absent or last precedes
+/// first.
+pub fn id_range(
+  ids: &[String],
+  first: &str,
+  last: &str,
+) -> std::io::Result<Range<IndividualIdx>> {
+  let position = |id: &str| {
+    ids
+      .iter()
+      .position(|other| other == id)
+      .ok_or_else(|| Error::Validation(format!("There is no individual <{}>.", id)))
+  };
+  let (start, end) = (position(first)?, position(last)?);
+  if end < start {
+    return Err(
+      Error::Validation(format!("Individual <{}> precedes <{}>.", last, first)).into(),
+    );
+  }
+  Ok(IndividualIdx(start)..IndividualIdx(end + 1))
+}
+
+/// @brief Calculates Kinship matrix from SNP lines, accumulating it directly
+/// in a memory-mapped file at path, for matrices which don't fit in RAM.
+///
+/// @note Columns of the matrix are split between the workers the same way as
+/// in ParallelMode::ColumnBlocks (options.mode is ignored), so each worker
+/// sweeps its own contiguous region of the file. Normalization and mirroring
+/// are done afterwards in a single tiled pass over the mapping.
+pub fn calc_kinship_out_of_core<R: BufRead, P: AsRef<Path>>(
+  lines: &mut std::io::Lines<R>,
+  ids_num: usize,
+  hab_mapper: &HashMap<char, f64>,
+  options: &KinshipOptions,
+  path: P,
+) -> std::io::Result<MappedKinship> {
+  calc_kinship_out_of_core_from(
+    &mut GenoLines::new(lines, ids_num, hab_mapper, options.missing.allows_na()),
+    options,
+    path,
+  )
+}
+
+/// @brief Same as calc_kinship_out_of_core for any SnpSource.
+pub fn calc_kinship_out_of_core_from<P: AsRef<Path>>(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  path: P,
+) -> std::io::Result<MappedKinship> {
+  let ids_num = source.ids_num();
+  options.check_batch_rows(ids_num)?;
+  let mut res = MappedKinship::create(path, ids_num)?;
+  let accumulated = accumulate_column_blocks(source, options, &mut [], res.as_mut_slice())?;
+  check_snps_num(accumulated.snps, ids_num)?;
+  check_weight(accumulated.snps, accumulated.weight)?;
+  finalize_kinship_by(res.as_mut_slice(), ids_num, accumulated.weight);
+  res.flush()?;
+  Ok(res)
+}
@@ -0,0 +1,148 @@
+// output.rs
+
+use std::io::Write;
+
+use super::na;
+
+/// @brief Notation of numbers in text output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Notation {
+  /// @brief The shortest representation which reads back to the same f64
+  /// (full precision), digits are ignored.
+  Shortest,
+  /// @brief Fixed amount of digits after the decimal point.
+  Fixed,
+  /// @brief Mantissa with fixed amount of digits after the decimal point and
+  /// exponent, e.g. 1.234e-5.
+  Scientific,
+  /// @brief Fixed amount of significant digits, scientific notation is used
+  /// for very small and very large values only (as printf "%g").
+  Significant,
+}
+
+/// @brief Formatting of numbers taken by all text writers.
+///
+/// @note Full precision is the default, but it takes up to 24 characters per
+/// value, while 6 significant digits are usually enough for Kinship
+/// matrices, and halve the output size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumberFormat {
+  pub notation: Notation,
+  pub digits: usize,
+}
+
+impl Default for NumberFormat {
+  fn default() -> Self {
+    NumberFormat {
+      notation: Notation::Shortest,
+      digits: 6,
+    }
+  }
+}
+
+impl NumberFormat {
+  pub fn fixed(digits: usize) -> Self {
+    NumberFormat {
+      notation: Notation::Fixed,
+      digits,
+    }
+  }
+
+  pub fn scientific(digits: usize) -> Self {
+    NumberFormat {
+      notation: Notation::Scientific,
+      digits,
+    }
+  }
+
+  pub fn significant(digits: usize) -> Self {
+    NumberFormat {
+      notation: Notation::Significant,
+      digits: digits.max(1),
+    }
+  }
+
+  /// @brief Parses format specification: "full", "fixed:<digits>",
+  /// "sci:<digits>" or "sig:<digits>".
+  pub fn parse(spec: &str) -> Option<Self> {
+    if spec == "full" {
+      return Some(NumberFormat::default());
+    }
+    let (notation, digits) = spec.split_once(':')?;
+    let digits = digits.parse::<usize>().ok()?;
+    match notation {
+      "fixed" => Some(NumberFormat::fixed(digits)),
+      "sci" => Some(NumberFormat::scientific(digits)),
+      "sig" if digits > 0 => Some(NumberFormat::significant(digits)),
+      _ => None,
+    }
+  }
+
+  /// @note Missing values (na::NA) are formatted as "NA".
+  pub fn format(&self, value: f64) -> String {
+    if na::is_na(value) {
+      return String::from("NA");
+    }
+    match self.notation {
+      Notation::Shortest => format!("{}", value),
+      Notation::Fixed => format!("{:.*}", self.digits, value),
+      Notation::Scientific => format!("{:.*e}", self.digits, value),
+      Notation::Significant => {
+        if value == 0.0 || !value.is_finite() {
+          return format!("{}", value);
+        }
+        let exponent = value.abs().log10().floor() as i64;
+        let digits = self.digits as i64;
+        let formatted = if exponent < -4 || exponent >= digits {
+          format!("{:.*e}", (digits - 1) as usize, value)
+        } else {
+          format!("{:.*}", (digits - 1 - exponent).max(0) as usize, value)
+        };
+        trim_fraction_zeros(formatted)
+      }
+    }
+  }
+
+  pub fn write<W: Write>(&self, writer: &mut W, value: f64) -> std::io::Result<()> {
+    writer.write_all(self.format(value).as_bytes())
+  }
+}
+
+/// @brief Streams matrix rows to writer in the format of GEMMA relatedness
+/// matrix (-k): a line of tab separated values per row, no header, rows in
+/// the order of the individuals in the genotype and phenotype files. Only a
+/// row is formatted at a time.
+pub fn write_gemma_rows<'a, W, I>(
+  writer: &mut W,
+  rows: I,
+  number_format: &NumberFormat,
+) -> std::io::Result<()>
+where
+  W: Write,
+  I: IntoIterator<Item = &'a [f64]>,
+{
+  for row in rows {
+    for (col, value) in row.iter().enumerate() {
+      if col > 0 {
+        writer.write_all(b"\t")?;
+      }
+      number_format.write(writer, *value)?;
+    }
+    writer.write_all(b"\n")?;
+  }
+  Ok(())
+}
+
+/// @brief Removes trailing zeros of the fractional part, e.g. 1.500 to 1.5
+/// and 2.000e3 to 2e3.
+fn trim_fraction_zeros(formatted: String) -> String {
+  let (mantissa, exponent) = match formatted.find('e') {
+    Some(pos) => formatted.split_at(pos),
+    None => (formatted.as_str(), ""),
+  };
+  if !mantissa.contains('.') {
+    return formatted;
+  }
+  let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+  format!("{}{}", mantissa, exponent)
+}
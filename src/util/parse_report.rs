@@ -0,0 +1,166 @@
+// parse_report.rs
+
+use std::fmt;
+
+use super::error::escape_json;
+
+/// @brief How serious a Diagnostic is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+  /// @brief Worth knowing, the data is used as is.
+  Info,
+  /// @brief The data was altered or partially ignored (e.g. lines skipped).
+  Warning,
+  /// @brief The data is likely wrong, results shouldn't be trusted without a
+  /// check.
+  Error,
+}
+
+impl Severity {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Severity::Info => "info",
+      Severity::Warning => "warning",
+      Severity::Error => "error",
+    }
+  }
+}
+
+/// @brief Classes of problems, pipelines can gate on them (see
+/// ParseReport::has).
+pub mod codes {
+  /// @brief Line was not valid UTF-8 and was decoded as Latin-1.
+  pub const LATIN1_DECODED: &str = "latin1_decoded";
+  /// @brief The same id is listed more than once.
+  pub const DUPLICATE_ID: &str = "duplicate_id";
+  /// @brief Individual has no sample metadata.
+  pub const METADATA_MISSING: &str = "metadata_missing";
+  /// @brief Calculation fell back to another method (e.g. kernel).
+  pub const FALLBACK: &str = "fallback";
+  /// @brief Empty or whitespace-only lines among SNP lines were skipped.
+  pub const BLANK_LINES: &str = "blank_lines";
+  /// @brief SNP line is not a row id and genotype codes separated with a
+  /// tab.
+  pub const MALFORMED_LINE: &str = "malformed_line";
+  /// @brief The same marker (row id) is on more than one SNP line.
+  pub const DUPLICATE_MARKER: &str = "duplicate_marker";
+  /// @brief Amount of genotypes of a SNP line differs from the amount of ids.
+  pub const ROW_LENGTH: &str = "row_length";
+  /// @brief Genotype code is absent from the code mapping.
+  pub const UNKNOWN_CODE: &str = "unknown_code";
+  /// @brief Individual is absent from some of the combined files and was
+  /// left out.
+  pub const DROPPED_ID: &str = "dropped_id";
+  /// @brief Chromosome was left out of a calculation (e.g. the X chromosome
+  /// of genotype probabilities).
+  pub const SKIPPED_CHROMOSOME: &str = "skipped_chromosome";
+}
+
+/// @brief Single problem found while parsing or checking data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  /// @brief One of codes.
+  pub code: &'static str,
+  /// @brief Line of the file (counting from 1), if the problem is tied to
+  /// one.
+  pub line: Option<usize>,
+  pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} [{}]", self.severity.name(), self.code)?;
+    if let Some(line) = self.line {
+      write!(f, " line {}", line)?;
+    }
+    write!(f, ": {}", self.message)
+  }
+}
+
+/// @brief All problems which didn't prevent parsing (or QC) from finishing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParseReport {
+  diagnostics: Vec<Diagnostic>,
+}
+
+impl ParseReport {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn push(&mut self, severity: Severity, code: &'static str, line: Option<usize>, msg: String) {
+    self.diagnostics.push(Diagnostic {
+      severity,
+      code,
+      line,
+      message: msg,
+    });
+  }
+
+  pub fn warn(&mut self, code: &'static str, line: Option<usize>, msg: String) {
+    self.push(Severity::Warning, code, line, msg);
+  }
+
+  /// @brief Appends diagnostics of other report.
+  pub fn merge(&mut self, other: &ParseReport) {
+    self.diagnostics.extend(other.diagnostics.iter().cloned());
+  }
+
+  pub fn diagnostics(&self) -> &[Diagnostic] {
+    &self.diagnostics
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.diagnostics.is_empty()
+  }
+
+  /// @brief Amount of diagnostics of severity.
+  pub fn count(&self, severity: Severity) -> usize {
+    self
+      .diagnostics
+      .iter()
+      .filter(|diagnostic| diagnostic.severity == severity)
+      .count()
+  }
+
+  /// @brief Whether there is a diagnostic of class code.
+  pub fn has(&self, code: &str) -> bool {
+    self.diagnostics.iter().any(|diagnostic| diagnostic.code == code)
+  }
+
+  /// @brief The most serious severity, None for an empty report.
+  pub fn max_severity(&self) -> Option<Severity> {
+    self.diagnostics.iter().map(|diagnostic| diagnostic.severity).max()
+  }
+
+  /// @brief Single line JSON object: {"counts":{...},"diagnostics":[...]}.
+  pub fn to_json(&self) -> String {
+    let counts = [Severity::Info, Severity::Warning, Severity::Error]
+      .iter()
+      .map(|severity| format!("\"{}\":{}", severity.name(), self.count(*severity)))
+      .collect::<Vec<String>>();
+    let diagnostics = self
+      .diagnostics
+      .iter()
+      .map(|diagnostic| {
+        let line = match diagnostic.line {
+          Some(line) => line.to_string(),
+          None => String::from("null"),
+        };
+        format!(
+          "{{\"severity\":\"{}\",\"code\":\"{}\",\"line\":{},\"message\":\"{}\"}}",
+          diagnostic.severity.name(),
+          diagnostic.code,
+          line,
+          escape_json(&diagnostic.message)
+        )
+      })
+      .collect::<Vec<String>>();
+    format!(
+      "{{\"counts\":{{{}}},\"diagnostics\":[{}]}}",
+      counts.join(","),
+      diagnostics.join(",")
+    )
+  }
+}
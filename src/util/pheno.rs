@@ -0,0 +1,158 @@
+// pheno.rs
+
+use super::error::Error;
+use super::na;
+
+/// @brief Transform of a phenotype, applied to each column of a phenotype
+/// matrix independently, so skewed traits behave under the LMM assumptions.
+///
+/// @note Missing values stay NA and are ignored by the statistics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhenoTransform {
+  /// @brief qnorm((rank - 0.5) / n) of the present values, ties get the
+  /// average rank (R/qtl2 nqrank).
+  RankInverseNormal,
+  /// @brief Natural logarithm, values must be positive.
+  Log,
+  /// @brief Subtracts the mean and divides by the sample standard deviation
+  /// (n - 1 denominator).
+  Standardize,
+}
+
+impl PhenoTransform {
+  /// @brief Transforms values of a single phenotype in place.
+  ///
+  /// @note Returns Error::Validation for a non-positive value (Log) or a
+  /// constant phenotype (Standardize).
+  pub fn apply(&self, values: &mut [f64]) -> std::io::Result<()> {
+    match self {
+      PhenoTransform::RankInverseNormal => {
+        let n = values.len() - na::count_na(values);
+        for (pos, rank) in average_ranks(values) {
+          values[pos] = qnorm((rank - 0.5) / n as f64);
+        }
+      }
+      PhenoTransform::Log => {
+        if let Some(value) = na::present(values).find(|value| *value <= 0.0) {
+          return Err(
+            Error::Validation(format!("Can't take logarithm of non-positive {}.", value)).into(),
+          );
+        }
+        values.iter_mut().for_each(|value| *value = value.ln());
+      }
+      PhenoTransform::Standardize => {
+        let mean = na::mean(values);
+        let (sum, count) = na::present(values).fold((0.0, 0usize), |(sum, count), value| {
+          (sum + (value - mean) * (value - mean), count + 1)
+        });
+        let sd = (sum / (count as f64 - 1.0)).sqrt();
+        if sd.is_nan() || sd <= 0.0 || sd.is_infinite() {
+          return Err(
+            Error::Validation(String::from(
+              "Can't standardize phenotype with less than 2 distinct values.",
+            ))
+            .into(),
+          );
+        }
+        values.iter_mut().for_each(|value| *value = (*value - mean) / sd);
+      }
+    }
+    Ok(())
+  }
+}
+
+/// @brief Applies transform to every column of values, a row-major matrix of
+/// cols_num phenotypes per individual (the layout of Lmm::fit_null_batch).
+pub fn transform(
+  values: &mut [f64],
+  cols_num: usize,
+  transform: PhenoTransform,
+) -> std::io::Result<()> {
+  if cols_num == 0 {
+    return Ok(());
+  }
+  let mut column = Vec::with_capacity(values.len() / cols_num);
+  for col in 0..cols_num {
+    column.clear();
+    column.extend(values.iter().skip(col).step_by(cols_num));
+    transform.apply(&mut column)?;
+    for (value, transformed) in values.iter_mut().skip(col).step_by(cols_num).zip(&column) {
+      *value = *transformed;
+    }
+  }
+  Ok(())
+}
+
+/// @brief Ranks (from 1) of the present values, by position, ties get the
+/// average of their ranks.
+fn average_ranks(values: &[f64]) -> Vec<(usize, f64)> {
+  let mut order = (0..values.len())
+    .filter(|pos| !na::is_na(values[*pos]))
+    .collect::<Vec<usize>>();
+  order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+  let mut ranks = Vec::with_capacity(order.len());
+  let mut start = 0;
+  while start < order.len() {
+    let mut end = start + 1;
+    while end < order.len() && values[order[end]] == values[order[start]] {
+      end += 1;
+    }
+    // Ranks start + 1 ..= end.
+    let rank = (start + end + 1) as f64 / 2.0;
+    ranks.extend(order[start..end].iter().map(|pos| (*pos, rank)));
+    start = end;
+  }
+  ranks
+}
+
+/// @brief Quantile function of the standard normal distribution (Acklam's
+/// rational approximation, relative error below 1.2e-9). NA outside (0, 1).
+pub fn qnorm(p: f64) -> f64 {
+  const A: [f64; 6] = [
+    -3.969683028665376e+01,
+    2.209460984245205e+02,
+    -2.759285104469687e+02,
+    1.38357751867269e+02,
+    -3.066479806614716e+01,
+    2.506628277459239e+00,
+  ];
+  const B: [f64; 5] = [
+    -5.447609879822406e+01,
+    1.615858368580409e+02,
+    -1.556989798598866e+02,
+    6.680131188771972e+01,
+    -1.328068155288572e+01,
+  ];
+  const C: [f64; 6] = [
+    -7.784894002430293e-03,
+    -3.223964580411365e-01,
+    -2.400758277161838e+00,
+    -2.549732539343734e+00,
+    4.374664141464968e+00,
+    2.938163982698783e+00,
+  ];
+  const D: [f64; 4] = [
+    7.784695709041462e-03,
+    3.224671290700398e-01,
+    2.445134137142996e+00,
+    3.754408661907416e+00,
+  ];
+  const P_LOW: f64 = 0.02425;
+  if p.is_nan() || p <= 0.0 || p >= 1.0 {
+    return na::NA;
+  }
+  let tail = |q: f64| {
+    (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+      / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+  };
+  if p < P_LOW {
+    tail((-2.0 * p.ln()).sqrt())
+  } else if p > 1.0 - P_LOW {
+    -tail((-2.0 * (1.0 - p).ln()).sqrt())
+  } else {
+    let q = p - 0.5;
+    let r = q * q;
+    (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+      / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+  }
+}
@@ -0,0 +1,192 @@
+// pheno_parser.rs
+
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+
+use super::error::Error;
+use super::matrix_csv::MatrixCsvReader;
+use super::na;
+use super::text;
+
+/// @brief Streaming parser of R/qtl2 phenotype file: CSV with individual ids
+/// in the first column and phenotype names in the first row. Lines starting
+/// with '#' are comments. Fields are split and missing values recognized by
+/// a MatrixCsvReader, so the delimiter and na_strings are its own.
+///
+/// @note https://kbroman.org/qtl2/assets/vignettes/input_files.html#Phenotype_data
+pub struct PhenoParser<R: BufRead = BufReader<File>> {
+  lines: text::Lines<R>,
+  phenotypes: Vec<String>,
+  csv: MatrixCsvReader,
+  /// @note Number of the last read line, counting from 1.
+  line_num: usize,
+}
+
+impl PhenoParser<BufReader<File>> {
+  pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    Self::with_csv(path, MatrixCsvReader::new())
+  }
+
+  /// @brief Same as new with the delimiter and na_strings of csv, e.g. for
+  /// the sep and na.strings of a control file.
+  pub fn with_csv<P: AsRef<Path>>(path: P, csv: MatrixCsvReader) -> std::io::Result<Self> {
+    Self::from_csv_reader(BufReader::new(File::open(path)?), csv)
+  }
+}
+
+impl<R: BufRead> PhenoParser<R> {
+  /// @brief Reads comments and the header line.
+  pub fn from_reader(reader: R) -> std::io::Result<Self> {
+    Self::from_csv_reader(reader, MatrixCsvReader::new())
+  }
+
+  /// @brief Same as from_reader with the delimiter and na_strings of csv.
+  pub fn from_csv_reader(reader: R, csv: MatrixCsvReader) -> std::io::Result<Self> {
+    let mut parser = PhenoParser {
+      lines: text::Lines::new(reader),
+      phenotypes: Vec::new(),
+      csv,
+      line_num: 0,
+    };
+    let header = parser.next_line()?.ok_or_else(|| {
+      Error::Parse {
+        line: None,
+        msg: String::from("Phenotype file is empty."),
+      }
+    })?;
+    parser.phenotypes = parser.csv.fields(&header).skip(1).collect();
+    Ok(parser)
+  }
+
+  /// @brief Sets strings which denote missing values (R/qtl2 na.strings),
+  /// empty cells are always missing.
+  pub fn with_na_strings(mut self, na_strings: Vec<String>) -> Self {
+    self.csv = self.csv.with_na_strings(na_strings);
+    self
+  }
+
+  /// @brief Phenotype names from the header line.
+  pub fn phenotypes(&self) -> &[String] {
+    &self.phenotypes
+  }
+
+  /// @brief Iterates over the remaining lines of the file.
+  pub fn iter(&mut self) -> PhenoParserIter<'_, R> {
+    PhenoParserIter { parser: self }
+  }
+
+  /// @brief Reads the remaining lines into memory.
+  pub fn read_matrix(mut self) -> std::io::Result<PhenoMatrix> {
+    let mut matrix = PhenoMatrix {
+      ids: Vec::new(),
+      phenotypes: self.phenotypes.clone(),
+      values: Vec::new(),
+    };
+    while let Some((id, values)) = self.next_record()? {
+      matrix.ids.push(id);
+      matrix.values.extend(values);
+    }
+    Ok(matrix)
+  }
+
+  /// @brief The next line which is neither a comment nor blank.
+  fn next_line(&mut self) -> std::io::Result<Option<String>> {
+    for line in self.lines.by_ref() {
+      let line = line?;
+      self.line_num += 1;
+      if !line.starts_with('#') && !line.trim().is_empty() {
+        return Ok(Some(line));
+      }
+    }
+    Ok(None)
+  }
+
+  fn next_record(&mut self) -> std::io::Result<Option<(String, Vec<f64>)>> {
+    let line = match self.next_line()? {
+      Some(line) => line,
+      None => return Ok(None),
+    };
+    let invalid = |msg: String| -> std::io::Error { Error::Parse { line: None, msg }.into() };
+    let mut fields = self.csv.fields(&line);
+    let id = fields.next().unwrap_or_default();
+    let values = fields
+      .enumerate()
+      .map(|(col, field)| {
+        if field.is_empty() || self.csv.is_na(&field) {
+          return Ok(na::NA);
+        }
+        field.parse::<f64>().map_err(|_| {
+          invalid(format!(
+            "Value <{}> of phenotype <{}> at line {} is not a number.",
+            field,
+            self.phenotypes.get(col).map_or("", |name| name.as_str()),
+            self.line_num
+          ))
+        })
+      })
+      .collect::<std::io::Result<Vec<f64>>>()?;
+    if values.len() != self.phenotypes.len() {
+      return Err(invalid(format!(
+        "Line {} has {} phenotypes, however the header has {}.",
+        self.line_num,
+        values.len(),
+        self.phenotypes.len()
+      )));
+    }
+    Ok(Some((id, values)))
+  }
+}
+
+/// @brief Iterator over individuals of PhenoParser. Returns tuple (id,
+/// phenotypes), missing values are na::NA.
+pub struct PhenoParserIter<'a, R: BufRead> {
+  parser: &'a mut PhenoParser<R>,
+}
+
+impl<'a, R: BufRead> Iterator for PhenoParserIter<'a, R> {
+  type Item = std::io::Result<(String, Vec<f64>)>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.parser.next_record().transpose()
+  }
+}
+
+/// @brief Phenotypes of all individuals held in memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhenoMatrix {
+  pub ids: Vec<String>,
+  pub phenotypes: Vec<String>,
+  /// @note Row-major, a line of phenotypes.len() values per individual (the
+  /// layout of Lmm::fit_null_batch and pheno::transform).
+  pub values: Vec<f64>,
+}
+
+impl PhenoMatrix {
+  pub fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  pub fn phenotypes_num(&self) -> usize {
+    self.phenotypes.len()
+  }
+
+  pub fn get(&self, row: usize, col: usize) -> f64 {
+    self.values[row * self.phenotypes.len() + col]
+  }
+
+  /// @brief Values of phenotype name for all individuals.
+  pub fn column(&self, name: &str) -> Option<Vec<f64>> {
+    let col = self.phenotypes.iter().position(|phenotype| phenotype == name)?;
+    Some(
+      self
+        .values
+        .iter()
+        .skip(col)
+        .step_by(self.phenotypes.len())
+        .copied()
+        .collect(),
+    )
+  }
+}
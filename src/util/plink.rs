@@ -0,0 +1,249 @@
+// plink.rs
+
+//! PLINK 1 binary genotypes: .fam (individuals), .bim (variants) and .bed
+//! (genotypes, 2 bits per genotype, a block of bytes per variant).
+//!
+//! https://www.cog-genomics.org/plink/1.9/formats#bed
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use super::dosage::calc_kinship_labeled;
+use super::error::Error;
+use super::kinship::{KinshipOptions, SnpSource};
+use super::kinship_matrix::KinshipMatrix;
+use super::na;
+use super::text;
+
+/// @brief Magic number of .bed files, followed by the mode byte.
+const BED_MAGIC: [u8; 2] = [0x6c, 0x1b];
+/// @brief Mode byte of variant-major .bed files, the only supported mode.
+const BED_VARIANT_MAJOR: u8 = 0x01;
+/// @brief Dosages (of allele 1) of the 2-bit genotype codes: homozygous
+/// allele 1, missing, heterozygous, homozygous allele 2.
+const BED_DOSAGES: [f64; 4] = [1.0, na::NA, 0.5, 0.0];
+
+/// @brief Variant (a line of .bim file).
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlinkVariant {
+  pub chromosome: String,
+  pub id: String,
+  /// @note Genetic position (cM), 0 when unknown.
+  pub position_cm: f64,
+  /// @note Base-pair coordinate.
+  pub position_bp: u64,
+  pub allele1: String,
+  pub allele2: String,
+}
+
+/// @brief SnpSource over the variants of a PLINK dataset, a SNP line per
+/// variant in .bed order with the variant id as the row id. Dosages are of
+/// allele 1 on the scale of the default genotype codes: 1 for homozygous
+/// allele 1, 0.5 for heterozygous, 0 for homozygous allele 2. Missing
+/// genotypes are na::NA, rejected unless allow_na is set.
+pub struct PlinkReader<R: Read> {
+  bed: R,
+  ids: Vec<String>,
+  variants: Vec<PlinkVariant>,
+  allow_na: bool,
+  /// @note Position of the next variant to read.
+  next: usize,
+  /// @note Bytes of a variant, 4 genotypes per byte.
+  block: Vec<u8>,
+}
+
+impl PlinkReader<BufReader<File>> {
+  /// @brief Opens prefix.bed, prefix.bim and prefix.fam (prefix as given
+  /// to plink --bfile).
+  ///
+  /// @note Returns Error::Validation if the size of .bed file doesn't match
+  /// the amounts of individuals and variants.
+  pub fn open<P: AsRef<Path>>(prefix: P, allow_na: bool) -> std::io::Result<Self> {
+    let path = |extension: &str| {
+      let mut path = OsString::from(prefix.as_ref().as_os_str());
+      path.push(extension);
+      path
+    };
+    let fam = BufReader::new(File::open(path(".fam"))?);
+    let bim = BufReader::new(File::open(path(".bim"))?);
+    let bed = File::open(path(".bed"))?;
+    let bed_len = bed.metadata()?.len();
+    let reader = Self::from_readers(BufReader::new(bed), fam, bim, allow_na)?;
+    let expected = 3 + (reader.block.len() * reader.variants.len()) as u64;
+    if bed_len != expected {
+      return Err(
+        Error::Validation(format!(
+          "Size of .bed file is {} bytes, {} expected for {} individuals and {} variants.",
+          bed_len,
+          expected,
+          reader.ids.len(),
+          reader.variants.len()
+        ))
+        .into(),
+      );
+    }
+    Ok(reader)
+  }
+}
+
+impl<R: Read> PlinkReader<R> {
+  /// @brief Reads individuals of fam, variants of bim and the header of bed.
+  ///
+  /// @note Returns Error::Validation for individual-major .bed files.
+  pub fn from_readers<F: BufRead, B: BufRead>(
+    mut bed: R,
+    fam: F,
+    bim: B,
+    allow_na: bool,
+  ) -> std::io::Result<Self> {
+    let ids = read_fam(fam)?;
+    let variants = read_bim(bim)?;
+    let mut header = [0u8; 3];
+    bed.read_exact(&mut header)?;
+    if header[..2] != BED_MAGIC {
+      return Err(Error::Validation(String::from("This is not a PLINK .bed file.")).into());
+    }
+    if header[2] != BED_VARIANT_MAJOR {
+      return Err(
+        Error::Validation(String::from("Individual-major .bed files are not supported.")).into(),
+      );
+    }
+    let block = vec![0; ids.len().div_ceil(4)];
+    Ok(PlinkReader {
+      bed,
+      ids,
+      variants,
+      allow_na,
+      next: 0,
+      block,
+    })
+  }
+
+  /// @brief Individual ids (within-family ids of .fam file).
+  pub fn ids(&self) -> &[String] {
+    &self.ids
+  }
+
+  /// @brief Variants in .bed order.
+  pub fn variants(&self) -> &[PlinkVariant] {
+    &self.variants
+  }
+}
+
+impl<R: Read> SnpSource for PlinkReader<R> {
+  fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let ids_num = self.ids.len();
+    if ids_num == 0 {
+      return Ok(0);
+    }
+    let mut rows = 0;
+    while (rows + 1) * ids_num <= buf.len() && self.next < self.variants.len() {
+      let variant = &self.variants[self.next];
+      self.bed.read_exact(&mut self.block).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::Parse {
+          line: None,
+          msg: format!(".bed file ends before variant <{}>.", variant.id),
+        }
+        .into(),
+        _ => e,
+      })?;
+      let line = &mut buf[rows * ids_num..(rows + 1) * ids_num];
+      decode_block(&self.block, line);
+      if !self.allow_na && na::count_na(line) > 0 {
+        return Err(
+          Error::Parse {
+            line: None,
+            msg: format!("Variant <{}> has missing genotypes.", variant.id),
+          }
+          .into(),
+        );
+      }
+      if let Some(row_ids) = row_ids.as_deref_mut() {
+        row_ids.push(variant.id.clone());
+      }
+      self.next += 1;
+      rows += 1;
+    }
+    Ok(rows)
+  }
+}
+
+/// @brief Decodes genotypes of a variant (4 per byte, the first one in the
+/// lowest bits) into line, padding bits of the last byte are ignored.
+fn decode_block(block: &[u8], line: &mut [f64]) {
+  for (genotypes, byte) in line.chunks_mut(4).zip(block) {
+    for (shift, genotype) in genotypes.iter_mut().enumerate() {
+      *genotype = BED_DOSAGES[(byte >> (2 * shift)) as usize & 0b11];
+    }
+  }
+}
+
+/// @brief Reads individual ids from .fam lines: family id, individual id,
+/// father, mother, sex and phenotype.
+fn read_fam<F: BufRead>(fam: F) -> std::io::Result<Vec<String>> {
+  let mut ids = Vec::new();
+  for (line_num, line) in text::Lines::new(fam).enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let id = line.split_whitespace().nth(1).ok_or_else(|| Error::Parse {
+      line: Some(line_num + 1),
+      msg: String::from(".fam line has no individual id."),
+    })?;
+    ids.push(String::from(id));
+  }
+  Ok(ids)
+}
+
+/// @brief Reads .bim lines: chromosome, variant id, position (cM),
+/// coordinate (bp), allele 1 and allele 2.
+fn read_bim<B: BufRead>(bim: B) -> std::io::Result<Vec<PlinkVariant>> {
+  let mut variants = Vec::new();
+  for (line_num, line) in text::Lines::new(bim).enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let invalid = |msg: &str| Error::Parse {
+      line: Some(line_num + 1),
+      msg: format!(".bim line <{}> {}", line, msg),
+    };
+    let fields = line.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != 6 {
+      return Err(invalid("should have 6 fields.").into());
+    }
+    variants.push(PlinkVariant {
+      chromosome: String::from(fields[0]),
+      id: String::from(fields[1]),
+      position_cm: fields[2].parse().map_err(|_| invalid("has invalid position."))?,
+      position_bp: fields[3].parse().map_err(|_| invalid("has invalid coordinate."))?,
+      allele1: String::from(fields[4]),
+      allele2: String::from(fields[5]),
+    });
+  }
+  Ok(variants)
+}
+
+/// @brief Calculates Kinship matrix of PLINK dataset prefix (see
+/// PlinkReader::open) by the same engine as of R/qtl2 genotype files.
+///
+/// @note Returns Error::Validation for GramOrientation::SnpsBySnps.
+pub fn calc_kinship_plink<P: AsRef<Path>>(
+  prefix: P,
+  options: &KinshipOptions,
+) -> std::io::Result<KinshipMatrix> {
+  let mut reader = PlinkReader::open(prefix, options.missing.allows_na())?;
+  let ids = reader.ids().to_vec();
+  calc_kinship_labeled(&mut reader, ids, options)
+}
@@ -0,0 +1,101 @@
+// pool.rs
+
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// @brief Worker threads shared by Kinship matrix calculations, see
+/// KinshipOptions::pool. A server running many calculations creates the
+/// threads once and bounds their total amount, whatever the amount of
+/// concurrent calculations.
+///
+/// @note Calculations sharing a pool wait for its threads in turn: a
+/// calculation holds a thread until it's finished. The threads are joined
+/// when the pool is dropped.
+pub struct WorkerPool {
+  sender: Option<Sender<Job>>,
+  threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+  /// @brief Starts threads_num (at least 1) threads.
+  pub fn new(threads_num: usize) -> Self {
+    let (sender, receiver) = channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let threads = (0..threads_num.max(1))
+      .map(|_| {
+        let receiver = receiver.clone();
+        thread::spawn(move || loop {
+          let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+          match job {
+            Ok(job) => job(),
+            // The pool was dropped.
+            Err(_) => break,
+          }
+        })
+      })
+      .collect();
+    WorkerPool {
+      sender: Some(sender),
+      threads,
+    }
+  }
+
+  pub fn threads_num(&self) -> usize {
+    self.threads.len()
+  }
+}
+
+impl Drop for WorkerPool {
+  fn drop(&mut self) {
+    drop(self.sender.take());
+    for thread in self.threads.drain(..) {
+      let _ = thread.join();
+    }
+  }
+}
+
+impl fmt::Debug for WorkerPool {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("WorkerPool").field("threads_num", &self.threads_num()).finish()
+  }
+}
+
+/// @brief Worker of a calculation, started by spawn_worker.
+pub(crate) enum Worker {
+  Thread(thread::JoinHandle<()>),
+  Pooled(Receiver<thread::Result<()>>),
+}
+
+impl Worker {
+  /// @brief Waits for the worker to finish, same as JoinHandle::join.
+  pub(crate) fn join(self) -> thread::Result<()> {
+    match self {
+      Worker::Thread(handle) => handle.join(),
+      Worker::Pooled(done) => done.recv().unwrap_or_else(|_| Err(Box::new("worker pool stopped"))),
+    }
+  }
+}
+
+/// @brief Runs work on a thread of pool, or on a new thread without one.
+pub(crate) fn spawn_worker<F>(pool: Option<&WorkerPool>, work: F) -> Worker
+where
+  F: FnOnce() + Send + 'static,
+{
+  let sender = match pool.and_then(|pool| pool.sender.as_ref()) {
+    Some(sender) => sender,
+    None => return Worker::Thread(thread::spawn(work)),
+  };
+  let (done_sender, done) = channel();
+  let job = Box::new(move || {
+    // A panic is handed to join, the pool thread goes on with the next job.
+    let _ = done_sender.send(catch_unwind(AssertUnwindSafe(work)));
+  });
+  // Threads of the pool live as long as the sender, so the job is received.
+  let _ = sender.send(job);
+  Worker::Pooled(done)
+}
@@ -0,0 +1,59 @@
+// precision.rs
+
+use std::fmt::Debug;
+use std::ops::{Add, AddAssign, DivAssign, Mul};
+
+/// @brief Floating point type of the Kinship matrix accumulation, see
+/// kinship::calc_kinship_typed.
+///
+/// @note f32 halves the memory taken by the matrix and the batches and
+/// doubles the SIMD lanes of the kernel, at the cost of about 7 significant
+/// digits instead of 16, which is plenty for genotype dosages.
+pub trait KinshipFloat:
+  Copy
+  + Default
+  + Debug
+  + PartialEq
+  + Send
+  + Sync
+  + Add<Output = Self>
+  + Mul<Output = Self>
+  + AddAssign
+  + DivAssign
+  + 'static
+{
+  fn from_f64(value: f64) -> Self;
+
+  fn to_f64(self) -> f64;
+
+  /// @brief Converts SNP lines parsed as f64, reusing the buffer if possible.
+  fn from_batch(batch: Vec<f64>) -> Vec<Self>;
+}
+
+impl KinshipFloat for f64 {
+  fn from_f64(value: f64) -> Self {
+    value
+  }
+
+  fn to_f64(self) -> f64 {
+    self
+  }
+
+  fn from_batch(batch: Vec<f64>) -> Vec<Self> {
+    batch
+  }
+}
+
+impl KinshipFloat for f32 {
+  fn from_f64(value: f64) -> Self {
+    value as f32
+  }
+
+  fn to_f64(self) -> f64 {
+    self as f64
+  }
+
+  fn from_batch(batch: Vec<f64>) -> Vec<Self> {
+    batch.into_iter().map(|value| value as f32).collect()
+  }
+}
@@ -0,0 +1,232 @@
+// probs.rs
+
+//! Multiparent crosses (e.g. Diversity Outbred, CC) are usually analysed via
+//! founder genotype probabilities (qtl2 calc_genoprob/genoprob_to_alleleprob,
+//! DOQTL) rather than observed genotypes. The expected allele dosage of an
+//! individual at a marker is the sum of its founder probabilities weighted by
+//! the founder genotypes at the marker, which brings probabilities into the
+//! dosage-based pipeline (Kinship matrix, statistics).
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::Write;
+
+use super::error::Error;
+use super::founders::FounderGenotypes;
+use super::kinship::SnpSource;
+use super::na;
+use super::output::NumberFormat;
+use super::text;
+
+/// @brief SnpSource converting founder probabilities to expected dosages.
+///
+/// Input is a CSV file (lines starting with '#' are comments) with markers as
+/// rows. The header holds "<individual><separator><founder>" column names,
+/// e.g. "DO-101.A,DO-101.B,...,DO-101.H,DO-102.A,...", as written by
+/// flattening the qtl2 probability array of a chromosome. Every individual
+/// must have a column for each founder of the file.
+///
+/// @note Markers absent from the founder genotypes, or where a founder
+/// genotype is missing, are skipped and counted in skipped_markers. Missing
+/// probabilities produce na::NA dosages.
+pub struct ProbsDosageReader<'a, R: BufRead> {
+  lines: text::Lines<R>,
+  founders: &'a FounderGenotypes,
+  ids: Vec<String>,
+  /// @note (individual, position in FounderGenotypes::founders) per column.
+  columns: Vec<(usize, usize)>,
+  na_strings: Vec<String>,
+  line_num: usize,
+  skipped: usize,
+}
+
+impl<'a, R: BufRead> ProbsDosageReader<'a, R> {
+  /// @brief Reads comments and the header line.
+  pub fn new(
+    reader: R,
+    founders: &'a FounderGenotypes,
+    separator: char,
+  ) -> std::io::Result<Self> {
+    let mut lines = text::Lines::new(reader);
+    let mut line_num = 0;
+    let header = loop {
+      line_num += 1;
+      match lines.next() {
+        Some(line) => {
+          let line = line?;
+          if !line.starts_with('#') && !line.trim().is_empty() {
+            break line;
+          }
+        }
+        None => {
+          return Err(
+            Error::Parse {
+              line: None,
+              msg: String::from("There is no header line in the probabilities file."),
+            }
+            .into(),
+          )
+        }
+      }
+    };
+
+    let mut ids = Vec::<String>::new();
+    let mut id_positions = HashMap::<String, usize>::new();
+    let mut columns = Vec::new();
+    for name in header.split(',').skip(1).map(str::trim) {
+      let (id, founder) = name.rsplit_once(separator).ok_or_else(|| {
+        Error::Validation(format!(
+          "Column <{}> is not in <individual>{}<founder> form.",
+          name, separator
+        ))
+      })?;
+      let founder = founders
+        .founders()
+        .iter()
+        .position(|f| f == founder)
+        .ok_or_else(|| Error::Validation(format!("There is no founder <{}>.", founder)))?;
+      let id = *id_positions.entry(String::from(id)).or_insert_with(|| {
+        ids.push(String::from(id));
+        ids.len() - 1
+      });
+      columns.push((id, founder));
+    }
+    let founders_per_id = columns.len() / ids.len().max(1);
+    let mut counts = vec![0; ids.len()];
+    columns.iter().for_each(|(id, _)| counts[*id] += 1);
+    if let Some(id) = counts.iter().position(|count| *count != founders_per_id) {
+      return Err(
+        Error::Validation(format!(
+          "Individual <{}> has {} founder columns, others have {}.",
+          ids[id], counts[id], founders_per_id
+        ))
+        .into(),
+      );
+    }
+    Ok(ProbsDosageReader {
+      lines,
+      founders,
+      ids,
+      columns,
+      na_strings: na::default_na_strings(),
+      line_num,
+      skipped: 0,
+    })
+  }
+
+  /// @brief Individual ids in the order of dosages in SNP lines.
+  pub fn ids(&self) -> &[String] {
+    &self.ids
+  }
+
+  /// @brief Amount of markers skipped so far, see ProbsDosageReader.
+  pub fn skipped_markers(&self) -> usize {
+    self.skipped
+  }
+
+  fn parse_line(&self, line: &str, dosages: &mut [f64]) -> std::io::Result<Option<String>> {
+    let mut fields = line.split(',').map(str::trim);
+    let id = fields.next().unwrap_or_default();
+    let genotypes = match self.founders.marker(id) {
+      Some(genotypes) if na::count_na(genotypes) == 0 => genotypes,
+      _ => return Ok(None),
+    };
+    let fields = fields.collect::<Vec<&str>>();
+    if fields.len() != self.columns.len() {
+      return Err(
+        Error::Parse {
+          line: Some(self.line_num),
+          msg: format!(
+            "Marker <{}> has {} probabilities, expected {}.",
+            id,
+            fields.len(),
+            self.columns.len()
+          ),
+        }
+        .into(),
+      );
+    }
+    dosages.iter_mut().for_each(|dosage| *dosage = 0.0);
+    for (field, (individual, founder)) in fields.into_iter().zip(&self.columns) {
+      let prob = if self.na_strings.iter().any(|s| s == field) {
+        na::NA
+      } else {
+        field.parse::<f64>().map_err(|_| Error::Parse {
+          line: Some(self.line_num),
+          msg: format!("Probability <{}> of marker <{}> is not a number.", field, id),
+        })?
+      };
+      dosages[*individual] += prob * genotypes[*founder];
+    }
+    Ok(Some(String::from(id)))
+  }
+}
+
+impl<'a, R: BufRead> SnpSource for ProbsDosageReader<'a, R> {
+  fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let ids_num = self.ids.len().max(1);
+    let mut rows = 0;
+    while (rows + 1) * ids_num <= buf.len() {
+      let line = match self.lines.next() {
+        Some(line) => line?,
+        None => break,
+      };
+      self.line_num += 1;
+      if line.starts_with('#') || line.trim().is_empty() {
+        continue;
+      }
+      match self.parse_line(&line, &mut buf[rows * ids_num..(rows + 1) * ids_num])? {
+        Some(id) => {
+          if let Some(row_ids) = row_ids.as_mut() {
+            row_ids.push(id);
+          }
+          rows += 1;
+        }
+        None => self.skipped += 1,
+      }
+    }
+    Ok(rows)
+  }
+}
+
+/// @brief Streams dosages of source to writer as CSV: a header with ids, then
+/// a line per marker. The output is read back by MatrixCsvReader. Returns
+/// amount of markers written.
+pub fn write_dosages<W: Write>(
+  source: &mut dyn SnpSource,
+  ids: &[String],
+  writer: &mut W,
+  number_format: &NumberFormat,
+) -> std::io::Result<usize> {
+  const BATCH_ROWS: usize = 256;
+  let ids_num = source.ids_num();
+  writeln!(writer, "marker,{}", ids.join(","))?;
+  let mut buf = vec![0.0; ids_num.max(1) * BATCH_ROWS];
+  let mut row_ids = Vec::new();
+  let mut markers_num = 0;
+  loop {
+    row_ids.clear();
+    let rows = source.read_batch(&mut buf, Some(&mut row_ids))?;
+    if rows == 0 {
+      break;
+    }
+    for (id, line) in row_ids.iter().zip(buf.chunks(ids_num.max(1))) {
+      writer.write_all(id.as_bytes())?;
+      for value in &line[..ids_num] {
+        writer.write_all(b",")?;
+        number_format.write(writer, *value)?;
+      }
+      writer.write_all(b"\n")?;
+    }
+    markers_num += rows;
+  }
+  Ok(markers_num)
+}
@@ -0,0 +1,96 @@
+// progress.rs
+
+use std::fmt;
+use std::sync::Arc;
+
+/// @brief Progress of a Kinship matrix calculation, reported to
+/// KinshipOptions::progress after every batch read from the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+  /// @note SNP lines read so far, including the ones dropped by the filters.
+  pub markers: usize,
+  /// @note Batches read so far. Workers accumulate them in the background, at
+  /// most a couple of batches per worker behind.
+  pub batches: usize,
+  /// @note Estimated total amount of SNP lines, see
+  /// ProgressHook::with_estimated_total. Exact once finished.
+  pub estimated_total: Option<usize>,
+  /// @note Whether the end of source was reached.
+  pub finished: bool,
+}
+
+impl Progress {
+  /// @brief Fraction of the estimated total read so far, in [0, 1]. None if
+  /// there is no estimate.
+  pub fn fraction(&self) -> Option<f64> {
+    self.estimated_total.map(|total| match total {
+      0 => 1.0,
+      total => (self.markers as f64 / total as f64).min(1.0),
+    })
+  }
+}
+
+/// @brief Callback driving e.g. a progress bar of a long calculation, see
+/// KinshipOptions::progress.
+///
+/// @note Called on the thread reading the source, a slow callback slows the
+/// reading down.
+#[derive(Clone)]
+pub struct ProgressHook {
+  callback: Arc<dyn Fn(&Progress) + Send + Sync>,
+  estimated_total: Option<usize>,
+}
+
+impl ProgressHook {
+  pub fn new<F: Fn(&Progress) + Send + Sync + 'static>(callback: F) -> Self {
+    ProgressHook {
+      callback: Arc::new(callback),
+      estimated_total: None,
+    }
+  }
+
+  /// @brief Sets Progress::estimated_total. GenoParser estimates it from the
+  /// size of the file when it's not given.
+  pub fn with_estimated_total(mut self, total: usize) -> Self {
+    self.estimated_total = Some(total);
+    self
+  }
+
+  pub fn estimated_total(&self) -> Option<usize> {
+    self.estimated_total
+  }
+
+  /// @brief Hook reporting progress of a part of the calculation which
+  /// starts after markers lines and batches batches (e.g. a segment of
+  /// calc_kinship_checkpointed). The end of the part isn't reported as
+  /// finished.
+  pub(crate) fn after(&self, markers: usize, batches: usize) -> ProgressHook {
+    let hook = self.clone();
+    ProgressHook {
+      callback: Arc::new(move |progress: &Progress| {
+        if !progress.finished {
+          hook.report(markers + progress.markers, batches + progress.batches, false);
+        }
+      }),
+      estimated_total: self.estimated_total,
+    }
+  }
+
+  pub(crate) fn report(&self, markers: usize, batches: usize, finished: bool) {
+    (self.callback)(&Progress {
+      markers,
+      batches,
+      estimated_total: match finished {
+        true => Some(markers),
+        false => self.estimated_total,
+      },
+      finished,
+    });
+  }
+}
+
+impl fmt::Debug for ProgressHook {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ProgressHook").field("estimated_total", &self.estimated_total).finish()
+  }
+}
@@ -0,0 +1,92 @@
+// report.rs
+
+use std::io::Write;
+
+use super::metadata::SampleMetadata;
+use super::output::NumberFormat;
+use super::stats::IndividualStats;
+
+/// @brief Writes per-individual statistics as CSV, followed by the columns of
+/// metadata (see SampleMetadata::select) when given. Missing values are
+/// written as NA.
+pub fn write_individual_stats<W: Write>(
+  writer: &mut W,
+  stats: &[IndividualStats],
+  metadata: Option<&SampleMetadata>,
+  number_format: &NumberFormat,
+) -> std::io::Result<()> {
+  let mut header = vec![String::from("id"), String::from("missing_rate"), String::from("het_rate")];
+  if let Some(metadata) = metadata {
+    header.extend(metadata.columns().iter().cloned());
+  }
+  write_record(writer, &header)?;
+  for stat in stats {
+    let mut record = vec![
+      stat.id.clone(),
+      number_format.format(stat.missing_rate),
+      number_format.format(stat.het_rate),
+    ];
+    if let Some(metadata) = metadata {
+      record.extend(metadata.values(&stat.id).into_iter().map(metadata_field));
+    }
+    write_record(writer, &record)?;
+  }
+  Ok(())
+}
+
+/// @brief Writes upper triangle (without the diagonal) of ids_num x ids_num
+/// Kinship matrix as CSV pair list: id1, id2, kinship, followed by every
+/// metadata column for both individuals (suffixed with _1 and _2) when
+/// metadata is given.
+pub fn write_kinship_pairs<W: Write>(
+  writer: &mut W,
+  ids: &[String],
+  kinship: &[f64],
+  metadata: Option<&SampleMetadata>,
+  number_format: &NumberFormat,
+) -> std::io::Result<()> {
+  let ids_num = ids.len();
+  let mut header = vec![String::from("id1"), String::from("id2"), String::from("kinship")];
+  if let Some(metadata) = metadata {
+    for suffix in ["_1", "_2"] {
+      header.extend(metadata.columns().iter().map(|column| format!("{}{}", column, suffix)));
+    }
+  }
+  write_record(writer, &header)?;
+  for i in 0..ids_num {
+    for j in i + 1..ids_num {
+      let mut record = vec![
+        ids[i].clone(),
+        ids[j].clone(),
+        number_format.format(kinship[i * ids_num + j]),
+      ];
+      if let Some(metadata) = metadata {
+        for id in [&ids[i], &ids[j]] {
+          record.extend(metadata.values(id).into_iter().map(metadata_field));
+        }
+      }
+      write_record(writer, &record)?;
+    }
+  }
+  Ok(())
+}
+
+fn metadata_field(value: Option<&str>) -> String {
+  String::from(value.unwrap_or("NA"))
+}
+
+/// @brief Writes comma separated fields, quoting the ones which contain
+/// commas or quotes.
+pub(crate) fn write_record<W: Write>(writer: &mut W, fields: &[String]) -> std::io::Result<()> {
+  let fields = fields
+    .iter()
+    .map(|field| {
+      if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+      } else {
+        field.clone()
+      }
+    })
+    .collect::<Vec<String>>();
+  writeln!(writer, "{}", fields.join(","))
+}
@@ -0,0 +1,160 @@
+// simulate.rs
+
+//! Random genotypes for tests and benchmarks: unrelated individuals in
+//! Hardy-Weinberg equilibrium, with the allele frequency of every marker
+//! drawn from a range and genotypes missing at random. The genotypes are
+//! written as a genotype file and come with their Kinship matrix calculated
+//! in memory, the reference for the file based paths (parsers, batching,
+//! threads, kernels).
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::dosage::calc_kinship_table;
+use super::error::Error;
+use super::geno_matrix::GenoMatrix;
+use super::geno_writer::GenoWriter;
+use super::hard_calls::HardCaller;
+use super::kinship::KinshipOptions;
+use super::kinship_matrix::KinshipMatrix;
+use super::matrix_csv::NumericTable;
+use super::na;
+use super::sketch::SplitMix64;
+
+/// @brief Genotype values of the codes, homozygous A, heterozygous and
+/// homozygous B (the default hab_mapper).
+const GENOTYPE_VALUES: [f64; 3] = [0.0, 0.5, 1.0];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationOptions {
+  pub ids_num: usize,
+  pub markers_num: usize,
+  /// @note Range of the B allele frequency, drawn uniformly per marker.
+  pub allele_freq: (f64, f64),
+  /// @note Probability of a genotype to be missing.
+  pub missing_rate: f64,
+  /// @note Codes of homozygous A, heterozygous and homozygous B.
+  pub codes: [char; 3],
+  pub missing_code: char,
+  /// @note Same seed gives the same genotypes.
+  pub seed: u64,
+}
+
+impl Default for SimulationOptions {
+  fn default() -> Self {
+    SimulationOptions {
+      ids_num: 100,
+      markers_num: 1000,
+      allele_freq: (0.05, 0.5),
+      missing_rate: 0.0,
+      codes: ['A', 'H', 'B'],
+      missing_code: '-',
+      seed: 0,
+    }
+  }
+}
+
+impl SimulationOptions {
+  /// @brief hab_mapper to read the simulated genotype file with.
+  pub fn hab_mapper(&self) -> HashMap<char, f64> {
+    let mut hab_mapper = self.codes.iter().copied().zip(GENOTYPE_VALUES).collect::<HashMap<_, _>>();
+    hab_mapper.insert(self.missing_code, na::NA);
+    hab_mapper
+  }
+
+  /// @note Returns Error::Validation if there are no individuals or markers,
+  /// the frequencies or the missing rate are out of [0, 1] (missing rate of
+  /// 1 is out too), or codes aren't distinct.
+  fn validate(&self) -> std::io::Result<()> {
+    let (min_freq, max_freq) = self.allele_freq;
+    let msg = if self.ids_num == 0 || self.markers_num == 0 {
+      "There should be at least one individual and one marker."
+    } else if !(0.0 <= min_freq && min_freq <= max_freq && max_freq <= 1.0) {
+      "Allele frequency range should be within [0, 1]."
+    } else if !(0.0..1.0).contains(&self.missing_rate) {
+      "Missing rate should be in [0, 1)."
+    } else if self.hab_mapper().len() != 4 {
+      "Genotype codes and the missing code should be distinct."
+    } else {
+      return Ok(());
+    };
+    Err(Error::Validation(String::from(msg)).into())
+  }
+}
+
+/// @brief Draws genotypes, ids are "ind1", "ind2"..., markers "snp1",
+/// "snp2"... Missing genotypes are na::NA.
+///
+/// @note Every marker has its own random stream, so the first markers are
+/// the same for any markers_num (and ids_num doesn't change frequencies).
+pub fn simulate_genotypes(options: &SimulationOptions) -> std::io::Result<GenoMatrix> {
+  options.validate()?;
+  let (min_freq, max_freq) = options.allele_freq;
+  let mut values = Vec::with_capacity(options.ids_num * options.markers_num);
+  for marker in 0..options.markers_num {
+    let mut rng = SplitMix64::new(options.seed, marker as u64);
+    let freq = min_freq + (max_freq - min_freq) * rng.next_f64();
+    values.extend((0..options.ids_num).map(|_| {
+      let (u1, u2, u3) = (rng.next_f64(), rng.next_f64(), rng.next_f64());
+      if u3 <= options.missing_rate {
+        na::NA
+      } else {
+        // next_f64 is in (0, 1], so a frequency of 1 always draws B.
+        let b_alleles = (u1 <= freq) as usize + (u2 <= freq) as usize;
+        GENOTYPE_VALUES[b_alleles]
+      }
+    }));
+  }
+  Ok(GenoMatrix {
+    marker_ids: (1..=options.markers_num).map(|marker| format!("snp{}", marker)).collect(),
+    ids: (1..=options.ids_num).map(|id| format!("ind{}", id)).collect(),
+    values,
+  })
+}
+
+/// @brief Writes genotypes (see simulate_genotypes) as a genotype file with
+/// the codes of options, with a comment line describing the simulation.
+pub fn write_simulated<W: Write>(
+  matrix: &GenoMatrix,
+  options: &SimulationOptions,
+  writer: W,
+) -> std::io::Result<W> {
+  let caller = HardCaller::new(&options.hab_mapper(), 0.0)?.with_missing(options.missing_code);
+  let comment = format!(
+    " Simulated genotypes: seed {}, allele frequencies [{}, {}], missing rate {}.",
+    options.seed, options.allele_freq.0, options.allele_freq.1, options.missing_rate
+  );
+  let mut writer = GenoWriter::new(writer, &matrix.ids, &[comment])?;
+  writer.write_matrix(matrix, &caller)?;
+  writer.into_inner()
+}
+
+/// @brief Kinship matrix of genotypes (see simulate_genotypes) calculated in
+/// memory, what calculations of the written file should give.
+///
+/// @note Returns Error::Validation for GramOrientation::SnpsBySnps.
+pub fn expected_kinship(
+  matrix: &GenoMatrix,
+  kinship_options: &KinshipOptions,
+) -> std::io::Result<KinshipMatrix> {
+  let table = NumericTable {
+    row_ids: matrix.marker_ids.clone(),
+    col_ids: matrix.ids.clone(),
+    values: matrix.values.clone(),
+  };
+  calc_kinship_table(&table, kinship_options)
+}
+
+/// @brief Simulates genotypes, writes them to the genotype file at path and
+/// returns their expected Kinship matrix, see expected_kinship.
+pub fn simulate_geno_file<P: AsRef<Path>>(
+  path: P,
+  options: &SimulationOptions,
+  kinship_options: &KinshipOptions,
+) -> std::io::Result<KinshipMatrix> {
+  let matrix = simulate_genotypes(options)?;
+  write_simulated(&matrix, options, BufWriter::new(File::create(path)?))?;
+  expected_kinship(&matrix, kinship_options)
+}
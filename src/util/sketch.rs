@@ -0,0 +1,165 @@
+// sketch.rs
+
+use super::error::Error;
+use super::kinship::{
+  calc_partial_kinship_threaded, check_weight, finalize_kinship_by, read_accumulated_batch,
+  Accumulated, KinshipOptions, SnpSource,
+};
+
+/// @brief Random projection of the marker space used by the sketch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SketchMethod {
+  /// @brief Every SNP line is added with a random sign to one random row of
+  /// the sketch. Sketching costs the same as parsing.
+  CountSketch,
+  /// @brief Every SNP line is added to all rows of the sketch with N(0, 1/d)
+  /// weights. Slower to sketch, but the error doesn't depend on the marker
+  /// distribution.
+  Gaussian,
+}
+
+/// @brief Approximate Kinship matrix calculation options.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SketchOptions {
+  /// @note Amount of rows d of the sketch: markers are projected to d
+  /// dimensions, the error decreases as 1 / sqrt(d).
+  pub dimensions: usize,
+  pub method: SketchMethod,
+  /// @note Same seed gives the same result.
+  pub seed: u64,
+}
+
+impl Default for SketchOptions {
+  fn default() -> Self {
+    SketchOptions {
+      dimensions: 1000,
+      method: SketchMethod::CountSketch,
+      seed: 0,
+    }
+  }
+}
+
+/// @brief Approximate Kinship matrix along with the error estimate.
+#[derive(Clone, Debug)]
+pub struct ApproxKinship {
+  pub ids_num: usize,
+  pub snps_num: usize,
+  /// @note Normalized and mirrored (full) ids_num x ids_num matrix.
+  pub matrix: Vec<f64>,
+  /// @note Estimated standard deviation of every element of the matrix:
+  /// sqrt((K[i][i] * K[j][j] + K[i][j]^2) / d), the variance of a random
+  /// projection to d dimensions.
+  pub std_errors: Vec<f64>,
+}
+
+/// @brief Calculates approximate Kinship matrix: SNP lines (m of them) are
+/// projected into a sketch of d << m rows, and the ids_num x ids_num product
+/// is calculated from the sketch, which takes O(d * ids_num^2) instead of
+/// O(m * ids_num^2). Meant for exploratory work before the exact calculation.
+///
+/// @note SNP lines are filtered, imputed, transformed and weighted as in
+/// calc_kinship_observed (see read_accumulated_batch), the kernel, parallel
+/// mode and time limit don't apply. Returns Error::Validation if the sketch
+/// dimensions or the batch size is 0.
+pub fn calc_kinship_sketch(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  sketch_options: &SketchOptions,
+) -> std::io::Result<ApproxKinship> {
+  let ids_num = source.ids_num();
+  options.check_batch_rows(ids_num)?;
+  let (dims, batch_size) = (sketch_options.dimensions, options.batch_rows(ids_num));
+  if dims < 1 {
+    return Err(Error::Validation(String::from("Sketch dimensions can't be less than 1.")).into());
+  }
+  let mut sketch = vec![0.0; dims * ids_num];
+  let mut batch = vec![0.0; ids_num * batch_size];
+  let mut snps_num: usize = 0;
+  let mut accumulated = Accumulated::default();
+  loop {
+    let rows = read_accumulated_batch(source, &mut batch, &mut [], options, &mut accumulated)?;
+    if rows == 0 {
+      break;
+    }
+    for line in batch[..rows * ids_num].chunks(ids_num.max(1)) {
+      let mut rng = SplitMix64::new(sketch_options.seed, snps_num as u64);
+      match sketch_options.method {
+        SketchMethod::CountSketch => {
+          let row = (rng.next_u64() % dims as u64) as usize;
+          let sign = if rng.next_u64() & 1 == 0 { 1.0 } else { -1.0 };
+          add_scaled(&mut sketch[row * ids_num..(row + 1) * ids_num], line, sign);
+        }
+        SketchMethod::Gaussian => {
+          let scale = 1.0 / (dims as f64).sqrt();
+          for row in sketch.chunks_mut(ids_num.max(1)) {
+            add_scaled(row, line, rng.next_gaussian() * scale);
+          }
+        }
+      }
+      snps_num += 1;
+    }
+  }
+
+  check_weight(accumulated.snps, accumulated.weight)?;
+  let mut matrix = vec![0.0; ids_num * ids_num];
+  calc_partial_kinship_threaded(&sketch, &mut matrix, ids_num, options.threads_num());
+  finalize_kinship_by(&mut matrix, ids_num, accumulated.weight);
+  let mut std_errors = vec![0.0; ids_num * ids_num];
+  for i in 0..ids_num {
+    for j in 0..ids_num {
+      let (diag_i, diag_j, elem) = (
+        matrix[i * ids_num + i],
+        matrix[j * ids_num + j],
+        matrix[i * ids_num + j],
+      );
+      std_errors[i * ids_num + j] = ((diag_i * diag_j + elem * elem) / dims as f64).sqrt();
+    }
+  }
+  Ok(ApproxKinship {
+    ids_num,
+    snps_num,
+    matrix,
+    std_errors,
+  })
+}
+
+fn add_scaled(dst: &mut [f64], src: &[f64], scale: f64) {
+  for (dst, src) in dst.iter_mut().zip(src) {
+    *dst += src * scale;
+  }
+}
+
+/// @brief SplitMix64 generator. Seeded with the SNP line number, so the
+/// projection of a line doesn't depend on the batching.
+pub(crate) struct SplitMix64 {
+  state: u64,
+}
+
+impl SplitMix64 {
+  pub(crate) fn new(seed: u64, stream: u64) -> Self {
+    let mut rng = SplitMix64 {
+      state: seed ^ stream.wrapping_mul(0xD1B5_4A32_D192_ED03),
+    };
+    rng.next_u64();
+    rng
+  }
+
+  pub(crate) fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+  }
+
+  /// @brief Uniform value in (0, 1].
+  pub(crate) fn next_f64(&mut self) -> f64 {
+    ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+  }
+
+  /// @brief Standard normal value (Box-Muller transform).
+  fn next_gaussian(&mut self) -> f64 {
+    let (u1, u2) = (self.next_f64(), self.next_f64());
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+  }
+}
@@ -0,0 +1,399 @@
+// stats.rs
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+
+use super::error::Error;
+use super::kinship::{
+  column_blocks, is_polymorphic, read_observed_batch, BatchObserver, KinshipOptions, SnpSource,
+};
+use super::marker_set::MarkerSet;
+use super::missing::MissingPolicy;
+use super::na;
+
+/// @brief Range of genotype values given by hab_mapper, used to interpret
+/// values as allele dosages: the minimal value is homozygous for the first
+/// allele, the maximal value is homozygous for the second one, anything in
+/// between is heterozygous.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DosageScale {
+  pub min: f64,
+  pub max: f64,
+}
+
+impl DosageScale {
+  pub fn from_mapper(hab_mapper: &HashMap<char, f64>) -> Self {
+    let values = hab_mapper.values().copied().filter(|v| !na::is_na(*v));
+    let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+      (min.min(v), max.max(v))
+    });
+    DosageScale { min, max }
+  }
+
+  /// @brief Frequency of the second allele given mean genotype value.
+  pub fn allele_freq(&self, mean: f64) -> f64 {
+    if self.max > self.min {
+      (mean - self.min) / (self.max - self.min)
+    } else {
+      0.0
+    }
+  }
+
+  pub fn is_het(&self, value: f64) -> bool {
+    value > self.min && value < self.max
+  }
+}
+
+/// @brief Minor allele frequency filter, see KinshipOptions::maf_filter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MafFilter {
+  /// @note SNP lines with MAF below it are skipped.
+  pub threshold: f64,
+  pub scale: DosageScale,
+}
+
+impl MafFilter {
+  /// @brief Filter with the dosage scale of hab_mapper.
+  pub fn new(threshold: f64, hab_mapper: &HashMap<char, f64>) -> Self {
+    MafFilter {
+      threshold,
+      scale: DosageScale::from_mapper(hab_mapper),
+    }
+  }
+
+  /// @brief Minor allele frequency of the present genotypes of line, NA if
+  /// all are missing.
+  pub fn maf(&self, line: &[f64]) -> f64 {
+    match na::to_option(na::mean(line)) {
+      Some(mean) => {
+        let freq = self.scale.allele_freq(mean);
+        freq.min(1.0 - freq)
+      }
+      None => na::NA,
+    }
+  }
+
+  /// @brief Determines whether line passes the filter.
+  pub(crate) fn keeps(&self, line: &[f64]) -> bool {
+    let maf = self.maf(line);
+    !na::is_na(maf) && maf >= self.threshold
+  }
+}
+
+/// @brief Statistics of a single SNP line (marker).
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarkerStats {
+  pub id: String,
+  /// @note Frequency of the allele at DosageScale::max, NA if all calls are
+  /// missing.
+  pub allele_freq: f64,
+  pub missing_rate: f64,
+  /// @note Fraction of heterozygous calls among non missing ones.
+  pub het_rate: f64,
+}
+
+impl MarkerStats {
+  /// @brief Minor allele frequency.
+  pub fn maf(&self) -> f64 {
+    self.allele_freq.min(1.0 - self.allele_freq)
+  }
+}
+
+/// @brief Statistics of a single individual (column) over all SNP lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndividualStats {
+  pub id: String,
+  pub missing_rate: f64,
+  pub het_rate: f64,
+}
+
+/// @brief Gathers MarkerStats of every SNP line.
+pub struct MarkerStatsAccumulator {
+  scale: DosageScale,
+  stats: Vec<MarkerStats>,
+}
+
+impl MarkerStatsAccumulator {
+  pub fn new(scale: DosageScale) -> Self {
+    MarkerStatsAccumulator {
+      scale,
+      stats: Vec::new(),
+    }
+  }
+
+  pub fn finish(self) -> Vec<MarkerStats> {
+    self.stats
+  }
+}
+
+impl BatchObserver for MarkerStatsAccumulator {
+  fn observe(&mut self, row_ids: &[String], snps: &[f64], ids_num: usize) {
+    for (id, line) in row_ids.iter().zip(snps.chunks(ids_num.max(1))) {
+      let present = na::present(line).count();
+      let het = na::present(line).filter(|v| self.scale.is_het(*v)).count();
+      let rate = |count: usize, total: usize| {
+        if total == 0 {
+          na::NA
+        } else {
+          count as f64 / total as f64
+        }
+      };
+      self.stats.push(MarkerStats {
+        id: id.clone(),
+        allele_freq: na::to_option(na::mean(line))
+          .map_or(na::NA, |mean| self.scale.allele_freq(mean)),
+        missing_rate: rate(ids_num - present, ids_num),
+        het_rate: rate(het, present),
+      });
+    }
+  }
+}
+
+/// @brief Gathers IndividualStats of every column.
+pub struct IndividualStatsAccumulator {
+  scale: DosageScale,
+  ids: Vec<String>,
+  missing: Vec<usize>,
+  het: Vec<usize>,
+  snps_num: usize,
+}
+
+impl IndividualStatsAccumulator {
+  pub fn new(scale: DosageScale, ids: Vec<String>) -> Self {
+    IndividualStatsAccumulator {
+      scale,
+      missing: vec![0; ids.len()],
+      het: vec![0; ids.len()],
+      ids,
+      snps_num: 0,
+    }
+  }
+
+  pub fn finish(self) -> Vec<IndividualStats> {
+    let snps_num = self.snps_num;
+    self
+      .ids
+      .into_iter()
+      .zip(self.missing.into_iter().zip(self.het))
+      .map(|(id, (missing, het))| {
+        let present = snps_num - missing;
+        IndividualStats {
+          id,
+          missing_rate: if snps_num == 0 {
+            na::NA
+          } else {
+            missing as f64 / snps_num as f64
+          },
+          het_rate: if present == 0 {
+            na::NA
+          } else {
+            het as f64 / present as f64
+          },
+        }
+      })
+      .collect()
+  }
+}
+
+impl BatchObserver for IndividualStatsAccumulator {
+  fn observe(&mut self, row_ids: &[String], snps: &[f64], ids_num: usize) {
+    for line in snps.chunks(ids_num.max(1)) {
+      for (i, value) in line.iter().enumerate() {
+        if na::is_na(*value) {
+          self.missing[i] += 1;
+        } else if self.scale.is_het(*value) {
+          self.het[i] += 1;
+        }
+      }
+    }
+    self.snps_num += row_ids.len();
+  }
+}
+
+/// @brief Statistics of every marker and individual, see geno_stats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenoStats {
+  pub markers: Vec<MarkerStats>,
+  pub individuals: Vec<IndividualStats>,
+}
+
+/// @brief Reads all SNP lines of source once, a batch at a time, gathering
+/// MarkerStats and IndividualStats, e.g. to choose a MafFilter or to spot
+/// bad samples before the Kinship matrix calculation.
+///
+/// @param[in] ids Ids of the columns of source.
+/// @note Source should pass lines with missing genotypes on. Returns
+/// Error::Validation if the amount of ids doesn't match source.
+pub fn geno_stats(
+  source: &mut dyn SnpSource,
+  ids: Vec<String>,
+  scale: DosageScale,
+) -> std::io::Result<GenoStats> {
+  let ids_num = source.ids_num();
+  if ids.len() != ids_num {
+    return Err(
+      Error::Validation(format!("Source has {} columns, got {} ids.", ids_num, ids.len())).into(),
+    );
+  }
+  let mut markers = MarkerStatsAccumulator::new(scale);
+  let mut individuals = IndividualStatsAccumulator::new(scale, ids);
+  let mut buf = vec![0.0; ids_num.max(1) * KinshipOptions::default().batch_rows(ids_num)];
+  while read_observed_batch(source, &mut buf, &mut [&mut markers, &mut individuals], None)? > 0 {}
+  Ok(GenoStats {
+    markers: markers.finish(),
+    individuals: individuals.finish(),
+  })
+}
+
+/// @brief Gathers the identity-by-state matrix: for every pair of individuals
+/// the proportion of alleles shared (1 for equal genotypes, 0.5 for a
+/// homozygous and a heterozygous one, 0 for opposite homozygous ones) over
+/// the markers genotyped in both, e.g. to check relatedness of samples along
+/// with the Kinship matrix.
+///
+/// @note Takes two ids_num x ids_num matrices of memory. Every batch is
+/// split between threads by column blocks of the matrix (as in
+/// ParallelMode::ColumnBlocks), the observing (reading) thread waits for
+/// them.
+pub struct IbsAccumulator {
+  scale: DosageScale,
+  ids_num: usize,
+  /// @note Upper part by columns (column j at [j * ids_num, (j + 1) *
+  /// ids_num)), sums of shared allele proportions.
+  shared: Vec<f64>,
+  /// @note Upper part by columns, amounts of markers genotyped in both.
+  markers: Vec<u32>,
+  freqs: Vec<f64>,
+  threads_num: usize,
+  /// @note Filters of lines, see with_options.
+  selected: Option<Arc<MarkerSet>>,
+  maf_filter: Option<MafFilter>,
+  drop_monomorphic: bool,
+  missing: MissingPolicy,
+}
+
+impl IbsAccumulator {
+  /// @brief Accumulator of all observed lines on a single thread.
+  pub fn new(scale: DosageScale, ids_num: usize) -> Self {
+    IbsAccumulator {
+      scale,
+      ids_num,
+      shared: vec![0.0; ids_num * ids_num],
+      markers: vec![0; ids_num * ids_num],
+      freqs: Vec::new(),
+      threads_num: 1,
+      selected: None,
+      maf_filter: None,
+      drop_monomorphic: false,
+      missing: MissingPolicy::default(),
+    }
+  }
+
+  /// @brief Accumulates the lines the Kinship matrix calculated with options
+  /// uses (options.markers, drop_monomorphic, maf_filter and lines skipped by
+  /// options.missing), on options.threads_num() threads.
+  pub fn with_options(mut self, options: &KinshipOptions) -> Self {
+    self.threads_num = options.threads_num();
+    self.selected = options.markers.clone();
+    self.maf_filter = options.maf_filter;
+    self.drop_monomorphic = options.drop_monomorphic;
+    self.missing = options.missing;
+    self
+  }
+
+  /// @brief Full (mirrored) ids_num x ids_num matrix, row-major. Pairs
+  /// without markers genotyped in both are na::NA.
+  pub fn finish(self) -> Vec<f64> {
+    let n = self.ids_num;
+    let mut res = vec![na::NA; n * n];
+    for j in 0..n {
+      for i in 0..=j {
+        let markers = self.markers[j * n + i];
+        if markers > 0 {
+          res[i * n + j] = self.shared[j * n + i] / markers as f64;
+          res[j * n + i] = res[i * n + j];
+        }
+      }
+    }
+    res
+  }
+
+  /// @brief Whether the line of row_id passes the filters of with_options.
+  fn keeps(&self, row_id: &str, line: &[f64]) -> bool {
+    if let Some(selected) = &self.selected {
+      if !selected.contains(row_id) {
+        return false;
+      }
+    }
+    if let Some(filter) = &self.maf_filter {
+      if !filter.keeps(line) {
+        return false;
+      }
+    }
+    (!self.drop_monomorphic || is_polymorphic(line)) && self.missing.keeps(line)
+  }
+}
+
+impl BatchObserver for IbsAccumulator {
+  fn observe(&mut self, row_ids: &[String], snps: &[f64], ids_num: usize) {
+    let n = self.ids_num;
+    let mut freqs = std::mem::take(&mut self.freqs);
+    freqs.clear();
+    for (row_id, line) in row_ids.iter().zip(snps.chunks_exact(ids_num.max(1))) {
+      if self.keeps(row_id, line) {
+        freqs.extend(line.iter().map(|value| match na::is_na(*value) {
+          true => na::NA,
+          false => self.scale.allele_freq(*value),
+        }));
+      }
+    }
+    if freqs.is_empty() {
+      self.freqs = freqs;
+      return;
+    }
+    let mut columns = self.shared.chunks_mut(n.max(1)).zip(self.markers.chunks_mut(n.max(1)));
+    let mut blocks = Vec::new();
+    for cols in column_blocks(n, self.threads_num) {
+      let block = columns.by_ref().take(cols.len()).collect::<Vec<_>>();
+      blocks.push((cols, block));
+    }
+    let kept = &freqs;
+    // The last block is accumulated on the observing thread.
+    let (last_cols, last_block) = blocks.pop().unwrap_or_default();
+    thread::scope(|scope| {
+      for (cols, block) in blocks {
+        scope.spawn(move || accumulate_ibs_block(kept, n, cols, block));
+      }
+      accumulate_ibs_block(kept, n, last_cols, last_block);
+    });
+    self.freqs = freqs;
+  }
+}
+
+/// @brief Adds lines of allele frequencies (freqs, ids_num values each) to
+/// the columns cols of IbsAccumulator, block holds the shared sums and the
+/// amounts of markers of every column.
+fn accumulate_ibs_block(
+  freqs: &[f64],
+  ids_num: usize,
+  cols: Range<usize>,
+  mut block: Vec<(&mut [f64], &mut [u32])>,
+) {
+  for line in freqs.chunks_exact(ids_num.max(1)) {
+    for (j, (shared, markers)) in cols.clone().zip(block.iter_mut()) {
+      let freq_j = line[j];
+      if na::is_na(freq_j) {
+        continue;
+      }
+      let column = shared.iter_mut().zip(markers.iter_mut()).zip(line);
+      for ((shared, markers), freq_i) in column.take(j + 1) {
+        if !na::is_na(*freq_i) {
+          *shared += 1.0 - (freq_i - freq_j).abs();
+          *markers += 1;
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,103 @@
+// stream.rs
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use super::chunked::ChunkedGenoReader;
+use super::kinship::{
+  calc_kinship_observed, calc_kinship_parallel, GramOrientation, KinshipOptions,
+};
+use super::kinship_matrix::KinshipMatrix;
+use super::parse_report::{codes, ParseReport};
+use super::read_header_line;
+use crate::reader::consume_comments;
+
+/// @brief Genotype data from a reader which can't seek (stdin, network
+/// streams): comments and the header line are read upfront, SNP lines in a
+/// single pass. See GenoParser for files and other seekable readers.
+///
+/// @note SNP lines must start with a row id. The methods reading SNP lines
+/// take the stream, since they can't rewind it.
+pub struct GenoStream<R: BufRead> {
+  reader: R,
+  comments: Vec<String>,
+  /// @note Ids of the individuals from the header line.
+  markers: Vec<String>,
+  hab_mapper: HashMap<char, f64>,
+  report: ParseReport,
+}
+
+impl<R: BufRead> GenoStream<R> {
+  /// @brief Reads comments and the header line, the reader is left at the
+  /// beginning of SNP lines.
+  pub fn new(mut reader: R, hab_mapper: HashMap<char, f64>) -> std::io::Result<Self> {
+    let comments = consume_comments(&mut reader)?;
+    let (mut markers, latin1, _) = read_header_line(&mut reader)?;
+    markers.remove(0);
+    let mut report = ParseReport::new();
+    if latin1 {
+      report.warn(
+        codes::LATIN1_DECODED,
+        Some(comments.len() + 1),
+        String::from("Header line is not valid UTF-8, ids were decoded as Latin-1."),
+      );
+    }
+    Ok(GenoStream {
+      reader,
+      comments,
+      markers,
+      hab_mapper,
+      report,
+    })
+  }
+
+  pub fn get_comments(&self) -> &Vec<String> {
+    &self.comments
+  }
+
+  /// @brief Ids of the individuals, as GenoParser::get_markers.
+  pub fn get_markers(&self) -> &Vec<String> {
+    &self.markers
+  }
+
+  pub fn get_report(&self) -> &ParseReport {
+    &self.report
+  }
+
+  /// @brief SnpSource over the SNP lines, e.g. for calc_kinship_loco. Lines
+  /// with missing genotypes are rejected unless allow_na is set.
+  pub fn snp_source(&mut self, allow_na: bool) -> std::io::Result<ChunkedGenoReader<'_, R>> {
+    let source =
+      ChunkedGenoReader::new(&mut self.reader, self.markers.len(), &self.hab_mapper, allow_na)?;
+    // SNP lines follow the comments and the header line.
+    Ok(source.at_line(self.comments.len() + 2))
+  }
+
+  /// @brief Same as GenoParser::calc_kinship_with_options.
+  pub fn calc_kinship_with_options(
+    mut self,
+    options: &KinshipOptions,
+  ) -> std::io::Result<Vec<f64>> {
+    match options.orientation {
+      GramOrientation::IdsByIds => {
+        let mut source = self.snp_source(options.missing.allows_na())?;
+        calc_kinship_observed(&mut source, options, &mut [])
+      }
+      GramOrientation::SnpsBySnps => calc_kinship_parallel(
+        &mut self.reader.lines(),
+        self.markers.len(),
+        &self.hab_mapper,
+        options,
+      ),
+    }
+  }
+
+  /// @brief Same as calc_kinship_with_options, the matrix comes with the ids
+  /// of its rows.
+  ///
+  /// @note Orientation GramOrientation::IdsByIds is expected.
+  pub fn calc_kinship_matrix(self, options: &KinshipOptions) -> std::io::Result<KinshipMatrix> {
+    let markers = self.markers.clone();
+    KinshipMatrix::new(markers, self.calc_kinship_with_options(options)?)
+  }
+}
@@ -0,0 +1,124 @@
+// summary.rs
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::error::escape_json;
+use super::kernel::KinshipKernel;
+use super::kinship::KinshipOptions;
+use super::metrics::Metrics;
+use super::parse_report::{ParseReport, Severity};
+
+/// @brief Provenance of a finished run (Kinship calculation, Dataset
+/// analyses, conversions), to be logged the same way by every caller.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunSummary {
+  /// @note SNP lines read from the input.
+  pub markers_read: usize,
+  /// @note SNP lines which contributed to the result (e.g. monomorphic lines
+  /// dropped by KinshipOptions::drop_monomorphic, rare ones dropped by
+  /// KinshipOptions::maf_filter, incomplete ones dropped by
+  /// MissingPolicy::SkipMarker and the ones out of KinshipOptions::markers
+  /// are not).
+  pub markers_used: usize,
+  pub individuals: usize,
+  pub wall_time: Duration,
+  /// @note Name of the kernel, see KinshipKernel::name.
+  pub backend: String,
+  /// @note Warnings of the parse report and kernel fallbacks.
+  pub warnings: usize,
+}
+
+impl RunSummary {
+  /// @brief Single line JSON object with the fields, wall_time in seconds.
+  pub fn to_json(&self) -> String {
+    format!(
+      "{{\"markers_read\":{},\"markers_used\":{},\"individuals\":{},\"wall_time\":{},\
+       \"backend\":\"{}\",\"warnings\":{}}}",
+      self.markers_read,
+      self.markers_used,
+      self.individuals,
+      self.wall_time.as_secs_f64(),
+      escape_json(&self.backend),
+      self.warnings
+    )
+  }
+}
+
+impl fmt::Display for RunSummary {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} of {} markers used, {} individuals, {:.3}s on {}, {} warnings",
+      self.markers_used,
+      self.markers_read,
+      self.individuals,
+      self.wall_time.as_secs_f64(),
+      self.backend,
+      self.warnings
+    )
+  }
+}
+
+/// @brief Collects RunSummary of a Kinship calculation: runs it with options
+/// which have metrics (the given ones, or fresh if there are none).
+pub(crate) struct KinshipRun {
+  pub(crate) options: KinshipOptions,
+  started: Instant,
+}
+
+impl KinshipRun {
+  pub(crate) fn start(options: &KinshipOptions) -> Self {
+    let mut options = options.clone();
+    options.metrics.get_or_insert_with(Default::default);
+    KinshipRun {
+      options,
+      started: Instant::now(),
+    }
+  }
+
+  pub(crate) fn metrics(&self) -> &Metrics {
+    self.options.metrics.as_deref().unwrap()
+  }
+
+  /// @param[in] report problems of the input, its warnings are counted.
+  pub(crate) fn finish(&self, individuals: usize, report: &ParseReport) -> RunSummary {
+    let metrics = self.metrics();
+    let backend = match &self.options.kernel {
+      Some(kernel) => kernel.name(),
+      None => super::kernel::CpuKernel.name(),
+    };
+    RunSummary {
+      markers_read: metrics.snps()
+        + metrics.monomorphic()
+        + metrics.rare()
+        + metrics.incomplete()
+        + metrics.excluded(),
+      markers_used: metrics.snps(),
+      individuals,
+      wall_time: self.started.elapsed(),
+      backend: String::from(backend),
+      warnings: report.count(Severity::Warning) + metrics.fallbacks().len(),
+    }
+  }
+}
+
+/// @brief RunSummary of a conversion started at started, which wrote every
+/// one of markers markers read, of individuals individuals, on the CPU.
+///
+/// @param[in] report problems of the input, its warnings are counted.
+pub(crate) fn conversion_summary(
+  started: Instant,
+  markers: usize,
+  individuals: usize,
+  report: &ParseReport,
+) -> RunSummary {
+  RunSummary {
+    markers_read: markers,
+    markers_used: markers,
+    individuals,
+    wall_time: started.elapsed(),
+    backend: String::from(super::kernel::CpuKernel.name()),
+    warnings: report.count(Severity::Warning),
+  }
+}
@@ -0,0 +1,124 @@
+// testing.rs
+
+//! Approximate comparison of matrices, for validating integrations against
+//! this crate: floating point results differ in the last bits between thread
+//! counts, kernels and batch sizes, so exact comparison is too strict.
+
+use std::fmt;
+
+use super::na;
+
+/// @brief Values a and b are close when |a - b| <= abs + rel * max(|a|, |b|).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tolerance {
+  pub abs: f64,
+  pub rel: f64,
+}
+
+impl Default for Tolerance {
+  fn default() -> Self {
+    Tolerance {
+      abs: 1e-12,
+      rel: 1e-9,
+    }
+  }
+}
+
+impl Tolerance {
+  pub fn absolute(abs: f64) -> Self {
+    Tolerance { abs, rel: 0.0 }
+  }
+
+  pub fn relative(rel: f64) -> Self {
+    Tolerance { abs: 0.0, rel }
+  }
+
+  /// @note Two missing values (na::NA) are close, a missing and a present one
+  /// are not.
+  pub fn is_close(&self, a: f64, b: f64) -> bool {
+    match (na::is_na(a), na::is_na(b)) {
+      (true, true) => true,
+      (false, false) => a == b || (a - b).abs() <= self.abs + self.rel * a.abs().max(b.abs()),
+      _ => false,
+    }
+  }
+}
+
+/// @brief Summary of differences between two matrices of the same shape.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatrixDiff {
+  pub cols_num: usize,
+  /// @brief Largest |expected - actual| over elements present in both.
+  pub max_abs_diff: f64,
+  /// @brief Position (row-major) of max_abs_diff, None if there are no
+  /// such elements.
+  pub max_abs_diff_pos: Option<usize>,
+  /// @brief Amount of elements which are not close.
+  pub mismatches: usize,
+  /// @brief Position of the first element which is not close.
+  pub first_mismatch: Option<usize>,
+}
+
+impl MatrixDiff {
+  pub fn is_close(&self) -> bool {
+    self.mismatches == 0
+  }
+}
+
+impl fmt::Display for MatrixDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let cols_num = self.cols_num.max(1);
+    write!(f, "{} mismatching elements", self.mismatches)?;
+    if let Some(pos) = self.first_mismatch {
+      write!(f, ", the first at ({}, {})", pos / cols_num, pos % cols_num)?;
+    }
+    if let Some(pos) = self.max_abs_diff_pos {
+      write!(
+        f,
+        ", max abs diff {:e} at ({}, {})",
+        self.max_abs_diff,
+        pos / cols_num,
+        pos % cols_num
+      )?;
+    }
+    Ok(())
+  }
+}
+
+/// @brief Compares row-major matrices with cols_num columns element-wise.
+///
+/// @note Panics if lengths of the matrices differ.
+pub fn compare(expected: &[f64], actual: &[f64], cols_num: usize, tol: Tolerance) -> MatrixDiff {
+  assert_eq!(expected.len(), actual.len(), "Matrices have different sizes.");
+  let mut diff = MatrixDiff {
+    cols_num,
+    max_abs_diff: 0.0,
+    max_abs_diff_pos: None,
+    mismatches: 0,
+    first_mismatch: None,
+  };
+  for (pos, (a, b)) in expected.iter().zip(actual).enumerate() {
+    if !tol.is_close(*a, *b) {
+      diff.mismatches += 1;
+      diff.first_mismatch.get_or_insert(pos);
+    }
+    let abs_diff = (a - b).abs();
+    if na::is_na(abs_diff) {
+      continue;
+    }
+    if diff.max_abs_diff_pos.is_none() || abs_diff > diff.max_abs_diff {
+      diff.max_abs_diff = abs_diff;
+      diff.max_abs_diff_pos = Some(pos);
+    }
+  }
+  diff
+}
+
+/// @brief Panics with a MatrixDiff report if the matrices are not close.
+#[track_caller]
+pub fn assert_matrix_close(expected: &[f64], actual: &[f64], cols_num: usize, tol: Tolerance) {
+  let diff = compare(expected, actual, cols_num, tol);
+  if !diff.is_close() {
+    panic!("Matrices are not close ({:?}): {}.", tol, diff);
+  }
+}
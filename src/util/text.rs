@@ -0,0 +1,85 @@
+// text.rs
+
+//! Text files are expected in UTF-8, however legacy phenotype and genotype
+//! exports sometimes contain Latin-1 characters in ids (e.g. sample names).
+//! Instead of failing on such lines, they are decoded as Latin-1, which maps
+//! every byte to a character, so no information is lost.
+
+use std::io::BufRead;
+
+/// @brief Decodes bytes as UTF-8, or as Latin-1 if they are not valid UTF-8.
+/// Returns the string and whether Latin-1 was used.
+pub fn decode(bytes: Vec<u8>) -> (String, bool) {
+  match String::from_utf8(bytes) {
+    Ok(s) => (s, false),
+    Err(e) => (e.into_bytes().iter().map(|b| char::from(*b)).collect(), true),
+  }
+}
+
+/// @brief Same as BufRead::read_line, but lines which are not valid UTF-8
+/// are decoded as Latin-1. Returns amount of bytes read and whether Latin-1
+/// was used.
+pub fn read_line<R: BufRead + ?Sized>(
+  reader: &mut R,
+  buf: &mut String,
+) -> std::io::Result<(usize, bool)> {
+  let mut bytes = Vec::new();
+  let read_bytes_count = reader.read_until(b'\n', &mut bytes)?;
+  let (line, latin1) = decode(bytes);
+  buf.push_str(&line);
+  Ok((read_bytes_count, latin1))
+}
+
+/// @brief Removes the line terminator ("\n" or "\r\n") of line, if there is
+/// one: the last line of a file may lack it.
+pub fn trim_line_end(line: &mut String) {
+  if line.ends_with('\n') {
+    line.pop();
+    if line.ends_with('\r') {
+      line.pop();
+    }
+  }
+}
+
+/// @brief Same as BufRead::lines, but lines which are not valid UTF-8 are
+/// decoded as Latin-1.
+pub struct Lines<R: BufRead> {
+  reader: R,
+  line_num: usize,
+  latin1_lines: Vec<usize>,
+}
+
+impl<R: BufRead> Lines<R> {
+  pub fn new(reader: R) -> Self {
+    Lines {
+      reader,
+      line_num: 0,
+      latin1_lines: Vec::new(),
+    }
+  }
+
+  /// @brief Numbers (starting from 1) of the lines decoded as Latin-1 so far.
+  pub fn latin1_lines(&self) -> &[usize] {
+    &self.latin1_lines
+  }
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+  type Item = std::io::Result<String>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let mut line = String::new();
+    match read_line(&mut self.reader, &mut line) {
+      Ok((0, _)) => None,
+      Ok((_, latin1)) => {
+        self.line_num += 1;
+        if latin1 {
+          self.latin1_lines.push(self.line_num);
+        }
+        trim_line_end(&mut line);
+        Some(Ok(line))
+      }
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
@@ -0,0 +1,85 @@
+// throttle.rs
+
+use std::time::Duration;
+
+/// @brief Limit of CPU usage for long running calculations, as a fraction of
+/// all logical cores (e.g. 0.5 for "50%").
+///
+/// The limit is applied by running fewer workers and, when even a single
+/// worker would exceed the limit, by making workers sleep after each piece of
+/// work for a time proportional to its duration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuLimit {
+  fraction: f64,
+}
+
+impl CpuLimit {
+  /// @brief Returns None unless fraction is in (0, 1].
+  pub fn new(fraction: f64) -> Option<Self> {
+    if fraction > 0.0 && fraction <= 1.0 {
+      Some(CpuLimit { fraction })
+    } else {
+      None
+    }
+  }
+
+  /// @brief Parses limits like "50%" or "0.5".
+  pub fn parse(limit: &str) -> Option<Self> {
+    let limit = limit.trim();
+    let fraction = match limit.strip_suffix('%') {
+      Some(percents) => percents.trim().parse::<f64>().ok()? / 100.0,
+      None => limit.parse::<f64>().ok()?,
+    };
+    CpuLimit::new(fraction)
+  }
+
+  pub fn fraction(&self) -> f64 {
+    self.fraction
+  }
+
+  /// @brief Amount of workers to run on a machine with cpus_num logical cores.
+  pub fn workers(&self, cpus_num: usize) -> usize {
+    ((cpus_num as f64 * self.fraction).ceil() as usize).clamp(1, cpus_num.max(1))
+  }
+
+  /// @brief Fraction of time each of the workers is allowed to be busy.
+  pub fn duty_cycle(&self, cpus_num: usize) -> f64 {
+    self.workers_duty_cycle(cpus_num, self.workers(cpus_num))
+  }
+
+  /// @brief Same as duty_cycle for workers_num workers, e.g. a given amount
+  /// of threads, which may be more than workers(cpus_num).
+  pub fn workers_duty_cycle(&self, cpus_num: usize, workers_num: usize) -> f64 {
+    (cpus_num as f64 * self.fraction / workers_num.max(1) as f64).min(1.0)
+  }
+
+  pub(crate) fn throttle(&self, cpus_num: usize, workers_num: usize) -> Throttle {
+    Throttle {
+      duty_cycle: Some(self.workers_duty_cycle(cpus_num, workers_num)),
+    }
+  }
+}
+
+/// @brief Yield point inserted by workers after each piece of work.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Throttle {
+  /// @note None when there is no limit.
+  duty_cycle: Option<f64>,
+}
+
+impl Throttle {
+  pub(crate) fn unlimited() -> Self {
+    Throttle { duty_cycle: None }
+  }
+
+  /// @brief Gives up the CPU after a piece of work which took busy time.
+  pub(crate) fn pause(&self, busy: Duration) {
+    match self.duty_cycle {
+      None => (),
+      Some(duty_cycle) if duty_cycle < 1.0 => {
+        std::thread::sleep(busy.mul_f64((1.0 - duty_cycle) / duty_cycle))
+      }
+      Some(_) => std::thread::yield_now(),
+    }
+  }
+}
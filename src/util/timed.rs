@@ -0,0 +1,342 @@
+// timed.rs
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::error::{ensure_memory, Error};
+use super::kinship::{
+  accumulate_kinship, check_snps_num, check_weight, finalize_kinship_by, Accumulated,
+  KinshipOptions, SnpSource,
+};
+
+/// @brief Magic bytes of the file written by KinshipCheckpoint::write_to.
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RQ2KCKP2";
+
+/// @brief Magic bytes of the checkpoints written before
+/// KinshipCheckpoint::offset, read without it.
+const CHECKPOINT_MAGIC_V1: &[u8; 8] = b"RQ2KCKP1";
+
+/// @brief KinshipCheckpoint::offset of None in the written checkpoint.
+const NO_OFFSET: u64 = u64::MAX;
+
+/// @brief State of a Kinship matrix calculation which stopped at
+/// KinshipOptions::time_limit, to resume it from (e.g. in the next job of a
+/// scheduler).
+#[derive(Clone, Debug, PartialEq)]
+pub struct KinshipCheckpoint {
+  pub ids_num: usize,
+  /// @note SNP lines consumed from the source, skipped on resume.
+  pub lines_read: usize,
+  /// @note SNP lines accumulated, lines dropped by the filters are not.
+  pub snps: usize,
+  /// @note Position (in bytes) of the SNP line after lines_read in the
+  /// input, to seek to on resume instead of reading lines_read lines again.
+  /// None if the source doesn't track it, or lines were read past lines_read.
+  pub offset: Option<u64>,
+  /// @note Sum of the weights of the accumulated lines, the divisor of the
+  /// matrix.
+  weight: f64,
+  /// @note Upper part of the matrix, not normalized.
+  matrix: Vec<f64>,
+}
+
+impl KinshipCheckpoint {
+  fn new(ids_num: usize) -> Self {
+    KinshipCheckpoint {
+      ids_num,
+      lines_read: 0,
+      snps: 0,
+      offset: None,
+      weight: 0.0,
+      matrix: vec![0.0; ids_num * ids_num],
+    }
+  }
+
+  /// @brief Checkpoint to resume from, a new one if resume is None.
+  ///
+  /// @note Returns Error::Validation if resume is of another amount of ids.
+  fn resumed(ids_num: usize, resume: Option<KinshipCheckpoint>) -> std::io::Result<Self> {
+    let checkpoint = resume.unwrap_or_else(|| KinshipCheckpoint::new(ids_num));
+    if checkpoint.ids_num != ids_num {
+      return Err(
+        Error::Validation(format!(
+          "Checkpoint is of {} ids, however the source has {}.",
+          checkpoint.ids_num, ids_num
+        ))
+        .into(),
+      );
+    }
+    Ok(checkpoint)
+  }
+
+  /// @brief Adds matrix of the accumulated SNP lines.
+  fn add(&mut self, matrix: &[f64], accumulated: &Accumulated) {
+    for (total, elem) in self.matrix.iter_mut().zip(matrix) {
+      *total += *elem;
+    }
+    self.lines_read += accumulated.lines_read;
+    self.snps += accumulated.snps;
+    self.weight += accumulated.weight;
+  }
+
+  /// @brief Normalized and mirrored matrix of the checkpoint.
+  fn finish(self, complete: bool) -> std::io::Result<TimedKinship> {
+    if complete {
+      check_snps_num(self.snps, self.ids_num)?;
+    }
+    check_weight(self.snps, self.weight)?;
+    let mut matrix = self.matrix.clone();
+    finalize_kinship_by(&mut matrix, self.ids_num, self.weight);
+    Ok(TimedKinship {
+      matrix,
+      complete,
+      checkpoint: self,
+    })
+  }
+
+  /// @brief Writes the checkpoint to path. It's written to a temporary file
+  /// next to path first, so a crash while saving leaves the previous
+  /// checkpoint at path intact.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    self.write_to(&mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)
+  }
+
+  /// @brief Reads checkpoint saved by save.
+  pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    Self::read_from(&mut BufReader::new(File::open(path)?))
+  }
+
+  /// @brief Writes the checkpoint in a binary format read by read_from:
+  /// magic, ids_num, lines_read, snps, offset (u64::MAX if None), weight and
+  /// the matrix, all little-endian.
+  pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+    writer.write_all(CHECKPOINT_MAGIC)?;
+    for value in [self.ids_num, self.lines_read, self.snps] {
+      writer.write_all(&(value as u64).to_le_bytes())?;
+    }
+    writer.write_all(&self.offset.unwrap_or(NO_OFFSET).to_le_bytes())?;
+    writer.write_all(&self.weight.to_le_bytes())?;
+    for value in &self.matrix {
+      writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+  }
+
+  /// @brief Reads checkpoint written by write_to, also the ones written
+  /// without offset.
+  ///
+  /// @note Returns Error::Validation if reader doesn't hold a checkpoint,
+  /// Error::OutOfMemory if its matrix wouldn't fit in memory.
+  pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != CHECKPOINT_MAGIC && &magic != CHECKPOINT_MAGIC_V1 {
+      return Err(Error::Validation(String::from("This is not a Kinship checkpoint.")).into());
+    }
+    let mut read_word = || -> std::io::Result<[u8; 8]> {
+      let mut word = [0u8; 8];
+      reader.read_exact(&mut word)?;
+      Ok(word)
+    };
+    let ids_num = u64::from_le_bytes(read_word()?) as usize;
+    let lines_read = u64::from_le_bytes(read_word()?) as usize;
+    let snps = u64::from_le_bytes(read_word()?) as usize;
+    let offset = match &magic == CHECKPOINT_MAGIC {
+      true => Some(u64::from_le_bytes(read_word()?)).filter(|offset| *offset != NO_OFFSET),
+      false => None,
+    };
+    let weight = f64::from_le_bytes(read_word()?);
+    let values_num = ids_num
+      .checked_mul(ids_num)
+      .filter(|values_num| values_num.checked_mul(size_of::<f64>()).is_some())
+      .ok_or_else(|| Error::Validation(format!("Checkpoint of {} ids is too large.", ids_num)))?;
+    ensure_memory((values_num * size_of::<f64>()) as u64)?;
+    // Grows with the values read, so a corrupt header fails at the end of the
+    // stream instead of allocating its ids_num^2 values up front.
+    let mut matrix = Vec::new();
+    for _ in 0..values_num {
+      matrix.push(f64::from_le_bytes(read_word()?));
+    }
+    Ok(KinshipCheckpoint {
+      ids_num,
+      lines_read,
+      snps,
+      offset,
+      weight,
+      matrix,
+    })
+  }
+}
+
+/// @brief Kinship matrix calculated within KinshipOptions::time_limit.
+#[derive(Clone, Debug)]
+pub struct TimedKinship {
+  /// @note Normalized and mirrored (full) ids_num x ids_num matrix of the SNP
+  /// lines processed so far, approximate unless complete.
+  pub matrix: Vec<f64>,
+  /// @note Whether all SNP lines of the source were processed.
+  pub complete: bool,
+  /// @note Resumes the calculation, see calc_kinship_timed.
+  pub checkpoint: KinshipCheckpoint,
+}
+
+/// @brief Calculates Kinship matrix (GramOrientation::IdsByIds) until the
+/// end of source or options.time_limit, whatever comes first. Given resume,
+/// the first resume.lines_read lines of source are skipped and the rest is
+/// added to the checkpoint, so a calculation can be split between runs.
+///
+/// @note The same source and options (except time_limit) are expected on
+/// resume. Returns Error::Validation if the checkpoint is of another amount
+/// of ids or source has fewer lines than it. Unlike calc_kinship_observed an
+/// incomplete result may have fewer SNP lines than ids. Holds another
+/// ids_num x ids_num matrix in memory for the checkpoint.
+pub fn calc_kinship_timed(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  resume: Option<KinshipCheckpoint>,
+) -> std::io::Result<TimedKinship> {
+  let ids_num = source.ids_num();
+  let mut checkpoint = KinshipCheckpoint::resumed(ids_num, resume)?;
+  skip_lines(source, checkpoint.lines_read, options.batch_rows(ids_num))?;
+  let (matrix, accumulated) = accumulate_kinship(source, options, &mut [])?;
+  checkpoint.add(&matrix, &accumulated);
+  // Lines read ahead past the deadline weren't accumulated.
+  checkpoint.offset = source.position().filter(|_| !accumulated.timed_out);
+  checkpoint.finish(!accumulated.timed_out)
+}
+
+/// @brief Where and how often calc_kinship_checkpointed saves the state of
+/// the calculation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckpointPolicy {
+  /// @note File the checkpoint is saved to, see KinshipCheckpoint::save.
+  pub path: PathBuf,
+  /// @note Amount of batches (of KinshipOptions::batch_rows SNP lines)
+  /// between the saves, at least 1.
+  pub every_batches: usize,
+}
+
+/// @brief Same as calc_kinship_timed, the checkpoint is saved to policy.path
+/// after every policy.every_batches batches and at the end, so a
+/// calculation interrupted by a crash (or cancelled) can be resumed from the
+/// last save, see KinshipCheckpoint::load.
+///
+/// @note SNP lines between the saves are accumulated as a calculation of
+/// their own (with its worker threads and another ids_num x ids_num matrix),
+/// so every_batches is meant to be large, e.g. a save per hour.
+pub fn calc_kinship_checkpointed(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  policy: &CheckpointPolicy,
+  resume: Option<KinshipCheckpoint>,
+) -> std::io::Result<TimedKinship> {
+  accumulate_checkpointed(source, options, policy, resume, 0)
+}
+
+/// @brief Same as calc_kinship_checkpointed for source which starts after
+/// the first skipped SNP lines, e.g. at the offset of resume.
+pub(crate) fn accumulate_checkpointed(
+  source: &mut dyn SnpSource,
+  options: &KinshipOptions,
+  policy: &CheckpointPolicy,
+  resume: Option<KinshipCheckpoint>,
+  skipped: usize,
+) -> std::io::Result<TimedKinship> {
+  let ids_num = source.ids_num();
+  let mut checkpoint = KinshipCheckpoint::resumed(ids_num, resume)?;
+  let batch_rows = options.batch_rows(ids_num);
+  skip_lines(source, checkpoint.lines_read.saturating_sub(skipped), batch_rows)?;
+  let segment_lines = policy.every_batches.max(1).saturating_mul(batch_rows);
+  // The time limit is of the whole calculation, not of a segment.
+  let deadline = options.time_limit.map(|limit| Instant::now() + limit);
+  let mut batches = 0;
+  let complete = loop {
+    let segment_options = KinshipOptions {
+      time_limit: deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+      progress: options.progress.as_ref().map(|hook| hook.after(checkpoint.lines_read, batches)),
+      ..options.clone()
+    };
+    let mut segment = Segment {
+      source: &mut *source,
+      lines_left: segment_lines,
+    };
+    let (matrix, accumulated) = accumulate_kinship(&mut segment, &segment_options, &mut [])?;
+    let exhausted = segment.lines_left > 0;
+    checkpoint.add(&matrix, &accumulated);
+    batches += accumulated.batches;
+    checkpoint.offset = source.position().filter(|_| !accumulated.timed_out);
+    checkpoint.save(&policy.path)?;
+    if accumulated.timed_out || exhausted {
+      break !accumulated.timed_out;
+    }
+  };
+  if let Some(progress) = options.progress.as_ref().filter(|_| complete) {
+    progress.report(checkpoint.lines_read, batches, true);
+  }
+  checkpoint.finish(complete)
+}
+
+/// @brief SnpSource ending after lines_left SNP lines of source.
+struct Segment<'a> {
+  source: &'a mut dyn SnpSource,
+  lines_left: usize,
+}
+
+impl<'a> SnpSource for Segment<'a> {
+  fn ids_num(&self) -> usize {
+    self.source.ids_num()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let ids_num = self.source.ids_num();
+    let rows = (buf.len() / ids_num.max(1)).min(self.lines_left);
+    if rows == 0 {
+      return Ok(0);
+    }
+    let read = self.source.read_batch(&mut buf[..rows * ids_num], row_ids)?;
+    self.lines_left -= read;
+    Ok(read)
+  }
+}
+
+/// @brief Reads and discards lines_num SNP lines of source, batch_rows lines
+/// at a time.
+fn skip_lines(
+  source: &mut dyn SnpSource,
+  lines_num: usize,
+  batch_rows: usize,
+) -> std::io::Result<()> {
+  let ids_num = source.ids_num();
+  let mut buf = vec![0.0; batch_rows.min(lines_num) * ids_num];
+  let mut skipped = 0;
+  while skipped < lines_num {
+    let rows = (lines_num - skipped).min(batch_rows);
+    match source.read_batch(&mut buf[..rows * ids_num], None)? {
+      0 => {
+        return Err(
+          Error::Validation(format!(
+            "Checkpoint is at SNP line {}, however there are {} of them.",
+            lines_num, skipped
+          ))
+          .into(),
+        )
+      }
+      read => skipped += read,
+    }
+  }
+  Ok(())
+}
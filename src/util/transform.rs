@@ -0,0 +1,106 @@
+// transform.rs
+
+use super::na;
+
+/// @brief Transform of genotype values applied to every SNP line (marker)
+/// right after it's decoded by hab_mapper, before it gets into the Kinship
+/// matrix calculation.
+///
+/// Tools disagree on the dosage convention (e.g. {0, 0.5, 1} vs {0, 1, 2}),
+/// the transform brings the values to the convention expected downstream
+/// instead of rescaling the resulting matrix afterwards.
+///
+/// @note Missing values stay NA.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum DosageTransform {
+  /// @brief Values are used as decoded.
+  #[default]
+  Identity,
+  /// @brief value * scale + offset, e.g. scale 2 maps {0, 0.5, 1} to
+  /// {0, 1, 2}, scale 2 with offset -1 maps it to {-1, 0, 1}.
+  Affine { scale: f64, offset: f64 },
+  /// @brief Subtracts the mean of the marker.
+  Center,
+  /// @brief Subtracts the mean of the marker and divides by its standard
+  /// deviation. Monomorphic markers become all zeros.
+  Standardize,
+}
+
+/// @brief Kind of the relatedness matrix (-gk option of GEMMA), applied to
+/// every SNP line after KinshipOptions::transform.
+///
+/// @note GEMMA matrices are calculated from {0, 1, 2} dosages with missing
+/// genotypes replaced by the mean of the marker, so the matching options are
+/// KinshipOptions::transform rescaling the codes to {0, 1, 2} and
+/// MissingPolicy::MeanImpute (Standardized doesn't depend on the scale).
+/// @note Centered and Standardized can't be combined with
+/// DosageTransform::Center or DosageTransform::Standardize, the Kinship
+/// calculations return Error::Validation for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KinshipKind {
+  /// @brief G'G / n of the (transformed) values.
+  #[default]
+  Raw,
+  /// @brief Subtracts the mean of every marker (GEMMA -gk 1).
+  Centered,
+  /// @brief Subtracts the mean of every marker and divides by its standard
+  /// deviation (GEMMA -gk 2).
+  Standardized,
+}
+
+impl KinshipKind {
+  /// @brief Applies the kind in place to snps, ids_num values per SNP line.
+  pub fn apply(&self, snps: &mut [f64], ids_num: usize) {
+    match self {
+      KinshipKind::Raw => (),
+      KinshipKind::Centered => DosageTransform::Center.apply(snps, ids_num),
+      KinshipKind::Standardized => DosageTransform::Standardize.apply(snps, ids_num),
+    }
+  }
+}
+
+impl DosageTransform {
+  /// @brief Maps value range [from_min, from_max] onto [to_min, to_max].
+  pub fn rescale(from: (f64, f64), to: (f64, f64)) -> Self {
+    let scale = (to.1 - to.0) / (from.1 - from.0);
+    DosageTransform::Affine {
+      scale,
+      offset: to.0 - from.0 * scale,
+    }
+  }
+
+  /// @brief Applies the transform in place to snps, ids_num values per SNP
+  /// line.
+  pub fn apply(&self, snps: &mut [f64], ids_num: usize) {
+    if ids_num == 0 {
+      return;
+    }
+    match *self {
+      DosageTransform::Identity => (),
+      DosageTransform::Affine { scale, offset } => {
+        for value in snps.iter_mut() {
+          *value = *value * scale + offset;
+        }
+      }
+      DosageTransform::Center | DosageTransform::Standardize => {
+        for line in snps.chunks_mut(ids_num) {
+          let mean = na::mean(line);
+          if na::is_na(mean) {
+            continue;
+          }
+          let mut divisor = 1.0;
+          if *self == DosageTransform::Standardize {
+            let (sum, count) = na::present(line).fold((0.0, 0usize), |(sum, count), value| {
+              (sum + (value - mean) * (value - mean), count + 1)
+            });
+            let sd = (sum / count as f64).sqrt();
+            divisor = if sd > 0.0 { sd } else { f64::INFINITY };
+          }
+          for value in line.iter_mut() {
+            *value = (*value - mean) / divisor;
+          }
+        }
+      }
+    }
+  }
+}
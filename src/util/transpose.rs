@@ -0,0 +1,30 @@
+// transpose.rs
+
+use std::ops::Range;
+
+use super::kinship::SnpSource;
+
+/// @brief Reads genotypes of the individuals at cols (positions in the header
+/// line) from all SNP lines of source: a vector per individual, a value per
+/// marker in file order.
+pub fn read_columns(
+  source: &mut dyn SnpSource,
+  cols: Range<usize>,
+) -> std::io::Result<Vec<Vec<f64>>> {
+  const BATCH_ROWS: usize = 256;
+  let ids_num = source.ids_num();
+  let mut columns = vec![Vec::new(); cols.len()];
+  let mut buf = vec![0.0; ids_num.max(1) * BATCH_ROWS];
+  loop {
+    let rows = source.read_batch(&mut buf, None)?;
+    if rows == 0 {
+      break;
+    }
+    for line in buf[..rows * ids_num].chunks(ids_num.max(1)) {
+      for (column, value) in columns.iter_mut().zip(&line[cols.clone()]) {
+        column.push(*value);
+      }
+    }
+  }
+  Ok(columns)
+}
@@ -0,0 +1,129 @@
+// validate.rs
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::BufRead;
+
+use super::parse_report::{codes, ParseReport, Severity};
+use super::text;
+
+/// @brief Most diagnostics of a single code listed by GenoParser::validate,
+/// the rest of them are counted in a summary diagnostic.
+pub const MAX_DIAGNOSTICS_PER_CODE: usize = 100;
+
+/// @brief Warns about every id listed more than once on the header line.
+pub(crate) fn report_duplicate_ids(report: &mut ParseReport, ids: &[String], line: Option<usize>) {
+  let mut seen = HashSet::new();
+  for id in ids.iter().filter(|id| !seen.insert(id.as_str())) {
+    report.warn(
+      codes::DUPLICATE_ID,
+      line,
+      format!("Individual <{}> is listed more than once.", id),
+    );
+  }
+}
+
+/// @brief Checks SNP lines of reader (from its cursor to the end) against
+/// the ids_num ids of the header and the genotype codes of hab_mapper.
+///
+/// @param[in] first_line number of the first SNP line in the file.
+pub(crate) fn validate_snp_lines<R: BufRead>(
+  reader: R,
+  ids_num: usize,
+  hab_mapper: &HashMap<char, f64>,
+  has_row_ids: bool,
+  first_line: usize,
+) -> std::io::Result<ParseReport> {
+  let mut report = LimitedReport::default();
+  let mut first_seen = HashMap::<String, usize>::new();
+  let mut blank_lines = 0;
+  let mut lines = text::Lines::new(reader);
+  for (line_num, line) in (first_line..).zip(&mut lines) {
+    let line = line?;
+    if line.trim().is_empty() {
+      blank_lines += 1;
+      continue;
+    }
+    let (row_id, genotypes) = match has_row_ids {
+      true => match line.split_once('\t') {
+        Some((row_id, genotypes)) if !genotypes.contains('\t') => (row_id, genotypes),
+        _ => {
+          let msg = "SNP line should be a row id and genotype codes separated with a tab.";
+          report.push(Severity::Error, codes::MALFORMED_LINE, line_num, String::from(msg));
+          continue;
+        }
+      },
+      false => ("", line.as_str()),
+    };
+    let marker = match has_row_ids {
+      true => format!("Marker <{}>", row_id),
+      false => String::from("SNP line"),
+    };
+    if has_row_ids {
+      if let Some(first) = first_seen.get(row_id) {
+        let msg = format!("{} is listed more than once, first on line {}.", marker, first);
+        report.push(Severity::Warning, codes::DUPLICATE_MARKER, line_num, msg);
+      } else {
+        first_seen.insert(String::from(row_id), line_num);
+      }
+    }
+    let codes_num = genotypes.chars().count();
+    if codes_num != ids_num {
+      let msg = format!(
+        "{} has {} genotypes, however there are {} ids.",
+        marker, codes_num, ids_num
+      );
+      report.push(Severity::Error, codes::ROW_LENGTH, line_num, msg);
+    }
+    let mut unknown =
+      genotypes.chars().enumerate().filter(|(_, code)| !hab_mapper.contains_key(code));
+    if let Some((column, code)) = unknown.next() {
+      let msg = format!(
+        "{} has unknown genotype code <{}> for individual {} ({} unknown codes in total).",
+        marker,
+        code,
+        column + 1,
+        unknown.count() + 1
+      );
+      report.push(Severity::Error, codes::UNKNOWN_CODE, line_num, msg);
+    }
+  }
+  for line in lines.latin1_lines() {
+    let msg = String::from("SNP line is not valid UTF-8, it was decoded as Latin-1.");
+    report.push(Severity::Warning, codes::LATIN1_DECODED, first_line + line - 1, msg);
+  }
+  let mut report = report.finish();
+  if blank_lines > 0 {
+    let msg = format!("{} blank lines among SNP lines were skipped.", blank_lines);
+    report.warn(codes::BLANK_LINES, None, msg);
+  }
+  Ok(report)
+}
+
+/// @brief ParseReport which lists at most MAX_DIAGNOSTICS_PER_CODE
+/// diagnostics of every code.
+#[derive(Default)]
+struct LimitedReport {
+  report: ParseReport,
+  /// @note Severity and amount of the diagnostics of every code.
+  counts: BTreeMap<&'static str, (Severity, usize)>,
+}
+
+impl LimitedReport {
+  fn push(&mut self, severity: Severity, code: &'static str, line: usize, msg: String) {
+    let count = &mut self.counts.entry(code).or_insert((severity, 0)).1;
+    *count += 1;
+    if *count <= MAX_DIAGNOSTICS_PER_CODE {
+      self.report.push(severity, code, Some(line), msg);
+    }
+  }
+
+  fn finish(mut self) -> ParseReport {
+    for (code, (severity, count)) in self.counts {
+      if count > MAX_DIAGNOSTICS_PER_CODE {
+        let msg = format!("{} more lines with this problem.", count - MAX_DIAGNOSTICS_PER_CODE);
+        self.report.push(severity, code, None, msg);
+      }
+    }
+    self.report
+  }
+}
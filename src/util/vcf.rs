@@ -0,0 +1,202 @@
+// vcf.rs
+
+//! Genotypes of VCF files: the GT field of every sample is converted to the
+//! dosage of the alternate alleles.
+//!
+//! https://samtools.github.io/hts-specs/VCFv4.3.pdf
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use super::dosage::calc_kinship_labeled;
+use super::error::Error;
+use super::kinship::{KinshipOptions, SnpSource};
+use super::kinship_matrix::KinshipMatrix;
+use super::na;
+use super::text;
+
+/// @brief Fixed columns of a VCF record before the sample columns.
+const FIXED_COLUMNS: usize = 9;
+
+/// @brief SnpSource over the records of an uncompressed VCF file, a SNP
+/// line per record with its ID (CHROM:POS if there is none) as the row id.
+/// The dosage of a genotype is the fraction of its alleles which are not the
+/// reference one, on the scale of the default genotype codes: 0 for
+/// homozygous reference, 0.5 for heterozygous, 1 for homozygous alternate
+/// (every alternate allele of a multi-allelic record counts the same).
+///
+/// @note Genotypes with a missing allele ('.') or without GT field are
+/// na::NA, lines with them are rejected unless allow_na is set. Compressed
+/// files (bgzip, BCF) are Error::Validation, they have to be decompressed
+/// first (e.g. bcftools view).
+pub struct VcfReader<R: BufRead> {
+  lines: text::Lines<R>,
+  ids: Vec<String>,
+  allow_na: bool,
+  line_num: usize,
+}
+
+impl VcfReader<BufReader<File>> {
+  pub fn open<P: AsRef<Path>>(path: P, allow_na: bool) -> std::io::Result<Self> {
+    Self::new(BufReader::new(File::open(path)?), allow_na)
+  }
+}
+
+impl<R: BufRead> VcfReader<R> {
+  /// @brief Reads the meta-information lines and the header line.
+  pub fn new(mut reader: R, allow_na: bool) -> std::io::Result<Self> {
+    // Both bgzip and BCF files start with a gzip member.
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+      return Err(
+        Error::Validation(String::from(
+          "Compressed VCF and BCF files are not supported, decompress the file first.",
+        ))
+        .into(),
+      );
+    }
+    let mut lines = text::Lines::new(reader);
+    let mut line_num = 0;
+    let header = loop {
+      line_num += 1;
+      match lines.next() {
+        Some(line) => {
+          let line = line?;
+          if !line.starts_with("##") {
+            break line;
+          }
+        }
+        None => {
+          return Err(
+            Error::Parse {
+              line: None,
+              msg: String::from("There is no header line in the VCF file."),
+            }
+            .into(),
+          )
+        }
+      }
+    };
+    if !header.starts_with("#CHROM") {
+      return Err(
+        Error::Parse {
+          line: Some(line_num),
+          msg: String::from("VCF header line should start with #CHROM."),
+        }
+        .into(),
+      );
+    }
+    Ok(VcfReader {
+      lines,
+      ids: header.split('\t').skip(FIXED_COLUMNS).map(String::from).collect(),
+      allow_na,
+      line_num,
+    })
+  }
+
+  /// @brief Sample ids from the header line.
+  pub fn ids(&self) -> &[String] {
+    &self.ids
+  }
+
+  /// @brief Parses record into dosages. Returns the record id.
+  fn parse_record(&self, line: &str, dosages: &mut [f64]) -> std::io::Result<String> {
+    let invalid = |msg: String| Error::Parse {
+      line: Some(self.line_num),
+      msg,
+    };
+    let mut fields = line.trim_end_matches('\r').split('\t');
+    let mut fixed = [""; FIXED_COLUMNS];
+    for field in fixed.iter_mut() {
+      *field = fields
+        .next()
+        .ok_or_else(|| invalid(String::from("VCF record has less than 9 columns.")))?;
+    }
+    let id = match fixed[2] {
+      "." => format!("{}:{}", fixed[0], fixed[1]),
+      id => String::from(id),
+    };
+    let gt = fixed[8].split(':').position(|key| key == "GT");
+    let mut found = 0;
+    for sample in fields {
+      let genotype = gt.and_then(|gt| sample.split(':').nth(gt));
+      if let Some(dosage) = dosages.get_mut(found) {
+        *dosage = genotype.map_or(na::NA, gt_dosage);
+      }
+      found += 1;
+    }
+    if found != self.ids.len() {
+      return Err(
+        Error::DimensionMismatch {
+          marker: id,
+          expected: self.ids.len(),
+          found,
+        }
+        .into(),
+      );
+    }
+    if !self.allow_na && na::count_na(dosages) > 0 {
+      return Err(invalid(format!("Record <{}> has missing genotypes.", id)).into());
+    }
+    Ok(id)
+  }
+}
+
+impl<R: BufRead> SnpSource for VcfReader<R> {
+  fn ids_num(&self) -> usize {
+    self.ids.len()
+  }
+
+  fn read_batch(
+    &mut self,
+    buf: &mut [f64],
+    mut row_ids: Option<&mut Vec<String>>,
+  ) -> std::io::Result<usize> {
+    let ids_num = self.ids.len().max(1);
+    let mut rows = 0;
+    while (rows + 1) * ids_num <= buf.len() {
+      let line = match self.lines.next() {
+        Some(line) => line?,
+        None => break,
+      };
+      self.line_num += 1;
+      if line.trim().is_empty() {
+        continue;
+      }
+      let id = self.parse_record(&line, &mut buf[rows * ids_num..(rows + 1) * ids_num])?;
+      if let Some(row_ids) = row_ids.as_mut() {
+        row_ids.push(id);
+      }
+      rows += 1;
+    }
+    Ok(rows)
+  }
+}
+
+/// @brief Dosage of GT value, e.g. "0/1" or "1|1", na::NA if an allele is
+/// missing or isn't an allele number.
+fn gt_dosage(gt: &str) -> f64 {
+  let (mut alleles, mut alternate) = (0, 0);
+  for allele in gt.split(['/', '|']) {
+    match allele {
+      "0" => {}
+      _ if !allele.is_empty() && allele.bytes().all(|b| b.is_ascii_digit()) => alternate += 1,
+      _ => return na::NA,
+    }
+    alleles += 1;
+  }
+  alternate as f64 / alleles as f64
+}
+
+/// @brief Calculates Kinship matrix of the VCF file at path (see VcfReader)
+/// by the same engine as of R/qtl2 genotype files.
+///
+/// @note Returns Error::Validation for GramOrientation::SnpsBySnps.
+pub fn calc_kinship_vcf<P: AsRef<Path>>(
+  path: P,
+  options: &KinshipOptions,
+) -> std::io::Result<KinshipMatrix> {
+  let mut reader = VcfReader::open(path, options.missing.allows_na())?;
+  let ids = reader.ids().to_vec();
+  calc_kinship_labeled(&mut reader, ids, options)
+}
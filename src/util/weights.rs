@@ -0,0 +1,88 @@
+// weights.rs
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::error::Error;
+use super::matrix_csv::{LabeledTable, MatrixCsvReader};
+
+/// @brief Per-marker weights of the Kinship accumulation, e.g. imputation
+/// INFO / R² scores of dosage input: K = sum(w * g * g.T) / sum(w), so
+/// poorly imputed markers contribute less.
+///
+/// @note The calculation returns Error::Validation when the weights of the
+/// SNP lines it keeps sum to 0.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MarkerWeights {
+  weights: HashMap<String, f64>,
+}
+
+impl MarkerWeights {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// @brief Reads CSV file with markers as rows and the weight (INFO score) in
+  /// the first column (marker,info).
+  pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    Self::from_table(&MatrixCsvReader::new().read(path)?, 0)
+  }
+
+  /// @brief Takes weights from column col of a table with markers as rows.
+  ///
+  /// @note Weights above 1 (some imputation tools report such R²) are
+  /// clamped to 1. Returns Error::Validation for a missing or negative weight.
+  pub fn from_table(table: &LabeledTable, col: usize) -> std::io::Result<Self> {
+    let mut weights = MarkerWeights::new();
+    for (id, weight) in table.row_ids.iter().zip(table.numeric_column(col)?) {
+      weights.insert(id.clone(), weight)?;
+    }
+    Ok(weights)
+  }
+
+  /// @note Returns Error::Validation for a missing or negative weight.
+  pub fn insert(&mut self, id: String, weight: f64) -> std::io::Result<()> {
+    if weight.is_nan() || weight < 0.0 {
+      return Err(
+        Error::Validation(format!("Weight of marker <{}> is invalid: {}.", id, weight)).into(),
+      );
+    }
+    self.weights.insert(id, weight.min(1.0));
+    Ok(())
+  }
+
+  pub fn get(&self, id: &str) -> Option<f64> {
+    self.weights.get(id).copied()
+  }
+
+  pub fn len(&self) -> usize {
+    self.weights.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.weights.is_empty()
+  }
+
+  /// @brief Scales every SNP line of snps by the square root of its weight,
+  /// so the line contributes w * g * g.T to the product. Returns sum of the
+  /// weights.
+  ///
+  /// @note Returns Error::Validation if a row id has no weight.
+  pub(crate) fn apply(
+    &self,
+    snps: &mut [f64],
+    ids_num: usize,
+    row_ids: &[String],
+  ) -> std::io::Result<f64> {
+    let mut sum = 0.0;
+    for (line, id) in snps.chunks_mut(ids_num.max(1)).zip(row_ids) {
+      let weight = self
+        .get(id)
+        .ok_or_else(|| Error::Validation(format!("SNP <{}> has no weight.", id)))?;
+      let scale = weight.sqrt();
+      line.iter_mut().for_each(|value| *value *= scale);
+      sum += weight;
+    }
+    Ok(sum)
+  }
+}
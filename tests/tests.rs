@@ -18,17 +18,19 @@ mod tests {
 
     let mut f = std::fs::OpenOptions::new()
       .create(true)
+      .truncate(true)
       .write(true)
       .read(true)
       .open(&path)?;
 
     // @note Remove old test data (in case when new test data was provided).
     f.set_len(0)?;
-    f.write(contents.as_bytes())?;
+    f.write_all(contents.as_bytes())?;
     f.seek(SeekFrom::Start(0))?;
     Ok(f)
   }
   #[test]
+  #[allow(deprecated)]
   fn parsers() {
     use rqtl2::util::GenoParser;
     let mut f = create_test_file(
@@ -56,21 +58,21 @@ mod tests {
   }
 
   #[test]
+  #[allow(deprecated)]
   fn read_snps() {
     let path = "test_geno_parsers_2.txt";
     let mut f = create_test_file(
-      &path,
+      path,
       "#test file\n#comment\nmarker	10	12	38	39\nrs31443144	ABAH\nrs31443154	ABHH\nrs31443157	BH--",
     )
     .expect("Failed to create test file.");
 
     let mut hab_mapper = HashMap::new();
-    use std::f64::NAN;
 
     hab_mapper.insert('A', 0.0);
     hab_mapper.insert('H', 0.5);
     hab_mapper.insert('B', 1.0);
-    hab_mapper.insert('-', NAN);
+    hab_mapper.insert('-', f64::NAN);
 
     let geno = rqtl2::util::parse_geno(&mut f, &hab_mapper).unwrap();
     let check_snps = |geno: &Vec<(String, Vec<f64>)>| {
@@ -110,12 +112,11 @@ mod tests {
     .expect("Failed to create test file.");
 
     let mut hab_mapper = HashMap::new();
-    use std::f64::NAN;
 
     hab_mapper.insert('A', 0.0);
     hab_mapper.insert('H', 0.5);
     hab_mapper.insert('B', 1.0);
-    hab_mapper.insert('-', NAN);
+    hab_mapper.insert('-', f64::NAN);
 
     let mut geno_parser =
       rqtl2::util::GenoParser::new_with_file(f, hab_mapper).expect("Failed to create GenoParser");
@@ -126,7 +127,7 @@ mod tests {
     ];
 
     for (rec, test_rec) in geno_parser.iter().unwrap().zip(test_recs.iter()) {
-      assert_eq!(&rec, test_rec);
+      assert_eq!(&rec.unwrap(), test_rec);
     }
   }
 
@@ -139,12 +140,11 @@ mod tests {
     .expect("Failed to create test file.");
 
     let mut hab_mapper = HashMap::new();
-    use std::f64::NAN;
 
     hab_mapper.insert('A', 0.0);
     hab_mapper.insert('H', 0.5);
     hab_mapper.insert('B', 1.0);
-    hab_mapper.insert('-', NAN);
+    hab_mapper.insert('-', f64::NAN);
 
     let expected_kinship_matrix: Vec<f64> = vec![1.0, 1.0, 0.0, 1.0, 3.0, 1.0, 0.0, 1.0, 0.5];
 
@@ -160,4 +160,4548 @@ mod tests {
     }
     assert_eq!(matr, expected_kinship_matrix);
   }
+
+  #[test]
+  fn calc_kinship_column_blocks() {
+    use rqtl2::util::kinship::ParallelMode;
+    use rqtl2::util::KinshipOptions;
+    let codes = ['A', 'H', 'B'];
+    let mut contents = String::from("#test file\nmarker\t1\t2\t3\t4\t5\t6\t7\n");
+    for snp in 0..23 {
+      let line = (0..7)
+        .map(|id| codes[(snp * 5 + id * id) % 3])
+        .collect::<String>();
+      contents.push_str(&format!("rs{}\t{}\n", snp, line));
+    }
+    let f = create_test_file("test_geno_parsers_5.txt", &contents)
+      .expect("Failed to create test file.");
+
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let batches = geno_parser
+      .calc_kinship_with_options(&KinshipOptions {
+        batch_size: 4,
+        mode: ParallelMode::Batches,
+        fast_path_work: 0,
+        ..KinshipOptions::default()
+      })
+      .unwrap();
+    let column_blocks = geno_parser
+      .calc_kinship_with_options(&KinshipOptions {
+        batch_size: 4,
+        mode: ParallelMode::ColumnBlocks,
+        fast_path_work: 0,
+        ..KinshipOptions::default()
+      })
+      .unwrap();
+    assert_eq!(batches.len(), 49);
+    assert_eq!(batches, column_blocks);
+  }
+
+  #[test]
+  fn calc_kinship_out_of_core() {
+    use rqtl2::util::idx::IndividualIdx;
+    use rqtl2::util::out_of_core::MappedKinship;
+    let f = create_test_file(
+      "test_geno_parsers_6.txt",
+      "#test file\n#comment\nmarker	10	12	38\nrs31443144	ABH\nrs31443154	ABH\nrs31443144	BBA",
+    )
+    .expect("Failed to create test file.");
+
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let in_memory = geno_parser.calc_kinship(1).unwrap();
+    assert_eq!(Some(IndividualIdx(2)), geno_parser.individual_idx("38"));
+    assert_eq!(None, geno_parser.individual_idx("39"));
+
+    let mut path = env::temp_dir();
+    path.push("test_kinship_out_of_core.bin");
+    let mapped = geno_parser
+      .calc_kinship_out_of_core(&path, &rqtl2::util::KinshipOptions::default())
+      .unwrap();
+    assert_eq!(mapped.as_slice(), &in_memory[..]);
+    drop(mapped);
+
+    let reopened = MappedKinship::open(&path, 3).unwrap();
+    assert_eq!(reopened.get(IndividualIdx(1), IndividualIdx(2)), in_memory[5]);
+    assert!(MappedKinship::open(&path, 4).is_err());
+  }
+
+  #[test]
+  fn calc_gram_orientation() {
+    use rqtl2::util::kinship::GramOrientation;
+    use rqtl2::util::KinshipOptions;
+    let f = create_test_file(
+      "test_geno_parsers_7.txt",
+      "#test file\nmarker	10	12	38\nrs31443144	ABH\nrs31443154	ABH\nrs31443157	BBA",
+    )
+    .expect("Failed to create test file.");
+
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let (ids, _) = geno_parser
+      .calc_gram_matrix(&KinshipOptions::default())
+      .unwrap();
+    assert_eq!(["10", "12", "38"], &ids[..]);
+
+    let (snp_ids, mut matr) = geno_parser
+      .calc_gram_matrix(&KinshipOptions {
+        orientation: GramOrientation::SnpsBySnps,
+        ..KinshipOptions::default()
+      })
+      .unwrap();
+    assert_eq!(["rs31443144", "rs31443154", "rs31443157"], &snp_ids[..]);
+    // Normalized by the amount of ids.
+    for e in matr.iter_mut() {
+      *e *= 3.0;
+    }
+    assert_eq!(matr, vec![1.25, 1.25, 1.0, 1.25, 1.25, 1.0, 1.0, 1.0, 2.0]);
+  }
+
+  #[test]
+  fn matrix_csv_reader() {
+    use rqtl2::util::matrix_csv::MatrixCsvReader;
+    let contents = "# covariate data\nid,sex,cross_direction,weight\n\
+                    1,female,(BxS)x(BxS),12.5\n\
+                    2,male,NA,NA\n\
+                    3,female,(SxB)x(SxB),-\n";
+    let table = MatrixCsvReader::new()
+      .read_from(contents.as_bytes())
+      .expect("Parsing failed");
+    assert_eq!([" covariate data"], &table.comments[..]);
+    assert_eq!("id", table.corner);
+    assert_eq!(["1", "2", "3"], &table.row_ids[..]);
+    assert_eq!(["sex", "cross_direction", "weight"], &table.col_ids[..]);
+    assert_eq!(Some("male"), table.get(1, 0));
+    assert_eq!(None, table.get(1, 1));
+    assert!(!table.is_numeric_column(0));
+    assert!(table.is_numeric_column(2));
+    let weight = table.numeric_column(2).unwrap();
+    assert_eq!(12.5, weight[0]);
+    assert!(weight[1].is_nan() && weight[2].is_nan());
+    assert!(table.to_numeric().is_err());
+
+    let bad = MatrixCsvReader::new().read_from("id,a,b\n1,2\n".as_bytes());
+    assert!(bad.is_err());
+  }
+
+  #[test]
+  fn missing_values() {
+    use rqtl2::util::na;
+    let values = [1.0, na::NA, 3.0];
+    assert_eq!(1, na::count_na(&values));
+    assert_eq!(2.0, na::mean(&values));
+    assert_eq!(None, na::to_option(values[1]));
+    assert!(na::is_na(na::from_option(None)));
+
+    let f = create_test_file(
+      "test_geno_parsers_8.txt",
+      "#test file\nmarker	10	12\nrs31443144	AB\nrs31443154	A-",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', na::NA);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    assert!(geno_parser.calc_kinship(1).is_err());
+  }
+
+  #[test]
+  fn cpu_limit() {
+    use rqtl2::util::throttle::CpuLimit;
+    use rqtl2::util::KinshipOptions;
+    let limit = CpuLimit::parse("50%").unwrap();
+    assert_eq!(CpuLimit::parse("0.5"), Some(limit));
+    assert_eq!(None, CpuLimit::parse("150%"));
+    assert_eq!(Some(limit), CpuLimit::new(0.5));
+    assert_eq!(None, CpuLimit::new(0.0));
+    assert_eq!(None, CpuLimit::new(f64::NAN));
+    assert_eq!(4, limit.workers(8));
+    assert_eq!(1.0, limit.duty_cycle(8));
+    assert_eq!(1, limit.workers(1));
+    assert_eq!(0.5, limit.duty_cycle(1));
+    // More threads than the limit allows are throttled to stay within it.
+    assert_eq!(0.5, limit.workers_duty_cycle(8, 8));
+    assert_eq!(0.25, limit.workers_duty_cycle(4, 8));
+    let cpus_num = num_cpus::get();
+    let threads = KinshipOptions {
+      max_cpu: Some(limit),
+      threads: Some(limit.workers(cpus_num) * 2),
+      ..KinshipOptions::default()
+    };
+    let expected = cpus_num as f64 * 0.5 / (limit.workers(cpus_num) * 2) as f64;
+    assert_eq!(Some(expected), threads.duty_cycle(threads.threads_num()));
+    assert!(expected <= 0.5);
+    assert_eq!(None, KinshipOptions::default().duty_cycle(4));
+
+    let f = create_test_file(
+      "test_geno_parsers_9.txt",
+      "#test file\nmarker	10	12	38\nrs31443144	ABH\nrs31443154	ABH\nrs31443157	BBA",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let unlimited = geno_parser.calc_kinship(1).unwrap();
+    let limited = geno_parser
+      .calc_kinship_with_options(&KinshipOptions {
+        batch_size: 1,
+        max_cpu: Some(CpuLimit::new(0.25).unwrap()),
+        fast_path_work: 0,
+        ..KinshipOptions::default()
+      })
+      .unwrap();
+    assert_eq!(unlimited, limited);
+  }
+
+  #[test]
+  fn parse_error_lines() {
+    use rqtl2::kinship::{GenoLines, SnpSource};
+    use rqtl2::util::error::Error;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', rqtl2::util::na::NA);
+    let line_of = |err: std::io::Error| match Error::from(err) {
+      Error::Parse { line, msg } => (line, msg),
+      err => panic!("Parse error expected, got {}", err),
+    };
+    let geno = "#comment\nmarker\t10\t12\nrs1\tAB\n\nrs2\tA-\nrs3 AB\n";
+    let f =
+      create_test_file("test_geno_parsers_103.txt", geno).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    let (line, msg) = line_of(geno_parser.calc_kinship(1).unwrap_err());
+    assert_eq!(Some(5), line);
+    assert!(msg.contains("column 2"), "{}", msg);
+
+    let (line, msg) = line_of(geno_parser.read_all().unwrap_err());
+    assert_eq!(Some(6), line);
+    assert!(msg.contains("column 7"), "{}", msg);
+    let errors = geno_parser.iter().unwrap().filter_map(Result::err);
+    let lines = errors.map(|err| line_of(err).0).collect::<Vec<_>>();
+    assert_eq!(vec![Some(6)], lines);
+
+    let reader = BufReader::new("rs1\tAB\n\nrs2\tA-\n".as_bytes());
+    let mut lines = reader.lines();
+    let mut source = GenoLines::new(&mut lines, 2, &hab_mapper, false).at_line(3);
+    let mut buf = vec![0.0; 4];
+    let (line, _) = line_of(source.read_batch(&mut buf, None).unwrap_err());
+    assert_eq!(Some(5), line);
+  }
+
+  #[test]
+  fn error_classes() {
+    use rqtl2::util::error::{Error, ErrorFormat};
+    let parse_error = Error::Parse {
+      line: Some(4),
+      msg: String::from("unknown \"X\" code"),
+    };
+    assert_eq!(3, parse_error.exit_code());
+    assert_eq!(
+      r#"{"error":"parse","exit_code":3,"message":"line 4: unknown \"X\" code","line":4}"#,
+      parse_error.report(ErrorFormat::Json)
+    );
+    // Error survives the round trip through io::Error.
+    let io_error: std::io::Error = Error::OutOfMemory {
+      required: 10,
+      available: 5,
+    }
+    .into();
+    assert_eq!(6, Error::from(io_error).exit_code());
+    let not_found = std::io::Error::new(std::io::ErrorKind::NotFound, "no file");
+    assert_eq!("io", Error::from(not_found).kind());
+
+    let f = create_test_file(
+      "test_geno_parsers_10.txt",
+      "#test file\nmarker	10	12\nrs31443144	AB\nrs31443154	AX",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let err = Error::from(geno_parser.calc_kinship(1).unwrap_err());
+    assert_eq!("parse", err.kind());
+  }
+
+  #[test]
+  fn snp_order() {
+    use rqtl2::util::index::OrderPolicy;
+    let f = create_test_file(
+      "test_geno_parsers_11.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs3	BB\nrs2	AA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+
+    let index = geno_parser.build_index().unwrap();
+    assert_eq!(["rs1", "rs3", "rs2"], index.ids());
+    let rs2 = index.position("rs2").unwrap();
+    assert_eq!(rqtl2::util::idx::MarkerIdx(2), rs2);
+    assert_eq!(Some("rs2"), index.id(rs2));
+    let map_order: Vec<String> = vec!["rs1".into(), "rs2".into(), "rs3".into()];
+    assert!(!index.matches_order(&map_order));
+
+    let verified = geno_parser
+      .iter_in_order(&map_order, OrderPolicy::Verify)
+      .unwrap()
+      .collect::<Vec<_>>();
+    assert_eq!(2, verified.len());
+    assert!(verified[0].is_ok());
+    assert!(verified[1].is_err());
+
+    let reordered = geno_parser
+      .iter_in_order(&map_order, OrderPolicy::Reorder)
+      .unwrap()
+      .collect::<std::io::Result<Vec<_>>>()
+      .unwrap();
+    assert_eq!(
+      vec![
+        (String::from("rs1"), vec![0.0, 1.0]),
+        (String::from("rs2"), vec![0.0, 0.0]),
+        (String::from("rs3"), vec![1.0, 1.0]),
+      ],
+      reordered
+    );
+
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    for policy in [OrderPolicy::Verify, OrderPolicy::Reorder].iter().copied() {
+      let f = create_test_file(
+        "test_geno_parsers_110.txt",
+        "#test file\nmarker	10	12\n\nrs1	AB\n  \t \nrs2	AA\n\r\nrs3	BB\n\n",
+      )
+      .expect("Failed to create test file.");
+      let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+        .expect("Failed to create GenoParser");
+      let lines = geno_parser
+        .iter_in_order(&map_order, policy)
+        .unwrap()
+        .collect::<std::io::Result<Vec<_>>>()
+        .unwrap();
+      assert_eq!(reordered, lines);
+      assert!(geno_parser.get_report().has(rqtl2::util::parse_report::codes::BLANK_LINES));
+    }
+  }
+
+  #[test]
+  fn dataset_single_pass() {
+    use rqtl2::util::dataset::{Analysis, AnalysisResult, Dataset};
+    use rqtl2::util::KinshipOptions;
+    let f = create_test_file(
+      "test_geno_parsers_12.txt",
+      "#test file\nmarker	10	12	13\nrs1	AHB\nrs2	BBH\nrs3	AAB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', f64::NAN);
+    let geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let mut dataset = Dataset::new(geno_parser);
+
+    let expected_kinship = dataset.geno().calc_kinship(1000).unwrap();
+    let res = dataset
+      .compute(&[
+        Analysis::MarkerStats,
+        Analysis::Kinship(KinshipOptions::default()),
+        Analysis::IndividualStats,
+      ])
+      .unwrap();
+    assert_eq!(3, res.len());
+    match &res[0] {
+      AnalysisResult::MarkerStats(stats) => {
+        assert_eq!(3, stats.len());
+        assert_eq!("rs1", stats[0].id);
+        assert!((stats[0].allele_freq - 0.5).abs() < 1e-12);
+        assert!((stats[0].het_rate - 1.0 / 3.0).abs() < 1e-12);
+        assert!((stats[1].allele_freq - 2.5 / 3.0).abs() < 1e-12);
+        assert_eq!(0.0, stats[1].missing_rate);
+        assert_eq!(0.0, stats[2].het_rate);
+      }
+      _ => panic!("MarkerStats expected"),
+    }
+    match &res[1] {
+      AnalysisResult::Kinship(kinship) => assert_eq!(&expected_kinship, kinship),
+      _ => panic!("Kinship expected"),
+    }
+    match &res[2] {
+      AnalysisResult::IndividualStats(stats) => {
+        assert_eq!(3, stats.len());
+        assert_eq!("13", stats[2].id);
+        assert_eq!(0.0, stats[1].missing_rate);
+        assert!((stats[1].het_rate - 1.0 / 3.0).abs() < 1e-12);
+        assert!((stats[2].het_rate - 1.0 / 3.0).abs() < 1e-12);
+      }
+      _ => panic!("IndividualStats expected"),
+    }
+
+    assert!(dataset
+      .compute(&[Analysis::MarkerStats, Analysis::MarkerStats])
+      .is_err());
+  }
+
+  #[derive(Debug, Default)]
+  struct FailingOnceKernel {
+    failed: std::sync::atomic::AtomicBool,
+  }
+
+  impl rqtl2::util::kernel::KinshipKernel for FailingOnceKernel {
+    fn name(&self) -> &'static str {
+      "failing"
+    }
+
+    fn update(
+      &self,
+      snps: &[f64],
+      partial_matrix: &mut [f64],
+      ids_num: usize,
+    ) -> Result<(), rqtl2::util::error::Error> {
+      partial_matrix[0] += 100.0;
+      if !self.failed.swap(true, std::sync::atomic::Ordering::SeqCst) {
+        return Err(rqtl2::util::error::Error::GpuUnavailable(String::from(
+          "device reset",
+        )));
+      }
+      partial_matrix[0] -= 100.0;
+      rqtl2::util::calc_partial_kinship(snps, partial_matrix, ids_num);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn kernel_fallback() {
+    use rqtl2::util::metrics::Metrics;
+    use rqtl2::util::KinshipOptions;
+    use std::sync::Arc;
+    let f = create_test_file(
+      "test_geno_parsers_13.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs2	BB\nrs3	AH\nrs4	HB\nrs5	BA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+
+    let expected = geno_parser.calc_kinship(2).unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let options = KinshipOptions {
+      batch_size: 2,
+      kernel: Some(Arc::new(FailingOnceKernel::default())),
+      metrics: Some(metrics.clone()),
+      ..KinshipOptions::default()
+    };
+    assert_eq!(expected, geno_parser.calc_kinship_with_options(&options).unwrap());
+    assert_eq!(3, metrics.batches());
+    assert_eq!(5, metrics.snps());
+    let fallbacks = metrics.fallbacks();
+    assert_eq!(1, fallbacks.len());
+    assert_eq!("failing", fallbacks[0].kernel);
+    assert!(fallbacks[0].reason.contains("device reset"));
+  }
+
+  /// Accumulates on the "device" (a host matrix), fails on the second batch.
+  #[derive(Debug, Default)]
+  struct AccumulatingKernel {
+    updates: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    finished: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+  }
+
+  struct HostAccumulation {
+    matrix: Vec<f64>,
+    ids_num: usize,
+    updates: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    finished: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+  }
+
+  impl rqtl2::util::kernel::KinshipKernel for AccumulatingKernel {
+    fn name(&self) -> &'static str {
+      "accumulating"
+    }
+
+    fn update(&self, _: &[f64], _: &mut [f64], _: usize) -> Result<(), rqtl2::util::error::Error> {
+      panic!("batches should go to the accumulation");
+    }
+
+    fn start(
+      &self,
+      ids_num: usize,
+    ) -> Result<Option<Box<dyn rqtl2::kinship::KernelAccumulation>>, rqtl2::util::error::Error> {
+      Ok(Some(Box::new(HostAccumulation {
+        matrix: vec![0.0; ids_num * ids_num],
+        ids_num,
+        updates: self.updates.clone(),
+        finished: self.finished.clone(),
+      })))
+    }
+  }
+
+  impl rqtl2::kinship::KernelAccumulation for HostAccumulation {
+    fn update(&mut self, snps: &[f64]) -> Result<(), rqtl2::util::error::Error> {
+      if self.updates.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 1 {
+        return Err(rqtl2::util::error::Error::GpuUnavailable(String::from("out of memory")));
+      }
+      rqtl2::util::calc_partial_kinship(snps, &mut self.matrix, self.ids_num);
+      Ok(())
+    }
+
+    fn finish(
+      self: Box<Self>,
+      partial_matrix: &mut [f64],
+    ) -> Result<(), rqtl2::util::error::Error> {
+      self.finished.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+      for (elem, value) in partial_matrix.iter_mut().zip(&self.matrix) {
+        *elem += value;
+      }
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn kernel_accumulation() {
+    use rqtl2::util::metrics::Metrics;
+    use rqtl2::util::KinshipOptions;
+    use rqtl2::util::testing::{assert_matrix_close, Tolerance};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHH\nrs4\tHBB\nrs5\tBAA\n";
+    let f =
+      create_test_file("test_geno_parsers_109.txt", geno).expect("Failed to create test file.");
+    let hab_mapper = [('A', 0.0), ('H', 0.5), ('B', 1.0)].iter().copied().collect();
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser.calc_kinship(2).unwrap();
+    for deterministic in [false, true] {
+      let (kernel, metrics) = (AccumulatingKernel::default(), Arc::new(Metrics::new()));
+      let (updates, finished) = (kernel.updates.clone(), kernel.finished.clone());
+      let options = KinshipOptions {
+        batch_size: 2,
+        deterministic,
+        kernel: Some(Arc::new(kernel)),
+        metrics: Some(metrics.clone()),
+        ..KinshipOptions::default()
+      };
+      let kinship = geno_parser.calc_kinship_with_options(&options).unwrap();
+      assert_matrix_close(&expected, &kinship, 3, Tolerance::absolute(1e-12));
+      assert_eq!(3, updates.load(Ordering::SeqCst));
+      assert_eq!(1, finished.load(Ordering::SeqCst));
+      assert_eq!(1, metrics.fallbacks().len());
+      assert!(metrics.fallbacks()[0].reason.contains("out of memory"));
+    }
+  }
+
+  #[test]
+  fn environment_report() {
+    use rqtl2::util::environment;
+    use rqtl2::util::kinship::ParallelMode;
+    let mut report = environment::report();
+    assert!(report.logical_cores >= 1);
+    assert!(report.physical_cores >= 1);
+    assert!(report.to_string().contains("CPU cores:"));
+    assert_eq!(cfg!(feature = "blas"), report.blas.is_some());
+
+    report.available_memory = Some(1024);
+    assert_eq!(ParallelMode::ColumnBlocks, report.recommended_options(1000).mode);
+    report.available_memory = None;
+    assert_eq!(ParallelMode::Batches, report.recommended_options(1000).mode);
+  }
+
+  #[test]
+  fn dosage_transform() {
+    use rqtl2::util::transform::DosageTransform;
+    use rqtl2::util::KinshipOptions;
+    let mut snps = [0.0, 0.5, 1.0, f64::NAN, 1.0, 1.0];
+    DosageTransform::rescale((0.0, 1.0), (-1.0, 1.0)).apply(&mut snps[..3], 3);
+    assert_eq!(vec![-1.0, 0.0, 1.0], snps[..3].to_vec());
+    DosageTransform::Center.apply(&mut snps[3..], 3);
+    assert!(snps[3].is_nan());
+    assert_eq!(vec![0.0, 0.0], snps[4..].to_vec());
+    let mut snps = vec![0.0, 1.0, 1.0, 1.0];
+    DosageTransform::Standardize.apply(&mut snps, 2);
+    assert_eq!(vec![-1.0, 1.0, 0.0, 0.0], snps);
+
+    let f = create_test_file(
+      "test_geno_parsers_14.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs2	BB\nrs3	AH\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let kinship = geno_parser.calc_kinship(2).unwrap();
+    let options = KinshipOptions {
+      batch_size: 2,
+      transform: DosageTransform::Affine {
+        scale: 2.0,
+        offset: 0.0,
+      },
+      ..KinshipOptions::default()
+    };
+    let scaled = geno_parser.calc_kinship_with_options(&options).unwrap();
+    for (value, scaled) in kinship.iter().zip(scaled.iter()) {
+      assert!((value * 4.0 - scaled).abs() < 1e-12);
+    }
+  }
+
+  #[test]
+  fn kinship_chromosomes() {
+    use rqtl2::util::loco::write_chromosome_kinship;
+    use rqtl2::util::out_of_core::MappedKinship;
+    use rqtl2::util::KinshipOptions;
+    let geno = "#test file\nmarker	10	12\nrs1	AB\nrs2	BB\nrs3	AH\nrs4	HB\n";
+    let f =
+      create_test_file("test_geno_parsers_15.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let chromosomes: HashMap<String, String> =
+      [("rs1", "1"), ("rs2", "1"), ("rs3", "2"), ("rs4", "2")]
+        .iter()
+        .map(|(snp, chr)| (String::from(*snp), String::from(*chr)))
+        .collect();
+    let options = KinshipOptions {
+      batch_size: 3,
+      ..KinshipOptions::default()
+    };
+
+    let dir = std::env::temp_dir().join("rqtl2_kinship_chromosomes");
+    fs::create_dir_all(&dir).unwrap();
+    let mut written = Vec::new();
+    geno_parser
+      .calc_kinship_chromosomes(
+        &chromosomes,
+        &options,
+        write_chromosome_kinship(&dir, &mut written),
+      )
+      .unwrap();
+    let written_chromosomes = written.iter().map(|(chr, _)| chr.as_str()).collect::<Vec<_>>();
+    assert_eq!(vec!["1", "2"], written_chromosomes);
+    let chr1 = MappedKinship::open(&written[0].1, 2).unwrap();
+    assert_eq!(&[0.5, 0.5, 0.5, 1.0], chr1.as_slice());
+    let chr2 = MappedKinship::open(&written[1].1, 2).unwrap();
+    assert_eq!(&[0.125, 0.25, 0.25, 0.625], chr2.as_slice());
+
+    let mut chromosomes = chromosomes;
+    chromosomes.insert(String::from("rs3"), String::from("1"));
+    chromosomes.insert(String::from("rs2"), String::from("2"));
+    let err = geno_parser
+      .calc_kinship_chromosomes(&chromosomes, &options, |_| Ok(()))
+      .unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+  }
+
+  #[test]
+  fn chunked_reader() {
+    use rqtl2::util::chunked::ChunkedGenoReader;
+    use rqtl2::util::kinship::SnpSource;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', f64::NAN);
+    let ids_num = 3000;
+    let line = |id: &str, code: &str| format!("{}\t{}\n", id, code.repeat(ids_num));
+    let data = [line("rs1", "A"), line("rs2", "H"), line("rs3", "-")].concat();
+    // Buffer much smaller than a line, so every line spans many refills.
+    let mut reader = std::io::BufReader::with_capacity(64, data.as_bytes());
+    let mut source = ChunkedGenoReader::new(&mut reader, ids_num, &hab_mapper, true).unwrap();
+    let mut buf = vec![1.0; ids_num * 2];
+    let mut row_ids = Vec::new();
+    assert_eq!(2, source.read_batch(&mut buf, Some(&mut row_ids)).unwrap());
+    assert_eq!(vec!["rs1", "rs2"], row_ids);
+    assert!(buf[..ids_num].iter().all(|v| *v == 0.0));
+    assert!(buf[ids_num..].iter().all(|v| *v == 0.5));
+    assert_eq!(1, source.read_batch(&mut buf, None).unwrap());
+    assert!(buf[..ids_num].iter().all(|v| v.is_nan()));
+    assert_eq!(0, source.read_batch(&mut buf, None).unwrap());
+
+    let data = "rs1\tAB\nrs2\tABH\n";
+    let mut reader = std::io::BufReader::with_capacity(4, data.as_bytes());
+    let mut source = ChunkedGenoReader::new(&mut reader, 2, &hab_mapper, false).unwrap();
+    assert!(source.read_batch(&mut [0.0; 4], None).is_err());
+    let data = "rs1\tAX\n";
+    let mut reader = std::io::BufReader::new(data.as_bytes());
+    let mut source = ChunkedGenoReader::new(&mut reader, 2, &hab_mapper, false).unwrap();
+    assert!(source.read_batch(&mut [0.0; 2], None).is_err());
+  }
+
+  #[test]
+  fn batch_bytes() {
+    use rqtl2::util::kinship::parse_byte_size;
+    use rqtl2::util::KinshipOptions;
+    assert_eq!(Some(256 << 20), parse_byte_size("256MB"));
+    assert_eq!(Some(1 << 30), parse_byte_size("1GiB"));
+    assert_eq!(Some(4096), parse_byte_size("4096"));
+    assert_eq!(None, parse_byte_size("12 parsecs"));
+
+    let options = KinshipOptions {
+      batch_bytes: Some(1 << 20),
+      ..KinshipOptions::default()
+    };
+    assert_eq!(128, options.batch_rows(1024));
+    assert_eq!(1, options.batch_rows(1 << 20));
+    assert_eq!(1000, KinshipOptions::default().batch_rows(1024));
+
+    let f = create_test_file(
+      "test_geno_parsers_16.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs2	BB\nrs3	AB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let options = KinshipOptions {
+      batch_bytes: Some(16),
+      ..KinshipOptions::default()
+    };
+    assert_eq!(
+      geno_parser.calc_kinship(1000).unwrap(),
+      geno_parser.calc_kinship_with_options(&options).unwrap()
+    );
+  }
+
+  #[test]
+  fn founder_errors() {
+    use rqtl2::util::dataset::{Analysis, AnalysisResult, Dataset};
+    use rqtl2::util::founders::FounderGenotypes;
+    use rqtl2::util::matrix_csv::MatrixCsvReader;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', f64::NAN);
+    let founders_csv = "marker,F1,F2\nrs1,A,A\nrs2,A,B\nrs3,B,-\n";
+    let table = MatrixCsvReader::new()
+      .read_from(founders_csv.as_bytes())
+      .unwrap();
+    let founders = FounderGenotypes::from_table(&table, &hab_mapper).unwrap();
+    assert_eq!(["F1", "F2"], founders.founders());
+    let duplicates = "marker,F1,F2\nrs1,A,A\nrs2,A,B\nrs3,B,-\nrs2,B,B\n";
+    let table = MatrixCsvReader::new().read_from(duplicates.as_bytes()).unwrap();
+    let err = FounderGenotypes::from_table(&table, &hab_mapper).unwrap_err();
+    assert_eq!(
+      "Marker <rs2> is listed more than once, at positions 2 and 4.",
+      rqtl2::util::error::Error::from(err).to_string()
+    );
+
+    let f = create_test_file(
+      "test_geno_parsers_17.txt",
+      "#test file\nmarker	10	12	13	14\nrs1	AAH-\nrs2	ABHB\nrs3	BBBA\nrs4	AAAA\n",
+    )
+    .expect("Failed to create test file.");
+    let geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let mut dataset = Dataset::new(geno_parser);
+    let res = dataset
+      .compute(&[Analysis::FounderErrors(founders)])
+      .unwrap();
+    let errors = match &res[0] {
+      AnalysisResult::FounderErrors(errors) => errors,
+      _ => panic!("FounderErrors expected"),
+    };
+    assert_eq!(4, errors.len());
+    assert_eq!((3, 1), (errors[0].checked, errors[0].errors));
+    assert!((errors[0].error_rate() - 1.0 / 3.0).abs() < 1e-12);
+    assert!(!errors[0].passes(0.1));
+    assert!(errors[1].error_rate().is_nan());
+    assert!(errors[1].passes(0.0));
+    assert!(errors[2].error_rate().is_nan());
+    assert!(errors[3].passes(0.0));
+  }
+
+  #[test]
+  fn kinship_sketch() {
+    use rqtl2::util::sketch::{SketchMethod, SketchOptions};
+    use rqtl2::util::KinshipOptions;
+    let codes = ['A', 'H', 'B'];
+    let mut geno = String::from("#test file\nmarker	1	2	3	4\n");
+    for snp in 0..2000 {
+      let line: String = (0..4).map(|id| codes[(snp * (id + 1) + snp / 7) % 3]).collect();
+      geno.push_str(&format!("rs{}\t{}\n", snp, line));
+    }
+    let f =
+      create_test_file("test_geno_parsers_18.txt", &geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let exact = geno_parser.calc_kinship(1000).unwrap();
+
+    for method in [SketchMethod::CountSketch, SketchMethod::Gaussian] {
+      let sketch_options = SketchOptions {
+        dimensions: 400,
+        method,
+        seed: 42,
+      };
+      let approx = geno_parser
+        .calc_kinship_sketch(&KinshipOptions::default(), &sketch_options)
+        .unwrap();
+      assert_eq!(2000, approx.snps_num);
+      for ((exact, approx), std_error) in exact.iter().zip(&approx.matrix).zip(&approx.std_errors) {
+        assert!((exact - approx).abs() <= 4.0 * std_error);
+      }
+      let small_batches = KinshipOptions {
+        batch_size: 3,
+        ..KinshipOptions::default()
+      };
+      let again = geno_parser
+        .calc_kinship_sketch(&small_batches, &sketch_options)
+        .unwrap();
+      assert_eq!(approx.matrix, again.matrix);
+    }
+  }
+
+  #[test]
+  fn prelude() {
+    use rqtl2::kinship::ParallelMode;
+    use rqtl2::prelude::*;
+    let f = create_test_file(
+      "test_geno_parsers_19.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs2	BB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let geno_parser =
+      GenoParser::new_with_file(f, hab_mapper).expect("Failed to create GenoParser");
+    assert_eq!(["10", "12"], &geno_parser.get_markers()[..]);
+    let mut dataset = Dataset::new(geno_parser);
+    let options = KinshipOptions {
+      mode: ParallelMode::ColumnBlocks,
+      ..KinshipOptions::default()
+    };
+    match &dataset.compute(&[Analysis::Kinship(options)]).unwrap()[0] {
+      AnalysisResult::Kinship(kinship) => assert_eq!(&vec![0.5, 0.5, 0.5, 1.0], kinship),
+      _ => panic!("Kinship expected"),
+    }
+  }
+
+  #[test]
+  fn number_format() {
+    use rqtl2::io::{MatrixCsvReader, MatrixCsvWriter, NumberFormat, NumericTable};
+    assert_eq!("0.1234567891", NumberFormat::default().format(0.1234567891));
+    assert_eq!("0.123", NumberFormat::fixed(3).format(0.1234567891));
+    assert_eq!("1.23e-1", NumberFormat::scientific(2).format(0.1234567891));
+    assert_eq!("0.123457", NumberFormat::significant(6).format(0.1234567891));
+    assert_eq!("1.5e-7", NumberFormat::significant(6).format(0.00000015));
+    assert_eq!("1234.5", NumberFormat::significant(6).format(1234.5));
+    assert_eq!("1.23457e6", NumberFormat::significant(6).format(1234567.0));
+    assert_eq!("NA", NumberFormat::fixed(3).format(f64::NAN));
+    assert_eq!(Some(NumberFormat::significant(6)), NumberFormat::parse("sig:6"));
+    assert_eq!(Some(NumberFormat::default()), NumberFormat::parse("full"));
+    assert_eq!(None, NumberFormat::parse("sig:x"));
+
+    let table = NumericTable {
+      row_ids: vec![String::from("1"), String::from("2")],
+      col_ids: vec![String::from("a"), String::from("b")],
+      values: vec![1.0 / 3.0, 2.0, f64::NAN, 0.5],
+    };
+    let mut out = Vec::new();
+    MatrixCsvWriter::new()
+      .with_number_format(NumberFormat::significant(3))
+      .write_to(&mut out, &table)
+      .unwrap();
+    assert_eq!("id,a,b\n1,0.333,2\n2,NA,0.5\n", String::from_utf8(out.clone()).unwrap());
+    let read = MatrixCsvReader::new()
+      .read_from(&out[..])
+      .unwrap()
+      .to_numeric()
+      .unwrap();
+    assert_eq!(table.col_ids, read.col_ids);
+    assert_eq!(0.333, read.get(0, 0));
+    assert!(read.get(1, 0).is_nan());
+  }
+
+  #[test]
+  fn latin1_ids() {
+    use rqtl2::io::MatrixCsvReader;
+    let mut path = env::temp_dir();
+    path.push("test_geno_parsers_20.txt");
+    let mut f = fs::File::create(&path).unwrap();
+    f.write_all(b"#test file\nmarker\tJos\xe9\t12\nrs1\tAB\nrs\xe92\tBB\n")
+      .unwrap();
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser =
+      rqtl2::util::GenoParser::new_with_file(fs::File::open(&path).unwrap(), hab_mapper.clone())
+        .expect("Failed to create GenoParser");
+    assert_eq!(["Jos\u{e9}", "12"], &geno_parser.get_markers()[..]);
+    assert_eq!(1, geno_parser.get_warnings().len());
+    let ids = geno_parser.iter().unwrap().map(|rec| rec.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(vec!["rs1", "rs\u{e9}2"], ids);
+    // The decoded row ids are reported once.
+    let warnings = geno_parser.get_warnings();
+    assert_eq!(2, warnings.len());
+    assert!(warnings[1].contains("1 row ids are not valid UTF-8"));
+    assert_eq!(vec!["rs1", "rs\u{e9}2"], geno_parser.row_ids().unwrap());
+    assert_eq!(2, geno_parser.get_warnings().len());
+    let mut lines = &b"rs1\tAB\nrs\xe92\tBB\n"[..];
+    let mut source =
+      rqtl2::util::chunked::ChunkedGenoReader::new(&mut lines, 2, &hab_mapper, false).unwrap();
+    let (mut buf, mut row_ids) = (vec![0.0; 4], Vec::new());
+    rqtl2::kinship::SnpSource::read_batch(&mut source, &mut buf, Some(&mut row_ids)).unwrap();
+    assert_eq!(vec!["rs1", "rs\u{e9}2"], row_ids);
+    assert_eq!(1, source.latin1_row_ids());
+    assert_eq!(2, geno_parser.build_index().unwrap().len());
+    assert!(geno_parser.calc_kinship(10).is_ok());
+
+    let table = MatrixCsvReader::new()
+      .read_from(&b"id,sex\nMu\xf1oz,male\n"[..])
+      .unwrap();
+    assert_eq!(vec!["Mu\u{f1}oz"], table.row_ids);
+    assert_eq!(1, table.report.diagnostics().len());
+  }
+
+  #[test]
+  fn founder_polarity() {
+    use rqtl2::io::{FounderGenotypes, MatrixCsvReader};
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let founders_csv = "marker,B6,D2\nrs1,A,B\nrs2,B,A\nrs3,A,A\n";
+    let table = MatrixCsvReader::new()
+      .read_from(founders_csv.as_bytes())
+      .unwrap();
+    let founders = FounderGenotypes::from_table(&table, &hab_mapper).unwrap();
+
+    let f = create_test_file(
+      "test_geno_parsers_21.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs2	AB\nrs3	BB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let options = rqtl2::util::KinshipOptions::default();
+    // rs2 is flipped to BA relative to B6.
+    assert_eq!(
+      vec![2.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 2.0 / 3.0],
+      geno_parser
+        .calc_kinship_polarized(&options, &founders, "B6")
+        .unwrap()
+    );
+    assert_eq!(
+      vec![1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 1.0],
+      geno_parser.calc_kinship_with_options(&options).unwrap()
+    );
+    assert!(geno_parser
+      .calc_kinship_polarized(&options, &founders, "CAST")
+      .is_err());
+  }
+
+  #[test]
+  fn sample_metadata() {
+    use rqtl2::io::{write_kinship_pairs, MatrixCsvReader, NumberFormat, SampleMetadata};
+    let covar_csv = "id,sex,cohort,age\n10,f,c1,5\n12,m,NA,7\n";
+    let metadata =
+      SampleMetadata::from_table(&MatrixCsvReader::new().read_from(covar_csv.as_bytes()).unwrap());
+    assert_eq!(Some("f"), metadata.get("10", "sex"));
+    assert_eq!(None, metadata.get("12", "cohort"));
+    assert!(metadata.select(&["weight"]).is_err());
+
+    let ids = vec![String::from("10"), String::from("12"), String::from("13")];
+    assert_eq!(vec!["13"], metadata.missing_ids(&ids));
+    let kinship = [1.0, 0.25, 0.5, 0.25, 1.0, 0.125, 0.5, 0.125, 1.0];
+    let selected = metadata.select(&["cohort", "sex"]).unwrap();
+    let mut out = Vec::new();
+    write_kinship_pairs(&mut out, &ids, &kinship, Some(&selected), &NumberFormat::default())
+      .unwrap();
+    assert_eq!(
+      "id1,id2,kinship,cohort_1,sex_1,cohort_2,sex_2\n\
+       10,12,0.25,c1,f,NA,m\n\
+       10,13,0.5,c1,f,NA,NA\n\
+       12,13,0.125,NA,m,NA,NA\n",
+      String::from_utf8(out).unwrap()
+    );
+  }
+
+  #[test]
+  fn lmm_batch() {
+    use rqtl2::lmm::{symmetric_eigen, Lmm};
+    let n = 6;
+    // Two families of three.
+    let mut kinship = vec![0.1; n * n];
+    for i in 0..n {
+      for j in 0..n {
+        if i / 3 == j / 3 {
+          kinship[i * n + j] = if i == j { 1.0 } else { 0.5 };
+        }
+      }
+    }
+    let eigen = symmetric_eigen(&kinship, n).unwrap();
+    for k in 0..n {
+      for i in 0..n {
+        let kv = (0..n).map(|j| kinship[i * n + j] * eigen.vectors[j * n + k]).sum::<f64>();
+        assert!((kv - eigen.values[k] * eigen.vectors[i * n + k]).abs() < 1e-10);
+      }
+    }
+    assert!(eigen.values.windows(2).all(|w| w[0] <= w[1]));
+
+    // Family-driven and noise-like phenotypes, individuals as rows.
+    let phenos = [1.0, 0.3, 1.2, -0.1, 0.9, 0.5, 3.0, -0.2, 3.1, 0.4, 2.9, 0.0];
+    let lmm = Lmm::new(&kinship, n, None).unwrap().with_threads(2);
+    let models = lmm.fit_null_batch(&phenos, 2).unwrap();
+    assert_eq!(2, models.len());
+    assert!(models[0].h2 > 0.9);
+    assert!(models[1].h2 < models[0].h2);
+    let single = lmm.fit_null(&[0.3, -0.1, 0.5, -0.2, 0.4, 0.0]).unwrap();
+    assert_eq!(single, models[1]);
+    assert!(lmm.fit_null(&[1.0, f64::NAN, 1.0, 1.0, 1.0, 1.0]).is_err());
+  }
+
+  #[test]
+  fn heritability() {
+    use rqtl2::lmm::{heritability, Lmm};
+    let n = 6;
+    let mut kinship = vec![0.1; n * n];
+    for i in 0..n {
+      for j in 0..n {
+        if i / 3 == j / 3 {
+          kinship[i * n + j] = if i == j { 1.0 } else { 0.5 };
+        }
+      }
+    }
+    let pheno = [1.0, 1.6, 0.4, 3.0, 2.2, 3.5];
+    let estimate = heritability(&kinship, &pheno, None).unwrap();
+    let model = Lmm::new(&kinship, n, None).unwrap().fit_null(&pheno).unwrap();
+    assert_eq!((model.h2, model.loglik), (estimate.h2, estimate.loglik));
+    assert!(estimate.h2 > 0.0 && estimate.h2 < 1.0 && estimate.se > 0.0);
+
+    // Individuals with NA are dropped, the rest are fitted as usual.
+    let with_na = [1.0, f64::NAN, 0.4, 3.0, 2.2, 3.5];
+    let kept = [0, 2, 3, 4, 5];
+    let sub_kinship = kept
+      .iter()
+      .flat_map(|i| kept.iter().map(move |j| (i / 3 == j / 3, i == j)))
+      .map(|(family, same)| if same { 1.0 } else if family { 0.5 } else { 0.1 })
+      .collect::<Vec<f64>>();
+    let sub_pheno = [1.0, 0.4, 3.0, 2.2, 3.5];
+    assert_eq!(
+      heritability(&sub_kinship, &sub_pheno, None).unwrap(),
+      heritability(&kinship, &with_na, None).unwrap()
+    );
+    assert!(heritability(&kinship, &pheno[..5], None).is_err());
+  }
+
+  #[test]
+  fn probs_to_dosages() {
+    use rqtl2::io::{write_dosages, FounderGenotypes, MatrixCsvReader, NumberFormat};
+    use rqtl2::io::ProbsDosageReader;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let founders_csv = "marker,F1,F2,F3\nrs1,A,B,B\nrs2,B,A,A\nrs3,A,-,B\n";
+    let table = MatrixCsvReader::new()
+      .read_from(founders_csv.as_bytes())
+      .unwrap();
+    let founders = FounderGenotypes::from_table(&table, &hab_mapper).unwrap();
+
+    let probs_csv = "# chr 1\nmarker,i1.F1,i1.F2,i1.F3,i2.F1,i2.F2,i2.F3\n\
+                     rs1,1,0,0,0.25,0.25,0.5\n\
+                     rs3,1,0,0,0,0,1\n\
+                     rs2,0.5,0.5,0,0,0,1\n";
+    let mut reader = ProbsDosageReader::new(probs_csv.as_bytes(), &founders, '.').unwrap();
+    assert_eq!(vec!["i1", "i2"], reader.ids());
+    let ids = reader.ids().to_vec();
+    let mut out = Vec::new();
+    assert_eq!(2, write_dosages(&mut reader, &ids, &mut out, &NumberFormat::default()).unwrap());
+    // rs3 is skipped, since the genotype of F2 is missing.
+    assert_eq!(1, reader.skipped_markers());
+    assert_eq!(
+      "marker,i1,i2\nrs1,0,0.75\nrs2,0.5,0\n",
+      String::from_utf8(out).unwrap()
+    );
+
+    let bad_header = "marker,i1.F1,i1.F4\n";
+    assert!(ProbsDosageReader::new(bad_header.as_bytes(), &founders, '.').is_err());
+  }
+
+  #[derive(Debug)]
+  struct PanickingKernel;
+
+  impl rqtl2::util::kernel::KinshipKernel for PanickingKernel {
+    fn name(&self) -> &'static str {
+      "panicking"
+    }
+
+    fn update(
+      &self,
+      snps: &[f64],
+      partial_matrix: &mut [f64],
+      ids_num: usize,
+    ) -> Result<(), rqtl2::util::error::Error> {
+      if snps.contains(&0.5) {
+        panic!("index out of bounds");
+      }
+      rqtl2::util::calc_partial_kinship(snps, partial_matrix, ids_num);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn worker_panic() {
+    use rqtl2::util::error::Error;
+    use rqtl2::util::KinshipOptions;
+    use std::sync::Arc;
+    let f = create_test_file(
+      "test_geno_parsers_22.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs2	BB\nrs3	AH\nrs4	BB\nrs5	BA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('H', 0.5);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let options = KinshipOptions {
+      batch_size: 2,
+      kernel: Some(Arc::new(PanickingKernel)),
+      ..KinshipOptions::default()
+    };
+    let e = Error::from(geno_parser.calc_kinship_with_options(&options).unwrap_err());
+    assert_eq!("processing", e.kind());
+    match e {
+      Error::Processing { batch, msg } => {
+        assert_eq!(Some(1), batch);
+        assert!(msg.contains("SNP lines 2..4"));
+        assert!(msg.contains("index out of bounds"));
+      }
+      e => panic!("Unexpected error {}", e),
+    }
+    // The parser stays usable.
+    let options = KinshipOptions::default();
+    assert!(geno_parser.calc_kinship_with_options(&options).is_ok());
+  }
+
+  #[test]
+  fn extend_kinship() {
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('H', 0.5);
+    let parser = |name: &str, contents: &str| {
+      let f = create_test_file(name, contents).expect("Failed to create test file.");
+      rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+        .expect("Failed to create GenoParser")
+    };
+    let mut old = parser(
+      "test_geno_parsers_23.txt",
+      "#test file\nmarker	10	12\nrs1	AB\nrs2	BB\nrs3	AH\nrs4	HB\nrs5	BA\n",
+    );
+    let mut new = parser(
+      "test_geno_parsers_24.txt",
+      "#test file\nmarker	13	14\nrs1	HB\nrs2	AA\nrs3	BH\nrs4	BB\nrs5	AB\n",
+    );
+    let mut all = parser(
+      "test_geno_parsers_25.txt",
+      "#test file\nmarker	10	12	13	14\n\
+       rs1	ABHB\nrs2	BBAA\nrs3	AHBH\nrs4	HBBB\nrs5	BAAB\n",
+    );
+    let options = rqtl2::util::KinshipOptions {
+      batch_size: 2,
+      ..rqtl2::util::KinshipOptions::default()
+    };
+    let existing = old.calc_kinship_with_options(&options).unwrap();
+    let extended = old.calc_kinship_extended(&existing, &mut new, &options).unwrap();
+    let expected = all.calc_kinship_with_options(&options).unwrap();
+    rqtl2::testing::assert_matrix_close(&expected, &extended, 4, Default::default());
+
+    let mut reordered = parser(
+      "test_geno_parsers_26.txt",
+      "#test file\nmarker	13	14\nrs2	AA\nrs1	HB\nrs3	BH\nrs4	BB\nrs5	AB\n",
+    );
+    assert!(old
+      .calc_kinship_extended(&existing, &mut reordered, &options)
+      .is_err());
+
+    // Centered over all individuals: the new rows match a full recompute.
+    let options = rqtl2::util::KinshipOptions {
+      transform: rqtl2::util::transform::DosageTransform::Center,
+      ..options
+    };
+    let existing = old.calc_kinship_with_options(&options).unwrap();
+    let extended = old.calc_kinship_extended(&existing, &mut new, &options).unwrap();
+    let expected = all.calc_kinship_with_options(&options).unwrap();
+    let new_rows = |matrix: &[f64]| [&matrix[2..4], &matrix[6..8], &matrix[8..]].concat();
+    let (expected, extended_rows) = (new_rows(&expected), new_rows(&extended));
+    rqtl2::testing::assert_matrix_close(&expected, &extended_rows, 4, Default::default());
+    assert_eq!(&extended[..2], &existing[..2]);
+  }
+
+  #[test]
+  fn extract_region() {
+    use rqtl2::io::{GeneticMap, MatrixCsvReader};
+    use rqtl2::prelude::MarkerIdx;
+    let gmap_csv = "marker,chr,pos\nrs1,1,2.0\nrs2,1,7.5\nrs3,1,1.0\nrs4,2,1.5\nrs6,1,2.5\n";
+    let gmap =
+      GeneticMap::from_table(&MatrixCsvReader::new().read_from(gmap_csv.as_bytes()).unwrap())
+        .unwrap();
+    assert_eq!(Some("2"), gmap.chromosome("rs4"));
+    assert_eq!(Some(7.5), gmap.position("rs2"));
+
+    let f = create_test_file(
+      "test_geno_parsers_27.txt",
+      "#test file\nmarker	10	12	14\nrs1	AB-\nrs2	BBA\nrs3	AHB\nrs4	HBB\nrs5	BAA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('-', f64::NAN);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let streamed = geno_parser.extract_region("1", 1.0, 3.0, &gmap).unwrap();
+    assert_eq!(vec!["rs3", "rs1"], streamed.marker_ids);
+    assert_eq!(&[0.0, 0.5, 1.0], streamed.marker(MarkerIdx(0)));
+    assert!(streamed.get(MarkerIdx(1), rqtl2::prelude::IndividualIdx(2)).is_nan());
+    assert_eq!(None, streamed.kinship());
+
+    let index = geno_parser.build_index().unwrap();
+    geno_parser.set_index(index);
+    let indexed = geno_parser.extract_region("1", 1.0, 3.0, &gmap).unwrap();
+    assert_eq!(streamed.marker_ids, indexed.marker_ids);
+    assert_eq!(streamed.ids, indexed.ids);
+    assert_eq!(
+      Some(vec![1.0, 1.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0]),
+      geno_parser.extract_region("1", 7.0, 8.0, &gmap).unwrap().kinship()
+    );
+  }
+
+  #[test]
+  fn matrix_comparison() {
+    use rqtl2::testing::{assert_matrix_close, compare, Tolerance};
+    let expected = [1.0, 0.5, f64::NAN, 0.25];
+    let actual = [1.0 + 1e-14, 0.5, f64::NAN, 0.26];
+    let diff = compare(&expected, &actual, 2, Tolerance::absolute(1e-3));
+    assert_eq!(1, diff.mismatches);
+    assert_eq!(Some(3), diff.first_mismatch);
+    assert_eq!(Some(3), diff.max_abs_diff_pos);
+    assert!(diff.to_string().contains("the first at (1, 1)"));
+    assert!(compare(&expected, &actual, 2, Tolerance::relative(0.05)).is_close());
+    assert!(!Tolerance::default().is_close(f64::NAN, 0.0));
+    assert_matrix_close(&expected[..2], &actual[..2], 2, Tolerance::default());
+    let report = std::panic::catch_unwind(|| {
+      assert_matrix_close(&expected, &actual, 2, Tolerance::default())
+    });
+    assert!(report.is_err());
+  }
+
+  #[test]
+  fn parse_report() {
+    use rqtl2::io::{codes, MatrixCsvReader, SampleMetadata, Severity};
+    use rqtl2::prelude::Dataset;
+    let f = create_test_file(
+      "test_geno_parsers_28.txt",
+      "#test file\nmarker	10	12	10\nrs1	ABA\nrs2	BBA\nrs3	AHB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('H', 0.5);
+    let geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let report = geno_parser.get_report();
+    assert!(report.has(codes::DUPLICATE_ID));
+    assert_eq!(Some(Severity::Warning), report.max_severity());
+    assert_eq!(Some(2), report.diagnostics()[0].line);
+    assert_eq!(
+      vec!["warning [duplicate_id] line 2: Individual <10> is listed more than once."],
+      geno_parser.get_warnings()
+    );
+
+    let covar = MatrixCsvReader::new().read_from("id,sex\n10,f\n".as_bytes()).unwrap();
+    let dataset = Dataset::new(geno_parser).with_metadata(SampleMetadata::from_table(&covar));
+    let report = dataset.report();
+    assert_eq!(2, report.count(Severity::Warning));
+    assert!(report.has(codes::METADATA_MISSING));
+    assert_eq!(
+      "{\"counts\":{\"info\":0,\"warning\":2,\"error\":0},\"diagnostics\":[\
+       {\"severity\":\"warning\",\"code\":\"duplicate_id\",\"line\":2,\
+       \"message\":\"Individual <10> is listed more than once.\"},\
+       {\"severity\":\"warning\",\"code\":\"metadata_missing\",\"line\":null,\
+       \"message\":\"Individual <12> has no metadata.\"}]}",
+      report.to_json()
+    );
+  }
+
+  #[test]
+  fn loco_threads_determinism() {
+    use rqtl2::util::KinshipOptions;
+    let codes = ['A', 'H', 'B'];
+    let mut contents = String::from("#test file\nmarker	1	2	3	4	5	6	7\n");
+    let mut chromosomes = HashMap::new();
+    for snp in 0..45 {
+      let line = (0..7).map(|id| codes[(snp * 7 + id * 3 + snp / 4) % 3]).collect::<String>();
+      contents.push_str(&format!("rs{}\t{}\n", snp, line));
+      chromosomes.insert(format!("rs{}", snp), format!("{}", snp / 15 + 1));
+    }
+    let f = create_test_file("test_geno_parsers_29.txt", &contents)
+      .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let mut run = |threads: usize, batch_size: usize| {
+      let options = KinshipOptions {
+        threads: Some(threads),
+        batch_size,
+        ..KinshipOptions::default()
+      };
+      let mut matrices = Vec::new();
+      geno_parser
+        .calc_kinship_chromosomes(&chromosomes, &options, |chr| {
+          matrices.push(chr);
+          Ok(())
+        })
+        .unwrap();
+      matrices
+    };
+    let expected = run(1, 1000);
+    assert_eq!(3, expected.len());
+    for (threads, batch_size) in [(2, 1000), (7, 1000), (3, 4), (16, 1)] {
+      // Bitwise equality, not just closeness.
+      assert_eq!(expected, run(threads, batch_size));
+    }
+  }
+
+  #[test]
+  fn drop_monomorphic() {
+    use rqtl2::util::metrics::Metrics;
+    use rqtl2::util::KinshipOptions;
+    use std::sync::Arc;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let f = create_test_file(
+      "test_geno_parsers_30.txt",
+      "#test file\nmarker\t10\t12\t14\nrs1\tABH\nrs2\tBBB\nrs3\tHHH\nrs4\tAHB\n\
+       rs5\tBBB\nrs6\tBAA\nrs7\tAAA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let f = create_test_file(
+      "test_geno_parsers_31.txt",
+      "#test file\nmarker\t10\t12\t14\nrs1\tABH\nrs4\tAHB\nrs6\tBAA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut polymorphic_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let expected = polymorphic_parser.calc_kinship(2).unwrap();
+
+    let metrics = Arc::new(Metrics::new());
+    let options = KinshipOptions {
+      batch_size: 2,
+      drop_monomorphic: true,
+      metrics: Some(metrics.clone()),
+      ..KinshipOptions::default()
+    };
+    assert_eq!(expected, geno_parser.calc_kinship_with_options(&options).unwrap());
+    assert_eq!(4, metrics.monomorphic());
+    assert_eq!(3, metrics.snps());
+  }
+
+  #[test]
+  fn info_weighted_kinship() {
+    use rqtl2::io::MatrixCsvReader;
+    use rqtl2::kinship::{KinshipOptions, MarkerWeights, ParallelMode};
+    use std::sync::Arc;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    // rs2 has zero weight, so is ignored, rs3 and its copy count as one line.
+    let f = create_test_file(
+      "test_geno_parsers_32.txt",
+      "#test file\nmarker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tHAB\nrs3c\tHAB\n\
+       rs4\tAHB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let f = create_test_file(
+      "test_geno_parsers_33.txt",
+      "#test file\nmarker\t10\t12\t14\nrs1\tABH\nrs3\tHAB\nrs4\tAHB\n",
+    )
+    .expect("Failed to create test file.");
+    let expected = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser")
+      .calc_kinship(2)
+      .unwrap();
+
+    let table = MatrixCsvReader::new()
+      .read_from("marker,info\nrs1,1\nrs2,0\nrs3,0.5\nrs3c,0.5\nrs4,1.02\n".as_bytes())
+      .unwrap();
+    let weights = Arc::new(MarkerWeights::from_table(&table, 0).unwrap());
+    assert_eq!(Some(1.0), weights.get("rs4"));
+    for mode in [ParallelMode::Batches, ParallelMode::ColumnBlocks] {
+      let options = KinshipOptions {
+        batch_size: 2,
+        mode,
+        marker_weights: Some(weights.clone()),
+        ..KinshipOptions::default()
+      };
+      let weighted = geno_parser.calc_kinship_with_options(&options).unwrap();
+      rqtl2::testing::assert_matrix_close(&expected, &weighted, 3, Default::default());
+    }
+
+    let mut partial = MarkerWeights::new();
+    partial.insert(String::from("rs1"), 1.0).unwrap();
+    assert!(partial.insert(String::from("rs2"), -0.1).is_err());
+    let options = KinshipOptions {
+      marker_weights: Some(Arc::new(partial)),
+      ..KinshipOptions::default()
+    };
+    assert!(geno_parser.calc_kinship_with_options(&options).is_err());
+
+    // All-zero weights leave nothing to normalize by.
+    let mut zero = MarkerWeights::new();
+    for marker in ["rs1", "rs2", "rs3", "rs3c", "rs4"] {
+      zero.insert(String::from(marker), 0.0).unwrap();
+    }
+    let zero = KinshipOptions {
+      marker_weights: Some(Arc::new(zero)),
+      ..KinshipOptions::default()
+    };
+    // On the fast path and in both modes.
+    let modes = [ParallelMode::Batches, ParallelMode::ColumnBlocks].map(|mode| KinshipOptions {
+      mode,
+      fast_path_work: 0,
+      ..zero.clone()
+    });
+    for options in modes.iter().chain([&zero]) {
+      let err = geno_parser.calc_kinship_with_options(options).unwrap_err();
+      assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+    }
+  }
+
+  #[test]
+  fn dosages_to_hard_calls() {
+    use rqtl2::io::{write_hard_calls, HardCallCounts, HardCaller, MatrixCsvReader};
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let dosages = MatrixCsvReader::new()
+      .read_from("marker,i1,i2,i3\nrs1,0.02,0.5,0.93\nrs2,0.3,NA,1\n".as_bytes())
+      .unwrap()
+      .to_numeric()
+      .unwrap();
+    let caller = HardCaller::new(&hab_mapper, 0.1).unwrap();
+    assert_eq!('-', caller.missing());
+    let mut out = Vec::new();
+    let counts = write_hard_calls(&mut dosages.snp_source(), &dosages.col_ids, &mut out, &caller)
+      .unwrap();
+    assert_eq!(
+      HardCallCounts {
+        markers: 2,
+        called: 4,
+        missing: 2
+      },
+      counts
+    );
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!("marker\ti1\ti2\ti3\nrs1\tAHB\nrs2\t--B\n", out);
+
+    // The output is read back with the mapper it was called with.
+    hab_mapper.insert('-', rqtl2::util::na::NA);
+    let f =
+      create_test_file("test_geno_parsers_34.txt", &out).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let lines = geno_parser.iter().unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+    assert_eq!((String::from("rs1"), vec![0.0, 0.5, 1.0]), lines[0]);
+    assert!(lines[1].1[1].is_nan());
+
+    assert!(HardCaller::new(&hab_mapper, 0.25).is_err());
+    assert!(HardCaller::new(&HashMap::new(), 0.1).is_err());
+  }
+
+  #[test]
+  fn kinship_block_export() {
+    use rqtl2::io::NumberFormat;
+    use rqtl2::kinship::{id_range, KinshipMatrix, MappedKinship};
+    use rqtl2::util::idx::IndividualIdx as Idx;
+    let mut path = env::temp_dir();
+    path.push("test_kinship_blocks.bin");
+    let mut mapped = MappedKinship::create(&path, 3).unwrap();
+    for (pos, value) in mapped.as_mut_slice().iter_mut().enumerate() {
+      *value = pos as f64;
+    }
+    let ids = vec![String::from("10"), String::from("12"), String::from("38")];
+    let rows = id_range(&ids, "12", "38").unwrap();
+    assert_eq!(Idx(1)..Idx(3), rows);
+    assert_eq!(vec![3.0, 4.0, 6.0, 7.0], mapped.block(rows.clone(), Idx(0)..Idx(2)).unwrap());
+    assert!(mapped.block(Idx(0)..Idx(4), Idx(0)..Idx(1)).is_err());
+    assert!(id_range(&ids, "38", "10").is_err());
+    assert!(id_range(&ids, "10", "39").is_err());
+
+    let mut out = Vec::new();
+    mapped
+      .write_block(&mut out, &ids, rows, Idx(2)..Idx(3), &NumberFormat::default())
+      .unwrap();
+    assert_eq!(",38\n12,5\n38,8\n", String::from_utf8(out).unwrap());
+    let quoted = vec![String::from("10"), String::from("a,b"), String::from("c\"d")];
+    let mut out = Vec::new();
+    let all = Idx(0)..Idx(3);
+    mapped.write_block(&mut out, &quoted, all.clone(), all, &NumberFormat::default()).unwrap();
+    let read = KinshipMatrix::read_csv_from(&out[..]).unwrap();
+    assert_eq!(quoted, read.ids());
+    assert_eq!(Some(5.0), read.get_by_id("a,b", "c\"d"));
+    let (first, ids) = (Idx(0)..Idx(1), &ids[..2]);
+    assert!(mapped
+      .write_block(&mut Vec::new(), ids, first.clone(), first, &NumberFormat::default())
+      .is_err());
+  }
+
+  #[test]
+  fn run_summary() {
+    use rqtl2::prelude::*;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let f = create_test_file(
+      "test_geno_parsers_35.txt",
+      "#test file\nmarker\t10\t12\t14\nrs1\tABH\nrs2\tBBB\nrs3\tHAB\nrs4\tAHB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut geno_parser = GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let options = KinshipOptions {
+      drop_monomorphic: true,
+      ..KinshipOptions::default()
+    };
+    let (kinship, summary) = geno_parser.calc_kinship_summarized(&options).unwrap();
+    assert_eq!(9, kinship.len());
+    assert_eq!((4, 3, 3), (summary.markers_read, summary.markers_used, summary.individuals));
+    assert_eq!(("cpu", 0), (summary.backend.as_str(), summary.warnings));
+    assert!(summary.to_string().starts_with("3 of 4 markers used, 3 individuals"));
+    assert!(summary.to_json().contains("\"markers_read\":4,\"markers_used\":3,"));
+
+    let mut dataset = Dataset::new(geno_parser);
+    let (results, summary) = dataset.compute_summarized(&[Analysis::MarkerStats]).unwrap();
+    assert_eq!(1, results.len());
+    assert_eq!((4, 4), (summary.markers_read, summary.markers_used));
+  }
+
+  #[test]
+  fn pheno_transforms() {
+    use rqtl2::pheno::{qnorm, transform, PhenoTransform};
+    use rqtl2::util::na::NA;
+    assert!((qnorm(0.975) - 1.959963984540054).abs() < 1e-8);
+    assert!((qnorm(0.001) + 3.090232306167813).abs() < 1e-8);
+    assert_eq!(0.0, qnorm(0.5));
+    assert!(qnorm(1.0).is_nan());
+
+    // Two phenotypes of 4 individuals, row-major.
+    let mut values = vec![3.0, 1.0, 1.0, NA, 3.0, 4.0, 10.0, 7.0];
+    transform(&mut values, 2, PhenoTransform::RankInverseNormal).unwrap();
+    // Tied 3.0 values share rank 2.5 of 4, NA is left out of the second one.
+    let (lowest_of_4, lowest_of_3) = (qnorm(0.125), qnorm(1.0 / 6.0));
+    let expected = [0.0, lowest_of_3, lowest_of_4, NA, 0.0, 0.0, -lowest_of_4, -lowest_of_3];
+    rqtl2::testing::assert_matrix_close(&expected, &values, 2, Default::default());
+
+    let mut values = vec![1.0, NA, 3.0];
+    transform(&mut values, 1, PhenoTransform::Standardize).unwrap();
+    let expected = [-0.5f64.sqrt(), NA, 0.5f64.sqrt()];
+    rqtl2::testing::assert_matrix_close(&expected, &values, 1, Default::default());
+    assert!(transform(&mut [2.0, 2.0], 1, PhenoTransform::Standardize).is_err());
+
+    let mut values = vec![1.0, std::f64::consts::E, NA];
+    transform(&mut values, 1, PhenoTransform::Log).unwrap();
+    assert_eq!(vec![0.0, 1.0], values[..2].to_vec());
+    assert!(transform(&mut [1.0, 0.0], 1, PhenoTransform::Log).is_err());
+  }
+
+  #[test]
+  fn pheno_parser() {
+    use rqtl2::io::PhenoParser;
+    let contents = "# phenotypes\nid,weight,\"glucose\"\n1,20.5,NA\n\n2,,7.25\n3,18,6\n";
+    let mut parser = PhenoParser::from_reader(contents.as_bytes()).unwrap();
+    assert_eq!(vec!["weight", "glucose"], parser.phenotypes());
+    let first = parser.iter().next().unwrap().unwrap();
+    assert_eq!("1", first.0);
+    assert_eq!(20.5, first.1[0]);
+    assert!(first.1[1].is_nan());
+
+    let matrix = parser.read_matrix().unwrap();
+    assert_eq!(vec!["2", "3"], matrix.ids);
+    assert_eq!((2, 2), (matrix.ids_num(), matrix.phenotypes_num()));
+    assert_eq!(Some(vec![7.25, 6.0]), matrix.column("glucose"));
+    assert!(matrix.get(0, 0).is_nan());
+    assert_eq!(None, matrix.column("insulin"));
+
+    let bad = "id,weight\n1,heavy\n";
+    let mut parser = PhenoParser::from_reader(bad.as_bytes()).unwrap();
+    let err = parser.iter().next().unwrap().unwrap_err();
+    assert!(err.to_string().contains("line 2"));
+    let short = "id,weight,glucose\n1,2\n";
+    assert!(PhenoParser::from_reader(short.as_bytes()).unwrap().read_matrix().is_err());
+    assert!(PhenoParser::from_reader("# only comments\n".as_bytes()).is_err());
+
+    // Delimiter and na_strings of a MatrixCsvReader.
+    let csv = rqtl2::io::MatrixCsvReader::new()
+      .with_delimiter('\t')
+      .with_na_strings(vec![String::from("-")]);
+    let tabs = "id\tweight\tglucose\n1\t20.5\t-\n2\t\t7.25\n";
+    let matrix = PhenoParser::from_csv_reader(tabs.as_bytes(), csv).unwrap().read_matrix().unwrap();
+    assert_eq!(vec!["weight", "glucose"], matrix.phenotypes);
+    assert_eq!(20.5, matrix.get(0, 0));
+    assert!(matrix.get(0, 1).is_nan() && matrix.get(1, 0).is_nan());
+  }
+
+  #[test]
+  fn design_matrix() {
+    use rqtl2::io::MatrixCsvReader;
+    use rqtl2::lmm::DesignBuilder;
+    let covar = MatrixCsvReader::new()
+      .read_from("id,sex,age,cohort\n1,M,10,a\n2,F,12,b\n3,M,NA,c\n4,F,9,a\n".as_bytes())
+      .unwrap();
+    let ids = vec![String::from("4"), String::from("1"), String::from("2")];
+    let design = DesignBuilder::new(&covar)
+      .numeric("age")
+      .factor("sex")
+      .interaction("sex", "age")
+      .build(&ids)
+      .unwrap();
+    assert_eq!(vec!["intercept", "age", "sex=M", "sex=M:age"], design.columns);
+    // Rows of individuals 4, 1 and 2.
+    let expected = vec![1.0, 9.0, 0.0, 0.0, 1.0, 10.0, 1.0, 10.0, 1.0, 12.0, 0.0, 0.0];
+    assert_eq!((&expected[..], 4), design.as_covariates());
+
+    let design = DesignBuilder::new(&covar)
+      .without_intercept()
+      .factor_with_reference("sex", "M")
+      .factor("cohort")
+      .build(&ids)
+      .unwrap();
+    assert_eq!(vec!["sex=F", "sex=M", "cohort=b"], design.columns);
+    assert_eq!(vec![1.0, 0.0, 0.0], design.values[..3].to_vec());
+
+    // Missing age of individual 3, unknown individual, column and term.
+    let all = vec![String::from("1"), String::from("3")];
+    assert!(DesignBuilder::new(&covar).numeric("age").build(&all).is_err());
+    assert!(DesignBuilder::new(&covar).build(&[String::from("5")]).is_err());
+    assert!(DesignBuilder::new(&covar).factor("weight").build(&ids).is_err());
+    assert!(DesignBuilder::new(&covar).interaction("sex", "age").build(&ids).is_err());
+    assert!(DesignBuilder::new(&covar).numeric("sex").build(&ids).is_err());
+  }
+
+  #[test]
+  fn control_file() {
+    use rqtl2::io::{ControlFile, ControlValue};
+    let mut dir = env::temp_dir();
+    dir.push("rqtl2_control_test");
+    fs::create_dir_all(&dir).unwrap();
+    let yaml = "# Data from Grant et al. (2006)\n\
+                crosstype: f2\n\
+                geno: geno.txt\n\
+                pheno: 'pheno.csv'\n\
+                gmap: gmap.csv # cM\n\
+                alleles:\n\
+                - S\n\
+                - B\n\
+                genotypes:\n  S: 1\n  H: 2\n  B: 3\n\
+                sex:\n  covar: sex\n  f: female\n\
+                na.strings: ['-', NA]\n";
+    fs::write(dir.join("iron.yaml"), yaml).unwrap();
+    fs::write(dir.join("geno.txt"), "marker\t1\t2\nrs1\tSH\nrs2\tB-\n").unwrap();
+    fs::write(dir.join("pheno.csv"), "id,liver\n1,61\n2,-\n").unwrap();
+    fs::write(dir.join("gmap.csv"), "marker,chr,pos\nrs1,1,0.5\nrs2,1,2\n").unwrap();
+
+    let control = ControlFile::read(dir.join("iron.yaml")).unwrap();
+    assert_eq!(Some("f2"), control.crosstype());
+    let alleles = ControlValue::List(vec![
+      ControlValue::Scalar(String::from("S")),
+      ControlValue::Scalar(String::from("B")),
+    ]);
+    assert_eq!(Some(&alleles), control.get("alleles"));
+    assert_eq!(
+      Some("female"),
+      control.get("sex").and_then(|sex| sex.get("f")).and_then(ControlValue::as_str)
+    );
+    assert_eq!(vec!["-", "NA"], control.na_strings());
+    let hab_mapper = control.hab_mapper().unwrap();
+    let numbers = ['S', 'H', 'B'].map(|code| hab_mapper[&code]);
+    assert_eq!([0.0, 0.5, 1.0], numbers);
+    assert!(hab_mapper[&'-'].is_nan());
+
+    let mut geno = control.geno_parser().unwrap();
+    let lines = geno.iter().unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+    assert_eq!(vec![0.0, 0.5], lines[0].1);
+    let pheno = control.pheno_parser().unwrap().read_matrix().unwrap();
+    assert!(pheno.get(1, 0).is_nan());
+    assert_eq!(Some(2.0), control.gmap().unwrap().position("rs2"));
+    assert!(control.table("covar").is_err());
+
+    let json = r#"{"crosstype": "bc", "geno": ["chr1.txt", "chr2.txt"],
+                   "genotypes": {"A": 1, "H": 2}, "na.strings": "-"}"#;
+    let control = ControlFile::parse(json, &dir, true).unwrap();
+    assert_eq!(Some("bc"), control.crosstype());
+    assert!(control.file("geno").is_err());
+    assert_eq!(Some(&1.0), control.hab_mapper().unwrap().get(&'H'));
+
+    fs::write(dir.join("pheno.tsv"), "id\tliver\tspleen\n1\t61\t-\n").unwrap();
+    let tabs = r#"{"pheno": "pheno.tsv", "sep": "\t", "na.strings": "-"}"#;
+    let control = ControlFile::parse(tabs, &dir, true).unwrap();
+    assert_eq!('\t', control.separator().unwrap());
+    let pheno = control.pheno_parser().unwrap().read_matrix().unwrap();
+    assert_eq!(vec!["liver", "spleen"], pheno.phenotypes);
+    assert!(pheno.get(0, 0) == 61.0 && pheno.get(0, 1).is_nan());
+
+    let multi_char = "genotypes:\n  SS: 1\n  BB: 2\n";
+    assert!(ControlFile::parse(multi_char, &dir, false).unwrap().hab_mapper().is_err());
+    assert!(ControlFile::parse("geno geno.txt\n", &dir, false).is_err());
+    assert!(ControlFile::parse("{\"geno\": \"x\"", &dir, true).is_err());
+  }
+
+  #[test]
+  fn small_input_fast_path() {
+    use rqtl2::kinship::{KinshipOptions, Metrics};
+    use std::sync::Arc;
+    let codes = ['A', 'H', 'B'];
+    let mut contents = String::from("#test file\nmarker\t1\t2\t3\t4\t5\n");
+    for snp in 0..300 {
+      let line = (0..5).map(|id| codes[(snp * 7 + id * id + snp / 3) % 3]).collect::<String>();
+      contents.push_str(&format!("rs{}\t{}\n", snp, line));
+    }
+    let f = create_test_file("test_geno_parsers_36.txt", &contents)
+      .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let mut run = |fast_path_work: u64| {
+      let metrics = Arc::new(Metrics::new());
+      let options = KinshipOptions {
+        batch_size: 16,
+        fast_path_work,
+        metrics: Some(metrics.clone()),
+        ..KinshipOptions::default()
+      };
+      let res = geno_parser.calc_kinship_with_options(&options).unwrap();
+      (res, metrics.batches(), metrics.snps())
+    };
+    let (threaded, batches, snps) = run(0);
+    assert_eq!((19, 300), (batches, snps));
+    // 300 lines of 5 ids are 300 * 25 / 2 multiply-adds.
+    for work in [rqtl2::kinship::FAST_PATH_WORK, 300 * 25 / 2] {
+      let (fast, batches, snps) = run(work);
+      assert_eq!((1, 300), (batches, snps));
+      rqtl2::testing::assert_matrix_close(&threaded, &fast, 5, Default::default());
+    }
+    // Prefetched lines are handed to the threads in batches too.
+    let (prefetched, batches, snps) = run(299 * 25 / 2);
+    assert!(batches > 1);
+    assert_eq!(300, snps);
+    rqtl2::testing::assert_matrix_close(&threaded, &prefetched, 5, Default::default());
+  }
+
+  #[test]
+  fn map_parser() {
+    use rqtl2::io::MapParser;
+    let pmap_csv = "# pmap\nmarker;chr;pos\nrs1;X;5.5\nrs2;10;1.0\nrs3;2;3.25\nrs4;2;-\n\
+                    rs5;2;0.75\nrs6;X;0.5\n";
+    let pmap = MapParser::new()
+      .with_delimiter(';')
+      .with_na_strings(vec![String::from("-")])
+      .read_from(pmap_csv.as_bytes())
+      .unwrap();
+    assert_eq!(6, pmap.len());
+    assert_eq!(Some("10"), pmap.chromosome("rs2"));
+    assert_eq!(Some(3.25), pmap.position("rs3"));
+    assert!(pmap.position("rs4").unwrap().is_nan());
+    assert_eq!(None, pmap.get("rs7"));
+    assert_eq!(vec!["2", "10", "X"], pmap.chromosome_names());
+    let ordered = pmap
+      .by_chromosome()
+      .into_iter()
+      .map(|(chr, markers)| (chr, markers.iter().map(|m| m.id.as_str()).collect::<Vec<_>>()))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      vec![("2", vec!["rs5", "rs3", "rs4"]), ("10", vec!["rs2"]), ("X", vec!["rs6", "rs1"])],
+      ordered
+    );
+
+    let duplicated = "marker,chr,pos\nrs1,1,0.5\nrs1,1,2\n";
+    assert!(MapParser::new().read_from(duplicated.as_bytes()).is_err());
+  }
+
+  #[test]
+  fn kinship_summary_streaming() {
+    use rqtl2::kinship::{DosageTransform, KinshipOptions};
+    let codes = ['A', 'H', 'B'];
+    let mut contents = String::from("#test file\nmarker\t1\t2\t3\t4\n");
+    for snp in 0..40 {
+      let line = (0..4).map(|id| codes[(snp * 5 + id * id * 2 + snp / 4) % 3]).collect::<String>();
+      contents.push_str(&format!("rs{}\t{}\n", snp, line));
+    }
+    let f = create_test_file("test_geno_parsers_37.txt", &contents)
+      .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    for transform in [DosageTransform::Identity, DosageTransform::Center] {
+      let options = KinshipOptions {
+        batch_size: 7,
+        transform,
+        ..KinshipOptions::default()
+      };
+      let summary = geno_parser.kinship_summary(&options).unwrap();
+      let kinship = geno_parser.calc_kinship_with_options(&options).unwrap();
+      assert_eq!((4, 40), (summary.ids_num, summary.snps_num));
+      let trace = (0..4).map(|i| kinship[i * 5]).sum::<f64>();
+      let off_diagonal = kinship.iter().sum::<f64>() - trace;
+      assert!((summary.trace - trace).abs() < 1e-12);
+      assert!((summary.mean_diagonal - trace / 4.0).abs() < 1e-12);
+      assert!((summary.mean_off_diagonal - off_diagonal / 12.0).abs() < 1e-12);
+      // The largest eigenvalue by power iteration.
+      let mut v = vec![1.0, 0.5, 0.25, 0.125];
+      let mut eigenvalue = 0.0;
+      for _ in 0..1000 {
+        let w = (0..4)
+          .map(|i| (0..4).map(|j| kinship[i * 4 + j] * v[j]).sum::<f64>())
+          .collect::<Vec<f64>>();
+        eigenvalue = w.iter().map(|x| x * x).sum::<f64>().sqrt();
+        v = w.iter().map(|x| x / eigenvalue).collect();
+      }
+      assert!(summary.max_eigenvalue_lower <= eigenvalue + 1e-9);
+      assert!(eigenvalue <= summary.max_eigenvalue_upper + 1e-9);
+    }
+
+    // What the matrix can't be normalized for is rejected, not NaN.
+    let mut zero = rqtl2::kinship::MarkerWeights::new();
+    for snp in 0..40 {
+      zero.insert(format!("rs{}", snp), 0.0).unwrap();
+    }
+    let markers = std::collections::HashSet::from([String::from("rs1")]);
+    let invalid = [
+      KinshipOptions {
+        batch_size: 0,
+        ..KinshipOptions::default()
+      },
+      KinshipOptions {
+        markers: Some(std::sync::Arc::new(rqtl2::kinship::MarkerSet::new(markers))),
+        ..KinshipOptions::default()
+      },
+      KinshipOptions {
+        marker_weights: Some(std::sync::Arc::new(zero)),
+        ..KinshipOptions::default()
+      },
+    ];
+    for options in invalid {
+      let err = geno_parser.kinship_summary(&options).unwrap_err();
+      assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+    }
+  }
+
+  #[test]
+  fn kinship_loco() {
+    use rqtl2::kinship::KinshipOptions;
+    let geno = "#test file\nmarker\t10\t12\nrs1\tAB\nrs2\tBB\nrs3\tAH\nrs4\tHB\nrs5\tBA\n";
+    let f =
+      create_test_file("test_geno_parsers_38.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let chromosome_map = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+      pairs
+        .iter()
+        .map(|(snp, chr)| (String::from(*snp), String::from(*chr)))
+        .collect()
+    };
+    let chromosomes =
+      chromosome_map(&[("rs1", "1"), ("rs2", "1"), ("rs3", "2"), ("rs4", "2"), ("rs5", "X")]);
+    let options = KinshipOptions {
+      batch_size: 2,
+      ..KinshipOptions::default()
+    };
+    let loco = geno_parser.calc_kinship_loco(&chromosomes, &options).unwrap();
+    assert_eq!(3, loco.len());
+    let close = |expected: &[f64], actual: &[f64]| {
+      rqtl2::testing::assert_matrix_close(expected, actual, 2, Default::default())
+    };
+    // Markers rs3, rs4, rs5.
+    close(&[1.25 / 3.0, 0.5 / 3.0, 0.5 / 3.0, 1.25 / 3.0], &loco["1"]);
+    // Markers rs1, rs2, rs5.
+    close(&[2.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 2.0 / 3.0], &loco["2"]);
+    // Markers rs1 - rs4.
+    close(&[1.25 / 4.0, 1.5 / 4.0, 1.5 / 4.0, 3.25 / 4.0], &loco["X"]);
+
+    let single = chromosomes.keys().map(|snp| (snp.clone(), String::from("1"))).collect();
+    let err = geno_parser.calc_kinship_loco(&single, &options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+    let compensated = KinshipOptions {
+      compensated: true,
+      ..options.clone()
+    };
+    let err = geno_parser.calc_kinship_loco(&chromosomes, &compensated).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+  }
+
+  #[test]
+  fn anonymized_export() {
+    use rqtl2::io::IdMasking;
+    let geno = "#secret study\nmarker\tmouse_a\tmouse_b\tmouse_c\nrs1\tABH\nrs2\tBBA\nrs3\tHAB\n";
+    let f =
+      create_test_file("test_geno_parsers_39.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser.calc_kinship(2).unwrap();
+
+    let mut exported = Vec::new();
+    let masking = IdMasking::Shuffle { seed: 7 };
+    let mapping = geno_parser.write_anonymized(&masking, &mut exported).unwrap();
+    let exported = String::from_utf8(exported).unwrap();
+    assert!(!exported.contains("mouse") && !exported.contains("secret"));
+    let mut anonymized = mapping.anonymized.clone();
+    anonymized.sort();
+    assert_eq!(vec!["ind1", "ind2", "ind3"], anonymized);
+    assert_eq!(
+      mapping,
+      geno_parser.write_anonymized(&masking, &mut Vec::new()).unwrap()
+    );
+    let mut mapping_file = Vec::new();
+    mapping.write_to(&mut mapping_file).unwrap();
+    let mapping_file = String::from_utf8(mapping_file).unwrap();
+    assert!(mapping_file.contains(&format!("mouse_b\t{}\n", mapping.anonymized[1])));
+
+    let f = create_test_file("test_geno_parsers_40.txt", &exported)
+      .expect("Failed to create test file.");
+    let mut exported_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    assert_eq!(expected, exported_parser.calc_kinship(2).unwrap());
+
+    let hashed = rqtl2::io::mask_ids(
+      &mapping.original,
+      &IdMasking::Hash {
+        salt: String::from("pepper"),
+      },
+    )
+    .unwrap();
+    assert_eq!(16, hashed.anonymized[0].len());
+    assert_ne!(hashed.anonymized[0], hashed.anonymized[1]);
+    assert_eq!(hashed.anonymized(&mapping.original[2]), Some(hashed.anonymized[2].as_str()));
+  }
+
+  #[test]
+  fn missing_genotype_policy() {
+    use rqtl2::kinship::{KinshipOptions, Metrics, MissingPolicy};
+    use std::sync::Arc;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', f64::NAN);
+    let parser = |name: &str, lines: &str| {
+      let f = create_test_file(name, &format!("#test file\nmarker\t1\t2\t3\n{}", lines))
+        .expect("Failed to create test file.");
+      rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+        .expect("Failed to create GenoParser")
+    };
+    let mut geno_parser = parser(
+      "test_geno_parsers_41.txt",
+      "rs1\tABH\nrs2\tA-B\nrs3\tBBA\nrs4\tHAB\nrs5\t--B\n",
+    );
+    let options = |missing: MissingPolicy| KinshipOptions {
+      batch_size: 2,
+      missing,
+      metrics: Some(Arc::new(Metrics::new())),
+      ..KinshipOptions::default()
+    };
+    let close = |expected: &[f64], actual: &[f64]| {
+      rqtl2::testing::assert_matrix_close(expected, actual, 3, Default::default())
+    };
+    assert!(geno_parser.calc_kinship_with_options(&options(MissingPolicy::Reject)).is_err());
+
+    let skip = options(MissingPolicy::SkipMarker);
+    let skipped = geno_parser.calc_kinship_with_options(&skip).unwrap();
+    let expected = parser("test_geno_parsers_42.txt", "rs1\tABH\nrs3\tBBA\nrs4\tHAB\n")
+      .calc_kinship(2)
+      .unwrap();
+    close(&expected, &skipped);
+    let metrics = skip.metrics.as_ref().unwrap();
+    assert_eq!((3, 2), (metrics.snps(), metrics.incomplete()));
+
+    // rs2 gets the mean 0.5 and rs5 gets 1, the matrix is divided by the
+    // five lines as in GEMMA.
+    let imputed = geno_parser.calc_kinship_with_options(&options(MissingPolicy::MeanImpute));
+    let expected = parser(
+      "test_geno_parsers_43.txt",
+      "rs1\tABH\nrs2\tAHB\nrs3\tBBA\nrs4\tHAB\nrs5\tBBB\n",
+    )
+    .calc_kinship(2)
+    .unwrap();
+    close(&expected, &imputed.unwrap());
+
+    let fixed = geno_parser.calc_kinship_with_options(&options(MissingPolicy::Fixed(0.0)));
+    let expected = parser(
+      "test_geno_parsers_44.txt",
+      "rs1\tABH\nrs2\tAAB\nrs3\tBBA\nrs4\tHAB\nrs5\tAAB\n",
+    )
+    .calc_kinship(2)
+    .unwrap();
+    close(&expected, &fixed.unwrap());
+
+    // LOCO matrices read the missing genotypes with the same policy.
+    let chromosomes = [("rs1", "1"), ("rs2", "1"), ("rs3", "2"), ("rs4", "2"), ("rs5", "2")]
+      .iter()
+      .map(|(snp, chr)| (String::from(*snp), String::from(*chr)))
+      .collect::<HashMap<String, String>>();
+    let loco = geno_parser.calc_kinship_loco(&chromosomes, &skip).unwrap();
+    // rs3 and rs4 without chromosome 1, rs1 without chromosome 2.
+    close(&[0.625, 0.5, 0.25, 0.5, 0.5, 0.0, 0.25, 0.0, 0.5], &loco["1"]);
+    close(&[0.0, 0.0, 0.0, 0.0, 1.0, 0.5, 0.0, 0.5, 0.25], &loco["2"]);
+  }
+
+  #[test]
+  fn repro_bundle() {
+    use rqtl2::debug::{make_repro_bundle, ReproOptions};
+    use rqtl2::io::IdMasking;
+    let geno = "#cohort X\nmarker\tm1\tm2\nrs1\tAB\nrs2\tBB\nrs3\tAH\nrs4\tHX\nrs5\tAA\n";
+    let f =
+      create_test_file("test_geno_parsers_45.txt", geno).expect("Failed to create test file.");
+    drop(f);
+    let path = env::temp_dir().join("test_geno_parsers_45.txt");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut options = ReproOptions {
+      hab_mapper,
+      ..ReproOptions::default()
+    };
+    // Names and contents of the files of tar archive.
+    let read_tar = |bundle: &[u8]| {
+      let mut entries = Vec::new();
+      let mut pos = 0;
+      while bundle[pos] != 0 {
+        let header = &bundle[pos..pos + 512];
+        let name = String::from_utf8_lossy(&header[..100]).trim_end_matches('\0').to_string();
+        let size = std::str::from_utf8(&header[124..135]).unwrap();
+        let size = usize::from_str_radix(size, 8).unwrap();
+        let contents = String::from_utf8(bundle[pos + 512..pos + 512 + size].to_vec()).unwrap();
+        entries.push((name, contents));
+        pos += 512 + size.div_ceil(512) * 512;
+      }
+      assert!(bundle[pos..].iter().all(|byte| *byte == 0));
+      entries
+    };
+
+    let mut bundle = Vec::new();
+    let offending = make_repro_bundle(&path, 2, &options, &mut bundle).unwrap();
+    assert_eq!(Some(6), offending);
+    let entries = read_tar(&bundle);
+    let names = entries.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+    assert_eq!(vec!["geno.txt", "error.txt", "environment.txt", "options.txt"], names);
+    assert_eq!("#cohort X\nmarker\tm1\tm2\nrs3\tAH\nrs4\tHX\n", entries[0].1);
+    assert!(entries[1].1.starts_with("line 6: "));
+
+    options.masking = Some(IdMasking::Shuffle { seed: 1 });
+    options.hab_mapper.insert('X', 1.0);
+    let mut bundle = Vec::new();
+    assert_eq!(None, make_repro_bundle(&path, 3, &options, &mut bundle).unwrap());
+    let entries = read_tar(&bundle);
+    assert_eq!(3, entries.len());
+    let geno_txt = &entries[0].1;
+    assert!(geno_txt.ends_with("rs1\tAB\nrs2\tBB\nrs3\tAH\n"));
+    assert!(!geno_txt.contains("cohort") && !geno_txt.contains("m1"));
+  }
+
+  #[test]
+  fn kinship_matrix_type() {
+    use rqtl2::prelude::*;
+    let geno = "#test file\nmarker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\n";
+    let f =
+      create_test_file("test_geno_parsers_46.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser =
+      GenoParser::new_with_file(f, hab_mapper).expect("Failed to create GenoParser");
+    let flat = geno_parser.calc_kinship(2).unwrap();
+    let kinship = geno_parser.calc_kinship_matrix(&KinshipOptions::default()).unwrap();
+    assert_eq!(flat, kinship.as_slice());
+    assert_eq!(vec!["10", "12", "14"], kinship.ids());
+    assert_eq!((3, 3), kinship.dims());
+    assert_eq!(flat[5], kinship.get(IndividualIdx(1), IndividualIdx(2)));
+    assert_eq!(Some(flat[2]), kinship.get_by_id("10", "14"));
+    assert_eq!(None, kinship.get_by_id("10", "16"));
+    assert_eq!(&flat[3..6], kinship.row(IndividualIdx(1)));
+    let column = kinship.column(IndividualIdx(1)).collect::<Vec<f64>>();
+    assert_eq!(vec![flat[1], flat[4], flat[7]], column);
+    assert_eq!(3, kinship.rows().count());
+    assert_eq!(flat[6..9].to_vec(), kinship.to_dense()[2]);
+    assert_eq!(flat, kinship.clone().into_vec());
+    assert!(KinshipMatrix::new(vec![String::from("10")], vec![1.0, 0.0]).is_err());
+  }
+
+  #[test]
+  fn line_terminators() {
+    use rqtl2::kinship::{GramOrientation, KinshipOptions, ParallelMode};
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let lines = ["#test file", "marker\t10\t12", "rs1\tAB", "rs2\tBH", "rs3\tHA"];
+    let variants = [
+      format!("{}\n", lines.join("\n")),
+      lines.join("\n"),
+      format!("{}\r\n", lines.join("\r\n")),
+      lines.join("\r\n"),
+    ];
+    let mut results = Vec::new();
+    for (n, contents) in variants.iter().enumerate() {
+      let f = create_test_file(&format!("test_geno_parsers_{}.txt", 47 + n), contents)
+        .expect("Failed to create test file.");
+      let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+        .expect("Failed to create GenoParser");
+      assert_eq!(&vec!["test file"], geno_parser.get_comments());
+      assert_eq!(&vec!["10", "12"], geno_parser.get_markers());
+      let records = geno_parser.read_all().unwrap();
+      let iterated = geno_parser.iter().unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+      assert_eq!(records, iterated);
+      assert_eq!(("rs3", vec![0.5, 0.0]), (records[2].0.as_str(), records[2].1.clone()));
+      let mut kinships = Vec::new();
+      for mode in [ParallelMode::Batches, ParallelMode::ColumnBlocks] {
+        let options = KinshipOptions {
+          batch_size: 2,
+          mode,
+          fast_path_work: 0,
+          ..KinshipOptions::default()
+        };
+        kinships.push(geno_parser.calc_kinship_with_options(&options).unwrap());
+      }
+      let options = KinshipOptions {
+        orientation: GramOrientation::SnpsBySnps,
+        ..KinshipOptions::default()
+      };
+      kinships.push(geno_parser.calc_kinship_with_options(&options).unwrap());
+      results.push(kinships);
+    }
+    assert!(results.iter().all(|kinships| *kinships == results[0]));
+    let expected = [1.25 / 3.0, 0.5 / 3.0, 0.5 / 3.0, 1.25 / 3.0];
+    rqtl2::testing::assert_matrix_close(&expected, &results[0][0], 2, Default::default());
+  }
+
+  #[test]
+  fn gemma_output() {
+    use rqtl2::io::NumberFormat;
+    use rqtl2::kinship::{KinshipMatrix, MappedKinship};
+    let ids = vec![String::from("10"), String::from("12")];
+    let data = vec![0.5, 0.125, 0.125, 1.0 / 3.0];
+    let kinship = KinshipMatrix::new(ids, data.clone()).unwrap();
+    let path = env::temp_dir().join("rqtl2_gemma.txt");
+    kinship.write_gemma(&path).unwrap();
+    let written = fs::read_to_string(&path).unwrap();
+    assert_eq!(format!("0.5\t0.125\n0.125\t{}\n", 1.0 / 3.0), written);
+    let lines = written.lines().map(|line| line.split('\t').map(|v| v.parse().unwrap()));
+    assert_eq!(data, lines.flatten().collect::<Vec<f64>>());
+
+    let mut mapped = MappedKinship::create(env::temp_dir().join("rqtl2_gemma.kinship"), 2).unwrap();
+    mapped.as_mut_slice().copy_from_slice(&data);
+    let mut streamed = Vec::new();
+    mapped.write_gemma(&mut streamed, &NumberFormat::fixed(3)).unwrap();
+    assert_eq!("0.500\t0.125\n0.125\t0.333\n", String::from_utf8(streamed).unwrap());
+  }
+
+  #[test]
+  fn multi_allele_codes() {
+    use rqtl2::io::DosageCodes;
+    use rqtl2::kinship::KinshipOptions;
+    // Blank lines are skipped.
+    let geno = "#test file\nmarker\t1\t2\t3\nrs1\tABC\n\nrs2\tAAH\n \t\nrs3\tCHB\nrs4\tBBA\n\n";
+    let f =
+      create_test_file("test_geno_parsers_51.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let codes = |pairs: &[(char, Vec<f64>)]| DosageCodes::new(pairs.iter().cloned().collect());
+
+    // Single dosage codes give the usual matrix.
+    let biallelic =
+      codes(&[('A', vec![0.0]), ('H', vec![0.5]), ('B', vec![1.0]), ('C', vec![1.0])]).unwrap();
+    let options = KinshipOptions {
+      batch_size: 2,
+      ..KinshipOptions::default()
+    };
+    let single = geno_parser.calc_kinship_multi_allele(&biallelic, &options).unwrap();
+    let expected = [2.0, 1.5, 1.0, 1.5, 2.25, 1.5, 1.0, 1.5, 2.25].map(|value| value / 4.0);
+    rqtl2::testing::assert_matrix_close(&expected, &single, 3, Default::default());
+
+    // Founder codes: K[i][j] is the mean dot product of the founder dosages.
+    let founders = codes(&[
+      ('A', vec![1.0, 0.0, 0.0]),
+      ('B', vec![0.0, 1.0, 0.0]),
+      ('C', vec![0.0, 0.0, 1.0]),
+      ('H', vec![0.5, 0.5, 0.0]),
+    ])
+    .unwrap();
+    let expected = [4.0, 2.0, 0.5, 2.0, 3.5, 1.0, 0.5, 1.0, 3.5].map(|value| value / 4.0);
+    for fast_path_work in [0, rqtl2::kinship::FAST_PATH_WORK] {
+      let options = KinshipOptions {
+        batch_size: 2,
+        fast_path_work,
+        ..KinshipOptions::default()
+      };
+      let kinship = geno_parser.calc_kinship_multi_allele(&founders, &options).unwrap();
+      rqtl2::testing::assert_matrix_close(&expected, &kinship, 3, Default::default());
+    }
+
+    assert!(codes(&[('A', vec![1.0, 0.0]), ('B', vec![1.0])]).is_err());
+    let partial = codes(&[('A', vec![1.0, 0.0]), ('B', vec![0.0, 1.0])]).unwrap();
+    assert!(geno_parser.calc_kinship_multi_allele(&partial, &options).is_err());
+  }
+
+  #[test]
+  fn kinship_csv_round_trip() {
+    use rqtl2::io::NumberFormat;
+    use rqtl2::kinship::KinshipMatrix;
+    let ids = vec![String::from("10"), String::from("12")];
+    let kinship = KinshipMatrix::new(ids, vec![0.5, 0.125, 0.125, 1.0 / 3.0]).unwrap();
+    let path = env::temp_dir().join("rqtl2_kinship.csv");
+    kinship.write_csv(&path).unwrap();
+    let written = fs::read_to_string(&path).unwrap();
+    assert!(written.starts_with(",10,12\n10,0.5,0.125\n"));
+    assert_eq!(kinship, KinshipMatrix::read_csv(&path).unwrap());
+
+    let ids = vec![String::from("line \"a\""), String::from("b")];
+    let kinship = KinshipMatrix::new(ids, vec![1.0, 0.25, 0.25, 1.0]).unwrap();
+    let mut written = Vec::new();
+    kinship.write_csv_to(&mut written, &NumberFormat::default()).unwrap();
+    let written = String::from_utf8(written).unwrap();
+    assert!(written.starts_with(",\"line \"\"a\"\"\",b\n\"line \"\"a\"\"\",1,0.25\n"));
+    assert_eq!(kinship, KinshipMatrix::read_csv_from(written.as_bytes()).unwrap());
+    let ids = vec![String::from("a,b"), String::from("c")];
+    let kinship = KinshipMatrix::new(ids, vec![1.0, 0.25, 0.25, 1.0]).unwrap();
+    let mut written = Vec::new();
+    kinship.write_csv_to(&mut written, &NumberFormat::default()).unwrap();
+    assert!(written.starts_with(b",\"a,b\",c\n\"a,b\",1,0.25\n"));
+    let read = KinshipMatrix::read_csv_from(&written[..]).unwrap();
+    assert_eq!(kinship, read);
+    assert_eq!(Some(0.25), read.get_by_id("a,b", "c"));
+    // Line breaks can't be read back.
+    let kinship = KinshipMatrix::new(vec![String::from("a\nb")], vec![1.0]).unwrap();
+    let err = kinship.write_csv_to(&mut Vec::new(), &NumberFormat::default()).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+
+    // As exported from R with write.csv(calc_kinship(probs)).
+    let from_r = "\"\",\"a\",\"b\"\n\"a\",0.75,0.25\n\"b\",0.25,0.5\n";
+    let kinship = KinshipMatrix::read_csv_from(from_r.as_bytes()).unwrap();
+    assert_eq!(vec!["a", "b"], kinship.ids());
+    assert_eq!(Some(0.25), kinship.get_by_id("b", "a"));
+    let mismatched = "\"\",\"a\",\"b\"\n\"a\",0.75,0.25\n\"c\",0.25,0.5\n";
+    assert!(KinshipMatrix::read_csv_from(mismatched.as_bytes()).is_err());
+  }
+
+  #[test]
+  fn individual_genotypes() {
+    use rqtl2::prelude::*;
+    let geno = "#test file\nmarker\t10\t12\t14\nrs1\tABH\nrs2\tB-A\nrs3\tAHB\nrs4\tHHB\n";
+    let f =
+      create_test_file("test_geno_parsers_52.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', f64::NAN);
+    let mut geno_parser =
+      GenoParser::new_with_file(f, hab_mapper).expect("Failed to create GenoParser");
+    let individuals = geno_parser.read_all_individuals().unwrap();
+    let ids = individuals.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>();
+    assert_eq!(vec!["10", "12", "14"], ids);
+    assert_eq!(vec![0.0, 1.0, 0.0, 0.5], individuals[0].1);
+    assert_eq!(vec![0.5, 0.0, 1.0, 1.0], individuals[2].1);
+    let second = geno_parser.read_individual(IndividualIdx(1)).unwrap();
+    assert!(second[1].is_nan());
+    assert_eq!((1.0, 0.5, 0.5), (second[0], second[2], second[3]));
+
+    // Two individuals per pass give the same vectors.
+    let mut streamed = Vec::new();
+    geno_parser
+      .for_each_individual(2, |id, genotypes| {
+        streamed.push((String::from(id), genotypes));
+        Ok(())
+      })
+      .unwrap();
+    assert_eq!(3, streamed.len());
+    assert_eq!(individuals[0], streamed[0]);
+    assert_eq!(individuals[2], streamed[2]);
+    // The cursor is back at the SNP lines.
+    assert_eq!(4, geno_parser.read_all().unwrap().len());
+  }
+
+  #[test]
+  fn kinship_loco_files() {
+    use rqtl2::kinship::{calc_kinship_loco_files, KinshipOptions, ParallelMode};
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let header = "#test file\nmarker\t10\t12\t14\n";
+    let chromosomes = [
+      ("1", "rs1\tABH\nrs2\tBBA\nrs3\tAHB\n"),
+      ("2", "rs4\tHBA\nrs5\tBAA\n"),
+      ("X", "rs6\tAAB\nrs7\tHHB\nrs8\tBHA\nrs9\tABB\n"),
+    ];
+    let mut files = Vec::new();
+    let mut combined = String::from(header);
+    let mut chromosome_map = HashMap::new();
+    for (n, (chromosome, lines)) in chromosomes.iter().enumerate() {
+      let name = format!("test_geno_parsers_{}.txt", 53 + n);
+      create_test_file(&name, &format!("{}{}", header, lines))
+        .expect("Failed to create test file.");
+      files.push((String::from(*chromosome), env::temp_dir().join(name)));
+      combined.push_str(lines);
+      for line in lines.lines() {
+        let snp = line.split('\t').next().unwrap();
+        chromosome_map.insert(String::from(snp), String::from(*chromosome));
+      }
+    }
+    let f = create_test_file("test_geno_parsers_56.txt", &combined)
+      .expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser
+      .calc_kinship_loco(&chromosome_map, &KinshipOptions::default())
+      .unwrap();
+
+    for (mode, fast_path_work) in [(ParallelMode::Batches, 0), (ParallelMode::ColumnBlocks, 0)] {
+      let options = KinshipOptions {
+        batch_size: 2,
+        threads: Some(4),
+        mode,
+        fast_path_work,
+        ..KinshipOptions::default()
+      };
+      let loco = calc_kinship_loco_files(&files, &hab_mapper, &options).unwrap();
+      assert_eq!(3, loco.len());
+      for (chromosome, matrix) in &expected {
+        rqtl2::testing::assert_matrix_close(matrix, &loco[chromosome], 3, Default::default());
+      }
+    }
+    let loco = calc_kinship_loco_files(&files, &hab_mapper, &KinshipOptions::default()).unwrap();
+    rqtl2::testing::assert_matrix_close(&expected["2"], &loco["2"], 3, Default::default());
+
+    assert!(calc_kinship_loco_files(&files[..1], &hab_mapper, &KinshipOptions::default()).is_err());
+    let other = create_test_file("test_geno_parsers_57.txt", "marker\t10\t14\t12\nrs10\tABH\n")
+      .map(|_| env::temp_dir().join("test_geno_parsers_57.txt"))
+      .unwrap();
+    let mismatched = [files[0].clone(), (String::from("Y"), other)];
+    let err = calc_kinship_loco_files(&mismatched, &hab_mapper, &KinshipOptions::default());
+    assert_eq!("validation", rqtl2::util::error::Error::from(err.unwrap_err()).kind());
+  }
+
+  #[test]
+  fn kinship_loco_single_pass() {
+    use rqtl2::kinship::{calc_kinship_loco, GenoLines, KinshipOptions};
+    use std::io::BufRead;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    // Chromosomes of the kinship_loco test, interleaved.
+    let geno = "rs3\tAH\nrs1\tAB\nrs5\tBA\nrs4\tHB\nrs2\tBB\n";
+    let chromosomes = [("rs1", "1"), ("rs2", "1"), ("rs3", "2"), ("rs4", "2"), ("rs5", "X")]
+      .iter()
+      .map(|(snp, chr)| (String::from(*snp), String::from(*chr)))
+      .collect::<HashMap<String, String>>();
+    let options = KinshipOptions {
+      batch_size: 2,
+      ..KinshipOptions::default()
+    };
+    // Lines can be read only once.
+    let mut lines = std::io::Cursor::new(geno).lines();
+    let mut source = GenoLines::new(&mut lines, 2, &hab_mapper, false);
+    let loco = calc_kinship_loco(&mut source, &chromosomes, &options).unwrap();
+    assert_eq!(3, loco.len());
+    let close = |expected: &[f64], actual: &[f64]| {
+      rqtl2::testing::assert_matrix_close(expected, actual, 2, Default::default())
+    };
+    close(&[1.25 / 3.0, 0.5 / 3.0, 0.5 / 3.0, 1.25 / 3.0], &loco["1"]);
+    close(&[2.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 2.0 / 3.0], &loco["2"]);
+    close(&[1.25 / 4.0, 1.5 / 4.0, 1.5 / 4.0, 3.25 / 4.0], &loco["X"]);
+
+    let mut lines = std::io::Cursor::new("rs1\tAB\nrs6\tBA\n").lines();
+    let mut source = GenoLines::new(&mut lines, 2, &hab_mapper, false);
+    let err = calc_kinship_loco(&mut source, &chromosomes, &options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+
+    // Marker selection applies, rs4 is left out.
+    let selected = ["rs1", "rs2", "rs3", "rs5"].map(String::from);
+    let options = KinshipOptions {
+      markers: Some(std::sync::Arc::new(rqtl2::util::marker_set::MarkerSet::new(
+        std::collections::HashSet::from(selected),
+      ))),
+      ..options
+    };
+    let mut lines = std::io::Cursor::new(geno).lines();
+    let mut source = GenoLines::new(&mut lines, 2, &hab_mapper, false);
+    let loco = calc_kinship_loco(&mut source, &chromosomes, &options).unwrap();
+    close(&[0.5, 0.0, 0.0, 0.125], &loco["1"]);
+    close(&[1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 0.75], &loco["X"]);
+
+    let options = KinshipOptions {
+      kernel: Some(std::sync::Arc::new(rqtl2::util::kernel::CpuKernel)),
+      ..options
+    };
+    let mut lines = std::io::Cursor::new(geno).lines();
+    let mut source = GenoLines::new(&mut lines, 2, &hab_mapper, false);
+    let err = calc_kinship_loco(&mut source, &chromosomes, &options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+  }
+
+  #[test]
+  fn transposed_geno_file() {
+    use rqtl2::io::ControlFile;
+    use rqtl2::kinship::{DosageTransform, GramOrientation, KinshipOptions};
+    let standard = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\n";
+    let transposed = "#individuals as rows\nid\trs1\trs2\trs3\trs4\n10\tABAH\n12\tBBHB\n14\tHABA\n";
+    create_test_file("test_geno_parsers_58.txt", standard).expect("Failed to create test file.");
+    create_test_file("test_geno_parsers_59.txt", transposed).expect("Failed to create test file.");
+    let control = "crosstype: f2\ngeno: test_geno_parsers_59.txt\ngeno_transposed: true\n\
+                   genotypes:\n  A: 1\n  H: 2\n  B: 3\n";
+    let control = ControlFile::parse(control, env::temp_dir(), false).unwrap();
+    let mut geno_parser = control.geno_parser().unwrap();
+    assert!(geno_parser.is_transposed());
+    assert_eq!(vec!["10", "12", "14"], *geno_parser.get_markers());
+    let mut expected_parser = rqtl2::util::GenoParser::new(
+      env::temp_dir().join("test_geno_parsers_58.txt").to_string_lossy().into_owned(),
+      control.hab_mapper().unwrap(),
+    )
+    .unwrap();
+
+    for orientation in [GramOrientation::IdsByIds, GramOrientation::SnpsBySnps] {
+      let options = KinshipOptions {
+        batch_size: 2,
+        orientation,
+        transform: DosageTransform::Center,
+        ..KinshipOptions::default()
+      };
+      let (labels, matrix) = geno_parser.calc_gram_matrix(&options).unwrap();
+      let (expected_labels, expected) = expected_parser.calc_gram_matrix(&options).unwrap();
+      assert_eq!(expected_labels, labels);
+      let dim = labels.len();
+      rqtl2::testing::assert_matrix_close(&expected, &matrix, dim, Default::default());
+    }
+    let options = KinshipOptions::default();
+    let matrix = geno_parser.calc_kinship_matrix(&options).unwrap();
+    assert_eq!(expected_parser.calc_kinship_with_options(&options).unwrap(), matrix.into_vec());
+    let err = geno_parser.kinship_summary(&options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+
+    geno_parser.set_transposed(false).unwrap();
+    assert_eq!(vec!["rs1", "rs2", "rs3", "rs4"], *geno_parser.get_markers());
+  }
+
+  #[test]
+  fn gemma_kinship_kinds() {
+    use rqtl2::kinship::{DosageTransform, KinshipKind, KinshipOptions, MissingPolicy, ParallelMode};
+    let geno = "marker\t10\t12\t14\t16\nrs1\tABHA\nrs2\tBB-A\nrs3\tAHBB\nrs4\tHBAH\nrs5\tBHAA\n";
+    let f =
+      create_test_file("test_geno_parsers_60.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', f64::NAN);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    // GEMMA: {0, 1, 2} dosages, missing ones replaced by the marker mean.
+    let dosages: [[f64; 4]; 5] = [
+      [0.0, 2.0, 1.0, 0.0],
+      [2.0, 2.0, 4.0 / 3.0, 0.0],
+      [0.0, 1.0, 2.0, 2.0],
+      [1.0, 2.0, 0.0, 1.0],
+      [2.0, 1.0, 0.0, 0.0],
+    ];
+    let gemma = |standardize: bool| {
+      let mut res = vec![0.0; 16];
+      for snp in &dosages {
+        let mean = snp.iter().sum::<f64>() / 4.0;
+        let var = snp.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / 4.0;
+        let sd = if standardize { var.sqrt() } else { 1.0 };
+        for i in 0..4 {
+          for j in 0..4 {
+            res[i * 4 + j] += (snp[i] - mean) * (snp[j] - mean) / (sd * sd) / 5.0;
+          }
+        }
+      }
+      res
+    };
+
+    for (kind, standardize) in [(KinshipKind::Centered, false), (KinshipKind::Standardized, true)] {
+      for mode in [ParallelMode::Batches, ParallelMode::ColumnBlocks] {
+        let options = KinshipOptions {
+          batch_size: 2,
+          mode,
+          fast_path_work: 0,
+          transform: DosageTransform::rescale((0.0, 1.0), (0.0, 2.0)),
+          kind,
+          missing: MissingPolicy::MeanImpute,
+          ..KinshipOptions::default()
+        };
+        let kinship = geno_parser.calc_kinship_with_options(&options).unwrap();
+        rqtl2::testing::assert_matrix_close(&gemma(standardize), &kinship, 4, Default::default());
+      }
+    }
+
+    let mut snps = vec![0.0, 1.0, 1.0, 1.0];
+    KinshipKind::Standardized.apply(&mut snps, 2);
+    assert_eq!(vec![-1.0, 1.0, 0.0, 0.0], snps);
+
+    // Centering is requested once, by the kind.
+    let options = KinshipOptions {
+      transform: DosageTransform::Center,
+      kind: KinshipKind::Centered,
+      missing: MissingPolicy::MeanImpute,
+      ..KinshipOptions::default()
+    };
+    let err = geno_parser.calc_kinship_with_options(&options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+  }
+
+  #[test]
+  fn snp_batches() {
+    let geno = "#test file\nmarker\t10\t12\nrs1\tAB\nrs2\tB-\nrs3\tAH\nrs4\tHB\nrs5\tBA\n";
+    let f =
+      create_test_file("test_geno_parsers_61.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', f64::NAN);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let mut batches = geno_parser.batches(2).unwrap();
+    assert_eq!(2, batches.ids_num());
+    let mut markers = Vec::new();
+    let mut sums = Vec::new();
+    while let Some(batch) = batches.next_batch() {
+      let batch = batch.unwrap();
+      assert_eq!(batch.rows * 2, batch.data.len());
+      assert_eq!(batch.rows, batch.markers.len());
+      sums.push(batch.data.iter().filter(|value| !value.is_nan()).sum::<f64>());
+      markers.extend(batch.markers);
+    }
+    assert_eq!(vec!["rs1", "rs2", "rs3", "rs4", "rs5"], markers);
+    assert_eq!(vec![2.0, 2.0, 1.0], sums);
+    assert!(batches.next_batch().is_none());
+  }
+
+  #[test]
+  fn maf_filter() {
+    use rqtl2::kinship::{KinshipOptions, ParallelMode};
+    use rqtl2::stats::MafFilter;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    // MAFs: 1/2, 1/6, 1/2, 0, 1/3, 1/2.
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tAAH\nrs3\tBHA\nrs4\tBBB\nrs5\tABA\nrs6\tAHB\n";
+    let common = "marker\t10\t12\t14\nrs1\tABH\nrs3\tBHA\nrs5\tABA\nrs6\tAHB\n";
+    let f =
+      create_test_file("test_geno_parsers_62.txt", geno).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let f =
+      create_test_file("test_geno_parsers_63.txt", common).expect("Failed to create test file.");
+    let mut common_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let filter = MafFilter::new(0.2, &hab_mapper);
+    assert_eq!(0.125, filter.maf(&[0.0, 0.0, 0.0, 0.5]));
+    assert!(filter.maf(&[f64::NAN, f64::NAN]).is_nan());
+
+    let expected = common_parser.calc_kinship(2).unwrap();
+    for mode in [ParallelMode::Batches, ParallelMode::ColumnBlocks] {
+      let options = KinshipOptions {
+        batch_size: 2,
+        mode,
+        fast_path_work: 0,
+        maf_filter: Some(filter),
+        ..KinshipOptions::default()
+      };
+      let (kinship, summary) = geno_parser.calc_kinship_summarized(&options).unwrap();
+      rqtl2::testing::assert_matrix_close(&expected, &kinship, 3, Default::default());
+      assert_eq!((6, 4), (summary.markers_read, summary.markers_used));
+    }
+  }
+
+  #[test]
+  fn blank_snp_lines() {
+    use rqtl2::kinship::{GramOrientation, KinshipOptions};
+    use rqtl2::util::parse_report::codes;
+    let clean = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\n";
+    let blank = "marker\t10\t12\t14\n\nrs1\tABH\n\r\nrs2\tBBA\n  \t \nrs3\tAHB\n\nrs4\tHBA\n\n  ";
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let f =
+      create_test_file("test_geno_parsers_64.txt", clean).expect("Failed to create test file.");
+    let mut clean_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let f =
+      create_test_file("test_geno_parsers_65.txt", blank).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+
+    assert_eq!(clean_parser.read_all().unwrap(), geno_parser.read_all().unwrap());
+    assert!(geno_parser.get_report().is_empty());
+    let lines = geno_parser.iter().unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+    assert_eq!(clean_parser.read_all().unwrap(), lines);
+    assert!(geno_parser.get_report().has(codes::BLANK_LINES));
+    assert_eq!(1, geno_parser.get_warnings().len());
+
+    for orientation in [GramOrientation::IdsByIds, GramOrientation::SnpsBySnps] {
+      let options = KinshipOptions {
+        batch_size: 2,
+        orientation,
+        fast_path_work: 0,
+        ..KinshipOptions::default()
+      };
+      let expected = clean_parser.calc_kinship_with_options(&options).unwrap();
+      assert_eq!(expected, geno_parser.calc_kinship_with_options(&options).unwrap());
+    }
+    assert_eq!(1, geno_parser.get_warnings().len());
+    let mut lines = std::io::BufReader::new(blank.as_bytes()).lines();
+    lines.next();
+    let kinship =
+      rqtl2::util::calc_kinship_parallel(&mut lines, 3, &hab_mapper, &KinshipOptions::default());
+    assert_eq!(clean_parser.calc_kinship(2).unwrap(), kinship.unwrap());
+
+    let f = create_test_file("test_geno_parsers_66.txt", "marker\t10\n\tA\n")
+      .expect("Failed to create test file.");
+    let mut geno_parser =
+      rqtl2::util::GenoParser::new_with_file(f, hab_mapper).expect("Failed to create GenoParser");
+    assert!(geno_parser.calc_kinship(1).is_err());
+  }
+
+  #[test]
+  fn kernel_selection() {
+    use rqtl2::kinship::{available_kernels, kernel_by_name, KinshipOptions};
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    let f =
+      create_test_file("test_geno_parsers_67.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser.calc_kinship(2).unwrap();
+    let kernels = available_kernels();
+    assert!(kernels.contains(&"cpu"));
+    assert_eq!(cfg!(feature = "blas"), kernels.contains(&"blas"));
+    // The CUDA libraries are loaded at run time, without them the GPU
+    // batches are recalculated on the CPU.
+    assert_eq!(cfg!(feature = "cuda"), kernels.contains(&"gpu"));
+    for name in kernels {
+      let kernel = kernel_by_name(name).unwrap();
+      assert_eq!(name, kernel.name());
+      let options = KinshipOptions {
+        batch_size: 2,
+        kernel: Some(kernel),
+        ..KinshipOptions::default()
+      };
+      let (kinship, summary) = geno_parser.calc_kinship_summarized(&options).unwrap();
+      rqtl2::testing::assert_matrix_close(&expected, &kinship, 3, Default::default());
+      assert_eq!(name, summary.backend);
+    }
+    assert!(kernel_by_name("cublas").is_none());
+  }
+
+  #[test]
+  fn no_row_id_column() {
+    use rqtl2::util::dataset::{Analysis, AnalysisResult, Dataset};
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\n\nrs3\tAHB\nrs4\tHBA\n";
+    let f =
+      create_test_file("test_geno_parsers_68.txt", geno).expect("Failed to create test file.");
+    let mut standard = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    assert!(standard.has_row_ids());
+    let expected = standard.calc_kinship(2).unwrap();
+
+    let ids = vec![String::from("10"), String::from("12"), String::from("14")];
+    for (name, geno) in [
+      ("test_geno_parsers_69.txt", "marker\t10\t12\t14\nABH\nBBA\n\nAHB\nHBA\n"),
+      ("test_geno_parsers_70.txt", "# no corner\n10\t12\t14\nABH\nBBA\n\nAHB\nHBA\n"),
+    ] {
+      let f = create_test_file(name, geno).expect("Failed to create test file.");
+      let mut geno_parser =
+        rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+      assert!(!geno_parser.has_row_ids());
+      assert_eq!(&ids, geno_parser.get_markers());
+      let kinship = geno_parser.calc_kinship(3).unwrap();
+      rqtl2::testing::assert_matrix_close(&expected, &kinship, 3, Default::default());
+
+      let rows = geno_parser.read_all().unwrap();
+      let row_ids = rows.iter().map(|(id, _)| id.as_str()).collect::<Vec<&str>>();
+      assert_eq!(vec!["1", "2", "3", "4"], row_ids);
+      assert_eq!(vec![1.0, 1.0, 0.0], rows[1].1);
+      let iterated = geno_parser.iter().unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+      assert_eq!(rows, iterated);
+
+      let mut batches = geno_parser.batches(3).unwrap();
+      let batch = batches.next_batch().unwrap().unwrap();
+      assert_eq!(vec!["1", "2", "3"], batch.markers);
+      assert!(geno_parser.build_index().is_err());
+
+      let kinship_analysis = Analysis::Kinship(rqtl2::util::KinshipOptions::default());
+      match &Dataset::new(geno_parser).compute(&[kinship_analysis]).unwrap()[0] {
+        AnalysisResult::Kinship(kinship) => {
+          rqtl2::testing::assert_matrix_close(&expected, kinship, 3, Default::default())
+        }
+        _ => panic!("Kinship expected"),
+      }
+    }
+
+    let f = create_test_file("test_geno_parsers_69.txt", "marker\t10\t12\t14\nABH\nBBA\n")
+      .expect("Failed to create test file.");
+    let mut geno_parser =
+      rqtl2::util::GenoParser::new_with_row_ids(f, hab_mapper, Some(true)).unwrap();
+    assert!(geno_parser.calc_kinship(2).is_err());
+  }
+
+  #[test]
+  fn tiled_kinship_kernel() {
+    use rqtl2::kinship::{
+      calc_partial_kinship, calc_partial_kinship_threaded, calc_partial_kinship_tiled,
+    };
+    // Larger than a tile in both dimensions, with partial tiles left over.
+    for (ids_num, rows) in [(1, 3), (7, 5), (300, 70), (513, 130)] {
+      let snps = (0..rows * ids_num)
+        .map(|pos| ((pos * 7 + pos / 11) % 3) as f64 / 2.0)
+        .collect::<Vec<f64>>();
+      let mut expected = vec![0.0; ids_num * ids_num];
+      calc_partial_kinship(&snps, &mut expected, ids_num);
+      let mut tiled = vec![0.0; ids_num * ids_num];
+      calc_partial_kinship_tiled(&snps, &mut tiled, ids_num);
+      rqtl2::testing::assert_matrix_close(&expected, &tiled, ids_num, Default::default());
+      let mut threaded = vec![0.0; ids_num * ids_num];
+      calc_partial_kinship_threaded(&snps, &mut threaded, ids_num, 3);
+      rqtl2::testing::assert_matrix_close(&expected, &threaded, ids_num, Default::default());
+    }
+  }
+
+  #[test]
+  fn time_limited_kinship() {
+    use rqtl2::kinship::{KinshipCheckpoint, KinshipOptions};
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    let f =
+      create_test_file("test_geno_parsers_71.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser.calc_kinship(2).unwrap();
+
+    let unlimited = KinshipOptions {
+      batch_size: 2,
+      threads: Some(1),
+      fast_path_work: 0,
+      ..KinshipOptions::default()
+    };
+    let res = geno_parser.calc_kinship_timed(&unlimited, None).unwrap();
+    assert!(res.complete);
+    assert_eq!(5, res.checkpoint.lines_read);
+    rqtl2::testing::assert_matrix_close(&expected, &res.matrix, 3, Default::default());
+
+    // A spent budget still lets a batch through.
+    let limited = KinshipOptions {
+      time_limit: Some(std::time::Duration::ZERO),
+      ..unlimited
+    };
+    let mut res = geno_parser.calc_kinship_timed(&limited, None).unwrap();
+    assert!(!res.complete);
+    assert_eq!((2, 2), (res.checkpoint.lines_read, res.checkpoint.snps));
+    assert_eq!(1.0, res.matrix[4]);
+    let mut runs = 1;
+    while !res.complete {
+      let mut saved = Vec::new();
+      res.checkpoint.write_to(&mut saved).unwrap();
+      let checkpoint = KinshipCheckpoint::read_from(&mut saved.as_slice()).unwrap();
+      assert_eq!(res.checkpoint, checkpoint);
+      res = geno_parser.calc_kinship_timed(&limited, Some(checkpoint)).unwrap();
+      runs += 1;
+    }
+    assert_eq!(3, runs);
+    rqtl2::testing::assert_matrix_close(&expected, &res.matrix, 3, Default::default());
+    assert!(KinshipCheckpoint::read_from(&mut &b"marker\t10\t12"[..]).is_err());
+    // Header of a huge matrix followed by no values.
+    for ids_num in [1u64 << 33, 1 << 20] {
+      let mut corrupt = b"RQ2KCKP2".to_vec();
+      for word in [ids_num, 0, 0, u64::MAX, 0] {
+        corrupt.extend(word.to_le_bytes());
+      }
+      let err = KinshipCheckpoint::read_from(&mut corrupt.as_slice()).unwrap_err();
+      let kind = rqtl2::util::error::Error::from(err).kind();
+      assert!(["validation", "out_of_memory", "io"].contains(&kind));
+    }
+  }
+
+  #[test]
+  fn bundled_example_data() {
+    use rqtl2::io::ControlFile;
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/data/f2.yaml");
+    let control = ControlFile::read(path).unwrap();
+    let mut geno = control.geno_parser().unwrap();
+    assert!(geno.get_warnings().is_empty());
+    let ids = geno.get_markers().clone();
+    let pheno = control.pheno_parser().unwrap().read_matrix().unwrap();
+    assert_eq!(ids, pheno.ids);
+    let covar = control.table("covar").unwrap();
+    assert!(ids.iter().all(|id| covar.row_index(id).is_some()));
+    let gmap = control.gmap().unwrap();
+    let lines = geno.read_all().unwrap();
+    assert!(lines.iter().all(|(marker, _)| gmap.chromosome(marker).is_some()));
+    let loco = geno.calc_kinship_loco(&gmap.chromosomes(), &Default::default()).unwrap();
+    assert_eq!(3, loco.len());
+  }
+
+  #[test]
+  fn single_precision_kinship() {
+    use rqtl2::kinship::{CpuKernel, DosageTransform, KinshipOptions, ParallelMode};
+    use rqtl2::testing::{assert_matrix_close, Tolerance};
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    let f =
+      create_test_file("test_geno_parsers_72.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    for transform in [DosageTransform::Identity, DosageTransform::Standardize] {
+      let options = KinshipOptions {
+        batch_size: 2,
+        threads: Some(2),
+        transform,
+        mode: ParallelMode::ColumnBlocks,
+        ..KinshipOptions::default()
+      };
+      let expected = geno_parser.calc_kinship_with_options(&options).unwrap();
+      let double = geno_parser.calc_kinship_typed::<f64>(&options).unwrap();
+      assert_matrix_close(&expected, &double, 3, Tolerance::absolute(1e-12));
+      let single = geno_parser.calc_kinship_typed::<f32>(&options).unwrap();
+      let single = single.iter().map(|value| *value as f64).collect::<Vec<f64>>();
+      assert_matrix_close(&expected, &single, 3, Tolerance::absolute(1e-6));
+    }
+    let options = KinshipOptions::default();
+    let saved =
+      options.required_memory_typed::<f64>(1000) - options.required_memory_typed::<f32>(1000);
+    assert!(saved >= 1000 * 1000 * 4);
+    // Options the calculation can't follow are rejected.
+    let kernel = KinshipOptions {
+      mode: ParallelMode::ColumnBlocks,
+      kernel: Some(std::sync::Arc::new(CpuKernel)),
+      ..KinshipOptions::default()
+    };
+    for options in [KinshipOptions::default(), kernel] {
+      let err = geno_parser.calc_kinship_typed::<f32>(&options).unwrap_err();
+      assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+    }
+
+    // Precision at a realistic amount of markers.
+    use rqtl2::simulate::{simulate_geno_file, SimulationOptions};
+    let simulation = SimulationOptions {
+      ids_num: 50,
+      markers_num: 50_000,
+      ..SimulationOptions::default()
+    };
+    let options = KinshipOptions {
+      mode: ParallelMode::ColumnBlocks,
+      ..KinshipOptions::default()
+    };
+    let mut path = env::temp_dir();
+    path.push("test_geno_parsers_105.txt");
+    let expected = simulate_geno_file(&path, &simulation, &options).unwrap();
+    let f = fs::File::open(&path).unwrap();
+    let mut geno_parser =
+      rqtl2::util::GenoParser::new_with_file(f, simulation.hab_mapper()).unwrap();
+    let single = geno_parser.calc_kinship_typed::<f32>(&options).unwrap();
+    let single = single.iter().map(|value| *value as f64).collect::<Vec<f64>>();
+    assert_matrix_close(expected.as_slice(), &single, 50, Tolerance::absolute(1e-7));
+  }
+
+  #[test]
+  fn kinship_progress_hook() {
+    use rqtl2::kinship::{KinshipOptions, Progress, ProgressHook};
+    use std::sync::{Arc, Mutex};
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    let f =
+      create_test_file("test_geno_parsers_73.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser.calc_kinship(2).unwrap();
+
+    let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+    let sink = reports.clone();
+    let options = KinshipOptions {
+      batch_size: 2,
+      threads: Some(2),
+      fast_path_work: 0,
+      progress: Some(ProgressHook::new(move |progress| sink.lock().unwrap().push(*progress))),
+      ..KinshipOptions::default()
+    };
+    let res = geno_parser.calc_kinship_with_options(&options).unwrap();
+    rqtl2::testing::assert_matrix_close(&expected, &res, 3, Default::default());
+    let reports = reports.lock().unwrap().clone();
+    let markers = reports.iter().map(|progress| progress.markers).collect::<Vec<usize>>();
+    assert_eq!(vec![2, 4, 5, 5], markers);
+    assert_eq!(3, reports[2].batches);
+    // Estimated from the file size, then exact.
+    assert_eq!(Some(5), reports[0].estimated_total);
+    assert_eq!(Some(0.4), reports[0].fraction());
+    assert!(reports.iter().take(3).all(|progress| !progress.finished));
+    assert!(reports[3].finished);
+    assert_eq!(Some(1.0), reports[3].fraction());
+
+    // The estimate of the caller is kept.
+    let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+    let sink = reports.clone();
+    let hook = ProgressHook::new(move |progress| sink.lock().unwrap().push(*progress));
+    let options = KinshipOptions {
+      progress: Some(hook.with_estimated_total(10)),
+      ..options
+    };
+    geno_parser.calc_kinship_with_options(&options).unwrap();
+    assert_eq!(Some(10), reports.lock().unwrap()[0].estimated_total);
+  }
+
+  #[test]
+  fn cancellable_kinship() {
+    use rqtl2::error::Error;
+    use rqtl2::kinship::{CancellationHandle, KinshipOptions, ParallelMode, ProgressHook};
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    let f =
+      create_test_file("test_geno_parsers_74.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser.calc_kinship(2).unwrap();
+
+    for mode in [ParallelMode::Batches, ParallelMode::ColumnBlocks] {
+      let cancel = CancellationHandle::new();
+      let options = KinshipOptions {
+        batch_size: 2,
+        threads: Some(2),
+        fast_path_work: 0,
+        mode,
+        cancel: Some(cancel.clone()),
+        ..KinshipOptions::default()
+      };
+      let res = geno_parser.calc_kinship_with_options(&options).unwrap();
+      rqtl2::testing::assert_matrix_close(&expected, &res, 3, Default::default());
+
+      // Cancelled in the middle, e.g. by another thread.
+      let handle = cancel.clone();
+      let mid_run = KinshipOptions {
+        progress: Some(ProgressHook::new(move |progress| {
+          if progress.markers >= 2 {
+            handle.cancel();
+          }
+        })),
+        ..options.clone()
+      };
+      let e = Error::from(geno_parser.calc_kinship_with_options(&mid_run).unwrap_err());
+      assert_eq!(("cancelled", 9), (e.kind(), e.exit_code()));
+      assert!(cancel.is_cancelled());
+      // Cancelled before the start.
+      assert!(geno_parser.calc_kinship_with_options(&options).is_err());
+    }
+    // The parser stays usable.
+    let res = geno_parser.calc_kinship_with_options(&KinshipOptions::default()).unwrap();
+    rqtl2::testing::assert_matrix_close(&expected, &res, 3, Default::default());
+  }
+
+  #[test]
+  fn kinship_command_line_tool() {
+    use rqtl2::io::ControlFile;
+    use rqtl2::kinship::KinshipMatrix;
+    use std::process::Command;
+    let run = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_rqtl2-kinship")).args(args).output();
+    let control = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/data/f2.yaml");
+    let expected = ControlFile::read(&control)
+      .unwrap()
+      .geno_parser()
+      .unwrap()
+      .calc_kinship_matrix(&Default::default())
+      .unwrap();
+
+    let out_dir = env::temp_dir().join("rqtl2_kinship_cli");
+    fs::create_dir_all(&out_dir).unwrap();
+    let out = out_dir.join("kinship.csv");
+    let args = [control.to_str().unwrap(), "--format", "csv", "-o", out.to_str().unwrap()];
+    assert!(run(&args).unwrap().status.success());
+    let matrix = KinshipMatrix::read_csv(&out).unwrap();
+    assert_eq!(expected.ids(), matrix.ids());
+    rqtl2::testing::assert_matrix_close(
+      expected.as_slice(),
+      matrix.as_slice(),
+      expected.ids_num(),
+      Default::default(),
+    );
+
+    // GEMMA format to standard output, a plain geno file with its codes.
+    let geno = control.with_file_name("geno.txt");
+    let output = run(&[geno.to_str().unwrap(), "--codes=A=0,H=0.5,B=1", "-b", "7"]).unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(expected.ids_num(), stdout.lines().count());
+    let first = stdout.lines().next().unwrap().split('\t').collect::<Vec<&str>>();
+    assert_eq!(expected.row(rqtl2::prelude::IndividualIdx(0))[1], first[1].parse().unwrap());
+
+    let loco_dir = out_dir.join("loco");
+    let loco_out = loco_dir.to_str().unwrap();
+    let args = [control.to_str().unwrap(), "--loco", "--progress", "-o", loco_out];
+    let output = run(&args).unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains(" markers"));
+    for chr in ["1", "2", "3"] {
+      assert!(loco_dir.join(format!("kinship_loco_{}.txt", chr)).exists());
+    }
+
+    // Usage errors exit with 2, failures with the code of their class.
+    assert_eq!(Some(2), run(&["--bogus"]).unwrap().status.code());
+    assert_eq!(Some(2), run(&[]).unwrap().status.code());
+    let output = run(&[control.to_str().unwrap(), "--gpu", "--error-format", "json"]).unwrap();
+    assert_eq!(Some(5), output.status.code());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("\"gpu_unavailable\""));
+    assert_eq!(Some(7), run(&["absent_geno.txt"]).unwrap().status.code());
+
+    // Environment report with the options recommended for an amount of
+    // individuals or the ones of the input.
+    let output = run(&["info"]).unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("CPU cores:"));
+    assert!(stdout.contains("Recommended options for 1000 individuals:"));
+    let stdout = String::from_utf8(run(&["info", "25"]).unwrap().stdout).unwrap();
+    assert!(stdout.contains("Recommended options for 25 individuals:"));
+    let stdout = String::from_utf8(run(&["info", control.to_str().unwrap()]).unwrap().stdout);
+    let recommended = format!("Recommended options for {} individuals:", expected.ids_num());
+    assert!(stdout.unwrap().contains(&recommended));
+    assert_eq!(Some(2), run(&["info", "25", "50"]).unwrap().status.code());
+    assert_eq!(Some(7), run(&["info", "absent_geno.txt"]).unwrap().status.code());
+  }
+
+  #[test]
+  fn generic_readers() {
+    use rqtl2::io::GenoStream;
+    use rqtl2::kinship::{GramOrientation, KinshipOptions};
+    use rqtl2::util::GenoParser;
+    use std::io::Cursor;
+    let geno = "#comment\nmarker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\n";
+    let f =
+      create_test_file("test_geno_parsers_75.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut from_file = GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    let expected = from_file.calc_kinship(2).unwrap();
+
+    // In-memory buffer, seekable.
+    let cursor = Cursor::new(geno.as_bytes().to_vec());
+    let mut from_cursor = GenoParser::from_reader(cursor, hab_mapper.clone()).unwrap();
+    assert_eq!(&vec![String::from("comment")], from_cursor.get_comments());
+    assert_eq!(from_file.get_markers(), from_cursor.get_markers());
+    assert_eq!(expected, from_cursor.calc_kinship(2).unwrap());
+    assert_eq!(from_file.read_all().unwrap(), from_cursor.read_all().unwrap());
+    assert_eq!(4, from_cursor.iter().unwrap().count());
+
+    // A byte slice can't seek, as stdin.
+    let stream = GenoStream::new(geno.as_bytes(), hab_mapper.clone()).unwrap();
+    assert_eq!(&vec![String::from("comment")], stream.get_comments());
+    assert_eq!(from_file.get_markers(), stream.get_markers());
+    let options = KinshipOptions {
+      batch_size: 3,
+      ..KinshipOptions::default()
+    };
+    let matrix = stream.calc_kinship_matrix(&options).unwrap();
+    assert_eq!(from_file.get_markers().as_slice(), matrix.ids());
+    rqtl2::testing::assert_matrix_close(&expected, matrix.as_slice(), 3, Default::default());
+    let options = KinshipOptions {
+      orientation: GramOrientation::SnpsBySnps,
+      ..options
+    };
+    let stream = GenoStream::new(geno.as_bytes(), hab_mapper.clone()).unwrap();
+    assert_eq!(
+      from_file.calc_kinship_with_options(&options).unwrap(),
+      stream.calc_kinship_with_options(&options).unwrap()
+    );
+    let mut reader = "#a\n#b\nmarker".as_bytes();
+    assert_eq!(vec!["a", "b"], rqtl2::reader::consume_comments(&mut reader).unwrap());
+    assert_eq!(b"marker", reader);
+    assert!(GenoStream::new("#only comments\n".as_bytes(), hab_mapper).is_err());
+  }
+
+  #[test]
+  fn validate_geno_file() {
+    use rqtl2::io::{codes, Severity};
+    let geno = "#comment\nmarker\t10\t12\t10\nrs1\tABH\nrs2\tAB\nrs1\tBBA\n\nrs3\tAXZ\nrs4 ABH\n";
+    let f =
+      create_test_file("test_geno_parsers_76.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let report = geno_parser.validate().unwrap();
+    let found = report
+      .diagnostics()
+      .iter()
+      .map(|diagnostic| (diagnostic.code, diagnostic.line, diagnostic.severity))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      vec![
+        (codes::DUPLICATE_ID, Some(2), Severity::Warning),
+        (codes::ROW_LENGTH, Some(4), Severity::Error),
+        (codes::DUPLICATE_MARKER, Some(5), Severity::Warning),
+        (codes::UNKNOWN_CODE, Some(7), Severity::Error),
+        (codes::MALFORMED_LINE, Some(8), Severity::Error),
+        (codes::BLANK_LINES, None, Severity::Warning),
+      ],
+      found
+    );
+    assert!(report.diagnostics()[2].message.contains("first on line 3"));
+    assert!(report.diagnostics()[3].message.contains("<X> for individual 2 (2 unknown"));
+    // The cursor is rewound.
+    assert_eq!(report, geno_parser.validate().unwrap());
+
+    // Diagnostics of a kind are capped.
+    let mut geno = String::from("marker\t1\t2\n");
+    for line in 0..150 {
+      geno.push_str(&format!("rs{}\tAQ\n", line));
+    }
+    let f =
+      create_test_file("test_geno_parsers_77.txt", &geno).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let report = geno_parser.validate().unwrap();
+    assert_eq!(rqtl2::util::validate::MAX_DIAGNOSTICS_PER_CODE + 1, report.count(Severity::Error));
+    assert!(report.diagnostics().last().unwrap().message.starts_with("50 more lines"));
+  }
+
+  #[test]
+  fn typed_parse_errors() {
+    use rqtl2::util::error::Error;
+    let f = create_test_file(
+      "test_geno_parsers_78.txt",
+      "#test file\nmarker\t10\t12\t14\nrs1\tABA\nrs2\tAXB\nrs3\tAB\n",
+    )
+    .expect("Failed to create test file.");
+    let hab_mapper = HashMap::from([('A', 0.0), ('B', 1.0)]);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .expect("Failed to create GenoParser");
+    let err = geno_parser.calc_kinship(1).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+    match Error::from(err) {
+      Error::UnknownGenotypeCode {
+        marker,
+        column,
+        code,
+      } => assert_eq!(("rs2", 2, 'X'), (marker.as_str(), column, code)),
+      e => panic!("unexpected error {:?}", e),
+    }
+
+    let contents = "marker\t10\t12\t14\nrs1\tABA\nrs3\tAB\n";
+    let mut reader = std::io::Cursor::new(contents);
+    let stream = rqtl2::io::GenoStream::new(&mut reader, hab_mapper).unwrap();
+    let e = Error::from(stream.calc_kinship_with_options(&Default::default()).unwrap_err());
+    assert_eq!(("parse", 3), (e.kind(), e.exit_code()));
+    match e {
+      Error::DimensionMismatch {
+        ref marker,
+        expected,
+        found,
+      } => assert_eq!(("rs3", 3, 2), (marker.as_str(), expected, found)),
+      ref e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!("marker <rs3>: there are 3 ids, however 2 genotypes were parsed", e.to_string());
+    assert!(e.to_json().contains(r#""marker":"rs3","expected":3,"found":2"#));
+  }
+
+  #[test]
+  fn iterator_yields_parse_errors() {
+    use rqtl2::util::error::Error;
+    let f = create_test_file(
+      "test_geno_parsers_79.txt",
+      "marker\t10\t12\nrs1\tAB\nrs2\tAX\nrs3 BA\n\nrs4\tBB\n",
+    )
+    .expect("Failed to create test file.");
+    let hab_mapper = HashMap::from([('A', 0.0), ('B', 1.0)]);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let records = geno_parser.iter().unwrap().collect::<Vec<_>>();
+    assert_eq!(4, records.len());
+    let mut records = records.into_iter();
+    assert_eq!((String::from("rs1"), vec![0.0, 1.0]), records.next().unwrap().unwrap());
+    match Error::from(records.next().unwrap().unwrap_err()) {
+      Error::UnknownGenotypeCode { marker, column, code } => {
+        assert_eq!(("rs2", 2, 'X'), (marker.as_str(), column, code))
+      }
+      e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!("parse", Error::from(records.next().unwrap().unwrap_err()).kind());
+    assert_eq!("rs4", records.next().unwrap().unwrap().0);
+    // Blank lines are still counted once the iteration finishes.
+    assert!(geno_parser.get_report().has(rqtl2::io::codes::BLANK_LINES));
+  }
+
+  #[test]
+  fn founder_geno_file() {
+    use rqtl2::io::ControlFile;
+    use rqtl2::util::founders::FounderGenotypes;
+    let mut dir = env::temp_dir();
+    dir.push("rqtl2_founder_geno_test");
+    fs::create_dir_all(&dir).unwrap();
+    let yaml = "crosstype: do\n\
+                founder_geno: founder_geno.txt\n\
+                genotypes:\n  A: 1\n  H: 2\n  B: 3\n\
+                na.strings: ['-']\n";
+    fs::write(dir.join("do.yaml"), yaml).unwrap();
+    let contents = "marker\tAJ\tB6\tCAST\nrs1\tAAB\nrs2\tB-A\n";
+    fs::write(dir.join("founder_geno.txt"), contents).unwrap();
+    let founders = ControlFile::parse(yaml, &dir, false).unwrap().founder_genotypes().unwrap();
+    assert_eq!(["AJ", "B6", "CAST"], founders.founders());
+    assert_eq!(["rs1", "rs2"], founders.markers());
+    let rs2 = founders.marker("rs2").unwrap();
+    assert_eq!((1.0, 0.0), (rs2[0], rs2[2]));
+    assert!(rs2[1].is_nan());
+    let dosages = founders.dosage_matrix();
+    assert_eq!(founders.markers(), &dosages.row_ids[..]);
+    assert_eq!(founders.founders(), &dosages.col_ids[..]);
+    assert_eq!(0.0, dosages.get(0, 0));
+    assert_eq!(1.0, dosages.get(0, 2));
+    assert!(dosages.get(1, 1).is_nan());
+
+    // Transposed file: founders as rows.
+    let transposed = "founder_geno_transposed: true\n";
+    let contents = "id\trs1\trs2\nAJ\tAB\nB6\tA-\nCAST\tBA\n";
+    fs::write(dir.join("founder_geno.txt"), contents).unwrap();
+    let control = ControlFile::parse(&format!("{}{}", yaml, transposed), &dir, false).unwrap();
+    let transposed = control.founder_genotypes().unwrap();
+    assert_eq!(founders.founders(), transposed.founders());
+    assert_eq!(founders.markers(), transposed.markers());
+    let bits = |f: &FounderGenotypes| {
+      f.dosage_matrix().values.iter().map(|value| value.to_bits()).collect::<Vec<_>>()
+    };
+    assert_eq!(bits(&founders), bits(&transposed));
+
+    let f = create_test_file("test_geno_parsers_80.txt", "marker\tAJ\tB6\nrs1\tAB\nrs2\tA\n")
+      .expect("Failed to create test file.");
+    let hab_mapper = HashMap::from([('A', 0.0), ('B', 1.0)]);
+    let mut parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    assert!(FounderGenotypes::from_geno_parser(&mut parser).is_err());
+  }
+
+  #[test]
+  fn align_individuals_across_files() {
+    use rqtl2::io::{align_individuals, codes, PhenoParser};
+    use rqtl2::prelude::KinshipMatrix;
+    let f = create_test_file(
+      "test_geno_parsers_81.txt",
+      "marker\t10\t12\t14\t16\nrs1\tABAB\nrs2\tAABB\nrs3\tBBAA\nrs4\tABBA\n",
+    )
+    .expect("Failed to create test file.");
+    let hab_mapper = HashMap::from([('A', 0.0), ('B', 1.0)]);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let pheno = "id,weight\n16,3.5\n18,4\n10,2.5\n12,3\n";
+    let pheno = PhenoParser::from_reader(pheno.as_bytes()).unwrap().read_matrix().unwrap();
+    let covar = ["12", "10", "16", "20"].map(String::from);
+    let geno_ids = geno_parser.get_markers().clone();
+
+    let alignment = align_individuals(&[&geno_ids, &pheno.ids, &covar]).unwrap();
+    assert_eq!(["10", "12", "16"], &alignment.ids[..]);
+    let indices = alignment
+      .indices
+      .iter()
+      .map(|idx| idx.iter().map(|i| i.index()).collect::<Vec<_>>())
+      .collect::<Vec<_>>();
+    assert_eq!(vec![vec![0, 1, 3], vec![2, 3, 0], vec![1, 0, 2]], indices);
+    assert_eq!(vec![vec!["14"], vec!["18"], vec!["20"]], alignment.dropped);
+    assert!(!alignment.is_complete() && !alignment.is_identity(0));
+    assert_eq!(vec![2.5, 3.0, 3.5], alignment.select_rows(1, &pheno.values, 1));
+    let report = alignment.report(&["geno", "pheno", "covar"]);
+    assert_eq!(3, report.diagnostics().len());
+    assert!(report.has(codes::DROPPED_ID));
+
+    let kinship = geno_parser.calc_kinship_matrix(&Default::default()).unwrap();
+    let restricted = kinship.select(&alignment.indices[0]);
+    assert_eq!(&alignment.ids[..], restricted.ids());
+    let full = |a: &str, b: &str| kinship.get_by_id(a, b);
+    assert_eq!(full("16", "12"), restricted.get_by_id("16", "12"));
+    assert_eq!(full("10", "10"), restricted.get_by_id("10", "10"));
+    let same = KinshipMatrix::new(kinship.ids().to_vec(), kinship.as_slice().to_vec()).unwrap();
+    let all = align_individuals(&[kinship.ids(), same.ids()]).unwrap();
+    assert!(all.is_complete() && all.is_identity(1));
+
+    let duplicates = ["1", "2", "1"].map(String::from);
+    assert!(align_individuals(&[&duplicates, &covar]).is_err());
+  }
+
+  #[test]
+  fn kinship_of_subset() {
+    let hab_mapper = HashMap::from([('A', 0.0), ('H', 0.5), ('B', 1.0), ('-', rqtl2::io::na::NA)]);
+    let f = create_test_file(
+      "test_geno_parsers_82.txt",
+      "marker\t10\t12\t14\t16\nrs1\tAB-B\nrs2\tHAXB\nrs3\tBBAA\nrs4\tABBA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    // The same genotypes of individuals 16 and 10 only.
+    let f = create_test_file(
+      "test_geno_parsers_83.txt",
+      "marker\t16\t10\nrs1\tBA\nrs2\tBH\nrs3\tAB\nrs4\tAA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut subset_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let options = rqtl2::util::KinshipOptions::default();
+    let expected = subset_parser.calc_kinship_matrix(&options).unwrap();
+    // Individual 14 has a missing and an unknown genotype, both are skipped.
+    let subset = geno_parser.calc_kinship_subset(&["16", "10"], &options).unwrap();
+    assert_eq!(expected, subset);
+    assert!(geno_parser.calc_kinship_subset(&["16", "14"], &options).is_err());
+    let err = geno_parser.calc_kinship_subset(&["16", "18"], &options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+    let err = geno_parser.calc_kinship_subset(&["16", "16"], &options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+  }
+
+  #[test]
+  fn kinship_of_marker_subset() {
+    use rqtl2::io::MapParser;
+    use rqtl2::kinship::{MarkerSet, Metrics};
+    use std::sync::Arc;
+    let hab_mapper = HashMap::from([('A', 0.0), ('H', 0.5), ('B', 1.0)]);
+    let lines = ["rs1\tABAB", "rs2\tHAAB", "rs3\tBBAA", "rs4\tABBA", "rs5\tAAHB", "rs6\tBHAB"];
+    let f = create_test_file(
+      "test_geno_parsers_84.txt",
+      &format!("marker\t10\t12\t14\t16\n{}\n", lines.join("\n")),
+    )
+    .expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    let subset_of = |name: &str, rows: &[usize]| {
+      let selected = rows.iter().map(|row| lines[*row]).collect::<Vec<_>>();
+      let contents = format!("marker\t10\t12\t14\t16\n{}\n", selected.join("\n"));
+      let f = create_test_file(name, &contents).expect("Failed to create test file.");
+      let mut parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+      parser.calc_kinship_with_options(&Default::default()).unwrap()
+    };
+
+    let metrics = Arc::new(Metrics::new());
+    let markers = ["rs1", "rs3", "rs4", "rs6", "rs9"].map(String::from);
+    let options = rqtl2::util::KinshipOptions {
+      markers: Some(Arc::new(MarkerSet::new(std::collections::HashSet::from(markers)))),
+      metrics: Some(metrics.clone()),
+      batch_size: 2,
+      fast_path_work: 0,
+      ..Default::default()
+    };
+    let expected = subset_of("test_geno_parsers_85.txt", &[0, 2, 3, 5]);
+    assert_eq!(expected, geno_parser.calc_kinship_with_options(&options).unwrap());
+    assert_eq!((4, 2), (metrics.snps(), metrics.excluded()));
+
+    let gmap = "marker,chr,pos\nrs1,1,0.5\nrs2,1,2\nrs3,1,3.5\nrs4,1,7\nrs5,2,1\nrs6,2,2\n";
+    let gmap = MapParser::new().read_from(gmap.as_bytes()).unwrap();
+    let region = MarkerSet::region(&gmap, "1", 0.0, 7.0);
+    assert_eq!(4, region.len());
+    let options = rqtl2::util::KinshipOptions {
+      markers: Some(Arc::new(region)),
+      ..Default::default()
+    };
+    let expected = subset_of("test_geno_parsers_86.txt", &[0, 1, 2, 3]);
+    assert_eq!(expected, geno_parser.calc_kinship_with_options(&options).unwrap());
+
+    let list = MarkerSet::read_from("# pruned\nrs2\n\n rs5 \n".as_bytes()).unwrap();
+    assert!(list.contains("rs5") && !list.contains("rs1"));
+    assert_eq!(2, list.len());
+  }
+
+  #[test]
+  fn several_geno_files() {
+    use rqtl2::io::{ControlFile, GenoFiles};
+    let mut dir = env::temp_dir();
+    dir.push("rqtl2_geno_files_test");
+    fs::create_dir_all(&dir).unwrap();
+    let header = "marker\t10\t12\t14";
+    let chr1 = "rs1\tABA\nrs2\tHAB\nrs3\tBBA\n";
+    let chr2 = "rs4\tABB\nrs5\tAHB\n";
+    fs::write(dir.join("geno.txt"), format!("{}\n{}{}", header, chr1, chr2)).unwrap();
+    fs::write(dir.join("geno1.txt"), format!("#chr 1\n{}\n{}", header, chr1)).unwrap();
+    fs::write(dir.join("geno2.txt"), format!("{}\n{}", header, chr2)).unwrap();
+    let hab_mapper = HashMap::from([('A', 0.0), ('H', 0.5), ('B', 1.0)]);
+    let file = fs::File::open(dir.join("geno.txt")).unwrap();
+    let mut single = rqtl2::util::GenoParser::new_with_file(file, hab_mapper.clone()).unwrap();
+    // Batches spanning both files.
+    let options = rqtl2::util::KinshipOptions {
+      batch_size: 2,
+      fast_path_work: 0,
+      ..Default::default()
+    };
+    let expected = single.calc_kinship_matrix(&options).unwrap();
+
+    let yaml = "geno: [geno1.txt, geno2.txt]\ngenotypes:\n  A: 1\n  H: 2\n  B: 3\n";
+    let control = ControlFile::parse(yaml, &dir, false).unwrap();
+    assert_eq!(2, control.files("geno").unwrap().len());
+    let mut files = control.geno_files().unwrap();
+    assert_eq!(&["10", "12", "14"], &files.get_markers()[..]);
+    assert_eq!(expected, files.calc_kinship_matrix(&options).unwrap());
+    assert_eq!(expected, files.calc_kinship_matrix(&Default::default()).unwrap());
+    assert_eq!(3, files.parsers()[0].read_all().unwrap().len());
+    let err = control.geno_parser().err().unwrap();
+    assert!(err.to_string().contains("geno_files"));
+
+    fs::write(dir.join("geno2.txt"), format!("marker\t10\t14\t12\n{}", chr2)).unwrap();
+    let err = GenoFiles::open(&[dir.join("geno1.txt"), dir.join("geno2.txt")], hab_mapper);
+    assert_eq!("validation", rqtl2::util::error::Error::from(err.err().unwrap()).kind());
+  }
+
+  #[test]
+  fn checkpointed_kinship() {
+    use rqtl2::kinship::{
+      CancellationHandle, CheckpointPolicy, KinshipCheckpoint, KinshipOptions, ProgressHook,
+    };
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    let f =
+      create_test_file("test_geno_parsers_87.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper)
+      .expect("Failed to create GenoParser");
+    let expected = geno_parser.calc_kinship(2).unwrap();
+
+    let policy = CheckpointPolicy {
+      path: env::temp_dir().join("rqtl2_kinship_checkpoint_test.bin"),
+      every_batches: 2,
+    };
+    let options = KinshipOptions {
+      batch_size: 1,
+      threads: Some(1),
+      fast_path_work: 0,
+      ..KinshipOptions::default()
+    };
+    let res = geno_parser.calc_kinship_checkpointed(&options, &policy).unwrap();
+    assert!(res.complete);
+    rqtl2::testing::assert_matrix_close(&expected, &res.matrix, 3, Default::default());
+    let saved = KinshipCheckpoint::load(&policy.path).unwrap();
+    assert_eq!((5, Some(geno.len() as u64)), (saved.lines_read, saved.offset));
+
+    // Interrupted while accumulating the second segment, the first one is
+    // saved.
+    let cancel = CancellationHandle::new();
+    let handle = cancel.clone();
+    let interrupted = KinshipOptions {
+      progress: Some(ProgressHook::new(move |progress| {
+        if progress.markers >= 3 {
+          handle.cancel();
+        }
+      })),
+      cancel: Some(cancel),
+      ..options.clone()
+    };
+    assert!(geno_parser.calc_kinship_checkpointed(&interrupted, &policy).is_err());
+    let saved = KinshipCheckpoint::load(&policy.path).unwrap();
+    let offset = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\n".len() as u64;
+    assert_eq!((2, Some(offset)), (saved.lines_read, saved.offset));
+    let res = geno_parser.resume_from_checkpoint(&options, &policy).unwrap();
+    assert!(res.complete);
+    assert_eq!(5, res.checkpoint.lines_read);
+    rqtl2::testing::assert_matrix_close(&expected, &res.matrix, 3, Default::default());
+    fs::remove_file(&policy.path).unwrap();
+  }
+
+  #[test]
+  fn memory_mapped_geno_file() {
+    use rqtl2::io::MappedGenoParser;
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    let f =
+      create_test_file("test_geno_parsers_88.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let expected = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone())
+      .unwrap()
+      .calc_kinship(2)
+      .unwrap();
+    let path = env::temp_dir().join("test_geno_parsers_88.txt");
+    let mut mapped = MappedGenoParser::open_mapped(&path, hab_mapper).unwrap();
+    assert_eq!(&vec!["10", "12", "14"], mapped.get_markers());
+    assert_eq!(expected, mapped.calc_kinship(2).unwrap());
+
+    let ranges = mapped.line_ranges(3);
+    assert_eq!(3, ranges.len());
+    assert_eq!(16, ranges[0].start);
+    assert_eq!(geno.len() as u64, ranges[2].end);
+    assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+    let mapped = &mapped;
+    let row_ids = std::thread::scope(|scope| {
+      let workers = ranges
+        .iter()
+        .map(|range| {
+          scope.spawn(move || {
+            mapped.with_range(range.clone(), false, |source| {
+              let (mut buf, mut row_ids) = (vec![0.0; 3 * 5], Vec::new());
+              source.read_batch(&mut buf, Some(&mut row_ids))?;
+              Ok(row_ids)
+            })
+          })
+        })
+        .collect::<Vec<_>>();
+      workers.into_iter().flat_map(|worker| worker.join().unwrap().unwrap()).collect::<Vec<_>>()
+    });
+    assert_eq!(vec!["rs1", "rs2", "rs3", "rs4", "rs5"], row_ids);
+  }
+
+  #[test]
+  fn byte_level_snp_parser() {
+    use rqtl2::io::ChunkedGenoReader;
+    use rqtl2::kinship::SnpSource;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut lines = &b"rs1\tABH\r\n\nrs2\tBBA\nrs3\tAHB"[..];
+    let mut source = ChunkedGenoReader::new(&mut lines, 3, &hab_mapper, false).unwrap();
+    let (mut buf, mut row_ids) = (vec![0.0; 3 * 4], Vec::new());
+    assert_eq!(3, source.read_batch(&mut buf, Some(&mut row_ids)).unwrap());
+    assert_eq!(vec!["rs1", "rs2", "rs3"], row_ids);
+    assert_eq!(&[0.0, 1.0, 0.5, 1.0, 1.0, 0.0, 0.0, 0.5, 1.0], &buf[..9]);
+    for line in [&b"rs1\tAB\n"[..], b"rs1\tABX\n", b"rs1 ABH\n"] {
+      let mut line = line;
+      let mut source = ChunkedGenoReader::new(&mut line, 3, &hab_mapper, false).unwrap();
+      assert!(source.read_batch(&mut buf, None).is_err());
+    }
+  }
+
+  #[test]
+  fn genotype_code_table() {
+    use rqtl2::io::CodeTable;
+    use rqtl2::kinship::SnpSource;
+    use rqtl2::util::chunked::ChunkedGenoReader;
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', rqtl2::io::na::NA);
+    hab_mapper.insert('\u{e9}', 0.5);
+    let codes = CodeTable::new(&hab_mapper);
+    assert_eq!((Some(0.0), Some(1.0)), (codes.get(b'A'), codes.get(b'B')));
+    assert!(codes.get(b'-').is_some_and(rqtl2::io::na::is_na));
+    assert_eq!((None, None), (codes.get(b'H'), codes.get(0xc3)));
+    assert_eq!((Some(0.5), None), (codes.get_char('\u{e9}'), codes.get_char('\u{e8}')));
+    assert_eq!(Some('\u{e9}'), codes.non_ascii_code());
+
+    // Non-ASCII codes work for lines parsed by char and in chunks.
+    let geno = "marker\t10\t12\nrs1\tA\u{e9}\nrs2\t-B\n";
+    let f =
+      create_test_file("test_geno_parsers_89.txt", geno).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    let lines = geno_parser.read_all().unwrap();
+    assert_eq!(vec![0.0, 0.5], lines[0].1);
+    assert!(rqtl2::io::na::is_na(lines[1].1[0]));
+    let parsed = geno_parser.iter().unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+    assert_eq!((String::from("rs1"), vec![0.0, 0.5]), parsed[0]);
+    // Lines are counted in codes, not bytes.
+    let order = vec![String::from("rs1"), String::from("rs2")];
+    let ordered = geno_parser.iter_in_order(&order, rqtl2::util::index::OrderPolicy::Verify);
+    let ordered = ordered.unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+    assert_eq!((2, &parsed[0]), (ordered.len(), &ordered[0]));
+    let geno = "marker\t10\t12\nrs1\tA\u{e9}\nrs2\t\u{e9}B\nrs3\tBA\n";
+    let f =
+      create_test_file("test_geno_parsers_107.txt", geno).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    let kinship = geno_parser.calc_kinship(2).unwrap();
+    let mut ascii_mapper = hab_mapper.clone();
+    ascii_mapper.insert('H', 0.5);
+    let geno = "marker\t10\t12\nrs1\tAH\nrs2\tHB\nrs3\tBA\n";
+    let f =
+      create_test_file("test_geno_parsers_108.txt", geno).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, ascii_mapper).unwrap();
+    assert_eq!(geno_parser.calc_kinship(2).unwrap(), kinship);
+    // A code split across buffer refills, and one which isn't mapped.
+    let mut lines = std::io::BufReader::with_capacity(6, "rs1\tA\u{e9}\u{e9}B\n".as_bytes());
+    let mut source = ChunkedGenoReader::new(&mut lines, 4, &hab_mapper, false).unwrap();
+    let mut buf = vec![0.0; 4];
+    assert_eq!(1, source.read_batch(&mut buf, None).unwrap());
+    assert_eq!(vec![0.0, 0.5, 0.5, 1.0], buf);
+    let mut lines = "rs1\tA\u{e8}\n".as_bytes();
+    let mut source = ChunkedGenoReader::new(&mut lines, 2, &hab_mapper, false).unwrap();
+    let err = rqtl2::util::error::Error::from(source.read_batch(&mut buf, None).unwrap_err());
+    assert!(matches!(err, rqtl2::util::error::Error::UnknownGenotypeCode { code: '\u{e8}', .. }));
+  }
+
+  #[test]
+  fn shared_worker_pool() {
+    use rqtl2::kinship::{KinshipOptions, WorkerPool};
+    use std::sync::Arc;
+    let geno = "marker\t10\t12\t14\nrs1\tABH\nrs2\tBBA\nrs3\tAHB\nrs4\tHBA\nrs5\tBHA\n";
+    create_test_file("test_geno_parsers_90.txt", geno).expect("Failed to create test file.");
+    let path = env::temp_dir().join("test_geno_parsers_90.txt");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let open = || {
+      rqtl2::util::GenoParser::new_with_file(fs::File::open(&path).unwrap(), hab_mapper.clone())
+        .unwrap()
+    };
+    let expected = open().calc_kinship(2).unwrap();
+
+    let options = KinshipOptions {
+      batch_size: 1,
+      fast_path_work: 0,
+      pool: Some(Arc::new(WorkerPool::new(2))),
+      ..KinshipOptions::default()
+    };
+    assert_eq!(2, options.threads_num());
+    // Concurrent calculations wait for the threads of the pool in turn.
+    let results = std::thread::scope(|scope| {
+      let jobs = (0..3)
+        .map(|_| scope.spawn(|| open().calc_kinship_with_options(&options).unwrap()))
+        .collect::<Vec<_>>();
+      jobs.into_iter().map(|job| job.join().unwrap()).collect::<Vec<_>>()
+    });
+    for res in results {
+      rqtl2::testing::assert_matrix_close(&expected, &res, 3, Default::default());
+    }
+    let mut geno_parser = open();
+    let res = geno_parser.calc_kinship_with_options(&options).unwrap();
+    rqtl2::testing::assert_matrix_close(&expected, &res, 3, Default::default());
+  }
+
+  #[test]
+  fn deterministic_accumulation() {
+    use rqtl2::kinship::KinshipOptions;
+    let codes = ['A', 'H', 'B'];
+    let mut geno = String::from("marker\t1\t2\t3\t4\t5\t6\n");
+    let mut state = 7u64;
+    for line in 0..60 {
+      let genotypes = (0..6)
+        .map(|_| {
+          state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+          codes[(state >> 33) as usize % 3]
+        })
+        .collect::<String>();
+      geno.push_str(&format!("rs{}\t{}\n", line, genotypes));
+    }
+    let f =
+      create_test_file("test_geno_parsers_91.txt", &geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.1);
+    hab_mapper.insert('H', 0.55);
+    hab_mapper.insert('B', 1.3);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let single = KinshipOptions {
+      batch_size: 1,
+      threads: Some(1),
+      fast_path_work: 0,
+      ..KinshipOptions::default()
+    };
+    let expected = geno_parser.calc_kinship_with_options(&single).unwrap();
+    let options = KinshipOptions {
+      threads: Some(4),
+      deterministic: true,
+      ..single
+    };
+    // Batches merged in order are summed exactly as by a single worker.
+    for _ in 0..5 {
+      assert_eq!(expected, geno_parser.calc_kinship_with_options(&options).unwrap());
+    }
+  }
+
+  #[test]
+  fn compensated_accumulation() {
+    use rqtl2::kinship::KinshipOptions;
+    // A large product followed by many small ones, which are lost when added
+    // to it one by one.
+    let mut geno = String::from("marker\t1\t2\nrs0\tAA\n");
+    for line in 1..=1000 {
+      geno.push_str(&format!("rs{}\tBB\n", line));
+    }
+    let f =
+      create_test_file("test_geno_parsers_92.txt", &geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 1e8);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let expected = (1e16 + 1000.0) / 1001.0;
+    let batches = KinshipOptions {
+      batch_size: 100,
+      threads: Some(2),
+      fast_path_work: 0,
+      ..KinshipOptions::default()
+    };
+    let naive = geno_parser.calc_kinship_with_options(&batches).unwrap();
+    assert_ne!(naive, vec![expected; 4]);
+    let compensated = KinshipOptions {
+      compensated: true,
+      ..batches.clone()
+    };
+    let res = geno_parser.calc_kinship_with_options(&compensated).unwrap();
+    assert_eq!(res, vec![expected; 4]);
+    // The fast path.
+    let compensated = KinshipOptions {
+      compensated: true,
+      ..KinshipOptions::default()
+    };
+    let res = geno_parser.calc_kinship_with_options(&compensated).unwrap();
+    assert_eq!(res, vec![expected; 4]);
+    // Column blocks and kernels don't compensate, even on the fast path.
+    let column_blocks = KinshipOptions {
+      mode: rqtl2::kinship::ParallelMode::ColumnBlocks,
+      ..compensated.clone()
+    };
+    let kernel = KinshipOptions {
+      kernel: Some(std::sync::Arc::new(rqtl2::kinship::CpuKernel)),
+      ..compensated
+    };
+    for options in [column_blocks, kernel] {
+      let err = geno_parser.calc_kinship_with_options(&options).unwrap_err();
+      assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+    }
+  }
+
+  #[test]
+  fn geno_parser_ids_and_row_ids() {
+    let f = create_test_file(
+      "test_geno_parsers_93.txt",
+      "# comment\nmarker\t1\t2\t3\nrs1\tAHB\n\nrs2\tBBA\r\nrs3\tAAA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    assert_eq!(geno_parser.ids(), ["1", "2", "3"]);
+    assert_eq!(geno_parser.markers(), None);
+    assert_eq!(geno_parser.row_ids().unwrap(), ["rs1", "rs2", "rs3"]);
+    // The cursor is rewound, so the file is read again from the first line.
+    assert_eq!(geno_parser.read_all().unwrap().len(), 3);
+    geno_parser.set_transposed(true).unwrap();
+    assert_eq!(geno_parser.ids(), ["rs1", "rs2", "rs3"]);
+    assert_eq!(geno_parser.markers().unwrap(), ["1", "2", "3"]);
+  }
+
+  #[test]
+  fn genotype_probabilities() {
+    use rqtl2::genoprob::{CrossType, GenoprobOptions};
+    use rqtl2::io::MapParser;
+    let f = create_test_file(
+      "test_geno_parsers_94.txt",
+      "marker\t1\t2\nrs3\tBB\nrs1\tAA\nrs2\tA-\nrs4\tA-\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', rqtl2::io::na::NA);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let gmap_csv = "marker,chr,pos\nrs1,1,0\nrs2,1,10\nrs3,1,20\nrs4,2,5\nrs5,2,7\n";
+    let gmap = MapParser::new().read_from(gmap_csv.as_bytes()).unwrap();
+    let options = GenoprobOptions {
+      error_prob: 1e-8,
+      ..GenoprobOptions::default()
+    };
+    let probs = geno_parser.calc_genoprob(&gmap, &options).unwrap();
+    assert_eq!(2, probs.len());
+    assert_eq!(probs[0].markers, ["rs1", "rs2", "rs3"]);
+    assert_eq!(probs[0].genotypes, ["AA", "AB", "BB"]);
+    // rs5 has no genotypes.
+    assert_eq!(probs[1].markers, ["rs4"]);
+    assert!((probs[0].get(1, 0)[0] - 1.0).abs() < 1e-6);
+    // AA and BB at the flanking markers: a recombination on either side, so
+    // AB is twice as likely as AA or BB.
+    let between = probs[0].get(1, 1);
+    assert!((between[0] - 0.25).abs() < 1e-6);
+    assert!((between[1] - 0.5).abs() < 1e-6);
+    assert!((between[2] - 0.25).abs() < 1e-6);
+    // A single missing marker has the prior of F2.
+    assert!((probs[1].get(0, 1)[1] - 0.5).abs() < 1e-9);
+    assert_eq!(probs[0].marker(1).len(), 6);
+    // BB isn't a genotype of a backcross.
+    let backcross = GenoprobOptions {
+      cross: CrossType::parse("bc").unwrap(),
+      ..options
+    };
+    assert!(geno_parser.calc_genoprob(&gmap, &backcross).is_err());
+
+    // Genotype values of hab_mapper are matched, not the default ones.
+    let f = create_test_file(
+      "test_geno_parsers_104.txt",
+      "marker\t1\t2\nrs3\tBB\nrs1\tAA\nrs2\tA-\nrs4\tA-\n",
+    )
+    .expect("Failed to create test file.");
+    let mut counts = HashMap::new();
+    counts.insert('A', 0.0);
+    counts.insert('H', 1.0);
+    counts.insert('B', 2.0);
+    counts.insert('-', rqtl2::io::na::NA);
+    let mut counts_parser = rqtl2::util::GenoParser::new_with_file(f, counts).unwrap();
+    let counted = counts_parser.calc_genoprob(&gmap, &options).unwrap();
+    for (chromosome, counted) in probs.iter().zip(&counted) {
+      assert_eq!(chromosome.markers, counted.markers);
+      for marker in 0..chromosome.markers.len() {
+        assert_eq!(chromosome.marker(marker), counted.marker(marker));
+      }
+    }
+    assert_eq!(2.0, counted[0].scale.max);
+    let dosages = rqtl2::genoprob::expected_dosages(&counted);
+    assert!((dosages.values[4] - 2.0).abs() < 1e-5);
+    // The X chromosome model isn't implemented.
+    let gmap_csv = "marker,chr,pos\nrs1,1,0\nrs2,1,10\nrs3,X,20\n";
+    let gmap = MapParser::new().read_from(gmap_csv.as_bytes()).unwrap();
+    let err = geno_parser.calc_genoprob(&gmap, &options).unwrap_err();
+    assert_eq!("validation", rqtl2::util::error::Error::from(err).kind());
+    let skip_x = GenoprobOptions {
+      skip_x: true,
+      ..options
+    };
+    let autosomes = geno_parser.calc_genoprob(&gmap, &skip_x).unwrap();
+    assert_eq!(1, autosomes.len());
+    assert_eq!(autosomes[0].markers, ["rs1", "rs2"]);
+    assert!(geno_parser.get_report().has(rqtl2::io::codes::SKIPPED_CHROMOSOME));
+  }
+
+  #[test]
+  fn kinship_from_dosages() {
+    use rqtl2::genoprob::{expected_dosages, GenoprobOptions};
+    use rqtl2::io::MapParser;
+    use rqtl2::kinship::{calc_kinship_dosages, calc_kinship_table, KinshipOptions};
+    let f = create_test_file(
+      "test_geno_parsers_95.txt",
+      "marker\t1\t2\t3\nrs1\tAHB\nrs2\tBHA\nrs3\tAAB\nrs4\tHBB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let options = KinshipOptions::default();
+    let expected = geno_parser.calc_kinship_with_options(&options).unwrap();
+    let csv = "# dosages\nmarker,1,2,3\nrs1,0,0.5,1\nrs2,1,0.5,0\nrs3,0,0,1\nrs4,0.5,1,1\n";
+    let res = calc_kinship_dosages(csv.as_bytes(), ',', &options).unwrap();
+    assert_eq!(res.ids(), ["1", "2", "3"]);
+    assert_eq!(res.as_slice(), expected.as_slice());
+    let short = "marker,1,2,3\nrs1,0,0.5\nrs2,1,0.5,0\nrs3,0,0,1\n";
+    assert!(calc_kinship_dosages(short.as_bytes(), ',', &options).is_err());
+    // Expected dosages of genotype probabilities at fully observed markers.
+    let gmap_csv = "marker,chr,pos\nrs1,1,0\nrs2,1,50\nrs3,2,0\nrs4,2,50\n";
+    let gmap = MapParser::new().read_from(gmap_csv.as_bytes()).unwrap();
+    let genoprob_options = GenoprobOptions {
+      error_prob: 1e-10,
+      ..GenoprobOptions::default()
+    };
+    let probs = geno_parser.calc_genoprob(&gmap, &genoprob_options).unwrap();
+    let dosages = expected_dosages(&probs);
+    assert_eq!(dosages.row_ids, ["rs1", "rs2", "rs3", "rs4"]);
+    let res = calc_kinship_table(&dosages, &options).unwrap();
+    for (elem, expected_elem) in res.as_slice().iter().zip(&expected) {
+      assert!((elem - expected_elem).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  fn plink_binary_input() {
+    use rqtl2::io::PlinkReader;
+    use rqtl2::kinship::{calc_kinship_dosages, calc_kinship_plink, KinshipOptions};
+    // Genotype codes: 0 homozygous allele 1, 2 heterozygous, 3 homozygous
+    // allele 2.
+    let genotypes = [
+      [0, 2, 3, 3, 0],
+      [3, 3, 2, 0, 0],
+      [2, 0, 0, 3, 2],
+      [0, 0, 3, 2, 3],
+      [3, 2, 2, 0, 0],
+      [2, 3, 0, 0, 3],
+    ];
+    let dosages = [1.0, 0.0, 0.5, 0.0];
+    let mut bed = vec![0x6c, 0x1b, 0x01];
+    let mut csv = String::from("marker,i1,i2,i3,i4,i5\n");
+    let mut bim = String::new();
+    for (variant, codes) in genotypes.iter().enumerate() {
+      for chunk in codes.chunks(4) {
+        bed.push(chunk.iter().enumerate().fold(0u8, |byte, (k, code)| byte | code << (2 * k)));
+      }
+      let line = codes.iter().map(|code| dosages[*code as usize].to_string());
+      csv.push_str(&format!("rs{},{}\n", variant, line.collect::<Vec<String>>().join(",")));
+      bim.push_str(&format!("1\trs{}\t0\t{}\tA\tG\n", variant, 1000 * (variant + 1)));
+    }
+    let fam = (1..=5).map(|i| format!("f{} i{} 0 0 1 -9\n", i, i)).collect::<String>();
+    let prefix = env::temp_dir().join("test_plink_1");
+    fs::write(prefix.with_extension("bed"), &bed).unwrap();
+    fs::write(prefix.with_extension("bim"), bim).unwrap();
+    fs::write(prefix.with_extension("fam"), fam).unwrap();
+
+    let reader = PlinkReader::open(&prefix, false).unwrap();
+    assert_eq!(reader.ids(), ["i1", "i2", "i3", "i4", "i5"]);
+    assert_eq!(reader.variants()[2].position_bp, 3000);
+    let options = KinshipOptions::default();
+    let res = calc_kinship_plink(&prefix, &options).unwrap();
+    let expected = calc_kinship_dosages(csv.as_bytes(), ',', &options).unwrap();
+    assert_eq!(res, expected);
+
+    // A truncated .bed file.
+    fs::write(prefix.with_extension("bed"), &bed[..bed.len() - 1]).unwrap();
+    assert!(PlinkReader::open(&prefix, false).is_err());
+  }
+
+  #[test]
+  fn vcf_input() {
+    use rqtl2::io::VcfReader;
+    use rqtl2::kinship::{calc_kinship_dosages, calc_kinship_vcf, KinshipOptions, SnpSource};
+    let vcf = "##fileformat=VCFv4.3\n\
+               ##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">\n\
+               #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2\ts3\ts4\n\
+               1\t100\trs1\tA\tG\t.\tPASS\t.\tGT:DP\t0/0:10\t0/1:12\t1/1:8\t0/0:9\n\
+               1\t200\t.\tC\tT\t.\tPASS\t.\tGT\t0|1\t1|1\t0|0\t1|0\n\
+               2\t300\trs3\tG\tA,T\t.\tPASS\t.\tDP:GT\t5:2/1\t5:0/2\t5:0/0\t5:1/1\n\
+               2\t400\trs4\tT\tC\t.\tPASS\t.\tGT\t1/1\t0/0\t0/1\t0/1\n\
+               3\t500\trs5\tA\tC\t.\tPASS\t.\tGT\t0/1\t0/0\t1/1\t1/1\n";
+    let mut path = env::temp_dir();
+    path.push("test_vcf_1.vcf");
+    fs::write(&path, vcf).unwrap();
+    let mut row_ids = Vec::new();
+    let mut buf = vec![0.0; 4 * 10];
+    let mut reader = VcfReader::new(vcf.as_bytes(), false).unwrap();
+    assert_eq!(reader.ids(), ["s1", "s2", "s3", "s4"]);
+    assert_eq!(5, reader.read_batch(&mut buf, Some(&mut row_ids)).unwrap());
+    assert_eq!(row_ids, ["rs1", "1:200", "rs3", "rs4", "rs5"]);
+    let csv = "marker,s1,s2,s3,s4\n\
+               rs1,0,0.5,1,0\n\
+               1:200,0.5,1,0,0.5\n\
+               rs3,1,0.5,0,1\n\
+               rs4,1,0,0.5,0.5\n\
+               rs5,0.5,0,1,1\n";
+    let options = KinshipOptions::default();
+    let expected = calc_kinship_dosages(csv.as_bytes(), ',', &options).unwrap();
+    assert_eq!(calc_kinship_vcf(&path, &options).unwrap(), expected);
+
+    let missing = vcf.replace("0/0:9", "./.:9");
+    assert!(VcfReader::new(missing.as_bytes(), false)
+      .and_then(|mut reader| reader.read_batch(&mut buf, None))
+      .is_err());
+    let compressed: &[u8] = &[0x1f, 0x8b, 0x08, 0x04];
+    assert!(VcfReader::new(compressed, false).is_err());
+  }
+
+  #[test]
+  fn bimbam_round_trip() {
+    use rqtl2::io::{write_hard_calls, BimbamReader, HardCaller, NumberFormat};
+    use rqtl2::kinship::{calc_kinship_observed, KinshipOptions};
+    let f = create_test_file(
+      "test_geno_parsers_96.txt",
+      "marker\t1\t2\t3\nrs1\tAHB\nrs2\tBH-\nrs3\tAAB\nrs4\tHBB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', rqtl2::io::na::NA);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    let mut bimbam = Vec::new();
+    assert_eq!(4, geno_parser.write_bimbam(&mut bimbam, &NumberFormat::default()).unwrap());
+    let summary = geno_parser.write_bimbam_summarized(&mut Vec::new(), &NumberFormat::default());
+    let summary = summary.unwrap();
+    assert_eq!((4, 4, 3), (summary.markers_read, summary.markers_used, summary.individuals));
+    let bimbam = String::from_utf8(bimbam).unwrap();
+    assert!(bimbam.starts_with("rs1, B, A, 0, 1, 2\nrs2, B, A, 2, 1, NA\n"));
+    let mut reader = BimbamReader::new(bimbam.as_bytes(), false).unwrap();
+    assert!(calc_kinship_observed(&mut reader, &KinshipOptions::default(), &mut []).is_err());
+
+    let options = KinshipOptions {
+      missing: rqtl2::kinship::MissingPolicy::MeanImpute,
+      ..KinshipOptions::default()
+    };
+    let expected = geno_parser.calc_kinship_with_options(&options).unwrap();
+    let mut reader = BimbamReader::new(bimbam.as_bytes(), true).unwrap();
+    assert_eq!(calc_kinship_observed(&mut reader, &options, &mut []).unwrap(), expected);
+
+    // Back to R/qtl2 format.
+    let mut reader = BimbamReader::new(bimbam.as_bytes(), true).unwrap();
+    let ids = geno_parser.get_markers().clone();
+    let mut geno = Vec::new();
+    write_hard_calls(&mut reader, &ids, &mut geno, &HardCaller::new(&hab_mapper, 0.1).unwrap())
+      .unwrap();
+    assert_eq!(
+      String::from_utf8(geno).unwrap(),
+      "marker\t1\t2\t3\nrs1\tAHB\nrs2\tBH-\nrs3\tAAB\nrs4\tHBB\n"
+    );
+
+    // Genotypes counted 0 to 2 keep their values and codes.
+    let mut counts = HashMap::new();
+    counts.insert('0', 0.0);
+    counts.insert('1', 1.0);
+    counts.insert('2', 2.0);
+    let dosages = "marker,1,2,3\nrs1,0,1,2\nrs2,2,2,0\n";
+    let mut source = rqtl2::io::DosageLines::new(dosages.as_bytes(), ',', false).unwrap();
+    let mut counted = Vec::new();
+    rqtl2::io::write_bimbam(&mut source, &counts, &mut counted, &NumberFormat::default()).unwrap();
+    assert_eq!("rs1, 2, 0, 0, 1, 2\nrs2, 2, 0, 2, 2, 0\n", String::from_utf8(counted).unwrap());
+  }
+
+  #[test]
+  fn geno_writer_round_trip() {
+    use rqtl2::io::{GenoMatrix, GenoWriter, HardCaller};
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', rqtl2::io::na::NA);
+    let caller = HardCaller::new(&hab_mapper, 0.1).unwrap();
+    let ids = vec![String::from("1"), String::from("2"), String::from("3")];
+    let comments = vec![String::from(" simulated genotypes")];
+    let mut writer = GenoWriter::new(Vec::new(), &ids, &comments).unwrap();
+    writer.write_codes("rs1", &['A', 'H', 'B']).unwrap();
+    writer.write_dosages("rs2", &[1.0, 0.48, rqtl2::io::na::NA], &caller).unwrap();
+    let matrix = GenoMatrix {
+      marker_ids: vec![String::from("rs3"), String::from("rs4")],
+      ids: ids.clone(),
+      values: vec![0.0, 0.0, 1.0, 0.5, 1.0, 1.0],
+    };
+    writer.write_matrix(&matrix, &caller).unwrap();
+    assert_eq!(4, writer.markers_num());
+    assert!(writer.write_codes("rs5", &['A', 'H']).is_err());
+    assert!(writer.write_codes("rs5", &['A', ' ', 'B']).is_err());
+    assert!(writer.write_codes("rs\t5", &['A', 'H', 'B']).is_err());
+    assert_eq!(4, writer.markers_num());
+    let geno = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    assert_eq!(
+      geno,
+      "# simulated genotypes\nmarker\t1\t2\t3\nrs1\tAHB\nrs2\tBH-\nrs3\tAAB\nrs4\tHBB\n"
+    );
+    assert!(GenoWriter::new(Vec::new(), &[String::from("a\tb")], &[]).is_err());
+    assert!(GenoWriter::new(Vec::new(), &ids, &[String::from("a\nb")]).is_err());
+
+    let f =
+      create_test_file("test_geno_parsers_97.txt", &geno).expect("Failed to create test file.");
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    assert_eq!(geno_parser.get_comments(), &comments);
+    assert_eq!(geno_parser.get_markers(), &ids);
+    let snps = geno_parser.read_all().unwrap();
+    assert_eq!(snps[0], (String::from("rs1"), vec![0.0, 0.5, 1.0]));
+    assert_eq!(snps[1].0, "rs2");
+    assert!(rqtl2::io::na::is_na(snps[1].1[2]));
+    assert_eq!(snps[3], (String::from("rs4"), vec![0.5, 1.0, 1.0]));
+  }
+
+  #[test]
+  fn simulated_genotypes() {
+    use rqtl2::kinship::{KinshipOptions, MissingPolicy};
+    use rqtl2::simulate::{expected_kinship, simulate_geno_file, simulate_genotypes};
+    use rqtl2::simulate::SimulationOptions;
+    use rqtl2::testing::{assert_matrix_close, Tolerance};
+    let options = SimulationOptions {
+      ids_num: 30,
+      markers_num: 200,
+      missing_rate: 0.05,
+      seed: 7,
+      ..SimulationOptions::default()
+    };
+    let matrix = simulate_genotypes(&options).unwrap();
+    assert_eq!((200, 30), (matrix.markers_num(), matrix.ids_num()));
+    let bits = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect::<Vec<u64>>();
+    assert_eq!(bits(&matrix.values), bits(&simulate_genotypes(&options).unwrap().values));
+    let missing = rqtl2::io::na::count_na(&matrix.values) as f64 / matrix.values.len() as f64;
+    assert!((0.03..0.07).contains(&missing));
+    let fewer = SimulationOptions {
+      markers_num: 10,
+      ..options.clone()
+    };
+    assert_eq!(bits(&simulate_genotypes(&fewer).unwrap().values), bits(&matrix.values[..300]));
+    let reseeded = SimulationOptions {
+      seed: 8,
+      ..options.clone()
+    };
+    assert_ne!(bits(&simulate_genotypes(&reseeded).unwrap().values), bits(&matrix.values));
+
+    let kinship_options = KinshipOptions {
+      missing: MissingPolicy::MeanImpute,
+      ..KinshipOptions::default()
+    };
+    let mut path = env::temp_dir();
+    path.push("test_geno_parsers_98.txt");
+    let expected = simulate_geno_file(&path, &options, &kinship_options).unwrap();
+    assert_eq!(expected, expected_kinship(&matrix, &kinship_options).unwrap());
+    let path = path.to_str().unwrap().to_string();
+    let mut geno_parser = rqtl2::util::GenoParser::new(path, options.hab_mapper()).unwrap();
+    assert_eq!(geno_parser.get_markers(), &matrix.ids);
+    let kinship = geno_parser.calc_kinship_matrix(&kinship_options).unwrap();
+    assert_eq!(kinship.ids(), expected.ids());
+    assert_matrix_close(expected.as_slice(), kinship.as_slice(), 30, Tolerance::default());
+
+    for invalid in [
+      SimulationOptions { ids_num: 0, ..SimulationOptions::default() },
+      SimulationOptions { allele_freq: (0.5, 0.1), ..SimulationOptions::default() },
+      SimulationOptions { missing_rate: 1.0, ..SimulationOptions::default() },
+      SimulationOptions { missing_code: 'H', ..SimulationOptions::default() },
+    ] {
+      assert!(simulate_genotypes(&invalid).is_err());
+    }
+  }
+
+  #[test]
+  fn kinship_eigen() {
+    use rqtl2::kinship::KinshipOptions;
+    use rqtl2::testing::{assert_matrix_close, Tolerance};
+    let f = create_test_file(
+      "test_geno_parsers_99.txt",
+      "marker\t1\t2\t3\t4\nrs1\tAHBB\nrs2\tBHAA\nrs3\tAABH\nrs4\tHBBA\nrs5\tABAB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let kinship = geno_parser.calc_kinship_matrix(&KinshipOptions::default()).unwrap();
+    let eigen = kinship.eigen().unwrap();
+    let n = 4;
+    assert_eq!(n, eigen.values.len());
+    assert!(eigen.values.windows(2).all(|pair| pair[0] <= pair[1]));
+    let (mut reconstructed, mut gram) = (vec![0.0; n * n], vec![0.0; n * n]);
+    for i in 0..n {
+      for j in 0..n {
+        for k in 0..n {
+          let (u_ik, u_jk) = (eigen.vectors[i * n + k], eigen.vectors[j * n + k]);
+          reconstructed[i * n + j] += u_ik * eigen.values[k] * u_jk;
+          gram[i * n + j] += eigen.vectors[k * n + i] * eigen.vectors[k * n + j];
+        }
+      }
+    }
+    assert_matrix_close(kinship.as_slice(), &reconstructed, n, Tolerance::absolute(1e-12));
+    let identity = (0..n * n).map(|pos| (pos / n == pos % n) as u8 as f64).collect::<Vec<f64>>();
+    assert_matrix_close(&identity, &gram, n, Tolerance::absolute(1e-12));
+
+    let mut data = kinship.into_vec();
+    data[1] = rqtl2::io::na::NA;
+    let ids = (1..=n).map(|id| id.to_string()).collect();
+    assert!(rqtl2::kinship::KinshipMatrix::new(ids, data).unwrap().eigen().is_err());
+  }
+
+  #[test]
+  fn kinship_gcta_output() {
+    use rqtl2::kinship::{KinshipMatrix, MarkerCounts};
+    let ids = vec![String::from("a"), String::from("b"), String::from("c")];
+    let data = vec![1.0, 0.25, 0.5, 0.25, 0.75, 0.125, 0.5, 0.125, 1.5];
+    let matrix = KinshipMatrix::new(ids, data).unwrap();
+    let (mut grm, mut counts, mut ids) = (Vec::new(), Vec::new(), Vec::new());
+    matrix.write_gcta_to(&mut grm, &mut counts, &mut ids, MarkerCounts::Uniform(42)).unwrap();
+    let floats = |bytes: &[u8]| {
+      bytes
+        .chunks(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect::<Vec<f32>>()
+    };
+    assert_eq!(floats(&grm), vec![1.0, 0.25, 0.75, 0.5, 0.125, 1.5]);
+    assert_eq!(floats(&counts), vec![42.0; 6]);
+    assert_eq!(String::from_utf8(ids).unwrap(), "a\ta\nb\tb\nc\tc\n");
+
+    let mut prefix = env::temp_dir();
+    prefix.push("test_kinship_gcta");
+    matrix.write_gcta(&prefix, MarkerCounts::Uniform(42)).unwrap();
+    let path = |extension: &str| format!("{}{}", prefix.to_str().unwrap(), extension);
+    assert_eq!(fs::read(path(".grm.bin")).unwrap(), grm);
+    assert_eq!(fs::read(path(".grm.N.bin")).unwrap(), counts);
+    assert_eq!(fs::read_to_string(path(".grm.id")).unwrap(), "a\ta\nb\tb\nc\tc\n");
+
+    // Pairs genotyped at different amounts of markers.
+    let pair_counts = [40.0, 38.0, 41.0, 38.0, 39.0, 37.0, 41.0, 37.0, 42.0];
+    let mut counts = Vec::new();
+    let markers = MarkerCounts::PerPair(&pair_counts);
+    matrix.write_gcta_to(&mut Vec::new(), &mut counts, &mut Vec::new(), markers).unwrap();
+    assert_eq!(floats(&counts), vec![40.0, 38.0, 39.0, 41.0, 37.0, 42.0]);
+    let markers = MarkerCounts::PerPair(&pair_counts[..4]);
+    assert!(matrix.write_gcta_to(&mut Vec::new(), &mut counts, &mut Vec::new(), markers).is_err());
+
+    let spaced = KinshipMatrix::new(vec![String::from("a b")], vec![1.0]).unwrap();
+    let (mut grm, mut counts) = (Vec::new(), Vec::new());
+    let markers = MarkerCounts::Uniform(1);
+    assert!(spaced.write_gcta_to(&mut grm, &mut counts, &mut Vec::new(), markers).is_err());
+  }
+
+  #[test]
+  fn ibs_along_with_kinship() {
+    use rqtl2::kinship::{calc_kinship_parallel_ibs, GramOrientation, KinshipOptions};
+    use rqtl2::kinship::MissingPolicy;
+    use rqtl2::prelude::{Analysis, AnalysisResult, Dataset};
+    use rqtl2::testing::{assert_matrix_close, Tolerance};
+    let geno = "marker\t1\t2\t3\nrs1\tAHB\nrs2\tBB-\nrs3\tAAB\nrs4\tHBB\n";
+    let f =
+      create_test_file("test_geno_parsers_100.txt", geno).expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', rqtl2::io::na::NA);
+    let options = KinshipOptions {
+      missing: MissingPolicy::MeanImpute,
+      ..KinshipOptions::default()
+    };
+    let expected_ibs = vec![1.0, 0.75, 1.0 / 6.0, 0.75, 1.0, 0.5, 1.0 / 6.0, 0.5, 1.0];
+    let mut lines = std::io::BufReader::new(geno.as_bytes()).lines();
+    lines.next();
+    let (kinship, ibs) = calc_kinship_parallel_ibs(&mut lines, 3, &hab_mapper, &options).unwrap();
+    assert_matrix_close(&expected_ibs, &ibs, 3, Tolerance::default());
+
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper.clone()).unwrap();
+    assert_eq!(kinship, geno_parser.calc_kinship_with_options(&options).unwrap());
+    let mut dataset = Dataset::new(geno_parser);
+    let results = dataset.compute(&[Analysis::Ibs, Analysis::Kinship(options.clone())]).unwrap();
+    match &results[..] {
+      [AnalysisResult::Ibs(ibs), AnalysisResult::Kinship(dataset_kinship)] => {
+        assert_matrix_close(&expected_ibs, ibs, 3, Tolerance::default());
+        assert_eq!(&kinship, dataset_kinship);
+      }
+      _ => panic!("Unexpected results."),
+    }
+    assert!(dataset.compute(&[Analysis::Ibs, Analysis::Ibs]).is_err());
+
+    // IBS covers the markers the Kinship matrix does, on several threads.
+    let markers = ["rs1", "rs3", "rs4"].iter().map(|marker| marker.to_string()).collect();
+    let selected = KinshipOptions {
+      markers: Some(std::sync::Arc::new(rqtl2::kinship::MarkerSet::new(markers))),
+      threads: Some(3),
+      ..options.clone()
+    };
+    let expected_ibs =
+      vec![1.0, 2.0 / 3.0, 1.0 / 6.0, 2.0 / 3.0, 1.0, 0.5, 1.0 / 6.0, 0.5, 1.0];
+    let results = dataset.compute(&[Analysis::Ibs, Analysis::Kinship(selected.clone())]).unwrap();
+    match &results[0] {
+      AnalysisResult::Ibs(ibs) => assert_matrix_close(&expected_ibs, ibs, 3, Tolerance::default()),
+      _ => panic!("Unexpected results."),
+    }
+    let mut lines = std::io::BufReader::new(geno.as_bytes()).lines();
+    lines.next();
+    let (_, ibs) = calc_kinship_parallel_ibs(&mut lines, 3, &hab_mapper, &selected).unwrap();
+    assert_matrix_close(&expected_ibs, &ibs, 3, Tolerance::default());
+
+    let options = KinshipOptions {
+      orientation: GramOrientation::SnpsBySnps,
+      ..options
+    };
+    let mut lines = std::io::BufReader::new(geno.as_bytes()).lines();
+    lines.next();
+    assert!(calc_kinship_parallel_ibs(&mut lines, 3, &hab_mapper, &options).is_err());
+  }
+
+  #[test]
+  fn geno_stats_pass() {
+    use rqtl2::prelude::{Analysis, AnalysisResult, Dataset};
+    let f = create_test_file(
+      "test_geno_parsers_101.txt",
+      "#comment\nmarker\t1\t2\t3\t4\nrs1\tAHBB\nrs2\tBB--\nrs3\tAAAA\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    hab_mapper.insert('-', rqtl2::io::na::NA);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let stats = geno_parser.geno_stats().unwrap();
+    let markers = &stats.markers;
+    assert_eq!(3, markers.len());
+    assert_eq!("rs1", markers[0].id);
+    assert_eq!(0.625, markers[0].allele_freq);
+    assert_eq!(0.0, markers[0].missing_rate);
+    assert_eq!(0.25, markers[0].het_rate);
+    assert_eq!((1.0, 0.5), (markers[1].allele_freq, markers[1].missing_rate));
+    assert_eq!(0.0, markers[2].maf());
+    let individuals = &stats.individuals;
+    assert_eq!(vec!["1", "2", "3", "4"], individuals.iter().map(|s| &s.id).collect::<Vec<_>>());
+    assert_eq!(0.0, individuals[0].missing_rate);
+    assert_eq!(1.0 / 3.0, individuals[2].missing_rate);
+    assert_eq!(1.0 / 3.0, individuals[1].het_rate);
+
+    // The file is rewound, so stats are the same as of Dataset.
+    assert_eq!(stats, geno_parser.geno_stats().unwrap());
+    let mut dataset = Dataset::new(geno_parser);
+    let results = dataset.compute(&[Analysis::MarkerStats, Analysis::IndividualStats]).unwrap();
+    match &results[..] {
+      [AnalysisResult::MarkerStats(markers), AnalysisResult::IndividualStats(individuals)] => {
+        assert_eq!(&stats.markers, markers);
+        assert_eq!(&stats.individuals, individuals);
+      }
+      _ => panic!("Unexpected results."),
+    }
+  }
+
+  #[test]
+  fn related_pairs_and_duplicates() {
+    use rqtl2::kinship::{KinshipMatrix, KinshipOptions, RelatedPair};
+    use rqtl2::io::na::NA;
+    let ids = ["a", "b", "c", "d"].iter().map(|id| id.to_string()).collect();
+    let data = vec![
+      1.0, 0.98, 0.3, NA,
+      0.98, 1.0, 0.25, 0.1,
+      0.3, 0.25, 0.5, 0.2,
+      NA, 0.1, 0.2, 2.0,
+    ];
+    let matrix = KinshipMatrix::new(ids, data).unwrap();
+    let pair = |id1: &str, id2: &str, value: f64| RelatedPair {
+      id1: String::from(id1),
+      id2: String::from(id2),
+      value,
+    };
+    assert_eq!(
+      matrix.find_related_pairs(0.25),
+      vec![pair("a", "b", 0.98), pair("a", "c", 0.3), pair("b", "c", 0.25)]
+    );
+    assert!(matrix.find_related_pairs(1.5).is_empty());
+    // The pair with NA kinship is skipped.
+    let pairs = matrix.find_duplicates(-10.0);
+    assert!(pairs.iter().all(|pair| (pair.id1.as_str(), pair.id2.as_str()) != ("a", "d")));
+
+    // Individual 3 is a copy of individual 1.
+    let f = create_test_file(
+      "test_geno_parsers_102.txt",
+      "marker\t1\t2\t3\nrs1\tAHA\nrs2\tBAB\nrs3\tHBH\nrs4\tBBB\n",
+    )
+    .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let kinship = geno_parser.calc_kinship_matrix(&KinshipOptions::default()).unwrap();
+    let duplicates = kinship.find_duplicates(0.99);
+    assert_eq!(1, duplicates.len());
+    assert_eq!(("1", "3"), (duplicates[0].id1.as_str(), duplicates[0].id2.as_str()));
+    assert!((duplicates[0].value - 1.0).abs() < 1e-12);
+
+    // Unrelated individuals with the common allele coded 1 (MAF 0.1) look
+    // alike in the raw matrix, but not once it's centered.
+    use rqtl2::simulate::{expected_kinship, simulate_genotypes, SimulationOptions};
+    use rqtl2::util::transform::DosageTransform;
+    let simulation = SimulationOptions {
+      ids_num: 20,
+      markers_num: 2000,
+      allele_freq: (0.9, 0.9),
+      ..SimulationOptions::default()
+    };
+    let mut genotypes = simulate_genotypes(&simulation).unwrap();
+    // Individual 20 is a copy of individual 1.
+    for marker in genotypes.values.chunks_mut(20) {
+      marker[19] = marker[0];
+    }
+    let raw = expected_kinship(&genotypes, &KinshipOptions::default()).unwrap();
+    let (ii, jj, ij) = (raw.as_slice()[21], raw.as_slice()[42], raw.as_slice()[2]);
+    assert!(ij / (ii * jj).sqrt() > 0.75);
+    let centered_options = KinshipOptions {
+      transform: DosageTransform::Center,
+      ..KinshipOptions::default()
+    };
+    let centered = expected_kinship(&genotypes, &centered_options).unwrap();
+    for kinship in [&raw, &centered] {
+      let duplicates = kinship.find_duplicates(0.5);
+      assert_eq!(vec![pair("ind1", "ind20", duplicates[0].value)], duplicates);
+      assert!((duplicates[0].value - 1.0).abs() < 1e-9);
+    }
+    let values = |kinship: &KinshipMatrix| {
+      kinship.find_duplicates(-1.0).iter().map(|pair| pair.value).collect::<Vec<_>>()
+    };
+    rqtl2::testing::assert_matrix_close(&values(&centered), &values(&raw), 1, Default::default());
+  }
+
+  #[test]
+  fn backend_selection() {
+    use rqtl2::kinship::{select_backend, Backend, KinshipOptions};
+    use rqtl2::util::environment::{self, GpuDevice, GpuPlatform};
+    use std::process::Command;
+    let options = KinshipOptions::default();
+    let mut report = environment::report();
+    report.gpu_devices.clear();
+    let cpu = select_backend(Backend::Cpu, 100, &options, &report).unwrap();
+    assert!(!cpu.is_gpu() && cpu.fallback.is_none());
+    let fallback = |report: &environment::EnvironmentReport, ids_num: usize| {
+      let choice = select_backend(Backend::Auto, ids_num, &options, report).unwrap();
+      assert!(!choice.is_gpu());
+      assert!(select_backend(Backend::Gpu, ids_num, &options, report).is_err());
+      choice.fallback.unwrap()
+    };
+    assert_eq!("no CUDA device found", fallback(&report, 100));
+
+    report.gpu_devices.push(GpuDevice {
+      platform: GpuPlatform::Cuda,
+      name: String::from("Test GPU"),
+      memory: Some(1024 * 1024),
+    });
+    report.cuda_libraries = vec!["libcudart"];
+    assert_eq!("libcublas is not found", fallback(&report, 100));
+    report.cuda_libraries = vec!["libcudart", "libcublas"];
+    assert!(fallback(&report, 1000).contains("MiB of GPU memory required"));
+    // Without the memory the matrix may not fit, the GPU isn't used.
+    let mut unknown = report.clone();
+    unknown.gpu_devices[0].memory = None;
+    match cfg!(feature = "cuda") {
+      true => assert_eq!("device memory unknown", fallback(&unknown, 100)),
+      false => assert!(fallback(&unknown, 100).contains("build with the cuda feature")),
+    }
+    match cfg!(feature = "cuda") {
+      true => assert!(select_backend(Backend::Auto, 100, &options, &report).unwrap().is_gpu()),
+      false => assert!(fallback(&report, 100).contains("build with the cuda feature")),
+    }
+    assert_eq!(Some(Backend::Auto), Backend::parse("auto"));
+    assert_eq!(None, Backend::parse("tpu"));
+
+    let run = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_rqtl2-kinship")).args(args).output();
+    let control = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/data/f2.yaml");
+    let output = run(&[control.to_str().unwrap(), "--backend", "auto"]).unwrap();
+    assert!(output.status.success());
+    assert!(!output.stdout.is_empty());
+    assert_eq!(Some(2), run(&[control.to_str().unwrap(), "--backend=tpu"]).unwrap().status.code());
+  }
+
+  #[test]
+  fn invalid_batch_size_and_snps_num() {
+    use rqtl2::kinship::KinshipOptions;
+    use rqtl2::util::sketch::SketchOptions;
+    let f = create_test_file("test_geno_parsers_106.txt", "marker\t1\t2\t3\nrs1\tAHB\nrs2\tBBA\n")
+      .expect("Failed to create test file.");
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('H', 0.5);
+    hab_mapper.insert('B', 1.0);
+    let mut geno_parser = rqtl2::util::GenoParser::new_with_file(f, hab_mapper).unwrap();
+    let is_validation =
+      |err: std::io::Error| rqtl2::util::error::Error::from(err).kind() == "validation";
+    // Fewer SNP lines than ids.
+    assert!(is_validation(geno_parser.calc_kinship(10).unwrap_err()));
+    let timed = geno_parser.calc_kinship_timed(&KinshipOptions::default(), None);
+    assert!(is_validation(timed.unwrap_err()));
+    let no_batch = KinshipOptions {
+      batch_size: 0,
+      ..KinshipOptions::default()
+    };
+    assert!(is_validation(geno_parser.calc_kinship_with_options(&no_batch).unwrap_err()));
+    let path = std::env::temp_dir().join("test_kinship_out_of_core_106.bin");
+    let mapped = geno_parser.calc_kinship_out_of_core(&path, &no_batch);
+    assert!(is_validation(mapped.err().unwrap()));
+    let sketch = geno_parser.calc_kinship_sketch(&no_batch, &SketchOptions::default());
+    assert!(is_validation(sketch.err().unwrap()));
+  }
+
+  #[test]
+  fn geno_parser_iter_io_error() {
+    use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+    // Fails every read past limit, as a broken disk or connection would.
+    struct Failing {
+      inner: Cursor<Vec<u8>>,
+      limit: u64,
+    }
+    impl Read for Failing {
+      fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.inner.position() >= self.limit {
+          return Err(std::io::Error::other("device is gone"));
+        }
+        let len = buf.len().min((self.limit - self.inner.position()) as usize);
+        self.inner.read(&mut buf[..len])
+      }
+    }
+    impl Seek for Failing {
+      fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+      }
+    }
+    let geno = b"marker\t10\t12\nrs1\tAB\nrs2\tBA\nrs3\tAA\n".to_vec();
+    let failing = Failing {
+      inner: Cursor::new(geno),
+      limit: 30,
+    };
+    let mut hab_mapper = HashMap::new();
+    hab_mapper.insert('A', 0.0);
+    hab_mapper.insert('B', 1.0);
+    let reader = BufReader::with_capacity(4, failing);
+    let mut geno_parser = rqtl2::util::GenoParser::from_reader(reader, hab_mapper).unwrap();
+    let records = geno_parser.iter().unwrap().take(10).collect::<Vec<_>>();
+    assert_eq!(3, records.len());
+    assert_eq!("rs2", records[1].as_ref().unwrap().0);
+    assert!(records[2].is_err());
+  }
 }